@@ -0,0 +1,54 @@
+//! Registering a burst of copies of one hot post, one at a time and in bulk.
+//! Run with `cargo bench --bench register_forwards`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use telegram_duplicate_message_checker::tracker::{
+    DuplicateTracker, ForwardLocation, OriginalMessageId,
+};
+
+/// `n` copies of one post, each in its own chat, every tenth one repeated.
+fn burst(n: i64) -> Vec<(OriginalMessageId, ForwardLocation)> {
+    let post = OriginalMessageId {
+        peer_id: -1001,
+        message_id: 7,
+    };
+    (0..n)
+        .chain((0..n).step_by(10))
+        .map(|i| (post.clone(), ForwardLocation::new(1000 + i, 50)))
+        .collect()
+}
+
+fn register_forwards(c: &mut Criterion) {
+    let mut group = c.benchmark_group("register_forwards");
+    for n in [1_000, 5_000] {
+        let copies = burst(n);
+        group.bench_with_input(BenchmarkId::new("one_at_a_time", n), &copies, |b, copies| {
+            b.iter_batched(
+                || copies.clone(),
+                |copies| {
+                    let mut tracker = DuplicateTracker::default();
+                    for (original, forward) in copies {
+                        tracker.register_forward(original, forward);
+                    }
+                    tracker
+                },
+                BatchSize::SmallInput,
+            )
+        });
+        group.bench_with_input(BenchmarkId::new("bulk", n), &copies, |b, copies| {
+            b.iter_batched(
+                || copies.clone(),
+                |copies| {
+                    let mut tracker = DuplicateTracker::default();
+                    tracker.register_forwards(copies);
+                    tracker
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, register_forwards);
+criterion_main!(benches);
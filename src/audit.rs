@@ -0,0 +1,155 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use tracing::warn;
+
+use crate::marker::MarkOutcome;
+use crate::tracker::{epoch_secs, ForwardLocation, OriginalMessageId};
+
+/// One line of the audit log: a single mark-read attempt.
+#[derive(Debug, Serialize)]
+pub struct AuditEntry<'a> {
+    /// Unix time of the attempt, in seconds.
+    pub ts: u64,
+    pub chat_id: i64,
+    pub chat_name: &'a str,
+    pub message_id: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_msg_id: Option<i32>,
+    /// The original post this location is a copy of.
+    pub original: &'a OriginalMessageId,
+    /// Whether the read was sent.
+    pub ok: bool,
+    /// Why the read was left out, if it was skipped rather than failed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub skipped: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl<'a> AuditEntry<'a> {
+    /// Describe the outcome of marking `location` read, timestamped now.
+    pub fn new(
+        original: &'a OriginalMessageId,
+        location: &ForwardLocation,
+        chat_name: &'a str,
+        result: &std::result::Result<MarkOutcome, impl std::fmt::Display>,
+    ) -> Self {
+        AuditEntry {
+            ts: epoch_secs(),
+            chat_id: location.chat_id,
+            chat_name,
+            message_id: location.message_id,
+            top_msg_id: location.top_msg_id,
+            original,
+            ok: matches!(result, Ok(MarkOutcome::Marked)),
+            skipped: match result {
+                Ok(MarkOutcome::Skipped(reason)) => Some(reason.as_str()),
+                _ => None,
+            },
+            error: result.as_ref().err().map(|e| e.to_string()),
+        }
+    }
+}
+
+/// Append-only JSONL record of every mark-read, separate from the tracing
+/// logs so it can be analyzed later. Writes are buffered; call `flush`
+/// periodically and on shutdown.
+pub struct AuditLog {
+    writer: Mutex<BufWriter<File>>,
+}
+
+impl AuditLog {
+    /// Open `path` for appending, creating it if needed.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open audit log {}", path.display()))?;
+        Ok(AuditLog {
+            writer: Mutex::new(BufWriter::new(file)),
+        })
+    }
+
+    /// Append one entry. Failures are logged rather than returned, so a full
+    /// disk never stops reads from being propagated.
+    pub fn record(&self, entry: &AuditEntry) {
+        let mut writer = self.writer.lock().unwrap();
+        let result = serde_json::to_writer(&mut *writer, entry)
+            .map_err(std::io::Error::from)
+            .and_then(|()| writer.write_all(b"\n"));
+        if let Err(e) = result {
+            warn!("Failed to write audit log entry: {}", e);
+        }
+    }
+
+    /// Write buffered entries out to the file.
+    pub fn flush(&self) {
+        if let Err(e) = self.writer.lock().unwrap().flush() {
+            warn!("Failed to flush audit log: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::marker::SkipReason;
+
+    #[test]
+    fn appends_one_json_object_per_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        std::fs::write(&path, "{\"existing\":true}\n").unwrap();
+
+        let original = OriginalMessageId {
+            peer_id: -1005,
+            message_id: 7,
+        };
+        let log = AuditLog::open(&path).unwrap();
+        log.record(&AuditEntry::new(
+            &original,
+            &ForwardLocation::new(10, 50),
+            "News",
+            &Ok::<_, &str>(MarkOutcome::Marked),
+        ));
+        log.record(&AuditEntry::new(
+            &original,
+            &ForwardLocation::new(20, 60),
+            "unknown",
+            &Err("boom"),
+        ));
+        log.record(&AuditEntry::new(
+            &original,
+            &ForwardLocation::new(30, 70),
+            "Muted",
+            &Ok::<_, &str>(MarkOutcome::Skipped(SkipReason::Muted)),
+        ));
+        log.flush();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 4, "existing content is kept");
+
+        let ok: serde_json::Value = serde_json::from_str(lines[1]).unwrap();
+        assert_eq!(ok["chat_name"], "News");
+        assert_eq!(ok["ok"], true);
+        assert!(ok.get("error").is_none());
+        assert!(ok.get("skipped").is_none());
+        assert!(ok.get("top_msg_id").is_none());
+
+        let failed: serde_json::Value = serde_json::from_str(lines[2]).unwrap();
+        assert_eq!(failed["ok"], false);
+        assert_eq!(failed["error"], "boom");
+
+        let skipped: serde_json::Value = serde_json::from_str(lines[3]).unwrap();
+        assert_eq!(skipped["ok"], false);
+        assert_eq!(skipped["skipped"], "muted");
+        assert!(skipped.get("error").is_none());
+    }
+}
@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+
+use futures::future::try_join_all;
+use tokio::sync::{Mutex, Semaphore};
+
+use crate::handler::{self, Action};
+use crate::marker::{MarkerError, ReadMarker};
+use crate::tracker::{ForwardLocation, OriginalMessageId};
+
+type Forwards = Vec<(OriginalMessageId, ForwardLocation)>;
+
+/// Execute a batch of planned actions in order, except that runs of
+/// propagations overlap, up to the marker's `max_concurrent_propagations`.
+/// Propagations touching the same chat still run one after the other, in
+/// the order they were planned. Other actions (caching peers, replies)
+/// wait for earlier propagations and finish before later ones, since a
+/// later propagation may need the peer they cache.
+pub async fn execute_batch<M: ReadMarker>(
+    actions: Vec<Action>,
+    marker: &mut M,
+) -> Result<(), MarkerError> {
+    let mut wave = Vec::new();
+    for action in actions {
+        match action {
+            Action::MarkForwards { forwards } => wave.push(forwards),
+            other => {
+                run_wave(std::mem::take(&mut wave), marker).await?;
+                handler::execute_action(other, marker).await?;
+            }
+        }
+    }
+    run_wave(wave, marker).await
+}
+
+/// Run propagations concurrently, each holding a permit and the locks of
+/// every chat it touches.
+async fn run_wave<M: ReadMarker>(wave: Vec<Forwards>, marker: &mut M) -> Result<(), MarkerError> {
+    if wave.is_empty() {
+        return Ok(());
+    }
+    let permits = Semaphore::new(marker.max_concurrent_propagations().max(1));
+    let chats: HashMap<i64, Mutex<()>> = wave
+        .iter()
+        .flatten()
+        .map(|(_, fwd)| (fwd.chat_id, Mutex::new(())))
+        .collect();
+
+    let (permits, chats, shared) = (&permits, &chats, &*marker);
+    let elapsed = try_join_all(wave.iter().map(|forwards| async move {
+        let mut chat_ids: Vec<i64> = forwards.iter().map(|(_, fwd)| fwd.chat_id).collect();
+        chat_ids.sort_unstable();
+        chat_ids.dedup();
+        // Always lock in ascending order so overlapping propagations can't
+        // deadlock. Tokio mutexes are fair, so same-chat work keeps its order.
+        let mut guards = Vec::with_capacity(chat_ids.len());
+        for chat_id in &chat_ids {
+            guards.push(chats[chat_id].lock().await);
+        }
+        let _permit = permits.acquire().await.expect("semaphore is never closed");
+        handler::propagate(shared, forwards).await
+    }))
+    .await?;
+
+    for elapsed in elapsed.into_iter().flatten() {
+        marker.record_propagation(elapsed);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::marker::mock::MockMarker;
+    use std::time::Duration;
+
+    fn propagation(chat_ids: &[i64]) -> Action {
+        let original = OriginalMessageId {
+            peer_id: -1005,
+            message_id: 7,
+        };
+        let forwards = chat_ids
+            .iter()
+            .enumerate()
+            .map(|(i, &chat_id)| ForwardLocation::new(chat_id, i as i32 + 1))
+            .map(|fwd| (original.clone(), fwd))
+            .collect();
+        Action::MarkForwards { forwards }
+    }
+
+    fn marker(max_concurrent: usize) -> MockMarker {
+        MockMarker {
+            max_concurrent,
+            read_latency: Duration::from_secs(1),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn at_most_the_limit_run_at_once() {
+        let mut marker = marker(2);
+        let actions = (1..=5).map(|chat_id| propagation(&[chat_id])).collect();
+
+        execute_batch(actions, &mut marker).await.unwrap();
+
+        assert_eq!(marker.reads().len(), 5);
+        assert_eq!(marker.peak_in_flight(None), 2);
+        assert_eq!(marker.propagations.len(), 5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_default_limit_runs_one_at_a_time() {
+        let mut marker = marker(0);
+        let actions = (1..=3).map(|chat_id| propagation(&[chat_id])).collect();
+
+        execute_batch(actions, &mut marker).await.unwrap();
+
+        assert_eq!(marker.peak_in_flight(None), 1);
+        assert_eq!(marker.reads(), vec![(1, 1), (2, 1), (3, 1)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn same_chat_propagations_stay_serialized_in_order() {
+        let mut marker = marker(4);
+        let actions = vec![
+            propagation(&[10]),
+            propagation(&[20, 10]),
+            propagation(&[10]),
+            propagation(&[30]),
+        ];
+
+        execute_batch(actions, &mut marker).await.unwrap();
+
+        assert_eq!(marker.peak_in_flight(Some(10)), 1);
+        assert!(marker.peak_in_flight(None) > 1, "other chats overlap");
+        let in_chat_10: Vec<i32> = marker
+            .reads()
+            .into_iter()
+            .filter(|(chat_id, _)| *chat_id == 10)
+            .map(|(_, max_id)| max_id)
+            .collect();
+        assert_eq!(in_chat_10, vec![1, 2, 1]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn other_actions_run_between_waves() {
+        let mut marker = marker(4);
+        let actions = vec![
+            propagation(&[10]),
+            Action::Reply {
+                chat_id: 1,
+                peer_ref: None,
+                text: "hi".to_owned(),
+            },
+            propagation(&[20]),
+        ];
+
+        execute_batch(actions, &mut marker).await.unwrap();
+
+        assert_eq!(marker.reads(), vec![(10, 1), (20, 1)]);
+        assert_eq!(marker.sent(), vec![(1, "hi".to_owned())]);
+    }
+}
@@ -0,0 +1,70 @@
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
+#[cfg(test)]
+use tokio::sync::mpsc;
+use tracing::warn;
+
+/// On-demand checkpoint requests from operators: SIGUSR1 on Unix, e.g.
+/// `kill -USR1 <pid>` before a planned restart. Elsewhere this never fires.
+pub struct CheckpointSignal {
+    #[cfg(unix)]
+    signal: Option<Signal>,
+    /// Requests sent through a channel instead of the signal, in tests.
+    #[cfg(test)]
+    injected: Option<mpsc::UnboundedReceiver<()>>,
+}
+
+impl CheckpointSignal {
+    /// Start listening. Must be called inside the runtime. If the handler
+    /// can't be installed this logs a warning and never fires.
+    pub fn new() -> Self {
+        #[cfg(unix)]
+        {
+            let signal = signal(SignalKind::user_defined1())
+                .map_err(|e| warn!("Failed to install SIGUSR1 handler: {}", e))
+                .ok();
+            CheckpointSignal {
+                signal,
+                #[cfg(test)]
+                injected: None,
+            }
+        }
+        #[cfg(not(unix))]
+        {
+            CheckpointSignal {
+                #[cfg(test)]
+                injected: None,
+            }
+        }
+    }
+
+    /// Requests that fire on each send rather than on SIGUSR1, so tests
+    /// needn't signal the whole test process.
+    #[cfg(test)]
+    pub fn injected() -> (Self, mpsc::UnboundedSender<()>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let checkpoint = CheckpointSignal {
+            #[cfg(unix)]
+            signal: None,
+            injected: Some(rx),
+        };
+        (checkpoint, tx)
+    }
+
+    /// Wait for the next request.
+    pub async fn recv(&mut self) {
+        #[cfg(test)]
+        if let Some(injected) = &mut self.injected {
+            if injected.recv().await.is_some() {
+                return;
+            }
+        }
+        #[cfg(unix)]
+        if let Some(signal) = &mut self.signal {
+            if signal.recv().await.is_some() {
+                return;
+            }
+        }
+        std::future::pending::<()>().await;
+    }
+}
@@ -0,0 +1,593 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use tracing::info;
+
+use grammers_session::storages::SqliteSession;
+
+use crate::config::Config;
+use crate::handler::{self, Action, PlanSettings};
+use crate::marker::session_chat_name;
+use crate::{read_recording, replay_recording};
+use crate::session_string;
+use crate::tracker::{DuplicateTracker, OriginalMessageId, StateFormat};
+
+const USAGE: &str = "\
+Usage:
+  telegram-duplicate-message-checker                  Run the daemon
+  telegram-duplicate-message-checker --setup          Sign in, then exit
+  telegram-duplicate-message-checker --print-config   Print the effective configuration
+  telegram-duplicate-message-checker forget original <peer_id> <message_id>
+  telegram-duplicate-message-checker forget chat <chat_id>
+  telegram-duplicate-message-checker stats [--since <unix_ts>]
+  telegram-duplicate-message-checker cleanup --before <unix_ts>
+  telegram-duplicate-message-checker session export
+  telegram-duplicate-message-checker merge <a.json> <b.json> --out <merged.json>
+  telegram-duplicate-message-checker export --format dot
+  telegram-duplicate-message-checker simulate-read <chat_id> <max_id>
+  telegram-duplicate-message-checker replay <updates.jsonl>
+
+Maintenance commands edit the state file directly; stop the daemon first,
+or it will overwrite the change on its next save.";
+
+/// What the binary was asked to do.
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// No subcommand: run the daemon.
+    Run,
+    /// Sign in (interactively if needed) and build the peer cache once,
+    /// then exit instead of listening for updates.
+    Setup,
+    /// Drop an original and all its forwards from the state file.
+    ForgetOriginal(OriginalMessageId),
+    /// Drop everything related to a chat from the state file.
+    ForgetChat(i64),
+    /// Print summary counts, optionally of originals seen since a time.
+    Stats { since: Option<u64> },
+    /// Drop originals first seen before an absolute time.
+    Cleanup { before: u64 },
+    /// Print the session file's authorization as a portable string.
+    ExportSession,
+    /// Combine two state files into a new one.
+    Merge {
+        a: PathBuf,
+        b: PathBuf,
+        out: PathBuf,
+    },
+    /// Print the tracked state in another format.
+    Export { format: ExportFormat },
+    /// Print what reading a chat up to a message would propagate to,
+    /// without marking anything or changing the state file.
+    SimulateRead { chat_id: i64, max_id: i32 },
+    /// Plan updates recorded with `TG_RECORD_UPDATES` against the state
+    /// file and print the actions, without marking anything or saving.
+    Replay { path: PathBuf },
+    /// Print the configuration as loaded, secrets redacted, then exit.
+    ShowConfig,
+}
+
+/// Output formats of the `export` command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// GraphViz DOT graph of originals and the chats their copies are in.
+    Dot,
+}
+
+/// Parse command-line arguments (without the program name).
+pub fn parse_args<I, S>(args: I) -> Result<Command>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let args: Vec<S> = args.into_iter().collect();
+    let args: Vec<&str> = args.iter().map(|s| s.as_ref()).collect();
+
+    match args.as_slice() {
+        [] => Ok(Command::Run),
+        ["--setup"] => Ok(Command::Setup),
+        ["--print-config"] | ["config", "show"] => Ok(Command::ShowConfig),
+        ["forget", "original", peer_id, message_id] => {
+            Ok(Command::ForgetOriginal(OriginalMessageId {
+                peer_id: parse_id(peer_id, "peer_id")?,
+                message_id: parse_id(message_id, "message_id")?,
+            }))
+        }
+        ["forget", "chat", chat_id] => Ok(Command::ForgetChat(parse_id(chat_id, "chat_id")?)),
+        ["stats"] => Ok(Command::Stats { since: None }),
+        ["stats", "--since", ts] => Ok(Command::Stats {
+            since: Some(parse_id(ts, "--since")?),
+        }),
+        ["cleanup", "--before", ts] => Ok(Command::Cleanup {
+            before: parse_id(ts, "--before")?,
+        }),
+        ["session", "export"] => Ok(Command::ExportSession),
+        ["merge", a, b, "--out", out] => Ok(Command::Merge {
+            a: PathBuf::from(a),
+            b: PathBuf::from(b),
+            out: PathBuf::from(out),
+        }),
+        ["export", "--format", "dot"] => Ok(Command::Export {
+            format: ExportFormat::Dot,
+        }),
+        ["export", "--format", format] => {
+            bail!("Unsupported export format {:?}, expected dot", format)
+        }
+        ["simulate-read", chat_id, max_id] => Ok(Command::SimulateRead {
+            chat_id: parse_id(chat_id, "chat_id")?,
+            max_id: parse_id(max_id, "max_id")?,
+        }),
+        ["replay", path] => Ok(Command::Replay {
+            path: PathBuf::from(path),
+        }),
+        ["help" | "--help" | "-h"] => {
+            println!("{}", USAGE);
+            std::process::exit(0);
+        }
+        _ => bail!("Unrecognized arguments: {}\n\n{}", args.join(" "), USAGE),
+    }
+}
+
+fn parse_id<T: std::str::FromStr>(value: &str, what: &str) -> Result<T> {
+    value
+        .parse()
+        .ok()
+        .with_context(|| format!("{} must be an integer, got {:?}", what, value))
+}
+
+/// Apply `TG_SETUP_ONLY`: a plain daemon run becomes a setup run. Explicit
+/// subcommands are left alone.
+pub fn with_setup_only(command: Command, setup_only: bool) -> Command {
+    match command {
+        Command::Run if setup_only => Command::Setup,
+        command => command,
+    }
+}
+
+/// Refuse everything but the daemon itself in an ephemeral run: the other
+/// commands read or write the state or session files, which `TG_EPHEMERAL`
+/// promises never to touch.
+pub fn refuse_ephemeral(command: &Command, ephemeral: bool) -> Result<()> {
+    if !ephemeral || *command == Command::Run {
+        return Ok(());
+    }
+    match command {
+        Command::Setup => bail!("--setup fills the session file, which TG_EPHEMERAL never writes"),
+        Command::ExportSession => {
+            bail!("export-session reads the session file, which TG_EPHEMERAL never uses")
+        }
+        _ => bail!("Maintenance commands work on the state file, which TG_EPHEMERAL never uses"),
+    }
+}
+
+/// Run a maintenance command against the state file.
+pub async fn run(command: Command, config: &Config) -> Result<()> {
+    if let Command::Merge { a, b, out } = command {
+        return merge_files(&a, &b, &out, config.state_format).await;
+    }
+
+    let path = &config.state_path;
+    let mut tracker = DuplicateTracker::load(path)
+        .with_context(|| format!("Failed to load state from {}", path.display()))?;
+    tracker.set_state_format(config.state_format);
+
+    match command {
+        // Commands that need no state file are dispatched before this
+        Command::Run | Command::Setup => bail!("Run and Setup are handled by main"),
+        Command::ExportSession => bail!("ExportSession is handled by export_session"),
+        Command::ShowConfig => bail!("ShowConfig is handled by main"),
+        Command::Merge { .. } => bail!("Merge is handled by merge_files"),
+        Command::ForgetOriginal(original) => {
+            if tracker.forget_original(&original) {
+                info!(
+                    "Forgot original ({}, {})",
+                    original.peer_id, original.message_id
+                );
+            } else {
+                info!(
+                    "Original ({}, {}) is not tracked, nothing to do",
+                    original.peer_id, original.message_id
+                );
+                return Ok(());
+            }
+        }
+        Command::ForgetChat(chat_id) => {
+            let removed = tracker.forget_chat(chat_id);
+            info!("Forgot {} forwards related to chat {}", removed, chat_id);
+            if removed == 0 {
+                return Ok(());
+            }
+        }
+        Command::Stats { since } => {
+            // Offline the session is the only peer cache there is
+            let session = open_session_if_present(config).await?;
+            let name = |chat_id| session.as_ref().and_then(|s| session_chat_name(s, chat_id));
+            print!("{}", format_stats(&tracker, since, name));
+            return Ok(());
+        }
+        Command::Export {
+            format: ExportFormat::Dot,
+        } => {
+            let session = open_session_if_present(config).await?;
+            let name = |chat_id| session.as_ref().and_then(|s| session_chat_name(s, chat_id));
+            tracker.export_dot(std::io::stdout().lock(), name)?;
+            return Ok(());
+        }
+        Command::SimulateRead { chat_id, max_id } => {
+            // Planning marks the originals read, but only in this copy
+            let settings = PlanSettings {
+                sources: config.sources.clone(),
+                min_duplicates: config.min_duplicates,
+                no_mark_chats: config.no_mark_chats.clone(),
+                ..Default::default()
+            };
+            print!("{}", simulate_read(&mut tracker, chat_id, max_id, &settings));
+            if config.observe_only {
+                println!("TG_OBSERVE_ONLY is set, so the daemon would not mark these");
+            }
+            return Ok(());
+        }
+        Command::Replay { path } => {
+            let updates = read_recording(&path)?;
+            let mut settings = PlanSettings {
+                observe_only: config.observe_only,
+                content_filter: config.content_filter,
+                identity: config.identity,
+                sources: config.sources.clone(),
+                preview_len: config.preview_len,
+                min_duplicates: config.min_duplicates,
+                mark_late_copies: !config.keep_late_copies_unread,
+                anonymous_forwards: config.track_anonymous_forwards,
+                min_forward_age_secs: config.min_forward_age_secs.unwrap_or(0),
+                no_mark_chats: config.no_mark_chats.clone(),
+                forwarder_sources: config.identity_forwarder_sources.clone(),
+                trace_raw_updates: config.trace_raw_updates,
+                ..Default::default()
+            };
+            let out = replay_recording(&updates, &mut tracker, &mut settings).await;
+            print!("{}", out);
+            return Ok(());
+        }
+        Command::Cleanup { before } => {
+            if tracker.cleanup_before(before) == 0 {
+                info!("Nothing first seen before {}", before);
+                return Ok(());
+            }
+        }
+    }
+
+    tracker.save(path).await?;
+    info!("State saved to {}", path.display());
+    Ok(())
+}
+
+/// Merge state file `b` into `a` and write the result to `out`. See
+/// `DuplicateTracker::merge` for how conflicts resolve.
+async fn merge_files(a: &Path, b: &Path, out: &Path, format: StateFormat) -> Result<()> {
+    let load = |path: &Path| {
+        DuplicateTracker::load(path)
+            .with_context(|| format!("Failed to load state from {}", path.display()))
+    };
+    let mut merged = load(a)?;
+    merged.merge(load(b)?);
+    merged.set_state_format(format);
+    merged.save(out).await?;
+    info!("Merged {} and {} into {}", a.display(), b.display(), out.display());
+    print!("{}", merged.stats());
+    Ok(())
+}
+
+/// Print the session as a string for `TG_SESSION_STRING`. Unlike the other
+/// commands this reads the session file, not the state file.
+pub async fn export_session(config: &Config) -> Result<()> {
+    let path = &config.session_path;
+    if !path.exists() {
+        bail!("No session at {}; run the daemon and sign in first", path.display());
+    }
+    let session = SqliteSession::open(path.to_str().unwrap_or("session.sqlite"))
+        .await
+        .with_context(|| format!("Failed to open session {}", path.display()))?;
+    let portable = session_string::export(&session)?;
+    // Printed bare so it can be piped straight into a secret store
+    println!("{}", session_string::encode(&portable));
+    Ok(())
+}
+
+/// The session file, if the daemon has signed in yet.
+async fn open_session_if_present(config: &Config) -> Result<Option<SqliteSession>> {
+    let path = &config.session_path;
+    if !path.exists() {
+        return Ok(None);
+    }
+    let session = SqliteSession::open(path.to_str().unwrap_or("session.sqlite"))
+        .await
+        .with_context(|| format!("Failed to open session {}", path.display()))?;
+    Ok(Some(session))
+}
+
+/// Plan a read of `chat_id` up to `max_id` the way the daemon would and
+/// render which copies it would mark.
+fn simulate_read(
+    tracker: &mut DuplicateTracker,
+    chat_id: i64,
+    max_id: i32,
+    settings: &PlanSettings,
+) -> String {
+    let forwards = match handler::plan_read_event(chat_id, max_id, tracker, settings) {
+        Action::MarkForwards { forwards } => forwards,
+        _ => Vec::new(),
+    };
+    if forwards.is_empty() {
+        return format!(
+            "Reading chat {} up to message {} would not mark anything\n",
+            chat_id, max_id
+        );
+    }
+    let mut out = format!(
+        "Reading chat {} up to message {} would mark {} copies:\n",
+        chat_id,
+        max_id,
+        forwards.len()
+    );
+    for (orig, fwd) in &forwards {
+        out.push_str(&format!(
+            "  chat {} message {} (copy of ({}, {}))",
+            fwd.chat_id, fwd.message_id, orig.peer_id, orig.message_id
+        ));
+        if let Some(preview) = tracker.preview(orig) {
+            out.push_str(&format!(" {:?}", preview));
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Render the `stats` command output, naming the chats each recent
+/// original appeared in with `name`, or by id where it knows none.
+fn format_stats(
+    tracker: &DuplicateTracker,
+    since: Option<u64>,
+    name: impl Fn(i64) -> Option<String>,
+) -> String {
+    let mut out = tracker.stats().to_string();
+    if let Some(ts) = since {
+        let recent = tracker.originals_since(ts);
+        out.push_str(&format!("Originals since {}: {}\n", ts, recent.len()));
+        for orig in recent {
+            out.push_str(&format!("  ({}, {})", orig.peer_id, orig.message_id));
+            if let Some(preview) = tracker.preview(orig) {
+                out.push_str(&format!(" {:?}", preview));
+            }
+            let chats = tracker.chat_names_for_original(orig, &name);
+            out.push_str(&format!(" in {}\n", chats.join(", ")));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ephemeral_runs_refuse_everything_but_the_daemon() {
+        assert!(refuse_ephemeral(&Command::Run, true).is_ok());
+        assert!(refuse_ephemeral(&Command::Setup, true).is_err());
+        assert!(refuse_ephemeral(&Command::ExportSession, true).is_err());
+        assert!(refuse_ephemeral(&Command::ForgetChat(-100), true).is_err());
+        assert!(refuse_ephemeral(&Command::Cleanup { before: 0 }, true).is_err());
+        let merge = Command::Merge {
+            a: PathBuf::from("a.json"),
+            b: PathBuf::from("b.json"),
+            out: PathBuf::from("out.json"),
+        };
+        assert!(refuse_ephemeral(&merge, true).is_err());
+        assert!(refuse_ephemeral(&merge, false).is_ok());
+    }
+
+    #[test]
+    fn no_args_runs_daemon() {
+        assert_eq!(parse_args(Vec::<String>::new()).unwrap(), Command::Run);
+    }
+
+    #[test]
+    fn setup_flag_or_variable_selects_setup() {
+        assert_eq!(parse_args(["--setup"]).unwrap(), Command::Setup);
+        assert!(parse_args(["--setup", "now"]).is_err());
+
+        assert_eq!(with_setup_only(Command::Run, false), Command::Run);
+        assert_eq!(with_setup_only(Command::Run, true), Command::Setup);
+        assert_eq!(with_setup_only(Command::Setup, false), Command::Setup);
+        assert_eq!(
+            with_setup_only(Command::ForgetChat(5), true),
+            Command::ForgetChat(5)
+        );
+    }
+
+    #[test]
+    fn parses_forget_commands() {
+        assert_eq!(
+            parse_args(["forget", "original", "-1001234", "42"]).unwrap(),
+            Command::ForgetOriginal(OriginalMessageId {
+                peer_id: -1001234,
+                message_id: 42,
+            })
+        );
+        assert_eq!(
+            parse_args(["forget", "chat", "-1005"]).unwrap(),
+            Command::ForgetChat(-1005)
+        );
+    }
+
+    #[test]
+    fn parses_stats_and_cleanup() {
+        assert_eq!(parse_args(["stats"]).unwrap(), Command::Stats { since: None });
+        assert_eq!(
+            parse_args(["stats", "--since", "1700000000"]).unwrap(),
+            Command::Stats {
+                since: Some(1_700_000_000)
+            }
+        );
+        assert_eq!(
+            parse_args(["cleanup", "--before", "1700000000"]).unwrap(),
+            Command::Cleanup {
+                before: 1_700_000_000
+            }
+        );
+        assert!(parse_args(["cleanup"]).is_err());
+        assert!(parse_args(["stats", "--since", "-5"]).is_err());
+    }
+
+    #[test]
+    fn parses_merge() {
+        assert_eq!(
+            parse_args(["merge", "a.json", "b.json", "--out", "m.json"]).unwrap(),
+            Command::Merge {
+                a: PathBuf::from("a.json"),
+                b: PathBuf::from("b.json"),
+                out: PathBuf::from("m.json"),
+            }
+        );
+        assert!(parse_args(["merge", "a.json", "b.json"]).is_err());
+    }
+
+    #[test]
+    fn parses_export() {
+        assert_eq!(
+            parse_args(["export", "--format", "dot"]).unwrap(),
+            Command::Export {
+                format: ExportFormat::Dot
+            }
+        );
+        assert!(parse_args(["export", "--format", "svg"]).is_err());
+        assert!(parse_args(["export"]).is_err());
+    }
+
+    #[test]
+    fn parses_simulate_read() {
+        assert_eq!(
+            parse_args(["simulate-read", "-1005", "42"]).unwrap(),
+            Command::SimulateRead {
+                chat_id: -1005,
+                max_id: 42
+            }
+        );
+        assert!(parse_args(["simulate-read", "-1005"]).is_err());
+        assert!(parse_args(["simulate-read", "-1005", "x"]).is_err());
+    }
+
+    #[test]
+    fn parses_replay() {
+        assert_eq!(
+            parse_args(["replay", "updates.jsonl"]).unwrap(),
+            Command::Replay {
+                path: PathBuf::from("updates.jsonl")
+            }
+        );
+        assert!(parse_args(["replay"]).is_err());
+    }
+
+    #[test]
+    fn parses_print_config() {
+        assert_eq!(parse_args(["--print-config"]).unwrap(), Command::ShowConfig);
+        assert_eq!(parse_args(["config", "show"]).unwrap(), Command::ShowConfig);
+        assert!(parse_args(["config"]).is_err());
+    }
+
+    #[test]
+    fn simulate_read_lists_the_copies_that_would_be_marked() {
+        let mut t = DuplicateTracker::default();
+        let post = OriginalMessageId { peer_id: -1001, message_id: 7 };
+        let other = OriginalMessageId { peer_id: -1001, message_id: 8 };
+        for (chat_id, message_id) in [(10, 50), (20, 60), (30, 70)] {
+            t.register_forward(
+                post.clone(),
+                crate::tracker::ForwardLocation::new(chat_id, message_id),
+            );
+        }
+        t.register_forward(other.clone(), crate::tracker::ForwardLocation::new(10, 51));
+        t.set_preview_if_absent(&post, "hello".to_owned());
+        let settings = PlanSettings::default();
+
+        assert_eq!(
+            simulate_read(&mut t, 10, 50, &settings),
+            "Reading chat 10 up to message 50 would mark 2 copies:\n  \
+             chat 20 message 60 (copy of (-1001, 7)) \"hello\"\n  \
+             chat 30 message 70 (copy of (-1001, 7)) \"hello\"\n"
+        );
+        assert_eq!(
+            simulate_read(&mut t, 10, 49, &settings),
+            "Reading chat 10 up to message 49 would not mark anything\n"
+        );
+        // Copies of never-duplicated posts have nowhere to propagate to
+        assert!(simulate_read(&mut t, 10, 51, &settings).contains("would not mark anything"));
+    }
+
+    #[tokio::test]
+    async fn merge_files_writes_combined_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, b, out) = (
+            dir.path().join("a.json"),
+            dir.path().join("b.json"),
+            dir.path().join("merged.json"),
+        );
+        let mut t = DuplicateTracker::default();
+        t.register_forward(
+            OriginalMessageId { peer_id: 1, message_id: 100 },
+            crate::tracker::ForwardLocation::new(10, 50),
+        );
+        t.save(&a).await.unwrap();
+        let mut t = DuplicateTracker::default();
+        t.register_forward(
+            OriginalMessageId { peer_id: 2, message_id: 200 },
+            crate::tracker::ForwardLocation::new(20, 60),
+        );
+        t.save(&b).await.unwrap();
+
+        merge_files(&a, &b, &out, StateFormat::Json).await.unwrap();
+
+        assert_eq!(DuplicateTracker::load(&out).unwrap().stats().originals, 2);
+    }
+
+    #[test]
+    fn parses_session_export() {
+        assert_eq!(
+            parse_args(["session", "export"]).unwrap(),
+            Command::ExportSession
+        );
+    }
+
+    #[test]
+    fn stats_output_lists_recent_originals() {
+        let mut t = DuplicateTracker::default();
+        t.register_forward(
+            OriginalMessageId { peer_id: 1, message_id: 100 },
+            crate::tracker::ForwardLocation::new(10, 50),
+        );
+
+        t.register_forward(
+            OriginalMessageId { peer_id: 1, message_id: 100 },
+            crate::tracker::ForwardLocation::new(20, 60),
+        );
+
+        let out = format_stats(&t, None, |_| None);
+        assert!(out.contains("Originals:      1"));
+        assert!(!out.contains("since"));
+
+        let out = format_stats(&t, Some(0), |_| None);
+        assert!(out.contains("Originals since 0: 1"));
+        assert!(out.contains("  (1, 100) in 10, 20\n"));
+
+        let name = |chat_id| (chat_id == 10).then(|| "Ten".to_owned());
+        let out = format_stats(&t, Some(0), name);
+        assert!(out.contains("  (1, 100) in Ten, 20\n"));
+    }
+
+    #[test]
+    fn rejects_malformed_forget_commands() {
+        assert!(parse_args(["forget", "chat"]).is_err());
+        assert!(parse_args(["forget", "chat", "abc"]).is_err());
+        assert!(parse_args(["forget", "original", "1"]).is_err());
+        assert!(parse_args(["session"]).is_err());
+        assert!(parse_args(["frobnicate"]).is_err());
+    }
+}
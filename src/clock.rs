@@ -0,0 +1,101 @@
+use std::fmt;
+use std::sync::Arc;
+
+use crate::tracker::epoch_secs;
+
+/// Source of the current Unix time for time-based tracker logic, so tests
+/// can pin it instead of backdating entries.
+pub trait Clock: Send + Sync {
+    /// Seconds since the Unix epoch.
+    fn now(&self) -> u64;
+}
+
+/// The real wall clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        epoch_secs()
+    }
+}
+
+/// A shared clock handle. Defaults to the system clock, so structs holding
+/// one can still derive `Default`.
+#[derive(Clone)]
+pub struct SharedClock(Arc<dyn Clock>);
+
+impl SharedClock {
+    pub fn new(clock: Arc<dyn Clock>) -> Self {
+        SharedClock(clock)
+    }
+
+    pub fn now(&self) -> u64 {
+        self.0.now()
+    }
+}
+
+impl Default for SharedClock {
+    fn default() -> Self {
+        SharedClock(Arc::new(SystemClock))
+    }
+}
+
+impl fmt::Debug for SharedClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SharedClock")
+    }
+}
+
+/// A clock that only moves when told to.
+#[cfg(test)]
+pub mod manual {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    #[derive(Default)]
+    pub struct ManualClock(AtomicU64);
+
+    impl ManualClock {
+        pub fn at(now: u64) -> Arc<Self> {
+            Arc::new(ManualClock(AtomicU64::new(now)))
+        }
+
+        pub fn set(&self, now: u64) {
+            self.0.store(now, Ordering::SeqCst);
+        }
+
+        pub fn advance(&self, secs: u64) {
+            self.0.fetch_add(secs, Ordering::SeqCst);
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> u64 {
+            self.0.load(Ordering::SeqCst)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::manual::ManualClock;
+    use super::*;
+
+    #[test]
+    fn manual_clock_moves_only_when_told() {
+        let clock = ManualClock::at(1000);
+        let shared = SharedClock::new(clock.clone());
+        assert_eq!(shared.now(), 1000);
+        clock.advance(5);
+        assert_eq!(shared.now(), 1005);
+        clock.set(10);
+        assert_eq!(shared.now(), 10);
+    }
+
+    #[test]
+    fn default_is_the_system_clock() {
+        let before = epoch_secs();
+        let now = SharedClock::default().now();
+        assert!(now >= before && now <= epoch_secs());
+    }
+}
@@ -0,0 +1,248 @@
+use crate::recent::RecentEvents;
+use crate::tracker::{
+    epoch_secs, DuplicateTracker, LiveStats, OriginalMessageId, CLEANUP_MAX_AGE,
+};
+
+/// Prefix shared by all control commands, so ordinary notes in Saved
+/// Messages (and our own replies) are never mistaken for commands.
+const PREFIX: &str = "/dup";
+
+const HELP: &str = "\
+Commands:
+/dupstats — show what is tracked
+/dupcleanup — drop entries older than 30 days now
+/dupforget <chat_id> — forget everything related to a chat
+/duprecent — show the latest detections, reads and marks
+/dupmark <peer_id> <message_id> — mark every copy of a post read
+/dupignore <peer_id> — stop tracking posts from a source
+/dupfolder <folder_id> — mark every duplicate in a folder's chats read";
+
+/// How many events `/duprecent` replies with, to stay well under Telegram's
+/// message length limit.
+const RECENT_REPLY_LIMIT: usize = 20;
+
+/// A command sent by the user to their own Saved Messages.
+#[derive(Debug, PartialEq)]
+pub enum ControlCommand {
+    Stats,
+    Cleanup,
+    Forget(i64),
+    Recent,
+    /// Mark every copy of this original read, e.g. from a duplicate notice.
+    Mark(OriginalMessageId),
+    /// Stop tracking posts from this source until restart or reload.
+    Ignore(i64),
+    /// Mark the duplicates in every chat of this folder (dialog filter) read.
+    Folder(i32),
+    /// A `/dup...` message we couldn't parse; reply with usage help.
+    Help,
+}
+
+/// Parse a Saved Messages text into a control command. Returns `None` for
+/// anything that isn't meant for us.
+pub fn parse_command(text: &str) -> Option<ControlCommand> {
+    let text = text.trim();
+    if !text.starts_with(PREFIX) {
+        return None;
+    }
+    let mut words = text.split_whitespace();
+    let command = match (words.next()?, words.next(), words.next()) {
+        ("/dupstats", None, None) => ControlCommand::Stats,
+        ("/dupcleanup", None, None) => ControlCommand::Cleanup,
+        ("/duprecent", None, None) => ControlCommand::Recent,
+        ("/dupforget", Some(chat_id), None) => match chat_id.parse() {
+            Ok(chat_id) => ControlCommand::Forget(chat_id),
+            Err(_) => ControlCommand::Help,
+        },
+        ("/dupmark", Some(peer_id), Some(message_id)) if words.next().is_none() => {
+            match (peer_id.parse(), message_id.parse()) {
+                (Ok(peer_id), Ok(message_id)) => ControlCommand::Mark(OriginalMessageId {
+                    peer_id,
+                    message_id,
+                }),
+                _ => ControlCommand::Help,
+            }
+        }
+        ("/dupignore", Some(peer_id), None) => match peer_id.parse() {
+            Ok(peer_id) => ControlCommand::Ignore(peer_id),
+            Err(_) => ControlCommand::Help,
+        },
+        ("/dupfolder", Some(folder_id), None) => match folder_id.parse() {
+            Ok(folder_id) => ControlCommand::Folder(folder_id),
+            Err(_) => ControlCommand::Help,
+        },
+        _ => ControlCommand::Help,
+    };
+    Some(command)
+}
+
+/// The note sent to Saved Messages when a post first turns up in enough
+/// chats to count as a duplicate, with the commands that act on it. Those
+/// are plain commands to send back, since a user account can't attach
+/// inline buttons.
+pub fn duplicate_notice(
+    original: &OriginalMessageId,
+    chats: usize,
+    preview: Option<&str>,
+) -> String {
+    let mut notice = format!(
+        "Duplicate: post ({}, {}) is now in {} chats",
+        original.peer_id, original.message_id, chats
+    );
+    if let Some(preview) = preview {
+        notice.push_str(&format!(" \"{}\"", preview));
+    }
+    notice.push_str(&format!(
+        "\nSend /dupmark {} {} to mark it read everywhere, or /dupignore {} to stop \
+         tracking its source",
+        original.peer_id, original.message_id, original.peer_id
+    ));
+    notice
+}
+
+/// The `/dupstats` reply. Built from `LiveStats` alone, so the update loop
+/// can answer it without waiting for the tracker lock.
+pub fn stats_reply(live: &LiveStats) -> String {
+    let mut reply = live.stats().to_string();
+    if let Some(save) = live.last_save() {
+        reply.push_str(&format!("Last save:      {}\n", save));
+    }
+    reply
+}
+
+/// Run a control command against the tracker and return the reply text.
+/// `Mark`, `Ignore` and `Folder` also act on the running planner, so the
+/// handler takes those itself; here they only get an error reply.
+pub fn dispatch(
+    command: &ControlCommand,
+    tracker: &mut DuplicateTracker,
+    recent: Option<&RecentEvents>,
+) -> String {
+    match command {
+        ControlCommand::Stats => stats_reply(&tracker.live_stats()),
+        ControlCommand::Cleanup => {
+            let removed = tracker.cleanup(CLEANUP_MAX_AGE);
+            format!("Cleaned up {} old originals", removed)
+        }
+        ControlCommand::Forget(chat_id) => {
+            let removed = tracker.forget_chat(*chat_id);
+            format!("Forgot {} forwards related to chat {}", removed, chat_id)
+        }
+        ControlCommand::Recent => match recent {
+            Some(recent) => recent.render(epoch_secs(), RECENT_REPLY_LIMIT),
+            None => "Recent events are disabled (TG_RECENT_EVENTS=0)".to_owned(),
+        },
+        // These need the running planner, which handles them itself
+        ControlCommand::Mark(_) | ControlCommand::Ignore(_) | ControlCommand::Folder(_) => {
+            "This command is handled by the planner, not here".to_owned()
+        }
+        ControlCommand::Help => HELP.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::tracker::{ForwardLocation, OriginalMessageId};
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(parse_command("/dupstats"), Some(ControlCommand::Stats));
+        assert_eq!(parse_command("  /dupcleanup \n"), Some(ControlCommand::Cleanup));
+        assert_eq!(parse_command("/duprecent"), Some(ControlCommand::Recent));
+        assert_eq!(
+            parse_command("/dupforget -1001234"),
+            Some(ControlCommand::Forget(-1001234))
+        );
+        assert_eq!(
+            parse_command("/dupmark -1001234 42"),
+            Some(ControlCommand::Mark(OriginalMessageId {
+                peer_id: -1001234,
+                message_id: 42
+            }))
+        );
+        assert_eq!(
+            parse_command("/dupignore -1001234"),
+            Some(ControlCommand::Ignore(-1001234))
+        );
+        assert_eq!(parse_command("/dupfolder 3"), Some(ControlCommand::Folder(3)));
+    }
+
+    #[test]
+    fn malformed_commands_get_help() {
+        assert_eq!(parse_command("/dupforget"), Some(ControlCommand::Help));
+        assert_eq!(parse_command("/dupforget abc"), Some(ControlCommand::Help));
+        assert_eq!(parse_command("/dupstats now"), Some(ControlCommand::Help));
+        assert_eq!(parse_command("/dupwhatever"), Some(ControlCommand::Help));
+        assert_eq!(parse_command("/dupmark -1001234"), Some(ControlCommand::Help));
+        assert_eq!(parse_command("/dupmark -1001234 42 7"), Some(ControlCommand::Help));
+        assert_eq!(parse_command("/dupignore x"), Some(ControlCommand::Help));
+        assert_eq!(parse_command("/dupfolder"), Some(ControlCommand::Help));
+        assert_eq!(parse_command("/dupfolder work"), Some(ControlCommand::Help));
+    }
+
+    #[test]
+    fn non_commands_are_ignored() {
+        assert_eq!(parse_command("buy milk"), None);
+        assert_eq!(parse_command("/start"), None);
+        assert_eq!(parse_command(""), None);
+        // Our own replies must never parse as commands
+        assert_eq!(parse_command(HELP), None);
+        let original = OriginalMessageId { peer_id: -1001, message_id: 42 };
+        assert_eq!(parse_command(&duplicate_notice(&original, 2, None)), None);
+    }
+
+    #[tokio::test]
+    async fn dispatch_runs_tracker_operations() {
+        let mut t = DuplicateTracker::default();
+        let o = OriginalMessageId { peer_id: 1, message_id: 100 };
+        t.register_forward(o.clone(), ForwardLocation::new(10, 50));
+        t.register_forward(o, ForwardLocation::new(20, 60));
+
+        let reply = dispatch(&ControlCommand::Stats, &mut t, None);
+        assert!(reply.contains("Forwards:       2"));
+        assert!(!reply.contains("Last save"));
+        let dir = tempfile::tempdir().unwrap();
+        t.save(&dir.path().join("state.json")).await.unwrap();
+        let reply = dispatch(&ControlCommand::Stats, &mut t, None);
+        assert!(reply.contains("Last save:      "));
+        assert!(!reply.contains("Latency"));
+        t.propagation_latency().lock().unwrap().record(Duration::from_millis(40));
+        let reply = dispatch(&ControlCommand::Stats, &mut t, None);
+        assert!(reply.contains("Latency:        p50=40ms"));
+
+        let reply = dispatch(&ControlCommand::Forget(10), &mut t, None);
+        assert_eq!(reply, "Forgot 1 forwards related to chat 10");
+        assert_eq!(t.stats().forwards, 1);
+
+        let reply = dispatch(&ControlCommand::Cleanup, &mut t, None);
+        assert_eq!(reply, "Cleaned up 0 old originals");
+
+        // Left to the planner, but an answer rather than a panic
+        let reply = dispatch(&ControlCommand::Folder(3), &mut t, None);
+        assert_eq!(reply, "This command is handled by the planner, not here");
+    }
+
+    #[test]
+    fn notice_offers_the_commands_for_its_post() {
+        let original = OriginalMessageId { peer_id: -1001, message_id: 42 };
+        let notice = duplicate_notice(&original, 2, Some("Hello"));
+        assert!(notice.starts_with("Duplicate: post (-1001, 42) is now in 2 chats \"Hello\"\n"));
+        assert!(notice.contains("/dupmark -1001 42"));
+        assert!(notice.contains("/dupignore -1001"));
+    }
+
+    #[test]
+    fn dispatch_recent_renders_ring() {
+        let mut t = DuplicateTracker::default();
+        let reply = dispatch(&ControlCommand::Recent, &mut t, None);
+        assert!(reply.contains("disabled"));
+
+        let recent = RecentEvents::new(10);
+        recent.record(crate::recent::Event::Read { chat_id: 10, max_id: 5 });
+        let reply = dispatch(&ControlCommand::Recent, &mut t, Some(&recent));
+        assert!(reply.ends_with("read chat 10 up to 5"));
+    }
+}
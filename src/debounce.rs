@@ -0,0 +1,173 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Collapses bursts of read events per chat into one.
+///
+/// Scrolling through a chat produces a stream of read updates with rising
+/// `max_id`s. The first read of a chat opens a window; reads arriving inside
+/// it only raise the pending `max_id`, and when the window closes a single
+/// `(chat_id, max_id)` is released. The window is measured from the first
+/// read, so continuous scrolling still propagates at least once per window.
+#[derive(Debug)]
+pub struct ReadDebouncer {
+    window: Duration,
+    pending: HashMap<i64, Pending>,
+}
+
+#[derive(Debug)]
+struct Pending {
+    max_id: i32,
+    deadline: Instant,
+}
+
+impl ReadDebouncer {
+    pub fn new(window: Duration) -> Self {
+        ReadDebouncer {
+            window,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Add a read of `chat_id` up to `max_id` observed at `now`.
+    pub fn push(&mut self, chat_id: i64, max_id: i32, now: Instant) {
+        let window = self.window;
+        self.pending
+            .entry(chat_id)
+            .and_modify(|p| p.max_id = p.max_id.max(max_id))
+            .or_insert(Pending {
+                max_id,
+                deadline: now + window,
+            });
+    }
+
+    /// Remove and return the reads whose window has closed by `now`,
+    /// ordered by chat id.
+    pub fn due(&mut self, now: Instant) -> Vec<(i64, i32)> {
+        let mut due: Vec<(i64, i32)> = self
+            .pending
+            .iter()
+            .filter(|(_, p)| p.deadline <= now)
+            .map(|(chat_id, p)| (*chat_id, p.max_id))
+            .collect();
+        for (chat_id, _) in &due {
+            self.pending.remove(chat_id);
+        }
+        due.sort_unstable();
+        due
+    }
+
+    /// Remove and return all pending reads regardless of their window,
+    /// e.g. on shutdown.
+    pub fn drain(&mut self) -> Vec<(i64, i32)> {
+        let mut all: Vec<(i64, i32)> = self
+            .pending
+            .drain()
+            .map(|(chat_id, p)| (chat_id, p.max_id))
+            .collect();
+        all.sort_unstable();
+        all
+    }
+
+    /// When the earliest pending window closes, if any read is pending.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|p| p.deadline).min()
+    }
+}
+
+/// Sleep until `deadline`, or forever if there is none. Meant as a
+/// `select!` branch alongside the update stream.
+pub async fn sleep_until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_millis(500);
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    /// Feed `(chat_id, max_id, offset_ms)` events and poll `due` after each,
+    /// the way the main loop would. Returns `(release_offset_ms, chat_id,
+    /// max_id)` for everything released, including a final poll well after
+    /// the last event.
+    fn run(events: &[(i64, i32, u64)]) -> Vec<(u64, i64, i32)> {
+        let start = Instant::now();
+        let mut d = ReadDebouncer::new(WINDOW);
+        let mut out = Vec::new();
+        let mut poll = |d: &mut ReadDebouncer, at: u64| {
+            for (chat_id, max_id) in d.due(start + ms(at)) {
+                out.push((at, chat_id, max_id));
+            }
+        };
+        for &(chat_id, max_id, at) in events {
+            poll(&mut d, at);
+            d.push(chat_id, max_id, start + ms(at));
+        }
+        poll(&mut d, 10_000);
+        out
+    }
+
+    #[test]
+    fn burst_in_one_chat_collapses_to_highest_id() {
+        let out = run(&[(10, 1, 0), (10, 5, 100), (10, 3, 200), (10, 9, 300)]);
+        assert_eq!(out, vec![(10_000, 10, 9)]);
+    }
+
+    #[test]
+    fn chats_are_debounced_independently() {
+        let out = run(&[(10, 1, 0), (20, 7, 100), (10, 2, 200)]);
+        assert_eq!(out, vec![(10_000, 10, 2), (10_000, 20, 7)]);
+    }
+
+    #[test]
+    fn reads_after_the_window_start_a_new_one() {
+        let out = run(&[(10, 1, 0), (10, 2, 400), (10, 3, 600), (10, 4, 700)]);
+        // The window opened at 0 closes at 500; the read at 600 sees it due
+        assert_eq!(out, vec![(600, 10, 2), (10_000, 10, 4)]);
+    }
+
+    #[test]
+    fn next_deadline_tracks_earliest_window() {
+        let start = Instant::now();
+        let mut d = ReadDebouncer::new(WINDOW);
+        assert_eq!(d.next_deadline(), None);
+
+        d.push(10, 1, start);
+        d.push(20, 1, start + ms(100));
+        assert_eq!(d.next_deadline(), Some(start + WINDOW));
+
+        // A later read in the same chat doesn't push its deadline back
+        d.push(10, 2, start + ms(300));
+        assert_eq!(d.next_deadline(), Some(start + WINDOW));
+
+        assert_eq!(d.due(start + WINDOW), vec![(10, 2)]);
+        assert_eq!(d.next_deadline(), Some(start + ms(600)));
+    }
+
+    #[test]
+    fn drain_releases_everything() {
+        let start = Instant::now();
+        let mut d = ReadDebouncer::new(WINDOW);
+        d.push(20, 4, start);
+        d.push(10, 8, start);
+        assert!(d.due(start).is_empty());
+        assert_eq!(d.drain(), vec![(10, 8), (20, 4)]);
+        assert_eq!(d.next_deadline(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sleep_until_wakes_at_deadline() {
+        let start = Instant::now();
+        sleep_until(Some(start + WINDOW)).await;
+        assert!(Instant::now() >= start + WINDOW);
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use tokio::time::Instant;
+use tracing::debug;
+
+use crate::handler::Action;
+use crate::tracker::{ForwardLocation, OriginalMessageId};
+
+/// Defers propagations by a grace period, so opening a chat by accident
+/// doesn't instantly mark every copy elsewhere. Scheduled propagations are
+/// keyed by original; copies the user reads themselves before the delay is
+/// up are dropped from them, and an original with nothing left to mark is
+/// cancelled outright.
+#[derive(Debug)]
+pub struct GraceQueue {
+    delay: Duration,
+    scheduled: HashMap<OriginalMessageId, Scheduled>,
+}
+
+#[derive(Debug)]
+struct Scheduled {
+    due: Instant,
+    forwards: Vec<ForwardLocation>,
+}
+
+impl GraceQueue {
+    pub fn new(delay: Duration) -> Self {
+        GraceQueue {
+            delay,
+            scheduled: HashMap::new(),
+        }
+    }
+
+    /// Change the delay for propagations offered from now on. Those already
+    /// scheduled keep their due time.
+    pub fn set_delay(&mut self, delay: Duration) {
+        self.delay = delay;
+    }
+
+    /// Returns the action if it should run now, or schedules it. Only
+    /// `MarkForwards` is deferred.
+    pub fn offer(&mut self, action: Action, now: Instant) -> Option<Action> {
+        let Action::MarkForwards { forwards } = action else {
+            return Some(action);
+        };
+        let due = now + self.delay;
+        for (original, fwd) in forwards {
+            let entry = self.scheduled.entry(original).or_insert(Scheduled {
+                due,
+                forwards: Vec::new(),
+            });
+            if !entry.forwards.contains(&fwd) {
+                entry.forwards.push(fwd);
+            }
+        }
+        None
+    }
+
+    /// The user read `chat_id` up to `max_id` themselves: drop scheduled
+    /// marks that read already covers. Returns how many were dropped.
+    pub fn observe_read(&mut self, chat_id: i64, max_id: i32) -> usize {
+        let mut dropped = 0;
+        self.scheduled.retain(|original, scheduled| {
+            let before = scheduled.forwards.len();
+            scheduled
+                .forwards
+                .retain(|f| !(f.chat_id == chat_id && f.message_id <= max_id));
+            dropped += before - scheduled.forwards.len();
+            if scheduled.forwards.is_empty() {
+                debug!(
+                    "Propagation of ({}, {}) cancelled, every copy was read directly",
+                    original.peer_id, original.message_id
+                );
+                return false;
+            }
+            true
+        });
+        dropped
+    }
+
+    /// Remove and return everything whose delay is up as one action, in
+    /// the same chat/message order as immediate propagations.
+    pub fn due(&mut self, now: Instant) -> Option<Action> {
+        let due: Vec<OriginalMessageId> = self
+            .scheduled
+            .iter()
+            .filter(|(_, s)| s.due <= now)
+            .map(|(o, _)| o.clone())
+            .collect();
+        self.take(due)
+    }
+
+    /// Remove and return everything regardless of delay, e.g. on shutdown.
+    pub fn drain(&mut self) -> Option<Action> {
+        let all: Vec<OriginalMessageId> = self.scheduled.keys().cloned().collect();
+        self.take(all)
+    }
+
+    /// When the earliest scheduled propagation is due.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.scheduled.values().map(|s| s.due).min()
+    }
+
+    fn take(&mut self, originals: Vec<OriginalMessageId>) -> Option<Action> {
+        let mut forwards = Vec::new();
+        for original in originals {
+            if let Some(scheduled) = self.scheduled.remove(&original) {
+                forwards.extend(scheduled.forwards.into_iter().map(|f| (original.clone(), f)));
+            }
+        }
+        if forwards.is_empty() {
+            return None;
+        }
+        forwards.sort_by_key(|(_, f)| (f.chat_id, f.message_id));
+        Some(Action::MarkForwards { forwards })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DELAY: Duration = Duration::from_secs(10);
+
+    fn orig(peer_id: i64, message_id: i32) -> OriginalMessageId {
+        OriginalMessageId {
+            peer_id,
+            message_id,
+        }
+    }
+
+    fn marks(pairs: &[(OriginalMessageId, i64, i32)]) -> Action {
+        Action::MarkForwards {
+            forwards: pairs
+                .iter()
+                .map(|(o, chat, msg)| (o.clone(), ForwardLocation::new(*chat, *msg)))
+                .collect(),
+        }
+    }
+
+    fn locations(action: Option<Action>) -> Vec<(i64, i32)> {
+        match action {
+            Some(Action::MarkForwards { forwards }) => forwards
+                .iter()
+                .map(|(_, f)| (f.chat_id, f.message_id))
+                .collect(),
+            Some(_) => panic!("expected MarkForwards"),
+            None => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn propagation_runs_after_the_delay() {
+        let start = Instant::now();
+        let mut q = GraceQueue::new(DELAY);
+        assert!(q
+            .offer(marks(&[(orig(1, 100), 30, 7), (orig(1, 100), 20, 5)]), start)
+            .is_none());
+        assert_eq!(q.next_deadline(), Some(start + DELAY));
+
+        assert!(q.due(start + Duration::from_secs(9)).is_none());
+        assert_eq!(locations(q.due(start + DELAY)), vec![(20, 5), (30, 7)]);
+        assert_eq!(q.next_deadline(), None);
+    }
+
+    #[test]
+    fn natural_read_cancels_covered_copies() {
+        let start = Instant::now();
+        let mut q = GraceQueue::new(DELAY);
+        q.offer(marks(&[(orig(1, 100), 20, 5), (orig(1, 100), 30, 7)]), start);
+        q.offer(marks(&[(orig(2, 200), 20, 9)]), start);
+
+        // Reading chat 20 up to 5 covers only the first original's copy
+        assert_eq!(q.observe_read(20, 5), 1);
+        assert_eq!(locations(q.due(start + DELAY)), vec![(20, 9), (30, 7)]);
+    }
+
+    #[test]
+    fn fully_read_original_is_cancelled() {
+        let start = Instant::now();
+        let mut q = GraceQueue::new(DELAY);
+        q.offer(marks(&[(orig(1, 100), 20, 5)]), start);
+
+        assert_eq!(q.observe_read(20, 50), 1);
+        assert_eq!(q.next_deadline(), None);
+        assert!(q.due(start + DELAY).is_none());
+    }
+
+    #[test]
+    fn other_actions_pass_through() {
+        let mut q = GraceQueue::new(DELAY);
+        assert!(matches!(
+            q.offer(Action::None, Instant::now()),
+            Some(Action::None)
+        ));
+    }
+
+    #[test]
+    fn drain_releases_everything_early() {
+        let start = Instant::now();
+        let mut q = GraceQueue::new(DELAY);
+        q.offer(marks(&[(orig(1, 100), 20, 5)]), start);
+        assert_eq!(locations(q.drain()), vec![(20, 5)]);
+        assert!(q.drain().is_none());
+    }
+}
@@ -1,10 +1,24 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
 use grammers_client::update::Update;
 use grammers_session::types::{PeerId, PeerRef};
 use grammers_tl_types as tl;
-use tracing::{debug, info};
+use serde::{Deserialize, Serialize};
+use tokio::time::Instant;
+use tracing::{debug, info, trace, warn};
 
-use crate::marker::Marker;
-use crate::tracker::{DuplicateTracker, ForwardLocation, OriginalMessageId};
+use crate::control::{self, ControlCommand};
+use crate::identity::{self, IdentityStrategy, MediaKey, MessageIdentity};
+use crate::log_gate::LogGate;
+use crate::marker::{Badge, MarkerError, ReadMarker};
+use crate::recent::{Event, RecentEvents};
+use crate::summary::DailyStats;
+use crate::tracker::{DuplicateTracker, ForwardLocation, LiveStats, OriginalMessageId};
+use crate::webhook::{Webhook, WebhookEvent};
 
 /// Extract an i64 chat identifier from a `tl::enums::Peer`.
 fn peer_to_chat_id(peer: &tl::enums::Peer) -> i64 {
@@ -12,26 +26,475 @@ fn peer_to_chat_id(peer: &tl::enums::Peer) -> i64 {
 }
 
 /// Try to extract the original message identity from a forward header.
+///
+/// Channel posts are identified exactly by `(from_id, channel_post)`.
+/// Forwards of ordinary user or group messages carry no message id of the
+/// original, so for those the original's send date stands in for it:
+/// `fwd_from.date` is the original message's date and is preserved across
+/// re-forwards, so copies of the same message agree. This is best-effort —
+/// two messages from the same sender within the same second collide.
 fn extract_original(fwd: &tl::enums::MessageFwdHeader) -> Option<OriginalMessageId> {
     let tl::enums::MessageFwdHeader::Header(header) = fwd;
     let from_id = header.from_id.as_ref()?;
-    let channel_post = header.channel_post?;
+    let message_id = match header.channel_post {
+        Some(channel_post) => channel_post,
+        None if header.date > 0 => header.date,
+        None => return None,
+    };
     Some(OriginalMessageId {
         peer_id: peer_to_chat_id(from_id),
-        message_id: channel_post,
+        message_id,
     })
 }
 
+/// A best-effort original for a forward whose header names no sender, as
+/// with anonymous group admins and hidden accounts: keyed by the signature
+/// (`post_author`, else `from_name`), the chat it was saved from if any, and
+/// the send date. See [`identity::anonymous_key`] for how this can collide.
+fn extract_anonymous_original(fwd: &tl::enums::MessageFwdHeader) -> Option<OriginalMessageId> {
+    let tl::enums::MessageFwdHeader::Header(header) = fwd;
+    if header.from_id.is_some() || header.date <= 0 {
+        return None;
+    }
+    let author = header
+        .post_author
+        .as_deref()
+        .or(header.from_name.as_deref())
+        .filter(|author| !author.trim().is_empty())?;
+    let saved_from = header.saved_from_peer.as_ref().map(peer_to_chat_id);
+    Some(identity::anonymous_key(author, saved_from, header.date))
+}
+
+/// For the automatic copy of a channel post into its linked discussion group,
+/// return the location of the post on the channel side. Telegram marks these
+/// copies with `saved_from_peer`/`saved_from_msg_id` pointing back at the
+/// channel message, so the channel post and its discussion echo can be linked
+/// under one original instead of the channel copy going untracked.
+fn linked_channel_post(
+    fwd: &tl::enums::MessageFwdHeader,
+    chat_id: i64,
+) -> Option<ForwardLocation> {
+    let tl::enums::MessageFwdHeader::Header(header) = fwd;
+    let saved_peer = header.saved_from_peer.as_ref()?;
+    let saved_msg_id = header.saved_from_msg_id?;
+    if !matches!(saved_peer, tl::enums::Peer::Channel(_)) {
+        return None;
+    }
+    let channel_id = peer_to_chat_id(saved_peer);
+    if channel_id == chat_id {
+        return None;
+    }
+    Some(ForwardLocation::new(channel_id, saved_msg_id))
+}
+
+/// The discussion thread a message was posted in, from its reply header.
+/// Replies deeper in a thread carry the root as `reply_to_top_id`; forum
+/// topic messages replying to the root directly only have `reply_to_msg_id`.
+fn thread_id(raw: &tl::enums::Message) -> Option<i32> {
+    let tl::enums::Message::Message(msg) = raw else {
+        return None;
+    };
+    match msg.reply_to.as_ref()? {
+        tl::enums::MessageReplyHeader::Header(h) => {
+            h.reply_to_top_id.or(h.reply_to_msg_id.filter(|_| h.forum_topic))
+        }
+        _ => None,
+    }
+}
+
+/// Who posted a message in its chat, from its `from_id`. Private chats leave
+/// that out for messages from the other side, which are the chat's own.
+fn sender_id(raw: &tl::enums::Message, chat_id: i64) -> Option<i64> {
+    let tl::enums::Message::Message(msg) = raw else {
+        return None;
+    };
+    match &msg.from_id {
+        Some(from) => Some(peer_to_chat_id(from)),
+        None if chat_id > 0 && !msg.out => Some(chat_id),
+        None => None,
+    }
+}
+
+/// The photo or document attached to a message. Link previews don't count,
+/// they're derived from the text.
+fn media_key(raw: &tl::enums::Message) -> Option<MediaKey> {
+    let tl::enums::Message::Message(msg) = raw else {
+        return None;
+    };
+    match msg.media.as_ref()? {
+        tl::enums::MessageMedia::Photo(m) => match m.photo.as_ref()? {
+            tl::enums::Photo::Photo(photo) => Some(MediaKey::Photo(photo.id)),
+            tl::enums::Photo::Empty(_) => None,
+        },
+        tl::enums::MessageMedia::Document(m) => match m.document.as_ref()? {
+            tl::enums::Document::Document(doc) => Some(MediaKey::Document(doc.id)),
+            tl::enums::Document::Empty(_) => None,
+        },
+        _ => None,
+    }
+}
+
+/// Links in a message that its text doesn't spell out: words linked to a
+/// URL, then the link preview's page.
+fn hidden_links(raw: &tl::enums::Message) -> Vec<String> {
+    let tl::enums::Message::Message(msg) = raw else {
+        return Vec::new();
+    };
+    let mut links: Vec<String> = msg
+        .entities
+        .iter()
+        .flatten()
+        .filter_map(|entity| match entity {
+            tl::enums::MessageEntity::TextUrl(e) => Some(e.url.clone()),
+            _ => None,
+        })
+        .collect();
+    if let Some(tl::enums::MessageMedia::WebPage(m)) = &msg.media {
+        if let tl::enums::WebPage::Page(page) = &m.webpage {
+            links.push(page.url.clone());
+        }
+    }
+    links
+}
+
+/// What the planner reads from a new message, taken off the TL message up
+/// front. Serializable, so updates can be recorded and planned again
+/// offline by `replay`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IncomingMessage {
+    pub chat_id: i64,
+    /// The chat's display name, if the client knew it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chat_name: Option<String>,
+    pub message_id: i32,
+    #[serde(default)]
+    pub outgoing: bool,
+    /// Who posted it in this chat, if known.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender_id: Option<i64>,
+    #[serde(default)]
+    pub text: String,
+    /// Links hidden behind words or in the link preview, for
+    /// `IdentityStrategy::PrimaryUrl`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub links: Vec<String>,
+    /// Joins, pins and other service messages, or an empty placeholder.
+    #[serde(default)]
+    pub service: bool,
+    /// Carries media other than a link preview.
+    #[serde(default)]
+    pub has_media: bool,
+    /// The photo or document attached, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media: Option<MediaKey>,
+    /// The discussion thread or forum topic it was posted in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_msg_id: Option<i32>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "crate::replay::tl_base64::option"
+    )]
+    pub forward: Option<tl::enums::MessageFwdHeader>,
+    /// `(old, new)` chat ids, if this is the notice of a group migrating.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub migration: Option<(i64, i64)>,
+}
+
+impl IncomingMessage {
+    pub fn of(message: &grammers_client::update::Message) -> Self {
+        let chat_id = message.peer_id().bot_api_dialog_id();
+        let content = MessageContent::of(&message.raw);
+        IncomingMessage {
+            chat_id,
+            chat_name: message.peer().and_then(|p| p.name().map(str::to_owned)),
+            message_id: message.id(),
+            outgoing: message.outgoing(),
+            sender_id: sender_id(&message.raw, chat_id),
+            text: message.text().to_owned(),
+            links: hidden_links(&message.raw),
+            service: content.service,
+            has_media: content.has_media,
+            media: media_key(&message.raw),
+            top_msg_id: thread_id(&message.raw),
+            forward: message.forward_header(),
+            migration: match &message.raw {
+                tl::enums::Message::Service(service) => migration(&service.action, chat_id),
+                _ => None,
+            },
+        }
+    }
+
+    fn content(&self) -> MessageContent {
+        MessageContent {
+            service: self.service,
+            has_text: !self.text.is_empty(),
+            has_media: self.has_media,
+        }
+    }
+}
+
+/// Default length of message previews in logs and in the tracker.
+pub const DEFAULT_PREVIEW_LEN: usize = 100;
+
 /// Truncate a string to at most `max` characters, appending "..." if truncated.
 fn truncate(s: &str, max: usize) -> String {
-    if s.len() <= max {
-        s.to_owned()
+    match s.char_indices().nth(max) {
+        Some((end, _)) => format!("{}...", &s[..end]),
+        None => s.to_owned(),
+    }
+}
+
+/// A preview of at most `len` characters, or None if previews are off.
+fn preview(text: &str, len: usize) -> Option<String> {
+    (len > 0).then(|| truncate(text, len))
+}
+
+/// Which kinds of messages are worth tracking as forwards.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ContentFilter {
+    /// Anything with text or media.
+    #[default]
+    Any,
+    /// Only messages with text and no media (link previews don't count as media).
+    TextOnly,
+    /// Only messages carrying media.
+    MediaOnly,
+}
+
+/// What a message carries, as far as `ContentFilter` cares.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct MessageContent {
+    service: bool,
+    has_text: bool,
+    has_media: bool,
+}
+
+impl MessageContent {
+    fn of(raw: &tl::enums::Message) -> Self {
+        match raw {
+            tl::enums::Message::Message(msg) => MessageContent {
+                service: false,
+                has_text: !msg.message.is_empty(),
+                has_media: matches!(
+                    msg.media,
+                    Some(ref m) if !matches!(
+                        m,
+                        tl::enums::MessageMedia::Empty | tl::enums::MessageMedia::WebPage(_)
+                    )
+                ),
+            },
+            _ => MessageContent {
+                service: true,
+                has_text: false,
+                has_media: false,
+            },
+        }
+    }
+}
+
+/// Whether a message is worth tracking. Service messages (joins, pins, ...)
+/// and messages with neither text nor media never are.
+fn is_trackable(content: MessageContent, filter: ContentFilter) -> bool {
+    if content.service {
+        return false;
+    }
+    match filter {
+        ContentFilter::Any => content.has_text || content.has_media,
+        ContentFilter::TextOnly => content.has_text && !content.has_media,
+        ContentFilter::MediaOnly => content.has_media,
+    }
+}
+
+/// Why a new message isn't tracked, for the debug log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Untracked {
+    /// Joins, pins and other service messages, or an empty placeholder.
+    Service,
+    /// Neither text nor media, or not what `TG_TRACK_TEXT_ONLY` or
+    /// `TG_TRACK_MEDIA_ONLY` keeps.
+    Content(ContentFilter),
+    /// A forward whose header names neither a sender nor a post, e.g. from
+    /// an account that hides itself behind a name.
+    AnonymousForward,
+    /// Nothing the identity strategy can key on, e.g. not a forward.
+    NoIdentity(IdentityStrategy),
+}
+
+impl fmt::Display for Untracked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Untracked::Service => f.write_str("service or empty message"),
+            Untracked::Content(filter) => write!(f, "content not kept by filter {:?}", filter),
+            Untracked::AnonymousForward => f.write_str("forward without a sender or post id"),
+            Untracked::NoIdentity(strategy) => write!(f, "nothing to identify by {}", strategy),
+        }
+    }
+}
+
+/// Why a message with this content isn't tracked, if it isn't.
+fn untracked_content(content: MessageContent, filter: ContentFilter) -> Option<Untracked> {
+    if content.service {
+        Some(Untracked::Service)
+    } else if !is_trackable(content, filter) {
+        Some(Untracked::Content(filter))
     } else {
-        let mut end = max;
-        while !s.is_char_boundary(end) && end > 0 {
-            end -= 1;
+        None
+    }
+}
+
+/// Why a message that produced no identity keys isn't tracked, if so.
+fn untracked_identity(
+    forward_header: Option<&tl::enums::MessageFwdHeader>,
+    keys: &[OriginalMessageId],
+    strategy: IdentityStrategy,
+) -> Option<Untracked> {
+    if !keys.is_empty() {
+        return None;
+    }
+    match forward_header {
+        Some(header) if extract_original(header).is_none() => Some(Untracked::AnonymousForward),
+        _ => Some(Untracked::NoIdentity(strategy)),
+    }
+}
+
+/// Which source peers' posts to track, by Bot API peer id.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SourceFilter {
+    /// Only these sources (None = any).
+    pub allow: Option<HashSet<i64>>,
+    /// Never these sources. Wins over `allow`.
+    pub ignore: HashSet<i64>,
+}
+
+impl SourceFilter {
+    /// Whether posts from `source` should be tracked. A message with no
+    /// known source (not a forward) only passes when there is no allowlist.
+    pub fn allows(&self, source: Option<i64>) -> bool {
+        match source {
+            Some(id) if self.ignore.contains(&id) => false,
+            Some(id) => self.allow.as_ref().is_none_or(|allow| allow.contains(&id)),
+            None => self.allow.is_none(),
+        }
+    }
+}
+
+/// Settings that influence planning, derived from `Config` at startup.
+#[derive(Debug, Clone)]
+pub struct PlanSettings {
+    /// Keep tracking reads (for stats) but never plan any marks.
+    pub observe_only: bool,
+    /// Our own user's chat id (Saved Messages), where control commands are
+    /// accepted. None disables control commands.
+    pub self_chat_id: Option<i64>,
+    /// Which messages to track at all.
+    pub content_filter: ContentFilter,
+    /// What makes two messages copies of the same post.
+    pub identity: IdentityStrategy,
+    /// Which sources to track.
+    pub sources: SourceFilter,
+    /// Where to note detections and reads for `/duprecent`, if anywhere.
+    pub recent: Option<Arc<RecentEvents>>,
+    /// Where to count the day's activity for the daily summary, if sent.
+    pub daily: Option<Arc<DailyStats>>,
+    /// Rate limit on the per-forward detection lines, if any.
+    pub log_gate: Option<Arc<LogGate>>,
+    /// Where to POST detections and propagated reads, if anywhere.
+    pub webhook: Option<Arc<Webhook>>,
+    /// Characters of message text kept in previews (0 = no previews).
+    pub preview_len: usize,
+    /// Copies an original needs before its reads propagate (0 or 1 = any).
+    pub min_duplicates: usize,
+    /// Channels in the user's dialog list, when only forwards from those
+    /// are tracked. None tracks forwards from anywhere, including while
+    /// the dialog list hasn't been scanned yet.
+    pub followed_sources: Option<HashSet<i64>>,
+    /// Mark a new copy of an already read post read as soon as it arrives.
+    pub mark_late_copies: bool,
+    /// Chats a single read may mark at once before the rest are spread out
+    /// over later batches (0 = no limit).
+    pub max_chats_per_event: usize,
+    /// Tell Saved Messages when a post first turns up as a duplicate.
+    pub notify_duplicates: bool,
+    /// Channels the user owns or administers, whose posts are left alone
+    /// when that is configured. Empty until the dialog list is scanned.
+    pub own_sources: HashSet<i64>,
+    /// Track forwards whose header names no sender by their signature and
+    /// date, accepting the occasional false match.
+    pub anonymous_forwards: bool,
+    /// Copies registered less than this many seconds ago are left out of
+    /// read propagation, since the user may be about to open them (0 = off).
+    pub min_forward_age_secs: u64,
+    /// Chats whose copies are tracked but never marked. Reads in them still
+    /// propagate elsewhere.
+    pub no_mark_chats: HashSet<i64>,
+    /// Sources whose copies are kept apart by who forwarded them, so a
+    /// read only propagates to copies from the same forwarder.
+    pub forwarder_sources: HashSet<i64>,
+    /// Let Saved Messages (`self_chat_id`) take part in propagation. When
+    /// false, copies there are neither tracked nor marked, and reads there
+    /// propagate nowhere. Control commands work either way.
+    pub include_saved_messages: bool,
+    /// Log the type of every raw update ignored, at trace level, for
+    /// finding out why a read went unnoticed.
+    pub trace_raw_updates: bool,
+}
+
+impl PlanSettings {
+    /// Whether a message with this forward source passes the followed
+    /// sources restriction. Messages that aren't forwards always pass.
+    pub fn follows_source(&self, source: Option<i64>) -> bool {
+        match (&self.followed_sources, source) {
+            (Some(followed), Some(source)) => followed.contains(&source),
+            _ => true,
+        }
+    }
+
+    /// Whether a message with this forward source comes from one of the
+    /// user's own channels.
+    pub fn is_own_source(&self, source: Option<i64>) -> bool {
+        source.is_some_and(|source| self.own_sources.contains(&source))
+    }
+
+    /// Whether a copy in `chat_id` may be marked read.
+    pub fn may_mark(&self, chat_id: i64) -> bool {
+        !self.no_mark_chats.contains(&chat_id) && !self.leaves_out(chat_id)
+    }
+
+    /// Whether `chat_id` is Saved Messages and that is left out of
+    /// propagation. Only known once signed in, so offline commands never
+    /// leave it out.
+    pub fn leaves_out(&self, chat_id: i64) -> bool {
+        !self.include_saved_messages && self.self_chat_id == Some(chat_id)
+    }
+}
+
+impl Default for PlanSettings {
+    fn default() -> Self {
+        PlanSettings {
+            observe_only: false,
+            self_chat_id: None,
+            content_filter: ContentFilter::default(),
+            identity: IdentityStrategy::default(),
+            sources: SourceFilter::default(),
+            recent: None,
+            daily: None,
+            log_gate: None,
+            webhook: None,
+            preview_len: DEFAULT_PREVIEW_LEN,
+            min_duplicates: 0,
+            followed_sources: None,
+            mark_late_copies: true,
+            max_chats_per_event: 0,
+            notify_duplicates: false,
+            own_sources: HashSet::new(),
+            anonymous_forwards: false,
+            min_forward_age_secs: 0,
+            no_mark_chats: HashSet::new(),
+            forwarder_sources: HashSet::new(),
+            include_saved_messages: true,
+            trace_raw_updates: false,
         }
-        format!("{}...", &s[..end])
     }
 }
 
@@ -40,170 +503,2610 @@ fn truncate(s: &str, max: usize) -> String {
 pub enum Action {
     /// No action needed.
     None,
-    /// Cache a peer we learned about from an incoming message.
+    /// Cache peers we learned about from an incoming message: its chat
+    /// (when it has a usable peer) and the chat a forward came from.
     CachePeer {
         chat_id: i64,
-        peer_ref: PeerRef,
+        peer_ref: Option<PeerRef>,
         name: String,
+        origin: Option<PeerId>,
     },
-    /// Mark these forward locations as read.
+    /// Mark these forward locations as read, each with the original it is
+    /// a copy of (for the audit log).
     MarkForwards {
-        forwards: Vec<ForwardLocation>,
+        forwards: Vec<(OriginalMessageId, ForwardLocation)>,
+    },
+    /// Mark the `forwards` that sit in a folder's chats, once the folder
+    /// has been looked up, and say how it went in `report_to`.
+    MarkFolder {
+        folder_id: i32,
+        report_to: Option<i64>,
+        forwards: Vec<(OriginalMessageId, ForwardLocation)>,
     },
+    /// Note a chat's new unread badge, so marking it read later clears it.
+    NoteBadge { chat_id: i64, badge: Badge },
+    /// Send a text message, e.g. a reply to a control command. Carries the
+    /// chat's peer when known, since Saved Messages may not have been a
+    /// dialog when the peer cache was built.
+    Reply {
+        chat_id: i64,
+        peer_ref: Option<PeerRef>,
+        text: String,
+    },
+}
+
+/// Catch up on reads made while the daemon was offline: treat each chat's
+/// current read cursor from the dialog list as a read event, so originals
+/// already read somewhere propagate to their other copies. Only returns
+/// actions with something to do.
+pub fn plan_catch_up(
+    read_cursors: &[(i64, i32)],
+    tracker: &mut DuplicateTracker,
+    settings: &PlanSettings,
+) -> Vec<Action> {
+    read_cursors
+        .iter()
+        .map(|&(chat_id, max_id)| plan_read_event(chat_id, max_id, tracker, settings))
+        .filter(|action| !matches!(action, Action::None))
+        .collect()
+}
+
+/// Finish the marks a previous run planned but never confirmed, e.g.
+/// because it crashed halfway through a propagation. Call once, right
+/// after loading the state and before planning anything new.
+pub fn plan_resume(tracker: &DuplicateTracker, settings: &PlanSettings) -> Option<Action> {
+    let mut forwards = tracker.resumable_marks();
+    forwards.retain(|(_, fwd)| settings.may_mark(fwd.chat_id));
+    if forwards.is_empty() {
+        return None;
+    }
+    if settings.observe_only {
+        info!(forwards = forwards.len(), "Observe-only: not resuming unfinished marks");
+        return None;
+    }
+    info!(
+        forwards = forwards.len(),
+        chats = chat_count(&forwards),
+        "Resuming marks left unfinished by the last run"
+    );
+    Some(Action::MarkForwards { forwards })
+}
+
+/// Mark the copies a read held back for being too new, once they are
+/// `min_forward_age_secs` old. Settings may have changed meanwhile, so
+/// they are checked again.
+pub fn plan_aged_forwards(
+    tracker: &mut DuplicateTracker,
+    settings: &PlanSettings,
+) -> Option<Action> {
+    let mut forwards = tracker.take_aged_forwards(settings.min_forward_age_secs);
+    forwards.retain(|(_, fwd)| settings.may_mark(fwd.chat_id));
+    if forwards.is_empty() {
+        return None;
+    }
+    if settings.observe_only {
+        info!(forwards = forwards.len(), "Observe-only: not marking copies that came of age");
+        return None;
+    }
+    info!(
+        forwards = forwards.len(),
+        "Copies old enough now, propagating the read to them"
+    );
+    if let Some(daily) = &settings.daily {
+        daily.record_propagated(forwards.len());
+    }
+    Some(Action::MarkForwards { forwards })
 }
 
 /// Phase 1: Inspect the update and compute what actions are needed.
-/// Only requires the tracker (no network I/O).
+/// Only requires the tracker (no network I/O). Control commands may adjust
+/// `settings`.
 pub async fn plan_update(
     update: &Update,
     tracker: &mut DuplicateTracker,
-) -> Action {
-    match update {
-        Update::NewMessage(message) => plan_new_message(message, tracker).await,
+    settings: &mut PlanSettings,
+) -> Vec<Action> {
+    let action = match update {
+        Update::NewMessage(message) => {
+            let incoming = IncomingMessage::of(message);
+            let mut actions =
+                plan_new_message(&incoming, message.peer_ref(), tracker, settings).await;
+            if mentions_us(&message.raw) {
+                actions.push(Action::NoteBadge {
+                    chat_id: incoming.chat_id,
+                    badge: Badge::Mention,
+                });
+            }
+            return actions;
+        }
+        Update::MessageEdited(message) => {
+            let chat_id = message.peer_id().bot_api_dialog_id();
+            plan_edit(chat_id, message.id(), message.text(), tracker, settings)
+        }
         // Read events come through as raw TL updates (not wrapped by grammers)
-        Update::Raw(raw) => plan_raw_update(&raw.raw, tracker),
+        Update::Raw(raw) => plan_raw_update(&raw.raw, tracker, settings),
         _ => Action::None,
+    };
+    vec![action]
+}
+
+/// Answer `/dupstats` from the user's Saved Messages out of `live`, so the
+/// update loop needn't take the tracker lock for it. Any other update is
+/// None, and goes through `plan_update` as usual.
+pub async fn plan_live_stats(
+    update: &Update,
+    live: &LiveStats,
+    settings: &PlanSettings,
+) -> Option<Action> {
+    let Update::NewMessage(message) = update else {
+        return None;
+    };
+    plan_stats_reply(&IncomingMessage::of(message), message.peer_ref(), live, settings).await
+}
+
+/// `plan_live_stats` for a message already taken apart.
+pub(crate) async fn plan_stats_reply(
+    message: &IncomingMessage,
+    peer_ref: impl Future<Output = Option<PeerRef>>,
+    live: &LiveStats,
+    settings: &PlanSettings,
+) -> Option<Action> {
+    let ours = message.outgoing && settings.self_chat_id == Some(message.chat_id);
+    if !ours || control::parse_command(&message.text) != Some(ControlCommand::Stats) {
+        return None;
+    }
+    info!("Control command from Saved Messages: {:?}", ControlCommand::Stats);
+    Some(Action::Reply {
+        chat_id: message.chat_id,
+        peer_ref: peer_ref.await,
+        text: control::stats_reply(live),
+    })
+}
+
+/// Whether a message mentions or replies to the user and is still unread,
+/// i.e. adds to its chat's mention badge.
+fn mentions_us(raw: &tl::enums::Message) -> bool {
+    matches!(raw, tl::enums::Message::Message(m) if m.mentioned && m.media_unread)
+}
+
+/// Mark forwards read and/or archive their chats, as the marker's
+/// duplicate action says. Only needs shared access, so several can run at
+/// once. Returns how long marking read took, if it was done.
+pub async fn propagate<M: ReadMarker>(
+    marker: &M,
+    forwards: &[(OriginalMessageId, ForwardLocation)],
+) -> Result<Option<Duration>, MarkerError> {
+    // Planned or held back before observe-only was switched on; left
+    // pending, like unfinished marks on a start in observe-only mode
+    if marker.observe_only() {
+        info!(forwards = forwards.len(), "Observe-only: not running a planned propagation");
+        return Ok(None);
+    }
+    let dup_action = marker.dup_action();
+    let mut elapsed = None;
+    let mut marked_read = Vec::new();
+    if dup_action.marks_read() {
+        for (_, fwd) in forwards {
+            if !admit(marker.log_gate(), "forwards marked read") {
+                continue;
+            }
+            let name = marker.get_chat_name(fwd.chat_id);
+            info!(
+                chat_id = fwd.chat_id,
+                message_id = fwd.message_id,
+                chat_name = %name,
+                "Marking as read"
+            );
+        }
+        // Timed from when the read was planned, so waits in the queues
+        // count too; marks resumed from a previous run start here
+        let start = marker
+            .pending_marks()
+            .and_then(|pending| pending.planned_at(forwards))
+            .map_or_else(Instant::now, Instant::from_std);
+        marked_read = marker.mark_forwards_read(forwards).await?;
+        elapsed = Some(start.elapsed());
+    }
+    if dup_action.archives() {
+        for chat_id in chats_to_archive(forwards) {
+            info!(
+                chat_id,
+                chat_name = %marker.get_chat_name(chat_id),
+                "Archiving"
+            );
+            if let Err(e) = marker.archive_chat(chat_id).await {
+                if e.is_fatal() {
+                    return Err(e);
+                }
+                warn!(chat_id, error = %e, "Failed to archive chat");
+            }
+        }
+    }
+    // Failures short of fatal were logged and won't fix themselves on a
+    // restart, so only an interrupted propagation stays pending
+    if let Some(pending) = marker.pending_marks() {
+        pending.confirm(forwards);
     }
+    // Skipped and failed copies are still unread, as are archived-only ones
+    if let Some(marked) = marker.marked_copies() {
+        marked.record(&marked_read);
+    }
+    Ok(elapsed)
 }
 
 /// Phase 2: Execute the planned action using the marker (network I/O).
-/// Only requires the marker.
-pub async fn execute_action(action: Action, marker: &mut Marker) {
+/// Only requires the marker. Returns an error only if it is fatal (e.g. the
+/// session was revoked) and the caller should shut down.
+pub async fn execute_action<M: ReadMarker>(
+    action: Action,
+    marker: &mut M,
+) -> Result<(), MarkerError> {
     match action {
         Action::None => {}
         Action::CachePeer {
             chat_id,
             peer_ref,
             name,
+            origin,
         } => {
-            marker.cache_peer(chat_id, peer_ref, name);
+            if let Some(peer_ref) = peer_ref {
+                marker.cache_peer(chat_id, peer_ref, name);
+            }
+            if let Some(origin) = origin {
+                marker.cache_origin(origin);
+            }
         }
+        Action::NoteBadge { chat_id, badge } => marker.note_badge(chat_id, badge),
         Action::MarkForwards { forwards } => {
-            for fwd in &forwards {
-                let name = marker.get_chat_name(fwd.chat_id);
-                info!(
-                    "Marking as read in {} (chat={}, msg={})",
-                    name, fwd.chat_id, fwd.message_id
-                );
+            if let Some(elapsed) = propagate(&*marker, &forwards).await? {
+                marker.record_propagation(elapsed);
+            }
+        }
+        Action::MarkFolder {
+            folder_id,
+            report_to,
+            forwards,
+        } => {
+            // Switched on since this was planned
+            let lookup = if marker.observe_only() {
+                None
+            } else {
+                Some(marker.folder_chats(folder_id).await)
+            };
+            let report = match lookup {
+                None => format!("Observe-only: not marking duplicates in folder {}", folder_id),
+                Some(Ok(chats)) => {
+                    let forwards = forwards_in_chats(forwards, &chats);
+                    if !forwards.is_empty() {
+                        if let Some(elapsed) = propagate(&*marker, &forwards).await? {
+                            marker.record_propagation(elapsed);
+                        }
+                    }
+                    format!(
+                        "Folder {}: marked {} duplicates in {} chats",
+                        folder_id,
+                        forwards.len(),
+                        chat_count(&forwards)
+                    )
+                }
+                Some(Err(e)) if e.is_fatal() => return Err(e),
+                Some(Err(e)) => {
+                    warn!(folder_id, error = %e, "Failed to look up folder");
+                    format!("Couldn't look up folder {}: {}", folder_id, e)
+                }
+            };
+            if let Some(chat_id) = report_to {
+                if let Err(e) = marker.send_message(chat_id, &report).await {
+                    if e.is_fatal() {
+                        return Err(e);
+                    }
+                    warn!(chat_id, error = %e, "Failed to send reply");
+                }
+            }
+        }
+        Action::Reply {
+            chat_id,
+            peer_ref,
+            text,
+        } => {
+            if let Some(peer_ref) = peer_ref {
+                marker.cache_peer(chat_id, peer_ref, "Saved Messages".to_owned());
             }
-            if let Err(e) = marker.mark_forwards_read(&forwards).await {
-                tracing::warn!("Error marking forwards as read: {}", e);
+            if let Err(e) = marker.send_message(chat_id, &text).await {
+                if e.is_fatal() {
+                    return Err(e);
+                }
+                warn!(chat_id, error = %e, "Failed to send reply");
             }
         }
     }
+    Ok(())
+}
+
+/// The forwards located in one of `chats`, in their original order.
+pub fn forwards_in_chats(
+    forwards: Vec<(OriginalMessageId, ForwardLocation)>,
+    chats: &HashSet<i64>,
+) -> Vec<(OriginalMessageId, ForwardLocation)> {
+    forwards
+        .into_iter()
+        .filter(|(_, fwd)| chats.contains(&fwd.chat_id))
+        .collect()
+}
+
+/// Each chat holding a copy, once, in first-seen order. Several copies can
+/// sit in the same chat but it only needs archiving once.
+fn chats_to_archive(forwards: &[(OriginalMessageId, ForwardLocation)]) -> Vec<i64> {
+    let mut chats: Vec<i64> = Vec::new();
+    for (_, fwd) in forwards {
+        if !chats.contains(&fwd.chat_id) {
+            chats.push(fwd.chat_id);
+        }
+    }
+    chats
 }
 
-/// Plan actions for an incoming new message — detect forwards and register them.
-async fn plan_new_message(
-    message: &grammers_client::update::Message,
+/// An edited message may be a tracked original in its source channel: the
+/// identity `(peer_id, channel_post)` is unchanged by edits, so just refresh
+/// its stored preview. Edits never need any marking.
+pub(crate) fn plan_edit(
+    chat_id: i64,
+    message_id: i32,
+    text: &str,
     tracker: &mut DuplicateTracker,
+    settings: &PlanSettings,
 ) -> Action {
-    let fwd_header = match message.forward_header() {
-        Some(h) => h,
-        None => return Action::None,
+    let Some(preview) = preview(text, settings.preview_len) else {
+        return Action::None;
+    };
+    let original = OriginalMessageId {
+        peer_id: chat_id,
+        message_id,
     };
+    if tracker.refresh_preview(&original, preview) {
+        debug!(chat_id, message_id, "Original was edited, preview refreshed");
+    }
+    Action::None
+}
+
+/// Plan actions for an incoming new message — detect forwards and register
+/// them. A new copy of a post already read is marked read right away.
+/// `peer_ref` looks up the chat's peer, and is only awaited if needed.
+pub(crate) async fn plan_new_message(
+    message: &IncomingMessage,
+    peer_ref: impl Future<Output = Option<PeerRef>>,
+    tracker: &mut DuplicateTracker,
+    settings: &mut PlanSettings,
+) -> Vec<Action> {
+    let chat_id = message.chat_id;
 
-    let original = match extract_original(&fwd_header) {
+    // Control commands the user sends to their own Saved Messages
+    if message.outgoing && settings.self_chat_id == Some(chat_id) {
+        if let Some((text, action)) = plan_control_command(&message.text, tracker, settings) {
+            let reply = Action::Reply {
+                chat_id,
+                peer_ref: peer_ref.await,
+                text,
+            };
+            return std::iter::once(reply).chain(action).collect();
+        }
+    }
+    let settings = &*settings;
+    if settings.leaves_out(chat_id) {
+        return Vec::new();
+    }
+
+    if let Some((old_id, new_id)) = message.migration {
+        let dropped = tracker.forget_migrated_chat(old_id);
+        if dropped > 0 {
+            info!(
+                old_chat_id = old_id,
+                new_chat_id = new_id,
+                dropped,
+                "Chat migrated, its tracked copies don't carry over"
+            );
+        }
+        return Vec::new();
+    }
+
+    let message_id = message.message_id;
+    if let Some(why) = untracked_content(message.content(), settings.content_filter) {
+        debug!(chat_id, message_id, reason = %why, "Not tracking message");
+        return Vec::new();
+    }
+
+    let fwd_header = message.forward.as_ref();
+    let source = fwd_header
+        .and_then(origin_peer)
+        .map(|p| p.bot_api_dialog_id());
+    if !settings.sources.allows(source) {
+        debug!(chat_id, source, "Ignoring message from filtered source");
+        return Vec::new();
+    }
+    if !settings.follows_source(source) {
+        debug!(chat_id, source, "Ignoring forward from a chat not followed");
+        return Vec::new();
+    }
+    if settings.is_own_source(source) {
+        debug!(chat_id, source, "Ignoring forward from the user's own channel");
+        return Vec::new();
+    }
+    let forwarder = message
+        .sender_id
+        .filter(|_| source.is_some_and(|s| settings.forwarder_sources.contains(&s)));
+    let keys = settings.identity.keys(&MessageIdentity {
+        forward: fwd_header.and_then(|header| {
+            extract_original(header).or_else(|| {
+                settings
+                    .anonymous_forwards
+                    .then(|| extract_anonymous_original(header))
+                    .flatten()
+            })
+        }),
+        text: &message.text,
+        links: &message.links,
+        media: message.media,
+        forwarder,
+    });
+    if let Some(why) = untracked_identity(fwd_header, &keys, settings.identity) {
+        debug!(chat_id, message_id, reason = %why, "Not tracking message");
+        return Vec::new();
+    }
+    let mut original = match pick_original(keys, tracker) {
         Some(o) => o,
-        None => return Action::None,
+        None => return Vec::new(),
     };
+    // A file reposted under no tracked key joins the original its earlier
+    // copies are tracked under, e.g. by their forward header in `combined`.
+    // Not across forwarders kept apart, whose copies would merge again
+    let media = message
+        .media
+        .filter(|_| settings.identity.uses_media() && forwarder.is_none());
+    if let Some(joined) = media
+        .filter(|_| !tracker.is_tracked(&original))
+        .and_then(|m| tracker.original_of_media(&m))
+    {
+        original = joined.clone();
+    }
 
-    let chat_id = message.peer_id().bot_api_dialog_id();
     let forward = ForwardLocation {
         chat_id,
-        message_id: message.id(),
+        message_id,
+        top_msg_id: message.top_msg_id,
     };
 
     let chat_name = message
-        .peer()
-        .and_then(|p| p.name().map(str::to_owned))
+        .chat_name
+        .clone()
         .unwrap_or_else(|| chat_id.to_string());
-    let preview = truncate(message.text(), 100);
+    let preview = preview(&message.text, settings.preview_len);
 
-    info!(
-        "Forward detected in {} ({}): original=({}, {}) msg={} \"{}\"",
-        chat_name, chat_id, original.peer_id, original.message_id,
-        forward.message_id, preview
-    );
+    if admit(settings.log_gate.as_deref(), "forwards detected") {
+        info!(
+            chat_id,
+            message_id = forward.message_id,
+            %chat_name,
+            original_peer_id = original.peer_id,
+            original_message_id = original.message_id,
+            preview = preview.as_deref(),
+            "Forward detected"
+        );
+    }
+
+    let channel_copy = fwd_header.and_then(|h| linked_channel_post(h, chat_id));
+    if let Some(recent) = &settings.recent {
+        recent.record(Event::ForwardDetected {
+            original: original.clone(),
+            forward: forward.clone(),
+        });
+    }
+    let copies_before = tracker.forward_count(&original);
+    if let (Some(daily), true) = (&settings.daily, copies_before > 0) {
+        daily.record_duplicate(source);
+    }
+    tracker.register_forward(original.clone(), forward.clone());
+    if let Some(media) = media {
+        tracker.register_media(media, forward.clone());
+    }
+    if let (Some(webhook), true) = (&settings.webhook, copies_before > 0) {
+        let copies = tracker.forwards_of(&original);
+        webhook.send(WebhookEvent::duplicate(&original, &forward, copies));
+    }
+    let late_copy = plan_copy_of_read(&original, forward, tracker, settings);
+    if let Some(preview) = preview {
+        tracker.set_preview_if_absent(&original, preview);
+    }
 
-    tracker.register_forward(original, forward);
+    // Discussion echo of a channel post: the channel-side copy is another
+    // location of the same original.
+    if let Some(copy) = channel_copy {
+        debug!(
+            chat_id = copy.chat_id,
+            message_id = copy.message_id,
+            discussion_chat_id = chat_id,
+            "Linked channel post to its discussion copy"
+        );
+        tracker.register_forward(original.clone(), copy);
+    }
+    let notice = plan_duplicate_notice(&original, copies_before, tracker, settings);
 
-    // Cache the peer so we can mark-read later
-    match message.peer_ref().await {
-        Some(peer_ref) => Action::CachePeer {
+    // Cache the peer so we can mark-read later, and the origin so the
+    // original itself can be targeted, before marking the copy
+    let peer_ref = peer_ref.await;
+    let origin = fwd_header.and_then(origin_peer);
+    let mut actions = Vec::new();
+    if peer_ref.is_some() || origin.is_some() {
+        actions.push(Action::CachePeer {
             chat_id,
             peer_ref,
             name: chat_name,
-        },
-        None => Action::None,
+            origin,
+        });
     }
+    actions.extend(late_copy);
+    actions.extend(notice);
+    actions
 }
 
-/// Plan actions for raw updates — specifically read-history events.
-fn plan_raw_update(
-    raw: &tl::enums::Update,
-    tracker: &mut DuplicateTracker,
-) -> Action {
-    match raw {
-        tl::enums::Update::ReadHistoryInbox(u) => {
-            let chat_id = peer_to_chat_id(&u.peer);
-            plan_read_event(chat_id, u.max_id, tracker)
+/// A note to Saved Messages if `original` just went from `copies_before`
+/// copies to enough to count as a duplicate, when notices are on. Sent
+/// once per post; not for posts already read.
+fn plan_duplicate_notice(
+    original: &OriginalMessageId,
+    copies_before: usize,
+    tracker: &DuplicateTracker,
+    settings: &PlanSettings,
+) -> Option<Action> {
+    let self_chat_id = settings.self_chat_id.filter(|_| settings.notify_duplicates)?;
+    let threshold = settings.min_duplicates.max(2);
+    let copies = tracker.forward_count(original);
+    if copies_before >= threshold || copies < threshold || tracker.is_original_read(original) {
+        return None;
+    }
+    Some(Action::Reply {
+        chat_id: self_chat_id,
+        peer_ref: None,
+        text: control::duplicate_notice(original, copies, tracker.preview(original)),
+    })
+}
+
+/// A copy of a post that was already read elsewhere: mark it read too,
+/// unless that is turned off. Reads only propagate on read events, so
+/// without this the late copy would stay unread.
+fn plan_copy_of_read(
+    original: &OriginalMessageId,
+    forward: ForwardLocation,
+    tracker: &DuplicateTracker,
+    settings: &PlanSettings,
+) -> Option<Action> {
+    if !settings.mark_late_copies
+        || !tracker.is_original_read(original)
+        || !settings.may_mark(forward.chat_id)
+    {
+        return None;
+    }
+    if settings.observe_only {
+        info!(
+            chat_id = forward.chat_id,
+            message_id = forward.message_id,
+            "Observe-only: copy of a read post would be marked read"
+        );
+        return None;
+    }
+    info!(
+        chat_id = forward.chat_id,
+        message_id = forward.message_id,
+        "Copy of a read post, marking it read"
+    );
+    if let Some(daily) = &settings.daily {
+        daily.record_propagated(1);
+    }
+    let forwards = vec![(original.clone(), forward)];
+    tracker.pending_marks().add(&forwards);
+    Some(Action::MarkForwards { forwards })
+}
+
+/// The (old, new) chat ids if a service message in `chat_id` announces a
+/// group's migration to a supergroup. The old group gets a "migrated to"
+/// and the new supergroup a "migrated from"; either is enough.
+fn migration(action: &tl::enums::MessageAction, chat_id: i64) -> Option<(i64, i64)> {
+    match action {
+        tl::enums::MessageAction::ChatMigrateTo(to) => {
+            let channel = tl::types::PeerChannel {
+                channel_id: to.channel_id,
+            };
+            Some((chat_id, peer_to_chat_id(&channel.into())))
+        }
+        tl::enums::MessageAction::ChannelMigrateFrom(from) => {
+            let chat = tl::types::PeerChat {
+                chat_id: from.chat_id,
+            };
+            Some((peer_to_chat_id(&chat.into()), chat_id))
         }
-        tl::enums::Update::ReadChannelInbox(u) => {
-            let chat_id = PeerId::channel(u.channel_id).bot_api_dialog_id();
-            plan_read_event(chat_id, u.max_id, tracker)
+        _ => None,
+    }
+}
+
+/// The peer a forward came from, if the header names it.
+fn origin_peer(fwd: &tl::enums::MessageFwdHeader) -> Option<PeerId> {
+    let tl::enums::MessageFwdHeader::Header(header) = fwd;
+    header.from_id.as_ref().map(PeerId::from)
+}
+
+/// Choose which of a message's identity keys to track it under. A location
+/// belongs to a single original, so with several keys the copy joins the
+/// first one that is already tracked, or else starts the most preferred.
+fn pick_original(
+    keys: Vec<OriginalMessageId>,
+    tracker: &DuplicateTracker,
+) -> Option<OriginalMessageId> {
+    let tracked = keys.iter().position(|k| tracker.is_tracked(k)).unwrap_or(0);
+    keys.into_iter().nth(tracked)
+}
+
+/// Run a Saved Messages control command, if the text is one, returning the
+/// reply text.
+fn plan_control_command(
+    text: &str,
+    tracker: &mut DuplicateTracker,
+    settings: &mut PlanSettings,
+) -> Option<(String, Option<Action>)> {
+    let command = control::parse_command(text)?;
+    info!("Control command from Saved Messages: {:?}", command);
+    let planned = match command {
+        ControlCommand::Mark(original) => plan_mark_everywhere(&original, tracker, settings),
+        ControlCommand::Folder(folder_id) => plan_mark_folder(folder_id, tracker, settings),
+        ControlCommand::Ignore(peer_id) => {
+            settings.sources.ignore.insert(peer_id);
+            let reply = format!(
+                "Ignoring source {} until restart or reload, add it to TG_IGNORE_SOURCES \
+                 to keep it",
+                peer_id
+            );
+            (reply, None)
         }
+        command => (control::dispatch(&command, tracker, settings.recent.as_deref()), None),
+    };
+    Some(planned)
+}
+
+/// `/dupmark`: mark every copy of `original` read, as if one was read.
+fn plan_mark_everywhere(
+    original: &OriginalMessageId,
+    tracker: &mut DuplicateTracker,
+    settings: &PlanSettings,
+) -> (String, Option<Action>) {
+    let label = format!("({}, {})", original.peer_id, original.message_id);
+    if tracker.forward_count(original) == 0 {
+        return (format!("Post {} is not tracked", label), None);
+    }
+    let forwards: Vec<_> = tracker
+        .mark_original_read(original)
+        .into_iter()
+        .filter(|f| settings.may_mark(f.chat_id))
+        .map(|f| (original.clone(), f))
+        .collect();
+    if settings.observe_only {
+        let reply = format!("Observe-only: would mark {} copies of {}", forwards.len(), label);
+        return (reply, None);
+    }
+    let reply = format!("Marking {} copies of {} read", forwards.len(), label);
+    tracker.pending_marks().add(&forwards);
+    (reply, Some(Action::MarkForwards { forwards }))
+}
+
+/// Whether `forward` has been tracked for at least `min_age_secs`. Copies
+/// from before startup have no registration time and always are; ones the
+/// clock was set back past count as just registered.
+fn old_enough(tracker: &DuplicateTracker, forward: &ForwardLocation, min_age_secs: u64) -> bool {
+    !tracker
+        .forward_age(forward)
+        .is_some_and(|age| age < min_age_secs)
+}
+
+/// Whether to log a per-forward info line, counting it under `what` if
+/// `gate` holds it back.
+fn admit(gate: Option<&LogGate>, what: &'static str) -> bool {
+    match gate {
+        Some(gate) => gate.admit(what),
+        None => true,
+    }
+}
+
+/// `/dupfolder`: mark the unread duplicates in a folder's chats read,
+/// leaving their copies elsewhere alone. Which chats are in the folder only
+/// Telegram knows, so the executor looks it up and picks them out.
+fn plan_mark_folder(
+    folder_id: i32,
+    tracker: &DuplicateTracker,
+    settings: &PlanSettings,
+) -> (String, Option<Action>) {
+    let mut forwards = tracker.unread_duplicates();
+    forwards.retain(|(_, fwd)| settings.may_mark(fwd.chat_id));
+    if forwards.is_empty() {
+        return ("No unread duplicates to mark".to_owned(), None);
+    }
+    if settings.observe_only {
+        let reply = format!("Observe-only: not marking duplicates in folder {}", folder_id);
+        return (reply, None);
+    }
+    forwards.sort_by_key(|(_, fwd)| (fwd.chat_id, fwd.message_id));
+    let action = Action::MarkFolder {
+        folder_id,
+        report_to: settings.self_chat_id,
+        forwards,
+    };
+    (format!("Looking up folder {} to mark its duplicates", folder_id), Some(action))
+}
+
+/// The `(chat_id, max_id)` of a read event, if this update is one. Main
+/// uses this to route reads through the debouncer instead of planning them
+/// immediately.
+pub fn read_event(update: &Update) -> Option<(i64, i32)> {
+    match update {
+        Update::Raw(raw) => raw_read_event(&raw.raw),
+        _ => None,
+    }
+}
+
+pub(crate) fn raw_read_event(raw: &tl::enums::Update) -> Option<(i64, i32)> {
+    match raw {
+        tl::enums::Update::ReadHistoryInbox(u) => Some((peer_to_chat_id(&u.peer), u.max_id)),
+        tl::enums::Update::ReadChannelInbox(u) => Some((
+            PeerId::channel(u.channel_id).bot_api_dialog_id(),
+            u.max_id,
+        )),
         // Discussion group threads (comments under channel posts).
         // The thread root (top_msg_id) is the auto-forwarded channel post
         // in the discussion group — treat it as read when the user opens
         // the thread.
-        tl::enums::Update::ReadChannelDiscussionInbox(u) => {
-            let chat_id = PeerId::channel(u.channel_id).bot_api_dialog_id();
-            plan_read_event(chat_id, u.top_msg_id, tracker)
+        tl::enums::Update::ReadChannelDiscussionInbox(u) => Some((
+            PeerId::channel(u.channel_id).bot_api_dialog_id(),
+            u.top_msg_id,
+        )),
+        // Outbox updates mean someone else read *our* outgoing messages, not
+        // that we read anything: our own reads on any device arrive as the
+        // inbox updates above. Propagating these would mark copies read
+        // because a recipient opened a chat, so they are ignored on purpose.
+        tl::enums::Update::ReadHistoryOutbox(_) | tl::enums::Update::ReadChannelOutbox(_) => None,
+        _ => None,
+    }
+}
+
+/// Plan actions for raw updates — specifically read-history events.
+pub(crate) fn plan_raw_update(
+    raw: &tl::enums::Update,
+    tracker: &mut DuplicateTracker,
+    settings: &PlanSettings,
+) -> Action {
+    // Secret chats never reach `plan_new_message`; their messages arrive
+    // here still end-to-end encrypted, so there is nothing to compare
+    if let tl::enums::Update::NewEncryptedMessage(_) = raw {
+        debug!("Not tracking a secret chat message, its content is encrypted");
+        return Action::None;
+    }
+    // Reactions to the user's messages add to the chat's reaction badge
+    if let tl::enums::Update::MessageReactions(u) = raw {
+        return Action::NoteBadge {
+            chat_id: peer_to_chat_id(&u.peer),
+            badge: Badge::Reaction,
+        };
+    }
+    match raw_read_event(raw) {
+        Some((chat_id, max_id)) => plan_read_event(chat_id, max_id, tracker, settings),
+        None => {
+            if settings.trace_raw_updates {
+                trace!(update = raw_update_name(raw), "Ignoring raw update");
+            }
+            Action::None
         }
-        _ => Action::None,
     }
 }
 
+/// The TL type of a raw update without its fields, e.g. `ReadFeaturedStickers`
+/// for `updateReadFeaturedStickers`. Taken from the `Debug` output, which
+/// starts with the variant name.
+fn raw_update_name(raw: &tl::enums::Update) -> String {
+    let debug = format!("{:?}", raw);
+    let end = debug
+        .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .unwrap_or(debug.len());
+    debug[..end].to_owned()
+}
+
 /// When the user reads messages in a chat, check if any tracked forwards
 /// were among them and plan read-propagation to other copies.
-fn plan_read_event(
+///
+/// A read up to `max_id` covers every lower id too, so several reads of the
+/// same chat can be collapsed into one call with the highest `max_id`.
+pub fn plan_read_event(
     chat_id: i64,
     max_id: i32,
     tracker: &mut DuplicateTracker,
+    settings: &PlanSettings,
 ) -> Action {
+    if settings.leaves_out(chat_id) {
+        return Action::None;
+    }
     let originals = tracker.find_read_originals_in_chat(chat_id, max_id);
     if originals.is_empty() {
         return Action::None;
     }
+    // Only reads that touched tracked posts, or every chat's reads would
+    // crowd out the interesting history
+    if let Some(recent) = &settings.recent {
+        recent.record(Event::Read { chat_id, max_id });
+    }
 
     debug!(
-        "Read event in chat {}: {} originals newly read",
         chat_id,
-        originals.len()
+        max_id,
+        originals = originals.len(),
+        "Read event with newly read originals"
     );
 
     let mut all_forwards = Vec::new();
+    // Tracked before the source was ignored; leave those copies alone too.
+    // Posts not yet seen in enough places stay unread, so a read after
+    // more copies turn up still propagates.
+    let originals = originals
+        .into_iter()
+        .filter(|o| !settings.sources.ignore.contains(&o.peer_id))
+        .filter(|o| !settings.is_own_source(Some(o.peer_id)))
+        .filter(|o| tracker.forward_count(o) >= settings.min_duplicates)
+        .collect::<Vec<_>>();
+    let mut too_new = Vec::new();
+    let mut read_here = Vec::new();
     for original in originals {
-        let forwards = tracker.mark_original_read(&original);
         // Collect forwards in other chats (or with msg_id > max_id in same chat)
-        let other_forwards = forwards
-            .into_iter()
-            .filter(|f| !(f.chat_id == chat_id && f.message_id <= max_id));
-        all_forwards.extend(other_forwards);
+        for f in tracker.mark_original_read(&original) {
+            if f.chat_id == chat_id && f.message_id <= max_id {
+                read_here.push((original.clone(), f));
+            } else if !settings.may_mark(f.chat_id) {
+                continue;
+            } else if old_enough(tracker, &f, settings.min_forward_age_secs) {
+                all_forwards.push((original.clone(), f));
+            } else {
+                too_new.push((original.clone(), f));
+            }
+        }
     }
-
+    tracker.marked_copies().record(&read_here);
+    // Copies that only just arrived wait out the window, see
+    // `plan_aged_forwards`
+    if !too_new.is_empty() && !settings.observe_only {
+        debug!(
+            chat_id,
+            forwards = too_new.len(),
+            min_age_secs = settings.min_forward_age_secs,
+            "Holding copies that only just arrived until they are old enough"
+        );
+        tracker.pending_marks().add(&too_new);
+        tracker.hold_until_aged(&too_new);
+    }
+
     if all_forwards.is_empty() {
         return Action::None;
     }
+    // Originals come out of hash maps in no particular order; sort so runs
+    // are reproducible and reads in the same chat end up next to each other
+    all_forwards.sort_by_key(|(_, f)| (f.chat_id, f.message_id));
+
+    // Observe-only: read_originals was still updated above so stats stay
+    // accurate, but nothing is ever marked.
+    if settings.observe_only {
+        info!(
+            chat_id,
+            forwards = all_forwards.len(),
+            "Observe-only: read would propagate to other forwards"
+        );
+        return Action::None;
+    }
 
     info!(
-        "Read in chat {}, propagating to {} other forwards",
         chat_id,
-        all_forwards.len()
+        forwards = all_forwards.len(),
+        "Read, propagating to other forwards"
     );
+    if let Some(daily) = &settings.daily {
+        daily.record_propagated(all_forwards.len());
+    }
+    if let Some(webhook) = &settings.webhook {
+        webhook.send(WebhookEvent::read(chat_id, max_id, &all_forwards));
+    }
+    tracker.pending_marks().add(&all_forwards);
+    let chats = chat_count(&all_forwards);
+    if settings.max_chats_per_event > 0 && chats > settings.max_chats_per_event {
+        warn!(
+            chat_id,
+            chats,
+            max_chats = settings.max_chats_per_event,
+            "Read would mark an unusual number of chats, spreading it out"
+        );
+    }
 
     Action::MarkForwards {
         forwards: all_forwards,
     }
 }
+
+/// How many different chats `forwards` touch.
+pub fn chat_count(forwards: &[(OriginalMessageId, ForwardLocation)]) -> usize {
+    forwards
+        .iter()
+        .map(|(_, f)| f.chat_id)
+        .collect::<HashSet<_>>()
+        .len()
+}
+
+/// Split `forwards` into batches touching at most `max_chats` chats each,
+/// never splitting one chat across batches. Chats keep their order of
+/// first appearance.
+pub fn split_by_chats(
+    forwards: Vec<(OriginalMessageId, ForwardLocation)>,
+    max_chats: usize,
+) -> Vec<Vec<(OriginalMessageId, ForwardLocation)>> {
+    let mut order: Vec<i64> = Vec::new();
+    let mut by_chat: HashMap<i64, Vec<(OriginalMessageId, ForwardLocation)>> = HashMap::new();
+    for pair in forwards {
+        let chat_id = pair.1.chat_id;
+        by_chat
+            .entry(chat_id)
+            .or_insert_with(|| {
+                order.push(chat_id);
+                Vec::new()
+            })
+            .push(pair);
+    }
+    order
+        .chunks(max_chats.max(1))
+        .map(|chats| {
+            chats
+                .iter()
+                .flat_map(|chat_id| by_chat.remove(chat_id).unwrap_or_default())
+                .collect()
+        })
+        .collect()
+}
+
+/// Messages for tests that plan new messages.
+#[cfg(test)]
+pub mod fixtures {
+    use super::*;
+
+    /// Message `message_id` of `chat_id`: an incoming forward of post 7 of
+    /// channel 5. Tests override what they need with struct update syntax.
+    pub fn incoming(chat_id: i64, message_id: i32) -> IncomingMessage {
+        let forward = tl::types::MessageFwdHeader {
+            imported: false,
+            saved_out: false,
+            from_id: Some(tl::types::PeerChannel { channel_id: 5 }.into()),
+            from_name: None,
+            date: 0,
+            channel_post: Some(7),
+            post_author: None,
+            saved_from_peer: None,
+            saved_from_msg_id: None,
+            saved_from_id: None,
+            saved_from_name: None,
+            saved_date: None,
+            psa_type: None,
+        };
+        IncomingMessage {
+            chat_id,
+            chat_name: None,
+            message_id,
+            outgoing: false,
+            sender_id: None,
+            text: "Breaking news".to_owned(),
+            links: Vec::new(),
+            service: false,
+            has_media: false,
+            media: None,
+            top_msg_id: None,
+            forward: Some(forward.into()),
+            migration: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::manual::ManualClock;
+    use crate::handler::fixtures::incoming;
+    use crate::marker::mock::MockMarker;
+    use crate::marker::DupAction;
+    use crate::tracker::ForwardStatus;
+
+    fn orig(peer: i64, msg: i32) -> OriginalMessageId {
+        OriginalMessageId { peer_id: peer, message_id: msg }
+    }
+
+    fn fwd(chat: i64, msg: i32) -> ForwardLocation {
+        ForwardLocation::new(chat, msg)
+    }
+
+    fn channel(id: i64) -> tl::enums::Peer {
+        tl::types::PeerChannel { channel_id: id }.into()
+    }
+
+    fn user(id: i64) -> tl::enums::Peer {
+        tl::types::PeerUser { user_id: id }.into()
+    }
+
+    /// Build a forward header with only the fields the handler looks at.
+    fn header(
+        from_id: Option<tl::enums::Peer>,
+        channel_post: Option<i32>,
+        saved_from_peer: Option<tl::enums::Peer>,
+        saved_from_msg_id: Option<i32>,
+    ) -> tl::enums::MessageFwdHeader {
+        tl::types::MessageFwdHeader {
+            imported: false,
+            saved_out: false,
+            from_id,
+            from_name: None,
+            date: 0,
+            channel_post,
+            post_author: None,
+            saved_from_peer,
+            saved_from_msg_id,
+            saved_from_id: None,
+            saved_from_name: None,
+            saved_date: None,
+            psa_type: None,
+        }
+        .into()
+    }
+
+    fn with_date(mut h: tl::enums::MessageFwdHeader, date: i32) -> tl::enums::MessageFwdHeader {
+        let tl::enums::MessageFwdHeader::Header(inner) = &mut h;
+        inner.date = date;
+        h
+    }
+
+    fn content(service: bool, has_text: bool, has_media: bool) -> MessageContent {
+        MessageContent {
+            service,
+            has_text,
+            has_media,
+        }
+    }
+
+    #[test]
+    fn content_filter_across_message_kinds() {
+        let service = content(true, false, false);
+        let empty = content(false, false, false);
+        let text = content(false, true, false);
+        let media = content(false, false, true);
+        let captioned = content(false, true, true);
+
+        let cases = [
+            (ContentFilter::Any, [false, false, true, true, true]),
+            (ContentFilter::TextOnly, [false, false, true, false, false]),
+            (ContentFilter::MediaOnly, [false, false, false, true, true]),
+        ];
+        for (filter, expected) in cases {
+            let got = [service, empty, text, media, captioned].map(|c| is_trackable(c, filter));
+            assert_eq!(got, expected, "{:?}", filter);
+        }
+    }
+
+    #[test]
+    fn unsupported_content_is_skipped_with_a_reason() {
+        let filter = ContentFilter::Any;
+        assert_eq!(
+            untracked_content(content(true, false, false), filter),
+            Some(Untracked::Service)
+        );
+        assert_eq!(
+            untracked_content(content(false, false, false), filter),
+            Some(Untracked::Content(filter))
+        );
+        assert_eq!(
+            untracked_content(content(false, false, true), ContentFilter::TextOnly),
+            Some(Untracked::Content(ContentFilter::TextOnly))
+        );
+        assert_eq!(untracked_content(content(false, true, false), filter), None);
+        assert_eq!(Untracked::Service.to_string(), "service or empty message");
+    }
+
+    #[test]
+    fn forwards_without_identity_are_skipped_with_a_reason() {
+        let strategy = IdentityStrategy::ForwardHeader;
+        // Hidden sender: neither a sender nor a post id in the header
+        let hidden = header(None, None, None, None);
+        assert_eq!(
+            untracked_identity(Some(&hidden), &[], strategy),
+            Some(Untracked::AnonymousForward)
+        );
+        // Not a forward at all
+        assert_eq!(
+            untracked_identity(None, &[], strategy),
+            Some(Untracked::NoIdentity(strategy))
+        );
+        // A sender but no usable id (date 0)
+        let undated = header(Some(user(42)), None, None, None);
+        assert_eq!(
+            untracked_identity(Some(&undated), &[], strategy),
+            Some(Untracked::AnonymousForward)
+        );
+
+        let h = header(Some(channel(5)), Some(7), None, None);
+        let keys = [orig(peer_to_chat_id(&channel(5)), 7)];
+        assert_eq!(untracked_identity(Some(&h), &keys, strategy), None);
+        // A content key is enough even when the forward header isn't
+        assert_eq!(untracked_identity(Some(&hidden), &keys, strategy), None);
+    }
+
+    #[test]
+    fn service_messages_are_classified_as_service() {
+        let raw: tl::enums::Message = tl::types::MessageEmpty {
+            id: 1,
+            peer_id: None,
+        }
+        .into();
+        assert_eq!(MessageContent::of(&raw), content(true, false, false));
+    }
+
+    #[test]
+    fn channel_post_identity_ignores_date() {
+        let h = with_date(header(Some(channel(5)), Some(7), None, None), 1_700_000_000);
+        assert_eq!(extract_original(&h), Some(orig(peer_to_chat_id(&channel(5)), 7)));
+    }
+
+    #[test]
+    fn user_forward_without_channel_post_uses_date() {
+        let h = with_date(header(Some(user(42)), None, None, None), 1_700_000_000);
+        assert_eq!(
+            extract_original(&h),
+            Some(orig(peer_to_chat_id(&user(42)), 1_700_000_000))
+        );
+
+        // The same message forwarded again elsewhere maps to the same original
+        let again = with_date(header(Some(user(42)), None, None, None), 1_700_000_000);
+        assert_eq!(extract_original(&h), extract_original(&again));
+
+        // A different message from the same sender does not
+        let other = with_date(header(Some(user(42)), None, None, None), 1_700_000_001);
+        assert_ne!(extract_original(&h), extract_original(&other));
+    }
+
+    #[test]
+    fn forward_without_channel_post_or_date_is_untracked() {
+        let h = header(Some(user(42)), None, None, None);
+        assert_eq!(extract_original(&h), None);
+        let h = with_date(header(None, None, None, None), 1_700_000_000);
+        assert_eq!(extract_original(&h), None);
+    }
+
+    fn anonymous(
+        post_author: Option<&str>,
+        from_name: Option<&str>,
+        saved_from_peer: Option<tl::enums::Peer>,
+        date: i32,
+    ) -> tl::enums::MessageFwdHeader {
+        let mut h = with_date(header(None, None, saved_from_peer, None), date);
+        let tl::enums::MessageFwdHeader::Header(inner) = &mut h;
+        inner.post_author = post_author.map(str::to_owned);
+        inner.from_name = from_name.map(str::to_owned);
+        h
+    }
+
+    #[test]
+    fn anonymous_forward_is_keyed_by_author_and_date() {
+        let h = anonymous(Some("Jane"), None, None, 1_700_000_000);
+        assert_eq!(extract_original(&h), None);
+        let original = extract_anonymous_original(&h).unwrap();
+
+        // Another copy of the same signed post groups with it
+        let again = anonymous(Some("Jane"), None, None, 1_700_000_000);
+        assert_eq!(extract_anonymous_original(&again), Some(original.clone()));
+
+        // A different author or second does not
+        let other = anonymous(Some("John"), None, None, 1_700_000_000);
+        assert_ne!(extract_anonymous_original(&other), Some(original.clone()));
+        let later = anonymous(Some("Jane"), None, None, 1_700_000_001);
+        assert_ne!(extract_anonymous_original(&later), Some(original));
+    }
+
+    #[test]
+    fn anonymous_forward_falls_back_to_from_name_and_uses_saved_peer() {
+        let named = anonymous(None, Some("Hidden Sender"), None, 1_700_000_000);
+        assert!(extract_anonymous_original(&named).is_some());
+
+        let saved = anonymous(Some("Jane"), None, Some(channel(5)), 1_700_000_000);
+        let unsaved = anonymous(Some("Jane"), None, None, 1_700_000_000);
+        assert_ne!(extract_anonymous_original(&saved), extract_anonymous_original(&unsaved));
+    }
+
+    #[test]
+    fn anonymous_fallback_needs_an_author_and_a_date() {
+        let date = 1_700_000_000;
+        assert_eq!(extract_anonymous_original(&anonymous(None, None, None, date)), None);
+        assert_eq!(extract_anonymous_original(&anonymous(Some(" "), None, None, date)), None);
+        assert_eq!(extract_anonymous_original(&anonymous(Some("Jane"), None, None, 0)), None);
+
+        // Headers that name a sender use the exact key instead
+        let h = with_date(header(Some(user(42)), None, None, None), 1_700_000_000);
+        assert_eq!(extract_anonymous_original(&h), None);
+    }
+
+    #[test]
+    fn migrations_are_recognized_from_either_side() {
+        let group = peer_to_chat_id(&tl::types::PeerChat { chat_id: 5 }.into());
+        let supergroup = peer_to_chat_id(&channel(9));
+
+        let to: tl::enums::MessageAction =
+            tl::types::MessageActionChatMigrateTo { channel_id: 9 }.into();
+        assert_eq!(migration(&to, group), Some((group, supergroup)));
+
+        let from: tl::enums::MessageAction = tl::types::MessageActionChannelMigrateFrom {
+            title: "Group".to_owned(),
+            chat_id: 5,
+        }
+        .into();
+        assert_eq!(migration(&from, supergroup), Some((group, supergroup)));
+
+        let other: tl::enums::MessageAction = tl::types::MessageActionHistoryClear {}.into();
+        assert_eq!(migration(&other, group), None);
+    }
+
+    #[test]
+    fn origin_is_the_forwarded_from_peer() {
+        let h = header(Some(channel(5)), Some(7), None, None);
+        assert_eq!(
+            origin_peer(&h).map(|p| p.bot_api_dialog_id()),
+            Some(peer_to_chat_id(&channel(5)))
+        );
+        assert_eq!(origin_peer(&header(None, None, None, None)), None);
+    }
+
+    #[tokio::test]
+    async fn cache_peer_caches_the_origin() {
+        let mut marker = MockMarker::default();
+        let origin = PeerId::from(&channel(5));
+        let action = Action::CachePeer {
+            chat_id: 10,
+            peer_ref: None,
+            name: "chat".to_owned(),
+            origin: Some(origin),
+        };
+        execute_action(action, &mut marker).await.unwrap();
+        assert_eq!(marker.origins, vec![origin.bot_api_dialog_id()]);
+    }
+
+    #[test]
+    fn discussion_echo_links_channel_post() {
+        let discussion = peer_to_chat_id(&channel(900));
+        let h = header(Some(channel(5)), Some(7), Some(channel(5)), Some(7));
+
+        let original = extract_original(&h).unwrap();
+        assert_eq!(original, orig(peer_to_chat_id(&channel(5)), 7));
+        assert_eq!(
+            linked_channel_post(&h, discussion),
+            Some(fwd(peer_to_chat_id(&channel(5)), 7))
+        );
+    }
+
+    #[test]
+    fn discussion_echo_of_reposted_post_links_channel_copy() {
+        // Channel 6 reposted channel 5's post as its own message 40; the
+        // discussion echo still names channel 5 as the original.
+        let discussion = peer_to_chat_id(&channel(900));
+        let h = header(Some(channel(5)), Some(7), Some(channel(6)), Some(40));
+
+        assert_eq!(extract_original(&h), Some(orig(peer_to_chat_id(&channel(5)), 7)));
+        assert_eq!(
+            linked_channel_post(&h, discussion),
+            Some(fwd(peer_to_chat_id(&channel(6)), 40))
+        );
+    }
+
+    #[test]
+    fn plain_forward_has_no_linked_post() {
+        let h = header(Some(channel(5)), Some(7), None, None);
+        assert_eq!(linked_channel_post(&h, 123), None);
+
+        // Saved from a user chat is not a channel echo
+        let h = header(Some(channel(5)), Some(7), Some(user(42)), Some(3));
+        assert_eq!(linked_channel_post(&h, 123), None);
+    }
+
+    #[test]
+    fn reading_channel_post_propagates_to_discussion_echo() {
+        let channel_chat = peer_to_chat_id(&channel(5));
+        let discussion = peer_to_chat_id(&channel(900));
+        let h = header(Some(channel(5)), Some(7), Some(channel(5)), Some(7));
+
+        let mut t = DuplicateTracker::default();
+        let original = extract_original(&h).unwrap();
+        t.register_forward(original.clone(), fwd(discussion, 300));
+        t.register_forward(original.clone(), linked_channel_post(&h, discussion).unwrap());
+
+        let action = plan_read_event(channel_chat, 7, &mut t, &PlanSettings::default());
+        match action {
+            Action::MarkForwards { forwards } => {
+                assert_eq!(forwards, vec![(original, fwd(discussion, 300))])
+            }
+            _ => panic!("expected MarkForwards"),
+        }
+    }
+
+    #[test]
+    fn read_event_propagates_to_other_chats() {
+        let mut t = DuplicateTracker::default();
+        let o = orig(1, 100);
+        t.register_forward(o.clone(), fwd(10, 50));
+        t.register_forward(o.clone(), fwd(20, 60));
+
+        let action = plan_read_event(10, 50, &mut t, &PlanSettings::default());
+        match action {
+            Action::MarkForwards { forwards } => assert_eq!(forwards, vec![(o, fwd(20, 60))]),
+            _ => panic!("expected MarkForwards"),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn marks_cut_short_by_a_restart_are_resumed_on_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let mut t = DuplicateTracker::default();
+        let o = orig(1, 100);
+        t.register_forward(o.clone(), fwd(10, 50));
+        t.register_forward(o.clone(), fwd(20, 60));
+        t.register_forward(o.clone(), fwd(30, 70));
+        let Action::MarkForwards { forwards } =
+            plan_read_event(10, 50, &mut t, &PlanSettings::default())
+        else {
+            panic!("expected MarkForwards");
+        };
+        // The process dies before the executor gets to it
+        t.save(&path).await.unwrap();
+
+        let loaded = DuplicateTracker::load(&path).unwrap();
+        let observe_only = PlanSettings {
+            observe_only: true,
+            ..Default::default()
+        };
+        assert!(plan_resume(&loaded, &observe_only).is_none());
+        let Some(Action::MarkForwards { forwards: resumed }) =
+            plan_resume(&loaded, &PlanSettings::default())
+        else {
+            panic!("expected MarkForwards");
+        };
+        assert_eq!(resumed, forwards);
+
+        let mut marker = MockMarker {
+            pending: Some(loaded.pending_marks()),
+            ..Default::default()
+        };
+        let action = Action::MarkForwards { forwards: resumed };
+        execute_action(action, &mut marker).await.unwrap();
+        assert_eq!(marker.reads(), vec![(20, 60), (30, 70)]);
+        assert!(plan_resume(&loaded, &PlanSettings::default()).is_none());
+    }
+
+    #[tokio::test]
+    async fn forward_details_follow_the_marks_made() {
+        let clock = ManualClock::at(1000);
+        let mut t = DuplicateTracker::default();
+        t.set_clock(clock.clone());
+        let o = orig(1, 100);
+        for forward in [fwd(10, 50), fwd(20, 60), fwd(30, 70)] {
+            t.register_forward(o.clone(), forward);
+        }
+        clock.advance(60);
+        t.register_forward(o.clone(), fwd(40, 80));
+        let settings = PlanSettings {
+            no_mark_chats: HashSet::from([30]),
+            min_forward_age_secs: 30,
+            ..Default::default()
+        };
+        let mut marker = MockMarker {
+            pending: Some(t.pending_marks()),
+            marked: Some(t.marked_copies()),
+            ..Default::default()
+        };
+        let status = |t: &DuplicateTracker| -> Vec<ForwardStatus> {
+            t.forward_details(&o).iter().map(|d| d.status).collect()
+        };
+
+        let action = plan_read_event(10, 50, &mut t, &settings);
+        execute_action(action, &mut marker).await.unwrap();
+        // Read in 10 and marked in 20; 30 is never marked and 40 too new
+        let (read, unmarked) = (ForwardStatus::Read, ForwardStatus::Unmarked);
+        assert_eq!(status(&t), vec![read, read, unmarked, ForwardStatus::Pending]);
+
+        clock.advance(30);
+        let action = plan_aged_forwards(&mut t, &settings).unwrap();
+        execute_action(action, &mut marker).await.unwrap();
+        assert_eq!(status(&t), vec![read, read, unmarked, read]);
+        assert_eq!(marker.reads(), vec![(20, 60), (40, 80)]);
+    }
+
+    #[tokio::test]
+    async fn forward_details_leave_skipped_copies_unread() {
+        let mut t = DuplicateTracker::default();
+        let o = orig(1, 100);
+        for forward in [fwd(10, 50), fwd(20, 60), fwd(30, 70)] {
+            t.register_forward(o.clone(), forward);
+        }
+        let mut marker = MockMarker {
+            muted: HashSet::from([20]),
+            pending: Some(t.pending_marks()),
+            marked: Some(t.marked_copies()),
+            ..Default::default()
+        };
+
+        let action = plan_read_event(10, 50, &mut t, &PlanSettings::default());
+        execute_action(action, &mut marker).await.unwrap();
+        let status: Vec<_> = t.forward_details(&o).iter().map(|d| d.status).collect();
+        assert_ne!(status[1], ForwardStatus::Read);
+        assert_eq!(status[2], ForwardStatus::Read);
+    }
+
+    #[test]
+    fn no_mark_chats_are_tracked_but_left_out_of_propagation() {
+        let mut t = DuplicateTracker::default();
+        let (a, b) = (orig(1, 100), orig(2, 200));
+        for (o, chat, msg) in [
+            (&a, 10, 50),
+            (&a, 20, 60),
+            (&a, 30, 70),
+            (&b, 10, 51),
+            (&b, 30, 71),
+        ] {
+            t.register_forward(o.clone(), fwd(chat, msg));
+        }
+        let settings = PlanSettings {
+            no_mark_chats: HashSet::from([30]),
+            ..Default::default()
+        };
+
+        let action = plan_read_event(10, 51, &mut t, &settings);
+        let Action::MarkForwards { forwards } = action else {
+            panic!("expected MarkForwards");
+        };
+        assert_eq!(forwards, vec![(a.clone(), fwd(20, 60))]);
+        assert!(t.is_original_read(&b));
+        assert_eq!(t.forward_count(&a), 3);
+        assert_eq!(t.pending_marks().forwards(), forwards);
+
+        // A read in the excluded chat still propagates out of it
+        let c = orig(3, 300);
+        t.register_forward(c.clone(), fwd(30, 72));
+        t.register_forward(c.clone(), fwd(20, 62));
+        let Action::MarkForwards { forwards } = plan_read_event(30, 72, &mut t, &settings) else {
+            panic!("expected MarkForwards");
+        };
+        assert_eq!(forwards, vec![(c.clone(), fwd(20, 62))]);
+
+        // Nor are late copies landing there marked
+        t.register_forward(c.clone(), fwd(30, 73));
+        assert!(plan_copy_of_read(&c, fwd(30, 73), &t, &settings).is_none());
+        assert!(plan_copy_of_read(&c, fwd(40, 80), &t, &settings).is_some());
+    }
+
+    #[tokio::test]
+    async fn saved_messages_can_be_left_out_of_propagation() {
+        let me = 777;
+        let post = orig(1, 100);
+        let tracked = || {
+            let mut t = DuplicateTracker::default();
+            for (chat, msg) in [(me, 5), (20, 60), (30, 70)] {
+                t.register_forward(post.clone(), fwd(chat, msg));
+            }
+            t
+        };
+        let included = PlanSettings {
+            self_chat_id: Some(me),
+            ..Default::default()
+        };
+        let excluded = PlanSettings {
+            include_saved_messages: false,
+            ..included.clone()
+        };
+
+        // Included by default: a read there propagates, and copies there
+        // are marked
+        let Action::MarkForwards { forwards } = plan_read_event(me, 5, &mut tracked(), &included)
+        else {
+            panic!("expected MarkForwards");
+        };
+        assert_eq!(forwards, vec![(post.clone(), fwd(20, 60)), (post.clone(), fwd(30, 70))]);
+        let Action::MarkForwards { forwards } = plan_read_event(20, 60, &mut tracked(), &included)
+        else {
+            panic!("expected MarkForwards");
+        };
+        assert_eq!(forwards, vec![(post.clone(), fwd(30, 70)), (post.clone(), fwd(me, 5))]);
+
+        // Left out: reading there changes nothing, and copies there stay
+        let mut t = tracked();
+        assert!(matches!(plan_read_event(me, 5, &mut t, &excluded), Action::None));
+        assert!(!t.is_original_read(&post));
+        let Action::MarkForwards { forwards } = plan_read_event(20, 60, &mut t, &excluded) else {
+            panic!("expected MarkForwards");
+        };
+        assert_eq!(forwards, vec![(post.clone(), fwd(30, 70))]);
+        assert!(plan_copy_of_read(&post, fwd(me, 6), &t, &excluded).is_none());
+
+        // Nor are new forwards saved there tracked; elsewhere they are
+        let saved = |chat_id| IncomingMessage {
+            outgoing: true,
+            ..incoming(chat_id, 8)
+        };
+        let other = orig(peer_to_chat_id(&channel(5)), 7);
+        let mut settings = excluded.clone();
+        let mut t = DuplicateTracker::default();
+        plan_new_message(&saved(me), std::future::ready(None), &mut t, &mut settings).await;
+        assert_eq!(t.forward_count(&other), 0);
+        plan_new_message(&saved(40), std::future::ready(None), &mut t, &mut settings).await;
+        assert_eq!(t.forward_count(&other), 1);
+
+        let mut settings = included;
+        plan_new_message(&saved(me), std::future::ready(None), &mut t, &mut settings).await;
+        assert_eq!(t.forward_count(&other), 2);
+    }
+
+    #[test]
+    fn propagation_is_ordered_by_chat_then_message() {
+        let mut t = DuplicateTracker::default();
+        // Register in deliberately scrambled order across several originals
+        for (o, chat, msg) in [
+            (orig(1, 100), 30, 7),
+            (orig(2, 200), 20, 9),
+            (orig(1, 100), 20, 3),
+            (orig(3, 300), 30, 1),
+            (orig(2, 200), 40, 2),
+            (orig(3, 300), 20, 5),
+        ] {
+            // Each original also has the copy in chat 10 that gets read
+            t.register_forward(o.clone(), fwd(10, o.message_id));
+            t.register_forward(o, fwd(chat, msg));
+        }
+
+        let action = plan_read_event(10, i32::MAX, &mut t, &PlanSettings::default());
+        let Action::MarkForwards { forwards } = action else {
+            panic!("expected MarkForwards");
+        };
+        let order: Vec<(i64, i32)> = forwards
+            .iter()
+            .map(|(_, f)| (f.chat_id, f.message_id))
+            .collect();
+        assert_eq!(order, vec![(20, 3), (20, 5), (20, 9), (30, 1), (30, 7), (40, 2)]);
+    }
+
+    #[test]
+    fn edit_of_tracked_original_refreshes_preview() {
+        let mut t = DuplicateTracker::default();
+        let source = -1005;
+        t.register_forward(orig(source, 7), fwd(10, 50));
+        t.set_preview_if_absent(&orig(source, 7), "old text".to_owned());
+
+        let action = plan_edit(source, 7, "new text", &mut t, &PlanSettings::default());
+        assert!(matches!(action, Action::None));
+        assert_eq!(t.preview(&orig(source, 7)), Some("new text"));
+        assert_eq!(t.stats().originals, 1);
+
+        // Edits of untracked messages change nothing
+        plan_edit(source, 8, "other", &mut t, &PlanSettings::default());
+        assert_eq!(t.stats().originals, 1);
+        assert_eq!(t.preview(&orig(source, 8)), None);
+    }
+
+    #[test]
+    fn edits_leave_previews_alone_when_disabled() {
+        let mut t = DuplicateTracker::default();
+        t.register_forward(orig(-1005, 7), fwd(10, 50));
+        t.set_preview_if_absent(&orig(-1005, 7), "old text".to_owned());
+        let settings = PlanSettings {
+            preview_len: 0,
+            ..Default::default()
+        };
+
+        plan_edit(-1005, 7, "new text", &mut t, &settings);
+        assert_eq!(t.preview(&orig(-1005, 7)), Some("old text"));
+    }
+
+    #[test]
+    fn previews_respect_length() {
+        assert_eq!(preview("hello world", 0), None);
+        assert_eq!(preview("hello world", 5).as_deref(), Some("hello..."));
+        assert_eq!(preview("hello world", 1000).as_deref(), Some("hello world"));
+        assert_eq!(preview("hello", 5).as_deref(), Some("hello"));
+    }
+
+    #[test]
+    fn previews_count_characters_not_bytes() {
+        // Cyrillic takes two bytes per character, these emoji four
+        assert_eq!(preview("привет", 4).as_deref(), Some("прив..."));
+        assert_eq!(preview("🙂🙂🙂🙂", 4).as_deref(), Some("🙂🙂🙂🙂"));
+        assert_eq!(preview("🙂🙂🙂🙂🙂", 2).as_deref(), Some("🙂🙂..."));
+    }
+
+    #[test]
+    fn channel_read_maps_to_dialog_id() {
+        let raw = tl::enums::Update::ReadChannelInbox(tl::types::UpdateReadChannelInbox {
+            folder_id: None,
+            channel_id: 1234,
+            max_id: 77,
+            still_unread_count: 0,
+            pts: 1,
+        });
+        assert_eq!(raw_read_event(&raw), Some((-1000000001234, 77)));
+    }
+
+    #[test]
+    fn outbox_reads_are_not_our_reads() {
+        let mut t = DuplicateTracker::default();
+        t.register_forward(orig(1, 100), fwd(-1000000001234, 50));
+        t.register_forward(orig(1, 100), fwd(20, 60));
+
+        let channel_outbox =
+            tl::enums::Update::ReadChannelOutbox(tl::types::UpdateReadChannelOutbox {
+                channel_id: 1234,
+                max_id: 50,
+            });
+        let history_outbox =
+            tl::enums::Update::ReadHistoryOutbox(tl::types::UpdateReadHistoryOutbox {
+                peer: user(20),
+                max_id: 60,
+                pts: 1,
+                pts_count: 1,
+            });
+
+        for raw in [channel_outbox, history_outbox] {
+            assert_eq!(raw_read_event(&raw), None);
+            let action = plan_raw_update(&raw, &mut t, &PlanSettings::default());
+            assert!(matches!(action, Action::None));
+        }
+        assert!(!t.is_original_read(&orig(1, 100)));
+    }
+
+    #[test]
+    fn ignored_raw_updates_are_named_by_type() {
+        assert_eq!(
+            raw_update_name(&tl::enums::Update::ReadFeaturedStickers),
+            "ReadFeaturedStickers"
+        );
+        let outbox = tl::enums::Update::ReadChannelOutbox(tl::types::UpdateReadChannelOutbox {
+            channel_id: 1234,
+            max_id: 50,
+        });
+        assert_eq!(raw_update_name(&outbox), "ReadChannelOutbox");
+
+        let mut t = DuplicateTracker::default();
+        let settings = PlanSettings {
+            trace_raw_updates: true,
+            ..Default::default()
+        };
+        assert!(matches!(plan_raw_update(&outbox, &mut t, &settings), Action::None));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throttled_logging_still_marks_every_copy() {
+        let gate = Arc::new(LogGate::new(1));
+        let mut marker = MockMarker {
+            log_gate: Some(Arc::clone(&gate)),
+            ..Default::default()
+        };
+        let o = orig(1, 100);
+        let forwards: Vec<_> = (0..5).map(|i| (o.clone(), fwd(20 + i, 60))).collect();
+
+        execute_action(Action::MarkForwards { forwards }, &mut marker).await.unwrap();
+
+        assert_eq!(marker.reads().len(), 5);
+        // The bucket was spent on the first line
+        assert!(!gate.admit("forwards marked read"));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throttled_detections_are_still_tracked() {
+        let mut t = DuplicateTracker::default();
+        let mut settings = PlanSettings {
+            log_gate: Some(Arc::new(LogGate::new(1))),
+            ..Default::default()
+        };
+        for chat_id in 10..15 {
+            let message = incoming(chat_id, 50);
+            plan_new_message(&message, std::future::ready(None), &mut t, &mut settings).await;
+        }
+        assert_eq!(t.forward_count(&orig(peer_to_chat_id(&channel(5)), 7)), 5);
+    }
+
+    #[tokio::test]
+    async fn forwarder_sources_keep_each_forwarders_copies_apart() {
+        let forwarded_by = |chat_id, sender| IncomingMessage {
+            sender_id: Some(sender),
+            ..incoming(chat_id, 50)
+        };
+        let source = peer_to_chat_id(&channel(5));
+        let copies = [(10, 101), (20, 102), (30, 101)];
+
+        // Off by default: the same post whoever forwarded it
+        let mut t = DuplicateTracker::default();
+        let mut settings = PlanSettings::default();
+        for (chat_id, sender) in copies {
+            let message = forwarded_by(chat_id, sender);
+            plan_new_message(&message, std::future::ready(None), &mut t, &mut settings).await;
+        }
+        assert_eq!(t.forward_count(&orig(source, 7)), 3);
+
+        // For a configured source, each forwarder's copies stand alone
+        let mut t = DuplicateTracker::default();
+        let mut settings = PlanSettings {
+            forwarder_sources: HashSet::from([source]),
+            ..Default::default()
+        };
+        for (chat_id, sender) in copies {
+            let message = forwarded_by(chat_id, sender);
+            plan_new_message(&message, std::future::ready(None), &mut t, &mut settings).await;
+        }
+        assert_eq!(t.forward_count(&orig(source, 7)), 0);
+        let first = t.lookup_forward(&fwd(10, 50)).cloned().unwrap();
+        assert_eq!(t.lookup_forward(&fwd(30, 50)), Some(&first));
+        assert_ne!(t.lookup_forward(&fwd(20, 50)), Some(&first));
+        let Action::MarkForwards { forwards } = plan_read_event(10, 50, &mut t, &settings) else {
+            panic!("expected MarkForwards");
+        };
+        assert_eq!(forwards, vec![(first, fwd(30, 50))]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn archive_action_archives_each_chat_once() {
+        let mut marker = MockMarker {
+            dup_action: DupAction::Archive,
+            ..Default::default()
+        };
+        let o = orig(1, 100);
+        let action = Action::MarkForwards {
+            forwards: vec![
+                (o.clone(), fwd(20, 60)),
+                (o.clone(), fwd(20, 61)),
+                (orig(2, 200), fwd(30, 70)),
+                (o, fwd(20, 62)),
+            ],
+        };
+
+        execute_action(action, &mut marker).await.unwrap();
+
+        assert_eq!(marker.archived(), vec![20, 30]);
+        assert!(marker.reads().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn both_action_reads_and_archives() {
+        let mut marker = MockMarker {
+            dup_action: DupAction::Both,
+            ..Default::default()
+        };
+        let action = Action::MarkForwards {
+            forwards: vec![(orig(1, 100), fwd(20, 60)), (orig(1, 100), fwd(30, 70))],
+        };
+
+        execute_action(action, &mut marker).await.unwrap();
+
+        assert_eq!(marker.reads(), vec![(20, 60), (30, 70)]);
+        assert_eq!(marker.archived(), vec![20, 30]);
+    }
+
+    #[tokio::test]
+    async fn propagations_held_into_observe_only_stay_pending() {
+        let t = DuplicateTracker::default();
+        let mut marker = MockMarker {
+            observe_only: true,
+            pending: Some(t.pending_marks()),
+            ..Default::default()
+        };
+        let forwards = vec![(orig(1, 100), fwd(20, 60))];
+        t.pending_marks().add(&forwards);
+
+        let action = Action::MarkForwards {
+            forwards: forwards.clone(),
+        };
+        execute_action(action, &mut marker).await.unwrap();
+        let folder = Action::MarkFolder {
+            folder_id: 3,
+            report_to: Some(99),
+            forwards,
+        };
+        execute_action(folder, &mut marker).await.unwrap();
+
+        assert!(marker.reads().is_empty());
+        assert_eq!(t.pending_marks().len(), 1);
+        assert_eq!(
+            marker.sent(),
+            vec![(99, "Observe-only: not marking duplicates in folder 3".to_owned())]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn execute_mark_forwards_issues_reads() {
+        let mut marker = MockMarker::default();
+        let action = Action::MarkForwards {
+            forwards: vec![(orig(1, 100), fwd(20, 60)), (orig(1, 100), fwd(30, 70))],
+        };
+
+        execute_action(action, &mut marker).await.unwrap();
+
+        assert_eq!(marker.reads(), vec![(20, 60), (30, 70)]);
+        // The default action only reads
+        assert!(marker.archived().is_empty());
+        // One propagation recorded, spanning the delay between the two reads
+        assert_eq!(marker.propagations.len(), 1);
+        assert!(marker.propagations[0] >= std::time::Duration::from_millis(500));
+    }
+
+    #[tokio::test]
+    async fn execute_none_issues_nothing() {
+        let mut marker = MockMarker::default();
+        execute_action(Action::None, &mut marker).await.unwrap();
+        assert!(marker.reads().is_empty());
+    }
+
+    #[test]
+    fn control_command_replies_with_result() {
+        let mut t = DuplicateTracker::default();
+        t.register_forward(orig(1, 100), fwd(10, 50));
+
+        let mut settings = PlanSettings::default();
+        let (reply, action) =
+            plan_control_command("/dupforget 10", &mut t, &mut settings).unwrap();
+        assert_eq!(reply, "Forgot 1 forwards related to chat 10");
+        assert!(action.is_none());
+        assert_eq!(t.stats().forwards, 0);
+    }
+
+    #[tokio::test]
+    async fn stats_are_answered_without_the_planner() {
+        let mut t = DuplicateTracker::default();
+        t.register_forward(orig(1, 100), fwd(10, 50));
+        let live = t.live_stats();
+        let settings = PlanSettings {
+            self_chat_id: Some(99),
+            ..Default::default()
+        };
+        let sent = |chat_id, text: &str| IncomingMessage {
+            outgoing: true,
+            text: text.to_owned(),
+            ..incoming(chat_id, 8)
+        };
+        let stats = sent(99, "/dupstats");
+        let reply = plan_stats_reply(&stats, std::future::ready(None), &live, &settings).await;
+        let Some(Action::Reply { chat_id: 99, text, .. }) = reply else {
+            panic!("expected a reply in Saved Messages");
+        };
+        assert!(text.contains("Forwards:       1"));
+
+        // Only /dupstats, and only from the user in Saved Messages
+        for message in [
+            sent(99, "/dupforget 10"),
+            sent(40, "/dupstats"),
+            IncomingMessage { outgoing: false, ..sent(99, "/dupstats") },
+        ] {
+            let reply = plan_stats_reply(&message, std::future::ready(None), &live, &settings);
+            assert!(reply.await.is_none());
+        }
+    }
+
+    #[test]
+    fn ordinary_saved_message_is_not_a_command() {
+        let mut t = DuplicateTracker::default();
+        let mut settings = PlanSettings::default();
+        assert!(plan_control_command("remember the milk", &mut t, &mut settings).is_none());
+    }
+
+    #[test]
+    fn mark_command_marks_every_copy() {
+        let mut t = DuplicateTracker::default();
+        t.register_forward(orig(-1001, 7), fwd(10, 50));
+        t.register_forward(orig(-1001, 7), fwd(20, 60));
+        let mut settings = PlanSettings::default();
+
+        let (reply, action) =
+            plan_control_command("/dupmark -1001 7", &mut t, &mut settings).unwrap();
+        assert_eq!(reply, "Marking 2 copies of (-1001, 7) read");
+        match action {
+            Some(Action::MarkForwards { forwards }) => assert_eq!(forwards.len(), 2),
+            _ => panic!("expected MarkForwards"),
+        }
+        assert!(t.is_original_read(&orig(-1001, 7)));
+
+        let (reply, action) =
+            plan_control_command("/dupmark -1001 8", &mut t, &mut settings).unwrap();
+        assert_eq!(reply, "Post (-1001, 8) is not tracked");
+        assert!(action.is_none());
+    }
+
+    #[test]
+    fn mark_command_only_records_when_observing() {
+        let mut t = DuplicateTracker::default();
+        t.register_forward(orig(-1001, 7), fwd(10, 50));
+        let mut settings = PlanSettings {
+            observe_only: true,
+            ..Default::default()
+        };
+
+        let (reply, action) =
+            plan_control_command("/dupmark -1001 7", &mut t, &mut settings).unwrap();
+        assert_eq!(reply, "Observe-only: would mark 1 copies of (-1001, 7)");
+        assert!(action.is_none());
+        assert!(t.is_original_read(&orig(-1001, 7)));
+    }
+
+    #[test]
+    fn folder_command_carries_every_unread_duplicate() {
+        let mut t = DuplicateTracker::default();
+        let mut settings = PlanSettings {
+            self_chat_id: Some(777),
+            ..Default::default()
+        };
+        let (reply, action) =
+            plan_control_command("/dupfolder 3", &mut t, &mut settings).unwrap();
+        assert_eq!(reply, "No unread duplicates to mark");
+        assert!(action.is_none());
+
+        t.register_forward(orig(-1001, 7), fwd(20, 60));
+        t.register_forward(orig(-1001, 7), fwd(10, 50));
+        t.register_forward(orig(-1001, 8), fwd(10, 51));
+        let (_, action) = plan_control_command("/dupfolder 3", &mut t, &mut settings).unwrap();
+        match action {
+            Some(Action::MarkFolder {
+                folder_id,
+                report_to,
+                forwards,
+            }) => {
+                assert_eq!((folder_id, report_to), (3, Some(777)));
+                let chats: Vec<i64> = forwards.iter().map(|(_, f)| f.chat_id).collect();
+                assert_eq!(chats, vec![10, 20]);
+            }
+            _ => panic!("expected MarkFolder"),
+        }
+        assert!(!t.is_original_read(&orig(-1001, 7)), "copies elsewhere stay unread");
+    }
+
+    #[test]
+    fn only_forwards_in_the_chosen_chats_are_kept() {
+        let forwards = vec![
+            (orig(1, 100), fwd(10, 50)),
+            (orig(1, 100), fwd(20, 60)),
+            (orig(2, 200), fwd(30, 70)),
+            (orig(2, 200), fwd(10, 51)),
+        ];
+        let kept = forwards_in_chats(forwards.clone(), &HashSet::from([10, 40]));
+        assert_eq!(kept.len(), 2);
+        assert!(kept.iter().all(|(_, f)| f.chat_id == 10));
+        assert!(forwards_in_chats(forwards, &HashSet::new()).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn folder_action_marks_its_chats_and_reports() {
+        let mut marker = MockMarker {
+            folders: HashMap::from([(3, HashSet::from([20, 30]))]),
+            ..Default::default()
+        };
+        let action = Action::MarkFolder {
+            folder_id: 3,
+            report_to: Some(777),
+            forwards: vec![(orig(1, 100), fwd(10, 50)), (orig(1, 100), fwd(20, 60))],
+        };
+        execute_action(action, &mut marker).await.unwrap();
+        assert_eq!(marker.reads(), vec![(20, 60)]);
+        assert_eq!(
+            marker.sent(),
+            vec![(777, "Folder 3: marked 1 duplicates in 1 chats".to_owned())]
+        );
+
+        let unknown = Action::MarkFolder {
+            folder_id: 9,
+            report_to: Some(777),
+            forwards: vec![(orig(1, 100), fwd(20, 60))],
+        };
+        execute_action(unknown, &mut marker).await.unwrap();
+        assert_eq!(marker.reads().len(), 1);
+        assert_eq!(marker.sent()[1].1, "Couldn't look up folder 9: No folder with id 9");
+    }
+
+    #[test]
+    fn ignore_command_filters_the_source_from_then_on() {
+        let mut t = DuplicateTracker::default();
+        let mut settings = PlanSettings::default();
+
+        let (_, action) =
+            plan_control_command("/dupignore -1001", &mut t, &mut settings).unwrap();
+        assert!(action.is_none());
+        assert!(!settings.sources.allows(Some(-1001)));
+        assert!(settings.sources.allows(Some(-1002)));
+    }
+
+    #[test]
+    fn notice_is_sent_once_when_a_post_becomes_a_duplicate() {
+        let mut t = DuplicateTracker::default();
+        let o = orig(-1001, 7);
+        let settings = PlanSettings {
+            self_chat_id: Some(777),
+            notify_duplicates: true,
+            ..Default::default()
+        };
+
+        t.register_forward(o.clone(), fwd(10, 50));
+        assert!(plan_duplicate_notice(&o, 0, &t, &settings).is_none(), "only one copy");
+        t.register_forward(o.clone(), fwd(20, 60));
+        match plan_duplicate_notice(&o, 1, &t, &settings) {
+            Some(Action::Reply { chat_id, text, .. }) => {
+                assert_eq!(chat_id, 777);
+                assert!(text.contains("/dupmark -1001 7"));
+            }
+            _ => panic!("expected Reply"),
+        }
+        t.register_forward(o.clone(), fwd(30, 70));
+        assert!(plan_duplicate_notice(&o, 2, &t, &settings).is_none(), "already noticed");
+    }
+
+    #[test]
+    fn no_notice_when_disabled_or_already_read() {
+        let mut t = DuplicateTracker::default();
+        let o = orig(-1001, 7);
+        t.register_forward(o.clone(), fwd(10, 50));
+        t.register_forward(o.clone(), fwd(20, 60));
+
+        let disabled = PlanSettings {
+            self_chat_id: Some(777),
+            ..Default::default()
+        };
+        assert!(plan_duplicate_notice(&o, 1, &t, &disabled).is_none());
+
+        let enabled = PlanSettings {
+            notify_duplicates: true,
+            ..disabled
+        };
+        t.mark_original_read(&o);
+        assert!(plan_duplicate_notice(&o, 1, &t, &enabled).is_none());
+    }
+
+    #[tokio::test]
+    async fn execute_reply_sends_message() {
+        let mut marker = MockMarker::default();
+        let action = Action::Reply {
+            chat_id: 777,
+            peer_ref: None,
+            text: "hello".to_owned(),
+        };
+        execute_action(action, &mut marker).await.unwrap();
+        assert_eq!(marker.sent(), vec![(777, "hello".to_owned())]);
+    }
+
+    #[test]
+    fn observe_only_never_marks_but_records_read() {
+        let mut t = DuplicateTracker::default();
+        let o = orig(1, 100);
+        t.register_forward(o.clone(), fwd(10, 50));
+        t.register_forward(o.clone(), fwd(20, 60));
+
+        let settings = PlanSettings {
+            observe_only: true,
+            ..Default::default()
+        };
+        let action = plan_read_event(10, 50, &mut t, &settings);
+
+        assert!(matches!(action, Action::None));
+        assert!(t.is_original_read(&o));
+    }
+
+    #[test]
+    fn copy_of_a_read_post_is_marked_on_arrival() {
+        let mut t = DuplicateTracker::default();
+        let o = orig(1, 100);
+        t.register_forward(o.clone(), fwd(10, 50));
+        t.mark_original_read(&o);
+        let settings = PlanSettings::default();
+
+        t.register_forward(o.clone(), fwd(30, 70));
+        let action = plan_copy_of_read(&o, fwd(30, 70), &t, &settings).unwrap();
+
+        match action {
+            Action::MarkForwards { forwards } => assert_eq!(forwards, vec![(o, fwd(30, 70))]),
+            _ => panic!("expected MarkForwards"),
+        }
+    }
+
+    #[test]
+    fn copy_of_an_unread_post_waits_for_a_read() {
+        let mut t = DuplicateTracker::default();
+        let o = orig(1, 100);
+        t.register_forward(o.clone(), fwd(10, 50));
+        t.register_forward(o.clone(), fwd(30, 70));
+
+        let settings = PlanSettings::default();
+        assert!(plan_copy_of_read(&o, fwd(30, 70), &t, &settings).is_none());
+    }
+
+    #[test]
+    fn late_copies_stay_unread_when_disabled_or_observing() {
+        let mut t = DuplicateTracker::default();
+        let o = orig(1, 100);
+        t.register_forward(o.clone(), fwd(10, 50));
+        t.mark_original_read(&o);
+        t.register_forward(o.clone(), fwd(30, 70));
+
+        let disabled = PlanSettings {
+            mark_late_copies: false,
+            ..Default::default()
+        };
+        assert!(plan_copy_of_read(&o, fwd(30, 70), &t, &disabled).is_none());
+        let observing = PlanSettings {
+            observe_only: true,
+            ..Default::default()
+        };
+        assert!(plan_copy_of_read(&o, fwd(30, 70), &t, &observing).is_none());
+    }
+
+    #[test]
+    fn copy_after_read_state_expired_is_left_unread() {
+        let clock = ManualClock::at(1000);
+        let mut t = DuplicateTracker::default();
+        t.set_clock(clock.clone());
+        t.set_read_state_ttl(Some(100));
+        let o = orig(1, 100);
+        t.register_forward(o.clone(), fwd(10, 50));
+        t.mark_original_read(&o);
+        clock.advance(101);
+
+        t.register_forward(o.clone(), fwd(30, 70));
+        let settings = PlanSettings::default();
+        assert!(plan_copy_of_read(&o, fwd(30, 70), &t, &settings).is_none());
+    }
+
+    #[test]
+    fn read_skips_copies_younger_than_the_minimum_age() {
+        let clock = ManualClock::at(1000);
+        let mut t = DuplicateTracker::default();
+        t.set_clock(clock.clone());
+        let o = orig(1, 100);
+        t.register_forward(o.clone(), fwd(10, 50));
+        t.register_forward(o.clone(), fwd(20, 60));
+        clock.advance(30);
+        t.register_forward(o.clone(), fwd(30, 70));
+        clock.advance(5);
+        let settings = PlanSettings {
+            min_forward_age_secs: 30,
+            ..Default::default()
+        };
+
+        // Chat 20's copy is 35s old, chat 30's only 5s
+        match plan_read_event(10, 50, &mut t, &settings) {
+            Action::MarkForwards { forwards } => {
+                let chats: Vec<i64> = forwards.iter().map(|(_, f)| f.chat_id).collect();
+                assert_eq!(chats, vec![20]);
+            }
+            _ => panic!("expected MarkForwards"),
+        }
+        assert!(t.is_original_read(&o));
+    }
+
+    #[test]
+    fn too_new_copies_are_marked_once_they_come_of_age() {
+        let clock = ManualClock::at(1000);
+        let mut t = DuplicateTracker::default();
+        t.set_clock(clock.clone());
+        let o = orig(1, 100);
+        t.register_forward(o.clone(), fwd(10, 50));
+        clock.advance(40);
+        t.register_forward(o.clone(), fwd(20, 60));
+        t.register_forward(o.clone(), fwd(30, 70));
+        clock.advance(10);
+        let settings = PlanSettings {
+            min_forward_age_secs: 30,
+            ..Default::default()
+        };
+
+        assert!(matches!(
+            plan_read_event(10, 50, &mut t, &settings),
+            Action::None
+        ));
+        // Held, and saved as pending in case of a restart meanwhile
+        assert_eq!(t.next_aged_in(30), Some(20));
+        assert_eq!(t.pending_marks().len(), 2);
+        assert!(plan_aged_forwards(&mut t, &settings).is_none());
+
+        // Chat 30's copy is forgotten before it comes of age
+        t.forget_forwards_in_chat(30);
+        clock.advance(20);
+        match plan_aged_forwards(&mut t, &settings) {
+            Some(Action::MarkForwards { forwards }) => {
+                assert_eq!(forwards, vec![(o.clone(), fwd(20, 60))]);
+            }
+            _ => panic!("expected MarkForwards"),
+        }
+        assert_eq!(t.next_aged_in(30), None);
+        assert!(plan_aged_forwards(&mut t, &settings).is_none());
+    }
+
+    #[test]
+    fn aged_and_loaded_copies_pass_the_minimum_age() {
+        let clock = ManualClock::at(1000);
+        let mut t = DuplicateTracker::default();
+        t.set_clock(clock.clone());
+        let o = orig(1, 100);
+        t.register_forward(o.clone(), fwd(10, 50));
+        t.register_forward(o.clone(), fwd(20, 60));
+        let settings = PlanSettings {
+            min_forward_age_secs: 30,
+            ..Default::default()
+        };
+        clock.advance(30);
+        assert!(old_enough(&t, &fwd(20, 60), 30));
+        assert!(!old_enough(&t, &fwd(20, 60), 31));
+        assert!(old_enough(&t, &fwd(20, 60), 0));
+        // Nothing is known about copies from before startup
+        assert!(old_enough(&t, &fwd(99, 1), 3600));
+        // Set back past the registration, a copy is too new again
+        clock.set(900);
+        assert!(!old_enough(&t, &fwd(20, 60), 30));
+        clock.set(1030);
+
+        assert!(matches!(
+            plan_read_event(10, 50, &mut t, &settings),
+            Action::MarkForwards { .. }
+        ));
+    }
+
+    #[test]
+    fn reads_over_the_chat_limit_split_at_chat_boundaries() {
+        let o = orig(1, 100);
+        let forwards = vec![
+            (o.clone(), fwd(10, 1)),
+            (o.clone(), fwd(10, 2)),
+            (o.clone(), fwd(20, 1)),
+            (o.clone(), fwd(30, 1)),
+        ];
+        assert_eq!(chat_count(&forwards), 3);
+
+        // At the limit everything goes in one batch
+        assert_eq!(split_by_chats(forwards.clone(), 3), vec![forwards.clone()]);
+
+        let batches = split_by_chats(forwards.clone(), 2);
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0], forwards[..3].to_vec(), "both copies in chat 10 together");
+        assert_eq!(batches[1], forwards[3..].to_vec());
+    }
+
+    #[test]
+    fn pick_original_joins_a_tracked_key() {
+        let mut t = DuplicateTracker::default();
+        assert_eq!(pick_original(vec![], &t), None);
+        assert_eq!(
+            pick_original(vec![orig(1, 100), orig(2, 200)], &t),
+            Some(orig(1, 100))
+        );
+
+        t.register_forward(orig(2, 200), fwd(10, 50));
+        assert_eq!(
+            pick_original(vec![orig(1, 100), orig(2, 200)], &t),
+            Some(orig(2, 200))
+        );
+    }
+
+    /// A repost of `photo` as message `message_id` of `chat_id`, not
+    /// forwarded.
+    fn photo(chat_id: i64, message_id: i32, photo: i64) -> IncomingMessage {
+        IncomingMessage {
+            forward: None,
+            has_media: true,
+            media: Some(MediaKey::Photo(photo)),
+            ..incoming(chat_id, message_id)
+        }
+    }
+
+    fn by_media() -> PlanSettings {
+        PlanSettings {
+            identity: IdentityStrategy::MediaFileId,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn same_photo_groups_together() {
+        let (mut t, mut settings) = (DuplicateTracker::default(), by_media());
+        for message in [photo(10, 50, 7), photo(20, 60, 7)] {
+            plan_new_message(&message, std::future::ready(None), &mut t, &mut settings).await;
+        }
+        let first = t.lookup_forward(&fwd(10, 50)).cloned().unwrap();
+        assert_eq!(t.lookup_forward(&fwd(20, 60)), Some(&first));
+        assert_eq!(t.original_of_media(&MediaKey::Photo(7)), Some(&first));
+
+        let action = plan_read_event(10, 50, &mut t, &settings);
+        let Action::MarkForwards { forwards } = action else {
+            panic!("expected marks");
+        };
+        assert_eq!(forwards, vec![(first, fwd(20, 60))]);
+    }
+
+    #[tokio::test]
+    async fn different_photos_stay_apart() {
+        let (mut t, mut settings) = (DuplicateTracker::default(), by_media());
+        for message in [photo(10, 50, 7), photo(20, 60, 8)] {
+            plan_new_message(&message, std::future::ready(None), &mut t, &mut settings).await;
+        }
+        assert_ne!(t.lookup_forward(&fwd(10, 50)), t.lookup_forward(&fwd(20, 60)));
+        assert!(matches!(
+            plan_read_event(10, 50, &mut t, &settings),
+            Action::None
+        ));
+    }
+
+    #[tokio::test]
+    async fn reposted_photos_join_the_forward_they_came_from() {
+        let mut t = DuplicateTracker::default();
+        let mut settings = PlanSettings {
+            identity: IdentityStrategy::Combined,
+            ..Default::default()
+        };
+        let forward = IncomingMessage {
+            has_media: true,
+            media: Some(MediaKey::Photo(7)),
+            ..incoming(10, 50)
+        };
+        plan_new_message(&forward, std::future::ready(None), &mut t, &mut settings).await;
+        let repost = IncomingMessage {
+            text: "Reposted without the header".to_owned(),
+            ..photo(20, 60, 7)
+        };
+        plan_new_message(&repost, std::future::ready(None), &mut t, &mut settings).await;
+        let post = orig(peer_to_chat_id(&channel(5)), 7);
+        assert_eq!(t.lookup_forward(&fwd(20, 60)), Some(&post));
+        assert_eq!(t.forward_count(&post), 2);
+
+        // Under the header alone the file isn't looked at
+        let mut t = DuplicateTracker::default();
+        let mut settings = PlanSettings::default();
+        plan_new_message(&forward, std::future::ready(None), &mut t, &mut settings).await;
+        assert_eq!(t.original_of_media(&MediaKey::Photo(7)), None);
+    }
+
+    #[test]
+    fn source_filter_ignore_wins_over_allow() {
+        let filter = |allow: Option<&[i64]>, ignore: &[i64]| SourceFilter {
+            allow: allow.map(|a| a.iter().copied().collect()),
+            ignore: ignore.iter().copied().collect(),
+        };
+
+        let any = filter(None, &[]);
+        assert!(any.allows(Some(1)) && any.allows(None));
+
+        let ignoring = filter(None, &[2]);
+        assert!(ignoring.allows(Some(1)));
+        assert!(!ignoring.allows(Some(2)));
+        assert!(ignoring.allows(None));
+
+        let allowing = filter(Some(&[1, 2]), &[2]);
+        assert!(allowing.allows(Some(1)));
+        assert!(!allowing.allows(Some(2)), "ignore wins");
+        assert!(!allowing.allows(Some(3)));
+        assert!(!allowing.allows(None));
+    }
+
+    #[test]
+    fn only_followed_sources_pass_once_known() {
+        let mut settings = PlanSettings::default();
+        assert!(settings.follows_source(Some(-1001)), "no restriction");
+
+        settings.followed_sources = Some(HashSet::from([-1001, -1002]));
+        assert!(settings.follows_source(Some(-1001)));
+        assert!(!settings.follows_source(Some(-1003)));
+        assert!(settings.follows_source(None), "not a forward");
+
+        settings.followed_sources = Some(HashSet::new());
+        assert!(!settings.follows_source(Some(-1001)));
+    }
+
+    #[test]
+    fn own_sources_are_recognized() {
+        let mut settings = PlanSettings::default();
+        assert!(!settings.is_own_source(Some(-1001)), "none known yet");
+
+        settings.own_sources = HashSet::from([-1001]);
+        assert!(settings.is_own_source(Some(-1001)));
+        assert!(!settings.is_own_source(Some(-1002)));
+        assert!(!settings.is_own_source(None), "not a forward");
+    }
+
+    #[test]
+    fn reads_of_own_posts_tracked_earlier_do_not_propagate() {
+        let mut t = DuplicateTracker::default();
+        t.register_forward(orig(-1001, 7), fwd(10, 50));
+        t.register_forward(orig(-1001, 7), fwd(20, 60));
+        let settings = PlanSettings {
+            own_sources: HashSet::from([-1001]),
+            ..Default::default()
+        };
+
+        let action = plan_read_event(10, 50, &mut t, &settings);
+        assert!(matches!(action, Action::None));
+    }
+
+    #[test]
+    fn reads_propagate_only_for_posts_with_enough_copies() {
+        let mut t = DuplicateTracker::default();
+        // A single forward, and a post copied to three chats
+        t.register_forward(orig(1, 100), fwd(10, 50));
+        t.register_forward(orig(1, 101), fwd(10, 51));
+        t.register_forward(orig(1, 101), fwd(20, 60));
+        t.register_forward(orig(1, 101), fwd(30, 70));
+        let settings = PlanSettings {
+            min_duplicates: 2,
+            ..Default::default()
+        };
+
+        let action = plan_read_event(10, 51, &mut t, &settings);
+
+        let Action::MarkForwards { forwards } = action else {
+            panic!("expected MarkForwards");
+        };
+        assert_eq!(
+            forwards,
+            vec![(orig(1, 101), fwd(20, 60)), (orig(1, 101), fwd(30, 70))]
+        );
+        assert!(t.is_original_read(&orig(1, 101)));
+        // Still tracked, and still unread until it turns into a cross-post
+        assert!(t.is_tracked(&orig(1, 100)));
+        assert!(!t.is_original_read(&orig(1, 100)));
+    }
+
+    #[test]
+    fn below_the_threshold_a_later_read_still_propagates() {
+        let mut t = DuplicateTracker::default();
+        t.register_forward(orig(1, 100), fwd(10, 50));
+        let settings = PlanSettings {
+            min_duplicates: 2,
+            ..Default::default()
+        };
+        assert!(matches!(
+            plan_read_event(10, 50, &mut t, &settings),
+            Action::None
+        ));
+
+        t.register_forward(orig(1, 100), fwd(20, 60));
+        let action = plan_read_event(10, 50, &mut t, &settings);
+        assert!(matches!(action, Action::MarkForwards { ref forwards } if forwards.len() == 1));
+    }
+
+    #[test]
+    fn reads_do_not_propagate_for_ignored_sources() {
+        let mut t = DuplicateTracker::default();
+        t.register_forward(orig(1, 100), fwd(10, 50));
+        t.register_forward(orig(1, 100), fwd(20, 60));
+        let settings = PlanSettings {
+            sources: SourceFilter {
+                ignore: [1].into_iter().collect(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let action = plan_read_event(10, 50, &mut t, &settings);
+        assert!(matches!(action, Action::None));
+    }
+
+    /// A layer noting the fields of every event, as (name, value) pairs.
+    #[derive(Clone, Default)]
+    struct CaptureFields(Arc<std::sync::Mutex<Vec<Vec<(String, String)>>>>);
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for CaptureFields {
+        fn on_event(
+            &self,
+            event: &tracing::Event<'_>,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            struct Visitor(Vec<(String, String)>);
+            impl tracing::field::Visit for Visitor {
+                fn record_debug(
+                    &mut self,
+                    field: &tracing::field::Field,
+                    value: &dyn std::fmt::Debug,
+                ) {
+                    self.0.push((field.name().to_owned(), format!("{:?}", value)));
+                }
+            }
+            let mut visitor = Visitor(Vec::new());
+            event.record(&mut visitor);
+            self.0.lock().unwrap().push(visitor.0);
+        }
+    }
+
+    #[test]
+    fn propagation_logs_ids_as_fields() {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let mut t = DuplicateTracker::default();
+        t.register_forward(orig(1, 100), fwd(10, 50));
+        t.register_forward(orig(1, 100), fwd(20, 60));
+        let capture = CaptureFields::default();
+        let subscriber = tracing_subscriber::registry().with(capture.clone());
+
+        tracing::subscriber::with_default(subscriber, || {
+            plan_read_event(10, 50, &mut t, &PlanSettings::default());
+        });
+
+        let events = capture.0.lock().unwrap();
+        let event = events
+            .iter()
+            .find(|fields| fields.iter().any(|(_, v)| v.starts_with("Read, propagating")))
+            .expect("propagation event");
+        let field = |name: &str| {
+            event
+                .iter()
+                .find(|(n, _)| n == name)
+                .map(|(_, v)| v.as_str())
+        };
+        assert_eq!(field("chat_id"), Some("10"));
+        assert_eq!(field("forwards"), Some("1"));
+    }
+
+    #[test]
+    fn catch_up_propagates_reads_missed_while_offline() {
+        let mut t = DuplicateTracker::default();
+        t.register_forward(orig(1, 100), fwd(10, 50));
+        t.register_forward(orig(1, 100), fwd(20, 60));
+        t.register_forward(orig(1, 101), fwd(10, 70));
+        t.register_forward(orig(1, 101), fwd(30, 80));
+        // The dialog list: chat 10 read up to 55, the rest unread, plus a
+        // chat nothing was tracked in
+        let dialogs = [(10, 55), (20, 0), (30, 0), (99, 1000)];
+
+        let actions = plan_catch_up(&dialogs, &mut t, &PlanSettings::default());
+
+        assert_eq!(actions.len(), 1);
+        let Action::MarkForwards { forwards } = &actions[0] else {
+            panic!("expected MarkForwards");
+        };
+        assert_eq!(forwards, &vec![(orig(1, 100), fwd(20, 60))]);
+        assert!(t.is_original_read(&orig(1, 100)));
+        assert!(!t.is_original_read(&orig(1, 101)));
+
+        // Running it again, e.g. after a restart, finds nothing new
+        assert!(plan_catch_up(&dialogs, &mut t, &PlanSettings::default()).is_empty());
+    }
+
+    #[test]
+    fn catch_up_respects_observe_only() {
+        let mut t = DuplicateTracker::default();
+        t.register_forward(orig(1, 100), fwd(10, 50));
+        t.register_forward(orig(1, 100), fwd(20, 60));
+        let settings = PlanSettings {
+            observe_only: true,
+            ..Default::default()
+        };
+
+        assert!(plan_catch_up(&[(10, 50)], &mut t, &settings).is_empty());
+        assert!(t.is_original_read(&orig(1, 100)));
+    }
+
+    #[test]
+    fn only_reads_touching_tracked_posts_are_noted() {
+        let mut t = DuplicateTracker::default();
+        t.register_forward(orig(1, 100), fwd(10, 50));
+        t.register_forward(orig(1, 100), fwd(20, 60));
+        let recent = Arc::new(RecentEvents::new(10));
+        let settings = PlanSettings {
+            recent: Some(Arc::clone(&recent)),
+            ..Default::default()
+        };
+
+        plan_read_event(30, 99, &mut t, &settings);
+        plan_read_event(10, 50, &mut t, &settings);
+        // Already read: nothing new happens, nothing noted
+        plan_read_event(20, 60, &mut t, &settings);
+
+        let events: Vec<_> = recent.snapshot().into_iter().map(|e| e.event).collect();
+        assert_eq!(
+            events,
+            vec![Event::Read {
+                chat_id: 10,
+                max_id: 50
+            }]
+        );
+    }
+}
@@ -0,0 +1,352 @@
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::links::primary_url;
+use crate::tracker::OriginalMessageId;
+
+/// Synthetic originals live far below any real Bot API peer id (channels
+/// bottom out around -10^13), one band of 2^32 peer ids per key kind. The
+/// high half of a 64-bit key picks the peer id within the band and the low
+/// half becomes the message id, so synthetic keys fit the state format
+/// unchanged and can never clash with a forward header key.
+const CONTENT_HASH_BAND: i64 = -(1 << 62);
+const PHOTO_BAND: i64 = -(1 << 61);
+const DOCUMENT_BAND: i64 = -(1 << 60);
+const ANONYMOUS_BAND: i64 = -(1 << 59);
+const FORWARDER_BAND: i64 = -(1 << 58);
+const URL_BAND: i64 = -(1 << 57);
+
+/// Shorter texts ("ok", "+1") are too common to say two messages are the
+/// same post.
+const CONTENT_HASH_MIN_CHARS: usize = 20;
+
+/// What makes two messages "the same post".
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IdentityStrategy {
+    /// The original named in the forward header. Only forwards are tracked.
+    #[default]
+    ForwardHeader,
+    /// The message text, so copy-pasted reposts count too.
+    ContentHash,
+    /// The attached photo or document, which keeps its id across reposts.
+    MediaFileId,
+    /// The first link, normalized, so cross-posts of one article count
+    /// however their text differs.
+    PrimaryUrl,
+    /// The forward header, media and text, preferring them in that order.
+    /// The link is left out: posts sharing only a footer or donate link
+    /// would count as one.
+    Combined,
+}
+
+#[derive(Debug, Error)]
+#[error("expected one of forward-header, content-hash, media-file-id, primary-url, combined")]
+pub struct ParseIdentityStrategyError;
+
+impl FromStr for IdentityStrategy {
+    type Err = ParseIdentityStrategyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "forward-header" => Ok(IdentityStrategy::ForwardHeader),
+            "content-hash" => Ok(IdentityStrategy::ContentHash),
+            "media-file-id" => Ok(IdentityStrategy::MediaFileId),
+            "primary-url" => Ok(IdentityStrategy::PrimaryUrl),
+            "combined" => Ok(IdentityStrategy::Combined),
+            _ => Err(ParseIdentityStrategyError),
+        }
+    }
+}
+
+impl fmt::Display for IdentityStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            IdentityStrategy::ForwardHeader => "forward-header",
+            IdentityStrategy::ContentHash => "content-hash",
+            IdentityStrategy::MediaFileId => "media-file-id",
+            IdentityStrategy::PrimaryUrl => "primary-url",
+            IdentityStrategy::Combined => "combined",
+        })
+    }
+}
+
+/// The file behind a message's media. Telegram keeps the id when a file is
+/// reposted, and numbers photos and documents (videos, GIFs, files)
+/// independently, so the kind is part of the key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum MediaKey {
+    Photo(i64),
+    Document(i64),
+}
+
+impl MediaKey {
+    /// The synthetic original that copies of this file are grouped under.
+    fn original(self) -> OriginalMessageId {
+        match self {
+            MediaKey::Photo(id) => synthetic(PHOTO_BAND, id as u64),
+            MediaKey::Document(id) => synthetic(DOCUMENT_BAND, id as u64),
+        }
+    }
+}
+
+/// The parts of a message the strategies look at.
+#[derive(Debug, Default)]
+pub struct MessageIdentity<'a> {
+    /// The original from the forward header, if it names one.
+    pub forward: Option<OriginalMessageId>,
+    pub text: &'a str,
+    /// Links not written out in the text: hidden behind words, or the
+    /// link preview.
+    pub links: &'a [String],
+    /// The attached photo or document.
+    pub media: Option<MediaKey>,
+    /// Who forwarded it, if copies from different forwarders are to be
+    /// kept apart.
+    pub forwarder: Option<i64>,
+}
+
+impl IdentityStrategy {
+    /// Whether copies are grouped by their media file at all.
+    pub fn uses_media(self) -> bool {
+        matches!(self, IdentityStrategy::MediaFileId | IdentityStrategy::Combined)
+    }
+
+    /// Keys identifying the message's post, most preferred first. Empty if
+    /// the strategy has nothing to go on. With a `forwarder`, each key is
+    /// that forwarder's own.
+    pub fn keys(self, message: &MessageIdentity) -> Vec<OriginalMessageId> {
+        let forward = || message.forward.clone();
+        let media = || message.media.map(MediaKey::original);
+        let url = || url_key(message.text, message.links);
+        let content = || content_key(message.text);
+        let keys: Vec<_> = match self {
+            IdentityStrategy::ForwardHeader => forward().into_iter().collect(),
+            IdentityStrategy::ContentHash => content().into_iter().collect(),
+            IdentityStrategy::MediaFileId => media().into_iter().collect(),
+            IdentityStrategy::PrimaryUrl => url().into_iter().collect(),
+            IdentityStrategy::Combined => [forward(), media(), content()]
+                .into_iter()
+                .flatten()
+                .collect(),
+        };
+        match message.forwarder {
+            Some(forwarder) => keys.iter().map(|key| forwarder_key(key, forwarder)).collect(),
+            None => keys,
+        }
+    }
+}
+
+/// `original` as forwarded by `forwarder`: copies of one post forwarded by
+/// different people group apart, each under their own synthetic original.
+fn forwarder_key(original: &OriginalMessageId, forwarder: i64) -> OriginalMessageId {
+    let mut bytes = original.peer_id.to_le_bytes().to_vec();
+    bytes.extend_from_slice(&original.message_id.to_le_bytes());
+    bytes.extend_from_slice(&forwarder.to_le_bytes());
+    synthetic(FORWARDER_BAND, fnv1a(&bytes))
+}
+
+fn content_key(text: &str) -> Option<OriginalMessageId> {
+    // Reposts often differ only in spacing and line breaks
+    let normalized = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if normalized.chars().count() < CONTENT_HASH_MIN_CHARS {
+        return None;
+    }
+    Some(synthetic(CONTENT_HASH_BAND, fnv1a(normalized.as_bytes())))
+}
+
+fn url_key(text: &str, links: &[String]) -> Option<OriginalMessageId> {
+    let url = primary_url(text, links)?;
+    Some(synthetic(URL_BAND, fnv1a(url.as_bytes())))
+}
+
+/// A best-effort original for a forward whose header names no sender,
+/// keyed by the signature and send date (and the chat it was saved from, if
+/// the header says). Unlike a real post id this can collide: two posts
+/// signed by the same author in the same second, or by two authors who sign
+/// with the same name, count as one.
+pub fn anonymous_key(author: &str, saved_from: Option<i64>, date: i32) -> OriginalMessageId {
+    let mut bytes = author.as_bytes().to_vec();
+    bytes.push(0);
+    bytes.extend_from_slice(&saved_from.unwrap_or(0).to_le_bytes());
+    bytes.extend_from_slice(&date.to_le_bytes());
+    synthetic(ANONYMOUS_BAND, fnv1a(&bytes))
+}
+
+fn synthetic(band: i64, key: u64) -> OriginalMessageId {
+    OriginalMessageId {
+        peer_id: band - (key >> 32) as i64,
+        message_id: key as u32 as i32,
+    }
+}
+
+/// 64-bit FNV-1a. Keys are persisted, so this must not change between
+/// builds the way `DefaultHasher` may.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "Breaking: something happened somewhere today";
+
+    fn header_key() -> OriginalMessageId {
+        OriginalMessageId { peer_id: -1005, message_id: 7 }
+    }
+
+    fn message(
+        forward: bool,
+        text: &'static str,
+        photo_id: Option<i64>,
+    ) -> MessageIdentity<'static> {
+        MessageIdentity {
+            forward: forward.then(header_key),
+            text,
+            links: &[],
+            media: photo_id.map(MediaKey::Photo),
+            forwarder: None,
+        }
+    }
+
+    #[test]
+    fn forward_header_uses_the_header_only() {
+        let s = IdentityStrategy::ForwardHeader;
+        assert_eq!(s.keys(&message(true, TEXT, Some(9))), vec![header_key()]);
+        assert!(s.keys(&message(false, TEXT, Some(9))).is_empty());
+    }
+
+    #[test]
+    fn content_hash_ignores_spacing_and_short_texts() {
+        let s = IdentityStrategy::ContentHash;
+        let keys = s.keys(&message(false, TEXT, None));
+        assert_eq!(keys.len(), 1);
+        let respaced = "  Breaking:  something happened\nsomewhere today ";
+        assert_eq!(s.keys(&message(true, respaced, None)), keys);
+        assert_ne!(s.keys(&message(false, "Breaking: nothing happened anywhere", None)), keys);
+        assert!(s.keys(&message(false, "ok", None)).is_empty());
+    }
+
+    #[test]
+    fn media_file_id_keys_by_media() {
+        let s = IdentityStrategy::MediaFileId;
+        let keys = s.keys(&message(false, "", Some(42)));
+        assert_eq!(keys, s.keys(&message(true, TEXT, Some(42))));
+        assert_ne!(keys, s.keys(&message(false, "", Some(43))));
+        assert!(s.keys(&message(true, TEXT, None)).is_empty());
+    }
+
+    #[test]
+    fn primary_url_keys_by_normalized_link() {
+        let s = IdentityStrategy::PrimaryUrl;
+        let keys = s.keys(&message(false, "Story: https://example.com/a?utm_source=x", None));
+        assert_eq!(keys.len(), 1);
+        assert!(keys[0].peer_id <= URL_BAND && keys[0].peer_id > FORWARDER_BAND);
+        assert_eq!(
+            s.keys(&message(true, "Different words, http://www.example.com/a/", None)),
+            keys
+        );
+        let hidden = ["https://example.com/a".to_owned()];
+        let via_link = MessageIdentity {
+            links: &hidden,
+            ..message(false, "Read this", None)
+        };
+        assert_eq!(s.keys(&via_link), keys);
+        assert_ne!(s.keys(&message(false, "https://example.com/b", None)), keys);
+        assert!(s.keys(&message(true, TEXT, Some(42))).is_empty());
+    }
+
+    #[test]
+    fn anonymous_keys_differ_by_author_date_and_saved_peer() {
+        let key = anonymous_key("Jane", None, 1_700_000_000);
+        assert_eq!(key, anonymous_key("Jane", None, 1_700_000_000));
+        assert_ne!(key, anonymous_key("John", None, 1_700_000_000));
+        assert_ne!(key, anonymous_key("Jane", None, 1_700_000_001));
+        assert_ne!(key, anonymous_key("Jane", Some(-1005), 1_700_000_000));
+        assert!(key.peer_id <= ANONYMOUS_BAND && key.peer_id > DOCUMENT_BAND);
+    }
+
+    #[test]
+    fn forwarder_keys_differ_by_forwarder_only_when_set() {
+        let by = |forwarder| MessageIdentity {
+            forwarder,
+            ..message(true, TEXT, Some(42))
+        };
+        for s in [IdentityStrategy::ForwardHeader, IdentityStrategy::Combined] {
+            // Disabled: everyone's copies share the post's keys
+            assert_eq!(s.keys(&by(None)), s.keys(&message(true, TEXT, Some(42))));
+
+            let alice = s.keys(&by(Some(101)));
+            assert_eq!(alice.len(), s.keys(&by(None)).len());
+            assert_eq!(alice, s.keys(&by(Some(101))));
+            assert_ne!(alice, s.keys(&by(Some(102))));
+            assert!(alice.iter().all(|k| !s.keys(&by(None)).contains(k)));
+            assert!(alice
+                .iter()
+                .all(|k| k.peer_id <= FORWARDER_BAND && k.peer_id > ANONYMOUS_BAND));
+        }
+        assert!(IdentityStrategy::ForwardHeader.keys(&MessageIdentity {
+            forwarder: Some(101),
+            ..message(false, TEXT, None)
+        })
+        .is_empty());
+    }
+
+    #[test]
+    fn photos_and_documents_with_the_same_id_differ() {
+        assert_ne!(MediaKey::Photo(42).original(), MediaKey::Document(42).original());
+        assert_eq!(MediaKey::Document(42).original(), MediaKey::Document(42).original());
+    }
+
+    #[test]
+    fn combined_orders_header_media_content() {
+        let keys = IdentityStrategy::Combined.keys(&message(true, TEXT, Some(42)));
+        assert_eq!(
+            keys,
+            vec![
+                header_key(),
+                IdentityStrategy::MediaFileId.keys(&message(false, "", Some(42)))[0].clone(),
+                IdentityStrategy::ContentHash.keys(&message(false, TEXT, None))[0].clone(),
+            ]
+        );
+        assert!(IdentityStrategy::Combined.keys(&message(false, "", None)).is_empty());
+
+        // A link alone never groups posts unless asked for
+        let linked = message(false, "Donate: https://example.com/donate", None);
+        let url = IdentityStrategy::PrimaryUrl.keys(&linked);
+        assert!(!IdentityStrategy::Combined.keys(&linked).contains(&url[0]));
+    }
+
+    #[test]
+    fn synthetic_keys_stay_out_of_real_peer_ids() {
+        for key in [0, 1, u64::MAX] {
+            let content = synthetic(CONTENT_HASH_BAND, key).peer_id;
+            let photo = synthetic(PHOTO_BAND, key).peer_id;
+            let document = synthetic(DOCUMENT_BAND, key).peer_id;
+            assert!(content <= CONTENT_HASH_BAND && content > CONTENT_HASH_BAND - (1 << 32));
+            assert!(photo <= PHOTO_BAND && photo > PHOTO_BAND - (1 << 32));
+            assert!(document <= DOCUMENT_BAND && document > DOCUMENT_BAND - (1 << 32));
+            assert!(content < photo && photo < document);
+            assert!(document < -1_000_000_000_000_000);
+        }
+        // Known FNV-1a vector, so persisted keys never drift
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn parses_strategy_names() {
+        assert_eq!(
+            "Content-Hash".parse::<IdentityStrategy>().unwrap(),
+            IdentityStrategy::ContentHash
+        );
+        assert!("hash".parse::<IdentityStrategy>().is_err());
+        for s in ["forward-header", "content-hash", "media-file-id", "primary-url", "combined"] {
+            assert_eq!(s.parse::<IdentityStrategy>().unwrap().to_string(), s);
+        }
+    }
+}
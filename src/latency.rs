@@ -0,0 +1,133 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::time::Duration;
+
+/// How many recent samples to keep. Older ones are dropped, so memory stays
+/// bounded and percentiles reflect recent behavior.
+const DEFAULT_CAPACITY: usize = 1024;
+
+/// Ring buffer of recent durations with percentile summaries.
+#[derive(Debug)]
+pub struct LatencyHistogram {
+    samples: VecDeque<Duration>,
+    capacity: usize,
+}
+
+/// Percentiles over the samples currently held.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LatencySummary {
+    pub count: usize,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub max: Duration,
+}
+
+impl fmt::Display for LatencySummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "p50={:?} p95={:?} max={:?} ({} samples)",
+            self.p50, self.p95, self.max, self.count
+        )
+    }
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self::with_capacity(DEFAULT_CAPACITY)
+    }
+}
+
+impl LatencyHistogram {
+    pub fn with_capacity(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        LatencyHistogram {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn record(&mut self, sample: Duration) {
+        if self.samples.len() == self.capacity {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    /// Percentiles over the held samples, or None if there are none yet.
+    pub fn summary(&self) -> Option<LatencySummary> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let mut sorted: Vec<Duration> = self.samples.iter().copied().collect();
+        sorted.sort_unstable();
+        Some(LatencySummary {
+            count: sorted.len(),
+            p50: percentile(&sorted, 50.0),
+            p95: percentile(&sorted, 95.0),
+            max: sorted[sorted.len() - 1],
+        })
+    }
+}
+
+/// Nearest-rank percentile of a sorted, non-empty slice.
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(n: u64) -> Duration {
+        Duration::from_millis(n)
+    }
+
+    #[test]
+    fn empty_has_no_summary() {
+        assert_eq!(LatencyHistogram::default().summary(), None);
+    }
+
+    #[test]
+    fn percentiles_of_known_samples() {
+        let mut h = LatencyHistogram::default();
+        // Insert out of order to make sure summary sorts
+        for n in (1..=100).rev() {
+            h.record(ms(n));
+        }
+        let s = h.summary().unwrap();
+        assert_eq!(s.count, 100);
+        assert_eq!(s.p50, ms(50));
+        assert_eq!(s.p95, ms(95));
+        assert_eq!(s.max, ms(100));
+    }
+
+    #[test]
+    fn single_sample_is_every_percentile() {
+        let mut h = LatencyHistogram::default();
+        h.record(ms(7));
+        let s = h.summary().unwrap();
+        assert_eq!((s.p50, s.p95, s.max), (ms(7), ms(7), ms(7)));
+    }
+
+    #[test]
+    fn small_sets_use_nearest_rank() {
+        let sorted = [ms(10), ms(20), ms(30), ms(40)];
+        assert_eq!(percentile(&sorted, 50.0), ms(20));
+        assert_eq!(percentile(&sorted, 95.0), ms(40));
+        assert_eq!(percentile(&sorted, 0.0), ms(10));
+    }
+
+    #[test]
+    fn capacity_bounds_memory_and_drops_oldest() {
+        let mut h = LatencyHistogram::with_capacity(3);
+        for n in [1000, 1, 2, 3] {
+            h.record(ms(n));
+        }
+        let s = h.summary().unwrap();
+        assert_eq!(s.count, 3);
+        // The 1000ms outlier was the oldest and got evicted
+        assert_eq!(s.max, ms(3));
+    }
+}
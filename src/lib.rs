@@ -0,0 +1,54 @@
+//! Duplicate tracking behind the `telegram-duplicate-message-checker`
+//! daemon, for programs that want to work with its state directly.
+//!
+//! [`DuplicateTracker`] groups copies of a post under the original they
+//! were forwarded from and tells you where the other copies are once
+//! one is read:
+//!
+//! ```
+//! use telegram_duplicate_message_checker::{
+//!     DuplicateTracker, ForwardLocation, OriginalMessageId,
+//! };
+//!
+//! let mut tracker = DuplicateTracker::default();
+//! let original = OriginalMessageId { peer_id: -1001, message_id: 42 };
+//! tracker.register_forward(original.clone(), ForwardLocation::new(-1002, 7));
+//! tracker.register_forward(original.clone(), ForwardLocation::new(-1003, 9));
+//!
+//! let copies = tracker.mark_original_read(&original);
+//! assert_eq!(copies.len(), 2);
+//! assert!(tracker.is_original_read(&original));
+//! ```
+//!
+//! The state file written by the daemon can be opened with
+//! [`DuplicateTracker::load`]. The `handler` and `marker` modules hold the
+//! update planning and Telegram side, which the binary builds on; the rest
+//! is implementation detail, of which only what the binary uses is
+//! re-exported.
+
+pub mod audit;
+pub mod batch;
+mod clock;
+mod control;
+pub mod handler;
+pub mod identity;
+mod latency;
+mod links;
+mod log_gate;
+pub mod marker;
+mod rate_limit;
+pub mod recent;
+mod replay;
+mod summary;
+mod timeparse;
+pub mod tracker;
+mod webhook;
+
+pub use log_gate::LogGate;
+pub use replay::{read_recording, replay_recording, UpdateRecorder};
+pub use summary::{local_second, DailyStats, DailyTime};
+pub use timeparse::{format_duration, parse_duration_or, parse_hhmm_window, TimeParseError};
+pub use tracker::{
+    DuplicateTracker, ForwardLocation, OriginalMessageId, StateFormat, TrackerError,
+};
+pub use webhook::{Webhook, WebhookEvents};
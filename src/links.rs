@@ -0,0 +1,169 @@
+/// Query parameters that only say where a click came from, dropped so
+/// links to the same page shared from different places compare equal.
+/// Anything starting with `utm_` is dropped too.
+const TRACKING_PARAMS: &[&str] = &[
+    "fbclid", "gclid", "yclid", "dclid", "msclkid", "igshid", "mc_cid", "mc_eid", "ref_src",
+    "ref_url", "_hsenc", "_hsmi", "mkt_tok",
+];
+
+/// Hosts of links back into Telegram. Channels sign their posts with a
+/// link to themselves, so these say nothing about what a post is about.
+const TELEGRAM_HOSTS: &[&str] = &["t.me", "telegram.me", "telegram.dog"];
+
+/// Characters a link in running text is often followed by, which are
+/// almost never the last character of the link itself.
+const TRAILING_PUNCTUATION: &[char] = &[
+    '.', ',', ';', ':', '!', '?', ')', ']', '}', '>', '"', '\'', '»',
+];
+
+/// The link a post is mostly about: the first web link in `text`, else the
+/// first of `links` (hidden links and the link preview, in that order),
+/// normalized with `normalize_url`. Links back into Telegram are skipped.
+pub fn primary_url(text: &str, links: &[String]) -> Option<String> {
+    urls_in(text)
+        .chain(links.iter().map(String::as_str))
+        .filter_map(normalize_url)
+        .find(|url| {
+            let host = url.split(['/', '?']).next().unwrap_or_default();
+            !TELEGRAM_HOSTS.contains(&host)
+        })
+}
+
+/// The `http(s)://` links written out in `text`, in order.
+fn urls_in(text: &str) -> impl Iterator<Item = &str> {
+    text.split_whitespace().filter_map(|word| {
+        let start = word.to_ascii_lowercase().find("http")?;
+        let url = word[start..].trim_end_matches(TRAILING_PUNCTUATION);
+        has_web_scheme(url).then_some(url)
+    })
+}
+
+fn has_web_scheme(url: &str) -> bool {
+    let lower = url.get(..8).unwrap_or(url).to_ascii_lowercase();
+    lower.starts_with("http://") || lower.starts_with("https://")
+}
+
+/// Reduce a web link to what identifies the page: no scheme (http and
+/// https count as one), no `www.`, default port, user info or fragment, no
+/// trailing slash, and only non-tracking query parameters, sorted. E.g.
+/// `https://www.Example.com/news/?utm_source=tg&id=3#top` becomes
+/// `example.com/news?id=3`. None if it isn't an http(s) link with a host.
+pub fn normalize_url(url: &str) -> Option<String> {
+    let url = url.trim();
+    if !has_web_scheme(url) {
+        return None;
+    }
+    let rest = &url[url.find("://")? + 3..];
+    let rest = rest.split('#').next().unwrap_or_default();
+    let (rest, query) = rest.split_once('?').unwrap_or((rest, ""));
+    let (authority, path) = rest.split_at(rest.find('/').unwrap_or(rest.len()));
+
+    let host = authority.rsplit('@').next().unwrap_or_default().to_ascii_lowercase();
+    let host = host
+        .strip_suffix(":80")
+        .or_else(|| host.strip_suffix(":443"))
+        .unwrap_or(&host);
+    let host = host.strip_prefix("www.").unwrap_or(host);
+    if host.is_empty() {
+        return None;
+    }
+
+    let mut params: Vec<&str> = query
+        .split('&')
+        .filter(|param| !param.is_empty() && !is_tracking_param(param))
+        .collect();
+    params.sort_unstable();
+
+    let mut normalized = format!("{}{}", host, path.trim_end_matches('/'));
+    if !params.is_empty() {
+        normalized.push('?');
+        normalized.push_str(&params.join("&"));
+    }
+    Some(normalized)
+}
+
+fn is_tracking_param(param: &str) -> bool {
+    let name = param.split('=').next().unwrap_or_default().to_ascii_lowercase();
+    name.starts_with("utm_") || TRACKING_PARAMS.contains(&name.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalized(url: &str) -> String {
+        normalize_url(url).unwrap()
+    }
+
+    #[test]
+    fn tracking_params_are_stripped() {
+        assert_eq!(
+            normalized("https://example.com/news/1?utm_source=tg&utm_medium=social&id=3"),
+            "example.com/news/1?id=3"
+        );
+        assert_eq!(
+            normalized("https://example.com/a?UTM_Campaign=x&fbclid=abc"),
+            "example.com/a"
+        );
+        // Other parameters stay, in a fixed order
+        assert_eq!(
+            normalized("https://example.com/a?b=2&gclid=z&a=1"),
+            normalized("https://example.com/a?a=1&b=2")
+        );
+        assert_ne!(
+            normalized("https://example.com/a?id=3"),
+            normalized("https://example.com/a?id=4")
+        );
+    }
+
+    #[test]
+    fn scheme_host_and_slash_variants_are_equivalent() {
+        let url = normalized("https://example.com/news/1");
+        for variant in [
+            "http://example.com/news/1",
+            "https://example.com/news/1/",
+            "HTTPS://Example.COM/news/1",
+            "https://www.example.com/news/1",
+            "https://example.com:443/news/1#comments",
+            "http://user@example.com:80/news/1//",
+        ] {
+            assert_eq!(normalized(variant), url, "{}", variant);
+        }
+        assert_eq!(normalized("https://example.com/"), "example.com");
+        // Paths are case-sensitive on most servers
+        assert_ne!(normalized("https://example.com/News/1"), url);
+        assert_ne!(normalized("https://example.org/news/1"), url);
+    }
+
+    #[test]
+    fn only_web_links_normalize() {
+        for bad in [
+            "",
+            "example.com/news",
+            "ftp://example.com/a",
+            "mailto:a@example.com",
+            "https://",
+        ] {
+            assert_eq!(normalize_url(bad), None, "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn primary_url_is_the_first_web_link() {
+        let text = "Big news (https://example.com/story?utm_source=tg). More: http://other.org";
+        assert_eq!(primary_url(text, &[]), Some("example.com/story".to_owned()));
+
+        // Hidden links count when the text has none
+        let links = vec!["https://example.com/story/".to_owned()];
+        assert_eq!(primary_url("Read the story", &links), Some("example.com/story".to_owned()));
+        assert_eq!(primary_url("No links here", &[]), None);
+    }
+
+    #[test]
+    fn telegram_links_are_not_primary() {
+        let text = "Subscribe: https://t.me/somechannel";
+        assert_eq!(primary_url(text, &[]), None);
+        let text = "https://t.me/somechannel/42 via https://example.com/story";
+        assert_eq!(primary_url(text, &[]), Some("example.com/story".to_owned()));
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use tokio::time::Instant;
+use tracing::info;
+
+/// Token bucket for the per-forward info lines (`TG_LOG_LINES_PER_SEC`), so
+/// a catch-up or a busy channel doesn't flood the log. Lines over the rate
+/// are counted instead and summed up as "N more ..." once logging resumes
+/// or on `flush`. Warnings and errors never go through it.
+#[derive(Debug)]
+pub struct LogGate {
+    state: Mutex<GateState>,
+}
+
+#[derive(Debug)]
+struct GateState {
+    lines_per_sec: f64,
+    tokens: f64,
+    last_refill: Instant,
+    /// Lines held back since the last summary, by what they were about.
+    held: BTreeMap<&'static str, usize>,
+}
+
+impl LogGate {
+    /// A gate letting through `lines_per_sec` lines a second, in bursts of
+    /// up to as many. Clamped to at least one line a second.
+    pub fn new(lines_per_sec: u32) -> Self {
+        let lines_per_sec = f64::from(lines_per_sec.max(1));
+        LogGate {
+            state: Mutex::new(GateState {
+                lines_per_sec,
+                tokens: lines_per_sec,
+                last_refill: Instant::now(),
+                held: BTreeMap::new(),
+            }),
+        }
+    }
+
+    /// Whether to log a line now. A line held back is counted under `what`,
+    /// e.g. "forwards detected"; one let through after some were held
+    /// first logs how many.
+    pub fn admit(&self, what: &'static str) -> bool {
+        match self.admit_at(what, Instant::now()) {
+            Some(held) => {
+                log_held(held);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Log how many lines were held back since the last summary, if any.
+    pub fn flush(&self) {
+        log_held(self.take_held());
+    }
+
+    /// Take a token at `now`. Returns the lines held back so far if one
+    /// was available, None if this line is held back too.
+    fn admit_at(&self, what: &'static str, now: Instant) -> Option<Vec<(&'static str, usize)>> {
+        let mut state = self.state.lock().unwrap();
+        let elapsed = now.saturating_duration_since(state.last_refill).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * state.lines_per_sec).min(state.lines_per_sec);
+        state.last_refill = now;
+        if state.tokens < 1.0 {
+            *state.held.entry(what).or_default() += 1;
+            return None;
+        }
+        state.tokens -= 1.0;
+        Some(std::mem::take(&mut state.held).into_iter().collect())
+    }
+
+    fn take_held(&self) -> Vec<(&'static str, usize)> {
+        std::mem::take(&mut self.state.lock().unwrap().held)
+            .into_iter()
+            .collect()
+    }
+}
+
+fn log_held(held: Vec<(&'static str, usize)>) {
+    for (what, count) in held {
+        info!("{} more {} (log rate limited)", count, what);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test(start_paused = true)]
+    async fn a_burst_is_cut_to_the_rate_and_counted() {
+        let gate = LogGate::new(3);
+        let now = Instant::now();
+        let admitted = (0..10)
+            .filter(|_| gate.admit_at("forwards detected", now).is_some())
+            .count();
+        assert_eq!(admitted, 3);
+        gate.admit_at("forwards marked", now);
+
+        assert_eq!(
+            gate.take_held(),
+            vec![("forwards detected", 7), ("forwards marked", 1)]
+        );
+        assert!(gate.take_held().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_next_line_through_reports_what_was_held() {
+        let gate = LogGate::new(2);
+        let now = Instant::now();
+        for _ in 0..5 {
+            gate.admit_at("forwards detected", now);
+        }
+
+        // Half a second buys one line at two a second
+        let later = now + Duration::from_millis(500);
+        assert_eq!(
+            gate.admit_at("forwards detected", later),
+            Some(vec![("forwards detected", 3)])
+        );
+        assert_eq!(gate.admit_at("forwards detected", later), None);
+        assert_eq!(
+            gate.admit_at("forwards detected", later + Duration::from_millis(500)),
+            Some(vec![("forwards detected", 1)])
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn quiet_time_refills_no_more_than_one_second() {
+        let gate = LogGate::new(2);
+        let later = Instant::now() + Duration::from_secs(60);
+        let admitted = (0..5)
+            .filter(|_| gate.admit_at("forwards detected", later).is_some())
+            .count();
+        assert_eq!(admitted, 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn admit_uses_the_clock() {
+        let gate = LogGate::new(1);
+        assert!(gate.admit("forwards detected"));
+        assert!(!gate.admit("forwards detected"));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(gate.admit("forwards detected"));
+        gate.flush();
+        assert!(gate.take_held().is_empty());
+    }
+}
@@ -1,47 +1,439 @@
 mod auth;
+mod checkpoint;
+mod cli;
 mod config;
-mod handler;
-mod marker;
-mod tracker;
+mod debounce;
+mod grace;
+mod pacing;
+mod queue;
+mod quiet;
+mod reload;
+mod save_trigger;
+mod session_string;
+mod startup;
+mod warmup;
+mod watchdog;
 
+// The binary's own modules reach the library through `crate::` paths
+use telegram_duplicate_message_checker::{
+    audit, batch, handler, identity, local_second, marker, parse_duration_or, parse_hhmm_window,
+    recent, tracker, DailyStats, DailyTime, LogGate, TimeParseError, UpdateRecorder, Webhook,
+    WebhookEvents,
+};
+
+use std::collections::HashSet;
+use std::path::Path;
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::{Context, Result};
 use grammers_client::client::UpdatesConfiguration;
-use grammers_client::{Client, SenderPool};
+use grammers_client::{Client, InvocationError, SenderPool};
 use grammers_session::storages::SqliteSession;
+use grammers_tl_types as tl;
 use tokio::sync::Mutex;
 use tokio::time::Instant;
-use tracing::{error, info};
+use tracing::{debug, error, info, warn};
+use tracing_subscriber::EnvFilter;
 
-use crate::config::Config;
-use crate::marker::Marker;
-use crate::tracker::DuplicateTracker;
+use crate::audit::AuditLog;
+use crate::checkpoint::CheckpointSignal;
+use crate::config::{Config, Reloadable};
+use crate::debounce::ReadDebouncer;
+use crate::grace::GraceQueue;
+use crate::handler::{Action, PlanSettings};
+use crate::identity::IdentityStrategy;
+use crate::marker::{scan_dialogs, DialogScan, DupAction, Marker, MarkerError, ReadMarker};
+use crate::pacing::PacedQueue;
+use crate::queue::BoundedQueue;
+use crate::quiet::QuietQueue;
+use crate::recent::RecentEvents;
+use crate::reload::ReloadSignal;
+use crate::save_trigger::SaveTrigger;
+use crate::tracker::{DuplicateTracker, TrackerError, CLEANUP_MAX_AGE};
+use crate::warmup::WarmupQueue;
+use crate::watchdog::Watchdog;
 
-/// 30 days in seconds
-const CLEANUP_MAX_AGE: u64 = 30 * 24 * 60 * 60;
 /// Save state every 5 minutes
 const SAVE_INTERVAL: Duration = Duration::from_secs(5 * 60);
 /// Cleanup interval (daily)
 const CLEANUP_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+/// How often lines held back by `TG_LOG_LINES_PER_SEC` are summed up
+const LOG_GATE_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+/// Wait before scanning the dialog list again after a scan was cut short
+const RESCAN_DELAY: Duration = Duration::from_secs(10 * 60);
+
+/// Build the tracing filter directives. `RUST_LOG` wins outright; otherwise
+/// `TG_LOG_LEVEL` sets this crate's level (default info) while grammers and
+/// everything else stays at warn.
+fn log_filter(rust_log: Option<&str>, level: Option<&str>) -> String {
+    if let Some(directives) = rust_log.filter(|s| !s.trim().is_empty()) {
+        return directives.to_owned();
+    }
+    let level = level
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .unwrap_or("info");
+    format!("warn,{}={}", env!("CARGO_CRATE_NAME"), level)
+}
+
+/// Report (and optionally prune) tracked forwards in chats missing from the
+/// peer cache, e.g. groups the user has left. Those can never be marked read.
+fn reconcile_peer_cache(tracker: &mut DuplicateTracker, marker: &Marker, prune: bool) {
+    let unresolvable = tracker.unresolvable_chats(|chat_id| marker.has_peer(chat_id));
+    if unresolvable.is_empty() {
+        return;
+    }
+
+    let total: usize = unresolvable.iter().map(|(_, count)| count).sum();
+    if prune {
+        for (chat_id, _) in &unresolvable {
+            tracker.forget_forwards_in_chat(*chat_id);
+        }
+        info!(
+            "Pruned {} forwards in {} chats that are no longer dialogs",
+            total,
+            unresolvable.len()
+        );
+    } else {
+        for (chat_id, count) in &unresolvable {
+            warn!(
+                "Chat {} is not in the peer cache, {} tracked forwards can't be marked read",
+                chat_id, count
+            );
+        }
+        info!("Set TG_PRUNE_UNRESOLVABLE=true to drop these {} forwards", total);
+    }
+}
+
+/// Apply the source restrictions that need the dialog list. Following
+/// chats only waits for a complete scan, since a partial one would drop
+/// forwards from every chat it didn't reach.
+fn apply_source_restrictions(scan: &DialogScan, config: &Config, settings: &mut PlanSettings) {
+    if config.track_followed_sources_only && scan.is_complete() {
+        let followed = scan.followed_ids();
+        info!(
+            chats = followed.len(),
+            "Tracking forwards from followed chats only"
+        );
+        settings.followed_sources = Some(followed);
+    }
+    if config.ignore_own_sources {
+        let owned = scan.owned_channel_ids().clone();
+        info!(channels = owned.len(), "Ignoring posts from your own channels");
+        if scan.is_complete() {
+            settings.own_sources = owned;
+        } else {
+            settings.own_sources.extend(owned);
+        }
+    }
+}
+
+/// Scan the dialog list again after `RESCAN_DELAY`, in the background,
+/// sending the result on `tx`.
+fn schedule_rescan(client: &Client, tx: &tokio::sync::mpsc::Sender<marker::Result<DialogScan>>) {
+    info!("Scanning the dialog list again in {:?}", RESCAN_DELAY);
+    let (client, tx) = (client.clone(), tx.clone());
+    tokio::spawn(async move {
+        tokio::time::sleep(RESCAN_DELAY).await;
+        let _ = tx.send(scan_dialogs(&client).await).await;
+    });
+}
+
+/// Save the tracker to `path`, or do nothing when running ephemeral
+/// (`path` is None). Returns whether anything was saved. The tracker lock
+/// is only held while the state is copied, not while it is serialized and
+/// synced. `saving` is held throughout, so saves run one at a time: two at
+/// once would share the temp file, and the older copy could be renamed
+/// over the newer one.
+async fn save_state(
+    tracker: &Mutex<DuplicateTracker>,
+    path: Option<&Path>,
+    saving: &Mutex<()>,
+) -> Result<bool, TrackerError> {
+    let Some(path) = path else {
+        return Ok(false);
+    };
+    let _saving = saving.lock().await;
+    let start = Instant::now();
+    let snapshot = tracker.lock().await.snapshot();
+    debug!(locked = ?start.elapsed(), "Copied state for saving");
+    snapshot.save(path).await.map(|()| true)
+}
+
+/// Save on a checkpoint request (SIGUSR1). The save counts as any other,
+/// so the event count starts over.
+async fn save_on_request(
+    tracker: &Mutex<DuplicateTracker>,
+    path: Option<&Path>,
+    saving: &Mutex<()>,
+    trigger: &SaveTrigger,
+) -> bool {
+    trigger.reset();
+    match save_state(tracker, path, saving).await {
+        Ok(true) => {
+            info!("State saved on request (SIGUSR1)");
+            true
+        }
+        Ok(false) => {
+            warn!("Running ephemeral, SIGUSR1 save skipped");
+            false
+        }
+        Err(e) => {
+            error!("Failed to save state on request: {}", e);
+            false
+        }
+    }
+}
+
+/// Nudge a connection that has gone quiet. Any request makes Telegram
+/// resume pushing updates, and if the connection is actually dead the
+/// request fails and the sender reconnects.
+async fn resubscribe(client: &Client) {
+    match client.invoke(&tl::functions::updates::GetState {}).await {
+        Ok(_) => info!("Connection is alive, updates resubscribed"),
+        Err(e) => warn!("Resubscribe request failed: {}", e),
+    }
+}
+
+/// Plan a batch of read events released by the debouncer.
+async fn plan_reads(
+    reads: Vec<(i64, i32)>,
+    tracker: &Mutex<DuplicateTracker>,
+    settings: &PlanSettings,
+    save_trigger: &SaveTrigger,
+) -> Vec<Action> {
+    if reads.is_empty() {
+        return Vec::new();
+    }
+    let mut t = tracker.lock().await;
+    let before = t.changes();
+    let actions = reads
+        .into_iter()
+        .map(|(chat_id, max_id)| handler::plan_read_event(chat_id, max_id, &mut t, settings))
+        .collect();
+    save_trigger.record(t.changes() - before);
+    actions
+}
+
+/// Take the dialog list's read cursors the way live reads are taken: they
+/// cancel held marks they cover, then wait out the debounce window or are
+/// planned and held for the grace delay. Returns what should run now.
+async fn catch_up_reads(
+    cursors: Vec<(i64, i32)>,
+    debouncer: &mut Option<ReadDebouncer>,
+    grace: &mut Option<GraceQueue>,
+    tracker: &Mutex<DuplicateTracker>,
+    settings: &PlanSettings,
+    save_trigger: &SaveTrigger,
+) -> Vec<Action> {
+    if let Some(g) = grace.as_mut() {
+        for &(chat_id, max_id) in &cursors {
+            g.observe_read(chat_id, max_id);
+        }
+    }
+    if let Some(d) = debouncer.as_mut() {
+        let now = Instant::now();
+        for (chat_id, max_id) in cursors {
+            d.push(chat_id, max_id, now);
+        }
+        return Vec::new();
+    }
+    let actions = {
+        let mut t = tracker.lock().await;
+        let before = t.changes();
+        let actions = handler::plan_catch_up(&cursors, &mut t, settings);
+        save_trigger.record(t.changes() - before);
+        actions
+    };
+    defer(actions, grace)
+}
+
+/// Hold back propagations for the grace delay, if one is configured.
+/// Returns what should run now.
+fn defer(actions: Vec<Action>, grace: &mut Option<GraceQueue>) -> Vec<Action> {
+    match grace {
+        Some(g) => {
+            let now = Instant::now();
+            actions.into_iter().filter_map(|a| g.offer(a, now)).collect()
+        }
+        None => actions,
+    }
+}
+
+/// Queue planned actions for the executor, holding back reads during
+/// quiet hours and while the peer cache is still warming up, and spreading
+/// out reads that touch too many chats.
+async fn enqueue(
+    actions: Vec<Action>,
+    queue: &BoundedQueue<Action>,
+    warmup: &mut WarmupQueue,
+    quiet: &mut Option<QuietQueue>,
+    paced: &mut Option<PacedQueue>,
+) {
+    let (minute, now) = (quiet::local_minute(), Instant::now());
+    let actions: Vec<Action> = actions
+        .into_iter()
+        .filter_map(|a| match quiet.as_mut() {
+            Some(q) => q.offer(a, minute, now),
+            None => Some(a),
+        })
+        .filter_map(|a| match paced.as_mut() {
+            Some(p) => p.offer(a, now),
+            None => Some(a),
+        })
+        .filter_map(|a| warmup.offer(a))
+        .collect();
+    for action in actions {
+        match queue.push(action).await {
+            Some(Action::MarkForwards { forwards }) => warn!(
+                forwards = forwards.len(),
+                "Executor is falling behind, deferred the oldest queued propagation to next start"
+            ),
+            Some(_) | None => {}
+        }
+    }
+}
+
+/// Execute queued actions, a batch at a time, until the queue is closed
+/// and drained. Stops at the first fatal error, closing the queue so the
+/// update loop never waits on it.
+async fn run_executor(
+    queue: Arc<BoundedQueue<Action>>,
+    marker: Arc<Mutex<Marker>>,
+) -> Result<(), MarkerError> {
+    let mut result = Ok(());
+    while let Some(actions) = queue.pop_all().await {
+        let mut m = marker.lock().await;
+        result = batch::execute_batch(actions, &mut *m).await;
+        if result.is_err() {
+            break;
+        }
+    }
+    queue.close();
+    result
+}
+
+/// Re-read `.env` and the environment for a reload. The whole config must
+/// still be valid, but only its reloadable subset is used.
+fn reload_config() -> Result<Reloadable> {
+    dotenvy::dotenv_override().ok();
+    let config = Config::from_env()?;
+    config.validate()?;
+    Ok(config.reloadable_subset())
+}
+
+/// Apply reloaded settings to the running planner, marker and queues.
+/// Returns actions that no longer need holding back, e.g. because quiet
+/// hours or the grace delay were switched off.
+async fn apply_reload(
+    subset: &Reloadable,
+    settings: &mut PlanSettings,
+    marker: &Mutex<Marker>,
+    grace: &mut Option<GraceQueue>,
+    quiet: &mut Option<QuietQueue>,
+) -> Vec<Action> {
+    {
+        let mut m = marker.lock().await;
+        // Propagations already planned are checked again as they run
+        m.set_observe_only(subset.observe_only);
+        m.set_skip_muted(subset.skip_muted);
+        m.set_verify_before_read(subset.verify_before_read);
+        m.set_clear_mentions(subset.clear_mentions);
+        m.set_chat_delays(subset.chat_delays.clone());
+    }
+    reload_planning(subset, settings, grace, quiet, quiet::local_minute(), Instant::now())
+}
+
+/// The planner and queue side of `apply_reload`, `minute` being the local
+/// time of day at `now`.
+fn reload_planning(
+    subset: &Reloadable,
+    settings: &mut PlanSettings,
+    grace: &mut Option<GraceQueue>,
+    quiet: &mut Option<QuietQueue>,
+    minute: u16,
+    now: Instant,
+) -> Vec<Action> {
+    settings.observe_only = subset.observe_only;
+    settings.sources = subset.sources.clone();
+    settings.min_duplicates = subset.min_duplicates;
+
+    let mut released = Vec::new();
+    match (grace.as_mut(), subset.propagate_delay) {
+        (Some(g), Some(delay)) => g.set_delay(delay),
+        (None, Some(delay)) => *grace = Some(GraceQueue::new(delay)),
+        (Some(g), None) => {
+            released.extend(g.drain());
+            *grace = None;
+        }
+        (None, None) => {}
+    }
+    match (quiet.as_mut(), subset.quiet_hours) {
+        (Some(q), Some(hours)) => q.set_hours(hours, minute, now),
+        (None, Some(hours)) => *quiet = Some(QuietQueue::new(hours)),
+        (Some(q), None) => {
+            released.extend(q.drain());
+            *quiet = None;
+        }
+        (None, None) => {}
+    }
+    released
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-
+    // Load .env first so TG_LOG_LEVEL/RUST_LOG can come from it
     dotenvy::dotenv().ok();
-    let config = Config::from_env()?;
-    config.ensure_dirs()?;
+
+    let filter = log_filter(
+        std::env::var("RUST_LOG").ok().as_deref(),
+        std::env::var("TG_LOG_LEVEL").ok().as_deref(),
+    );
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new(filter))
+        .init();
+
+    let command = cli::parse_args(std::env::args().skip(1))?;
+    let mut config = Config::from_env()?;
+    if command == cli::Command::ShowConfig {
+        // Printed before validating, so a bad setting shows in context
+        print!("{}", config.describe());
+        return config.validate();
+    }
+    config.validate()?;
+    let command = cli::with_setup_only(command, config.setup_only);
+    cli::refuse_ephemeral(&command, config.ephemeral)?;
+    let persistence = config.ensure_dirs()?;
+
+    if command == cli::Command::ExportSession {
+        return cli::export_session(&config).await;
+    }
+    if !matches!(command, cli::Command::Run | cli::Command::Setup) {
+        return cli::run(command, &config).await;
+    }
 
     info!("Starting Telegram duplicate message checker");
+    startup::startup_delay(config.startup_delay).await;
 
     // Set up session and connect
+    let fresh_session = config.ephemeral || !config.session_path.exists();
     let session = Arc::new(
-        SqliteSession::open(config.session_path.to_str().unwrap_or("session.sqlite"))
+        SqliteSession::open(config.session_location())
             .await
             .context("Failed to open session")?,
     );
+    if let Some(s) = &config.session_string {
+        if fresh_session {
+            session_string::import(&session, &session_string::decode(s)?).await;
+            info!("Initialized session from TG_SESSION_STRING");
+        } else {
+            warn!(
+                "Ignoring TG_SESSION_STRING, {} already exists",
+                config.session_path.display()
+            );
+        }
+    }
 
     let SenderPool {
         runner,
@@ -53,16 +445,50 @@ async fn main() -> Result<()> {
     let client = Client::new(handle.clone());
     let pool_task = tokio::spawn(runner.run());
 
-    // Authenticate
+    // Wait for the network, then authenticate. Any RPC answer, even an
+    // error, means Telegram is reachable, and ensure_authorized sees to it;
+    // anything else is the network not being up yet
+    startup::until_connected(
+        || async {
+            match client.is_authorized().await {
+                Err(InvocationError::Rpc(_)) => Ok(()),
+                result => result.map(drop),
+            }
+        },
+        |_: &InvocationError| true,
+    )
+    .await
+    .context("Failed to reach Telegram")?;
     auth::ensure_authorized(&client, &config.api_hash, config.phone_number.as_deref()).await?;
 
+    if command == cli::Command::Setup {
+        // Scanning once stores every dialog's access hash in the session,
+        // so the first headless run has them too
+        let scan = scan_dialogs(&client).await;
+        handle.quit();
+        let _ = pool_task.await;
+        let scan = scan.context("Failed to scan dialogs")?;
+        info!(
+            chats = scan.read_cursors().len(),
+            "Setup complete, run without --setup to start"
+        );
+        return Ok(());
+    }
+
     // Load or create tracker state
-    let tracker = if config.state_path.exists() {
-        match DuplicateTracker::load(&config.state_path) {
+    let mut tracker = if config.ephemeral {
+        info!("Ephemeral run, starting with empty state");
+        DuplicateTracker::default()
+    } else if config.state_path.exists() {
+        match DuplicateTracker::load_or_backup(&config.state_path) {
             Ok(t) => {
                 info!("Loaded state from {}", config.state_path.display());
                 t
             }
+            Err(e) if e.is_corrupt() => {
+                error!("State file is corrupt and no backup loaded, starting fresh: {}", e);
+                DuplicateTracker::default()
+            }
             Err(e) => {
                 error!("Failed to load state, starting fresh: {}", e);
                 DuplicateTracker::default()
@@ -73,13 +499,127 @@ async fn main() -> Result<()> {
         DuplicateTracker::default()
     };
 
+    tracker.set_max_forwards_per_original(config.max_forwards_per_original);
+    tracker.set_state_format(config.state_format);
+    if let Some(ttl) = config.read_state_ttl {
+        info!("Read state expires for copies arriving {} days later", ttl.as_secs() / 86_400);
+    }
+    tracker.set_read_state_ttl(config.read_state_ttl.map(|ttl| ttl.as_secs()));
+    let unmarkable = tracker.unmarkable_chats();
+    if config.reset_unmarkable {
+        let cleared = unmarkable.clear();
+        info!(chats = cleared, "Cleared the chats flagged as refusing reads");
+    }
+    let pending = tracker.pending_marks();
+    let marked = tracker.marked_copies();
+    let latency = tracker.propagation_latency();
+    let live_stats = tracker.live_stats();
     let tracker = Arc::new(Mutex::new(tracker));
 
-    // Build marker with peer cache
+    // Our own chat (Saved Messages) accepts control commands
+    let me = client.get_me().await.context("Failed to fetch own user")?;
+    let mut plan_settings = PlanSettings {
+        observe_only: config.observe_only,
+        self_chat_id: Some(me.id().bot_api_dialog_id()),
+        content_filter: config.content_filter,
+        identity: config.identity,
+        sources: config.sources.clone(),
+        preview_len: config.preview_len,
+        min_duplicates: config.min_duplicates,
+        followed_sources: None,
+        mark_late_copies: !config.keep_late_copies_unread,
+        max_chats_per_event: config.max_chats_per_event.unwrap_or(0),
+        notify_duplicates: config.notify_duplicates,
+        own_sources: HashSet::new(),
+        anonymous_forwards: config.track_anonymous_forwards,
+        min_forward_age_secs: config.min_forward_age_secs.unwrap_or(0),
+        no_mark_chats: config.no_mark_chats.clone(),
+        forwarder_sources: config.identity_forwarder_sources.clone(),
+        include_saved_messages: config.include_saved_messages,
+        trace_raw_updates: config.trace_raw_updates,
+        recent: (config.recent_events > 0)
+            .then(|| Arc::new(RecentEvents::new(config.recent_events))),
+        daily: config.daily_summary_at.map(|_| Arc::new(DailyStats::default())),
+        log_gate: config.log_lines_per_sec.map(|lines| Arc::new(LogGate::new(lines))),
+        webhook: config.webhook_url.as_deref().map(|url| {
+            info!(events = %config.webhook_events, "Posting events to a webhook");
+            Arc::new(Webhook::spawn(url, config.webhook_events))
+        }),
+    };
+    if plan_settings.identity != IdentityStrategy::ForwardHeader {
+        info!("Identity strategy: {}", plan_settings.identity);
+    }
+    if plan_settings.observe_only {
+        info!("Observe-only mode: tracking duplicates, never marking as read");
+    }
+
+    // Build marker; its peer cache fills in the background (see below)
     let mut marker = Marker::new(client.clone());
-    marker.build_peer_cache().await?;
+    marker.set_session(Arc::clone(&session));
+    marker.set_rate_limit(config.max_requests_per_sec);
+    marker.set_skip_muted(config.skip_muted);
+    marker.set_read_ahead(config.read_ahead);
+    marker.set_verify_before_read(config.verify_before_read);
+    marker.set_verify_after_read(config.verify_after_read);
+    marker.set_clear_mentions(config.clear_mentions);
+    marker.set_chat_delays(config.chat_delays.clone());
+    marker.set_max_concurrent_propagations(config.max_concurrent_propagations);
+    marker.set_dup_action(config.dup_action);
+    marker.set_observe_only(config.observe_only);
+    marker.set_unmarkable_chats(unmarkable);
+    marker.set_pending_marks(Some(pending));
+    marker.set_marked_copies(Some(marked));
+    marker.set_latency(latency);
+    if config.dup_action != DupAction::Read {
+        info!("Duplicate action: {}", config.dup_action);
+    }
+    if let Some(path) = &config.audit_log_path {
+        marker.set_audit_log(Some(AuditLog::open(path)?));
+        info!("Recording mark-read attempts to {}", path.display());
+    }
+    marker.set_recent_events(plan_settings.recent.clone());
+    marker.set_log_gate(plan_settings.log_gate.clone());
+    if let Some(gate) = plan_settings.log_gate.clone() {
+        // Sum up the tail of a burst even if nothing is logged after it
+        tokio::spawn(async move {
+            let mut flush = tokio::time::interval(LOG_GATE_FLUSH_INTERVAL);
+            loop {
+                flush.tick().await;
+                gate.flush();
+            }
+        });
+    }
+    let mut recorder = match &config.record_updates_path {
+        Some(path) => {
+            info!("Recording updates to {} for replay", path.display());
+            Some(UpdateRecorder::open(path)?)
+        }
+        None => None,
+    };
     let marker = Arc::new(Mutex::new(marker));
 
+    // Scanning thousands of dialogs takes a while, so do it alongside the
+    // update loop. Reads planned meanwhile wait in the warmup queue.
+    let (scan_tx, mut scan_rx) = tokio::sync::oneshot::channel();
+    // A scan cut short by errors leaves a partial cache and tries again
+    let (rescan_tx, mut rescan_rx) = tokio::sync::mpsc::channel(1);
+    let mut warmup = WarmupQueue::default();
+    if config.lazy_peer_cache {
+        // Nothing to wait for: chats are resolved as marks need them
+        info!("Lazy peer cache, resolving chats as they are needed");
+        if config.catch_up_reads || config.track_followed_sources_only || config.ignore_own_sources
+        {
+            warn!("Catch-up reads and source restrictions need the dialog scan, which is skipped");
+        }
+        marker.lock().await.set_lazy_peers(true);
+        warmup.finish();
+    } else {
+        let scan_client = client.clone();
+        tokio::spawn(async move {
+            let _ = scan_tx.send(scan_dialogs(&scan_client).await);
+        });
+    }
+
     // Start update stream
     let mut update_stream = client
         .stream_updates(
@@ -93,11 +633,23 @@ async fn main() -> Result<()> {
 
     info!("Listening for updates...");
 
+    // Optional event-count saves on top of the timer
+    let save_trigger = Arc::new(SaveTrigger::new(config.save_every_events));
+
     // Spawn periodic save task. Use interval_at to skip the immediate
     // first tick — no need to save/cleanup right at startup.
     let save_tracker = Arc::clone(&tracker);
-    let save_path = config.state_path.clone();
-    tokio::spawn(async move {
+    // None when ephemeral: every save below is then skipped
+    let state_path = config.saved_state_path(persistence);
+    let save_path = state_path.clone();
+    // Held by whichever save is running, see `save_state`
+    let saving = Arc::new(Mutex::new(()));
+    let task_saving = Arc::clone(&saving);
+    let task_trigger = Arc::clone(&save_trigger);
+    let task_marker = Arc::clone(&marker);
+    let task_daily = plan_settings.daily.clone();
+    let mut checkpoint = CheckpointSignal::new();
+    let save_task = tokio::spawn(async move {
         let start = Instant::now();
         let mut save_interval =
             tokio::time::interval_at(start + SAVE_INTERVAL, SAVE_INTERVAL);
@@ -106,41 +658,341 @@ async fn main() -> Result<()> {
         loop {
             tokio::select! {
                 _ = save_interval.tick() => {
-                    let t = save_tracker.lock().await;
-                    if let Err(e) = t.save(&save_path) {
-                        error!("Failed to save state: {}", e);
-                    } else {
-                        info!("State saved");
+                    task_trigger.reset();
+                    match save_state(&save_tracker, save_path.as_deref(), &task_saving).await {
+                        Ok(true) => info!("State saved"),
+                        Ok(false) => {}
+                        Err(e) => error!("Failed to save state: {}", e),
+                    }
+                    let m = task_marker.lock().await;
+                    m.flush_audit_log();
+                    if let Some(latency) = m.latency_summary() {
+                        info!("Propagation latency: {}", latency);
+                    }
+                }
+                _ = task_trigger.requested() => {
+                    match save_state(&save_tracker, save_path.as_deref(), &task_saving).await {
+                        Ok(true) => debug!("State saved (event threshold reached)"),
+                        Ok(false) => {}
+                        Err(e) => error!("Failed to save state: {}", e),
                     }
                 }
+                _ = checkpoint.recv() => {
+                    let path = save_path.as_deref();
+                    save_on_request(&save_tracker, path, &task_saving, &task_trigger).await;
+                }
                 _ = cleanup_interval.tick() => {
                     let mut t = save_tracker.lock().await;
-                    t.cleanup(CLEANUP_MAX_AGE);
+                    let removed = t.cleanup(CLEANUP_MAX_AGE);
+                    if let Some(daily) = &task_daily {
+                        daily.record_cleanup(removed);
+                    }
                 }
             }
         }
     });
 
+    // Reads are coalesced per chat before planning, if configured
+    let mut debouncer = config.read_debounce.map(|window| {
+        info!("Coalescing read events over {:?}", window);
+        ReadDebouncer::new(window)
+    });
+
+    // Propagations wait out a grace delay, if configured
+    let mut grace = config.propagate_delay.map(|delay| {
+        info!("Delaying propagation by {:?}", delay);
+        GraceQueue::new(delay)
+    });
+
+    // Hold back marks during quiet hours, if configured
+    let mut quiet = config.quiet_hours.map(|hours| {
+        info!("Not marking anything read during quiet hours {}", hours);
+        QuietQueue::new(hours)
+    });
+
+    // Spread out reads that would mark too many chats at once, if configured
+    let mut paced = config.max_chats_per_event.map(|max| {
+        info!("Marking at most {} chats per batch", max);
+        PacedQueue::new(max, pacing::BATCH_INTERVAL)
+    });
+
+    // Marks run on their own task so a slow one doesn't hold up intake
+    // Only propagations may be dropped when full: they stay pending and are
+    // resumed on the next start, while replies and peers would be lost
+    let queue = BoundedQueue::new(config.queue_capacity, config.queue_full)
+        .evicting(|action| matches!(action, Action::MarkForwards { .. }));
+    let queue = Arc::new(queue);
+    let mut executor = tokio::spawn(run_executor(Arc::clone(&queue), Arc::clone(&marker)));
+    let mut executor_done = false;
+
+    // Finish what the last run planned but never got to mark. Queued
+    // behind the peer cache like any other propagation.
+    let resumed = handler::plan_resume(&*tracker.lock().await, &plan_settings);
+    enqueue(resumed.into_iter().collect(), &queue, &mut warmup, &mut quiet, &mut paced).await;
+
+    // Runtime-tunable settings can be changed with SIGHUP
+    let mut reload = ReloadSignal::new();
+
+    // Send the day's summary to Saved Messages, if configured
+    let mut next_summary = config.daily_summary_at.map(|at| {
+        info!("Sending a daily summary at {}", at);
+        Instant::now() + at.until_next(local_second())
+    });
+
+    // Detect a silently stalled connection, if configured
+    let mut watchdog = config
+        .watchdog_timeout
+        .map(|timeout| Watchdog::new(timeout, Instant::now()));
+
     // Main update loop — two-phase processing to avoid holding both locks
     // across network I/O. Phase 1 (plan) only holds the tracker lock.
-    // Phase 2 (execute) only holds the marker lock.
+    // Phase 2 (execute) runs on the executor task and only holds the marker
+    // lock.
     loop {
+        let deadline = debouncer.as_ref().and_then(ReadDebouncer::next_deadline);
+        let watchdog_deadline = watchdog.as_ref().map(Watchdog::deadline);
+        let grace_deadline = grace.as_ref().and_then(GraceQueue::next_deadline);
+        let quiet_deadline = quiet.as_ref().and_then(QuietQueue::next_deadline);
+        let paced_deadline = paced.as_ref().and_then(PacedQueue::next_deadline);
+        let aging_deadline = match plan_settings.min_forward_age_secs {
+            0 => None,
+            min_age => tracker
+                .lock()
+                .await
+                .next_aged_in(min_age)
+                .map(|secs| Instant::now() + Duration::from_secs(secs)),
+        };
         tokio::select! {
             _ = tokio::signal::ctrl_c() => {
                 info!("Received Ctrl+C, shutting down...");
+                // Don't drop reads still waiting out their window or grace
+                // delay; the user did read them
+                let mut actions = Vec::new();
+                if let Some(d) = debouncer.as_mut() {
+                    actions = plan_reads(d.drain(), &tracker, &plan_settings, &save_trigger).await;
+                }
+                actions.extend(grace.as_mut().and_then(GraceQueue::drain));
+                enqueue(actions, &queue, &mut warmup, &mut quiet, &mut paced).await;
+                // Running them now would undo the pacing; they're pending,
+                // so the next start picks them up
+                if let Some(p) = paced.as_ref().filter(|p| !p.is_empty()) {
+                    info!("Shutting down, {} paced batches deferred to next start", p.len());
+                }
+                if !warmup.is_empty() {
+                    info!(
+                        "Peer cache never finished building, {} queued propagations deferred \
+                         to next start",
+                        warmup.len()
+                    );
+                }
+                if let Some(q) = quiet.as_ref().filter(|q| !q.is_empty()) {
+                    info!("Quiet hours, {} held propagations deferred to next start", q.len());
+                }
+                break;
+            }
+            result = &mut executor => {
+                match result {
+                    Ok(Err(e)) => error!("Session is no longer authorized, shutting down: {}", e),
+                    Ok(Ok(())) => error!("Executor stopped unexpectedly, shutting down"),
+                    Err(e) => error!("Executor task failed, shutting down: {}", e),
+                }
+                executor_done = true;
                 break;
             }
+            scan = &mut scan_rx, if !warmup.is_ready() => {
+                let catch_up = match scan {
+                    Ok(Ok(scan)) => {
+                        apply_source_restrictions(&scan, &config, &mut plan_settings);
+                        let complete = scan.is_complete();
+                        let catch_up = if config.catch_up_reads {
+                            scan.read_cursors().to_vec()
+                        } else {
+                            Vec::new()
+                        };
+                        let mut t = tracker.lock().await;
+                        let mut m = marker.lock().await;
+                        m.merge_dialogs(scan);
+                        if complete {
+                            reconcile_peer_cache(&mut t, &m, config.prune_unresolvable);
+                        } else {
+                            // Chats the scan didn't reach aren't gone, so
+                            // nothing is pruned until a full scan
+                            schedule_rescan(&client, &rescan_tx);
+                        }
+                        catch_up
+                    }
+                    Ok(Err(e)) => {
+                        error!("Failed to build peer cache, shutting down: {}", e);
+                        break;
+                    }
+                    Err(_) => {
+                        error!("Peer cache task ended unexpectedly, shutting down");
+                        break;
+                    }
+                };
+                let mut queued = warmup.finish();
+                if !queued.is_empty() {
+                    info!("Peer cache ready, running {} queued propagations", queued.len());
+                }
+                let catch_up = catch_up_reads(
+                    catch_up,
+                    &mut debouncer,
+                    &mut grace,
+                    &tracker,
+                    &plan_settings,
+                    &save_trigger,
+                )
+                .await;
+                if !catch_up.is_empty() {
+                    info!("Catching up on reads in {} chats made while offline", catch_up.len());
+                }
+                queued.extend(catch_up);
+                enqueue(queued, &queue, &mut warmup, &mut quiet, &mut paced).await;
+            }
+            Some(rescan) = rescan_rx.recv() => {
+                match rescan {
+                    Ok(scan) => {
+                        apply_source_restrictions(&scan, &config, &mut plan_settings);
+                        let complete = scan.is_complete();
+                        let mut t = tracker.lock().await;
+                        let mut m = marker.lock().await;
+                        m.merge_dialogs(scan);
+                        if complete {
+                            reconcile_peer_cache(&mut t, &m, config.prune_unresolvable);
+                        } else {
+                            schedule_rescan(&client, &rescan_tx);
+                        }
+                    }
+                    // The executor shuts down on a lost session by itself
+                    Err(e) if e.is_fatal() => {}
+                    Err(e) => {
+                        warn!("Dialog rescan failed, keeping the partial peer cache: {}", e);
+                        schedule_rescan(&client, &rescan_tx);
+                    }
+                }
+            }
+            _ = reload.recv() => {
+                let subset = match reload_config() {
+                    Ok(subset) => subset,
+                    Err(e) => {
+                        error!("Failed to reload config, keeping the current one: {:#}", e);
+                        continue;
+                    }
+                };
+                if subset == config.reloadable_subset() {
+                    info!("Config reloaded, nothing changed");
+                    continue;
+                }
+                let released =
+                    apply_reload(&subset, &mut plan_settings, &marker, &mut grace, &mut quiet)
+                        .await;
+                info!(?subset, "Config reloaded");
+                config.apply_reloadable(subset);
+                enqueue(released, &queue, &mut warmup, &mut quiet, &mut paced).await;
+            }
+            _ = debounce::sleep_until(watchdog_deadline) => {
+                let now = Instant::now();
+                if let Some(w) = watchdog.as_mut().filter(|w| w.should_reconnect(now)) {
+                    warn!("No updates received for a while, resubscribing");
+                    // Save first in case reconnecting goes badly
+                    if let Err(e) = save_state(&tracker, state_path.as_deref(), &saving).await {
+                        error!("Failed to save state: {}", e);
+                    }
+                    resubscribe(&client).await;
+                    w.touch(Instant::now());
+                }
+            }
+            _ = debounce::sleep_until(next_summary) => {
+                let (Some(at), Some(daily)) = (config.daily_summary_at, &plan_settings.daily)
+                else {
+                    continue;
+                };
+                next_summary = Some(Instant::now() + at.until_next(local_second()));
+                let counters = daily.take();
+                let text = {
+                    let m = marker.lock().await;
+                    counters.render(|source| m.get_chat_name(source).to_owned())
+                };
+                if let Some(chat_id) = plan_settings.self_chat_id {
+                    let reply = Action::Reply {
+                        chat_id,
+                        peer_ref: None,
+                        text,
+                    };
+                    enqueue(vec![reply], &queue, &mut warmup, &mut quiet, &mut paced).await;
+                }
+            }
+            _ = debounce::sleep_until(quiet_deadline) => {
+                let released = quiet
+                    .as_mut()
+                    .map(|q| q.release(quiet::local_minute(), Instant::now()))
+                    .unwrap_or_default();
+                if !released.is_empty() {
+                    info!("Quiet hours over, running {} held propagations", released.len());
+                }
+                enqueue(released, &queue, &mut warmup, &mut quiet, &mut paced).await;
+            }
+            _ = debounce::sleep_until(paced_deadline) => {
+                // Already split, so only quiet hours and warmup apply
+                let due = paced.as_mut().and_then(|p| p.due(Instant::now()));
+                let due = due.into_iter().collect();
+                enqueue(due, &queue, &mut warmup, &mut quiet, &mut None).await;
+            }
+            _ = debounce::sleep_until(grace_deadline) => {
+                let due = grace.as_mut().and_then(|g| g.due(Instant::now()));
+                let due = due.into_iter().collect();
+                enqueue(due, &queue, &mut warmup, &mut quiet, &mut paced).await;
+            }
+            _ = debounce::sleep_until(aging_deadline) => {
+                let aged = handler::plan_aged_forwards(&mut *tracker.lock().await, &plan_settings);
+                let actions = defer(aged.into_iter().collect(), &mut grace);
+                enqueue(actions, &queue, &mut warmup, &mut quiet, &mut paced).await;
+            }
+            _ = debounce::sleep_until(deadline) => {
+                let due = debouncer
+                    .as_mut()
+                    .map(|d| d.due(Instant::now()))
+                    .unwrap_or_default();
+                let actions = plan_reads(due, &tracker, &plan_settings, &save_trigger).await;
+                let actions = defer(actions, &mut grace);
+                enqueue(actions, &queue, &mut warmup, &mut quiet, &mut paced).await;
+            }
             result = update_stream.next() => {
+                if let Some(w) = watchdog.as_mut() {
+                    w.touch(Instant::now());
+                }
                 match result {
                     Ok(update) => {
+                        if let Some(r) = recorder.as_mut() {
+                            r.record(&update);
+                        }
+                        let read = handler::read_event(&update);
+                        // Copies the user reads directly need no propagation
+                        if let (Some(g), Some((chat_id, max_id))) = (grace.as_mut(), read) {
+                            g.observe_read(chat_id, max_id);
+                        }
+                        if let (Some(d), Some((chat_id, max_id))) = (debouncer.as_mut(), read) {
+                            d.push(chat_id, max_id, Instant::now());
+                            continue;
+                        }
+                        // Stats come from counters that need no lock
+                        let stats = handler::plan_live_stats(&update, &live_stats, &plan_settings);
+                        if let Some(reply) = stats.await {
+                            enqueue(vec![reply], &queue, &mut warmup, &mut quiet, &mut paced).await;
+                            continue;
+                        }
                         // Phase 1: plan (tracker lock only)
-                        let action = {
+                        let actions = {
                             let mut t = tracker.lock().await;
-                            handler::plan_update(&update, &mut t).await
+                            let before = t.changes();
+                            let actions =
+                                handler::plan_update(&update, &mut t, &mut plan_settings).await;
+                            save_trigger.record(t.changes() - before);
+                            actions
                         };
-                        // Phase 2: execute (marker lock only)
-                        let mut m = marker.lock().await;
-                        handler::execute_action(action, &mut m).await;
+                        // Phase 2: hand off to the executor (marker lock only)
+                        let actions = defer(actions, &mut grace);
+                        enqueue(actions, &queue, &mut warmup, &mut quiet, &mut paced).await;
                     }
                     Err(e) => {
                         error!("Error receiving update: {}", e);
@@ -150,15 +1002,27 @@ async fn main() -> Result<()> {
         }
     }
 
-    // Shutdown: save state
-    info!("Saving final state...");
-    {
-        let t = tracker.lock().await;
-        if let Err(e) = t.save(&config.state_path) {
-            error!("Failed to save final state: {}", e);
+    // Let the executor finish what is already queued
+    if !executor_done {
+        queue.close();
+        if let Ok(Err(e)) = executor.await {
+            error!("Failed to propagate pending reads: {}", e);
         }
     }
 
+    // Shutdown: save state, once no periodic save can come after it
+    save_task.abort();
+    let _ = save_task.await;
+    if state_path.is_some() {
+        info!("Saving final state...");
+    } else {
+        warn!("Running ephemeral, tracked state is discarded");
+    }
+    if let Err(e) = save_state(&tracker, state_path.as_deref(), &saving).await {
+        error!("Failed to save final state: {}", e);
+    }
+    marker.lock().await.flush_audit_log();
+
     // Sync update state and shut down gracefully
     update_stream.sync_update_state().await;
     handle.quit();
@@ -167,3 +1031,142 @@ async fn main() -> Result<()> {
     info!("Goodbye!");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::SourceFilter;
+    use crate::marker::ChatDelays;
+    use crate::tracker::{ForwardLocation, OriginalMessageId};
+
+    #[tokio::test(start_paused = true)]
+    async fn reloads_retune_the_planner_and_release_held_marks() {
+        let mark = || Action::MarkForwards {
+            forwards: vec![(
+                OriginalMessageId { peer_id: 1, message_id: 100 },
+                ForwardLocation::new(10, 50),
+            )],
+        };
+        let (late, now) = (23 * 60, Instant::now());
+        let mut grace = Some(GraceQueue::new(Duration::from_secs(60)));
+        assert!(grace.as_mut().unwrap().offer(mark(), now).is_none());
+        let mut quiet = Some(QuietQueue::new("23:00-07:00".parse().unwrap()));
+        assert!(quiet.as_mut().unwrap().offer(mark(), late, now).is_none());
+        let mut settings = PlanSettings::default();
+
+        let mut subset = Reloadable {
+            observe_only: true,
+            sources: SourceFilter::default(),
+            min_duplicates: 3,
+            skip_muted: false,
+            verify_before_read: false,
+            clear_mentions: false,
+            chat_delays: ChatDelays::default(),
+            propagate_delay: None,
+            quiet_hours: "01:00-02:00".parse().ok(),
+        };
+        let released = reload_planning(&subset, &mut settings, &mut grace, &mut quiet, late, now);
+        assert!(settings.observe_only);
+        assert_eq!(settings.min_duplicates, 3);
+        // The grace delay is off, so what it held runs now
+        assert_eq!(released.len(), 1);
+        assert!(grace.is_none());
+        // Not quiet any more under the new window
+        assert_eq!(quiet.as_ref().and_then(QuietQueue::next_deadline), Some(now));
+
+        subset.quiet_hours = None;
+        let released = reload_planning(&subset, &mut settings, &mut grace, &mut quiet, late, now);
+        assert_eq!(released.len(), 1);
+        assert!(quiet.is_none());
+    }
+
+    #[tokio::test]
+    async fn checkpoint_requests_save_the_state() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let mut t = DuplicateTracker::default();
+        t.register_forward(
+            OriginalMessageId { peer_id: 1, message_id: 100 },
+            ForwardLocation::new(10, 50),
+        );
+        let (tracker, saving) = (Mutex::new(t), Mutex::new(()));
+        let trigger = SaveTrigger::new(Some(2));
+        assert!(!trigger.record(1));
+
+        let (mut checkpoint, requests) = CheckpointSignal::injected();
+        requests.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(5), checkpoint.recv())
+            .await
+            .expect("checkpoint should be requested");
+        assert!(save_on_request(&tracker, Some(path.as_path()), &saving, &trigger).await);
+
+        let loaded = DuplicateTracker::load(&path).unwrap();
+        assert_eq!(loaded.stats().forwards, 1);
+        // The save reset the event count
+        assert!(!trigger.record(1));
+        // Nothing to save to when ephemeral
+        assert!(!save_on_request(&tracker, None, &saving, &trigger).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn catch_up_reads_go_through_the_debouncer_and_grace_delay() {
+        let tracker = || {
+            let mut t = DuplicateTracker::default();
+            let o = OriginalMessageId { peer_id: 1, message_id: 100 };
+            t.register_forward(o.clone(), ForwardLocation::new(10, 50));
+            t.register_forward(o, ForwardLocation::new(20, 60));
+            Mutex::new(t)
+        };
+        let (settings, trigger) = (PlanSettings::default(), SaveTrigger::new(None));
+        let cursors = || vec![(10, 50), (30, 5)];
+
+        let t = tracker();
+        let ran = catch_up_reads(cursors(), &mut None, &mut None, &t, &settings, &trigger).await;
+        assert_eq!(ran.len(), 1, "only chat 10 has anything to propagate");
+
+        // Held for the grace delay like a live read's marks
+        let t = tracker();
+        let mut grace = Some(GraceQueue::new(Duration::from_secs(60)));
+        let ran = catch_up_reads(cursors(), &mut None, &mut grace, &t, &settings, &trigger).await;
+        assert!(ran.is_empty());
+        assert!(grace.as_ref().and_then(GraceQueue::next_deadline).is_some());
+
+        // Coalesced with live reads before anything is planned
+        let t = tracker();
+        let mut debouncer = Some(ReadDebouncer::new(Duration::from_millis(500)));
+        let ran =
+            catch_up_reads(cursors(), &mut debouncer, &mut None, &t, &settings, &trigger).await;
+        assert!(ran.is_empty());
+        let o = OriginalMessageId { peer_id: 1, message_id: 100 };
+        assert!(!t.lock().await.is_original_read(&o), "nothing planned yet");
+        let mut held = debouncer.unwrap().drain();
+        held.sort_unstable();
+        assert_eq!(held, cursors());
+    }
+
+    #[test]
+    fn default_log_filter() {
+        assert_eq!(
+            log_filter(None, None),
+            "warn,telegram_duplicate_message_checker=info"
+        );
+    }
+
+    #[test]
+    fn log_level_overrides_crate_level_only() {
+        assert_eq!(
+            log_filter(None, Some("debug")),
+            "warn,telegram_duplicate_message_checker=debug"
+        );
+    }
+
+    #[test]
+    fn rust_log_wins() {
+        assert_eq!(log_filter(Some("trace"), Some("debug")), "trace");
+        // Empty RUST_LOG is treated as unset
+        assert_eq!(
+            log_filter(Some(""), None),
+            "warn,telegram_duplicate_message_checker=info"
+        );
+    }
+}
@@ -1,111 +1,2851 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use anyhow::{bail, Result};
-use grammers_client::Client;
-use grammers_session::types::{PeerKind, PeerRef};
+use grammers_client::{Client, InvocationError};
+use grammers_session::storages::SqliteSession;
+use grammers_session::types::{PeerId, PeerInfo, PeerKind, PeerRef};
+use grammers_session::Session;
 use grammers_tl_types as tl;
-use tokio::time::sleep;
+use thiserror::Error;
+use tokio::time::{sleep, Instant};
 use tracing::{debug, info, warn};
 
-use crate::tracker::ForwardLocation;
+use crate::audit::{AuditEntry, AuditLog};
+use crate::latency::{LatencyHistogram, LatencySummary};
+use crate::log_gate::LogGate;
+use crate::rate_limit::RateLimiter;
+use crate::recent::{Event, RecentEvents};
+use crate::timeparse::parse_duration_or;
+use crate::tracker::{
+    epoch_secs, ForwardLocation, MarkedCopies, OriginalMessageId, PendingMarks, UnmarkableChats,
+};
 
 /// Delay between consecutive mark-as-read API calls to avoid flood limits.
+/// Applies within one propagation; the optional global rate limit bounds
+/// the total request rate on top of it.
 const MARK_READ_DELAY: Duration = Duration::from_millis(500);
 
-/// Caches peer references and names so we can make API calls for any known chat.
-pub struct Marker {
-    client: Client,
-    /// chat_id (bot_api_dialog_id) -> (PeerRef, display name)
-    peer_cache: HashMap<i64, (PeerRef, String)>,
+/// How many times a read that didn't take is issued again before giving up,
+/// with `verify_after_read`.
+const VERIFY_RETRIES: usize = 1;
+
+/// RPC error names meaning our session is no longer authorized. Telegram
+/// also signals these with HTTP-style code 401.
+const AUTH_ERRORS: &[&str] = &[
+    "AUTH_KEY_UNREGISTERED",
+    "AUTH_KEY_INVALID",
+    "AUTH_KEY_PERM_EMPTY",
+    "SESSION_REVOKED",
+    "SESSION_EXPIRED",
+    "USER_DEACTIVATED",
+    "USER_DEACTIVATED_BAN",
+];
+
+/// Whether an RPC error means the session was revoked or expired.
+fn is_auth_rpc_error(code: i32, name: &str) -> bool {
+    code == 401 || AUTH_ERRORS.contains(&name)
 }
 
-impl Marker {
-    pub fn new(client: Client) -> Self {
-        Marker {
-            client,
-            peer_cache: HashMap::new(),
+/// RPC error names asking us to slow down. grammers strips the trailing
+/// seconds (`FLOOD_WAIT_30`) into the error's value.
+const FLOOD_ERRORS: &[&str] = &["FLOOD_WAIT", "FLOOD_PREMIUM_WAIT", "SLOWMODE_WAIT"];
+
+/// RPC error names meaning Telegram rejected the peer we sent, usually
+/// because its access hash has changed since we cached it.
+const INVALID_PEER_ERRORS: &[&str] = &["PEER_ID_INVALID", "CHANNEL_INVALID"];
+
+/// RPC error names meaning we may not mark the chat read at all, e.g. a
+/// broadcast channel that keeps members from moving its read cursor. The
+/// chat is still ours, so it's flagged rather than forgotten.
+const NOT_PERMITTED_ERRORS: &[&str] = &["CHAT_ADMIN_REQUIRED", "CHAT_WRITE_FORBIDDEN"];
+
+/// RPC error names meaning the account can't reach the chat any more: it
+/// left, was removed or banned, or the chat is gone. Not a permission the
+/// chat could grant back, so these aren't flagged as unmarkable.
+const NO_ACCESS_ERRORS: &[&str] = &["CHAT_FORBIDDEN", "CHANNEL_PRIVATE", "USER_BANNED_IN_CHANNEL"];
+
+/// Seconds to wait if an RPC error is a flood wait.
+fn flood_wait_seconds(name: &str, value: Option<u32>) -> Option<u32> {
+    FLOOD_ERRORS.contains(&name).then(|| value.unwrap_or(0))
+}
+
+/// Why a marker operation failed.
+#[derive(Debug, Error)]
+pub enum MarkerError {
+    /// The chat is not in the peer cache, so there is nothing to call the
+    /// API with.
+    #[error("No cached peer for chat_id={0}")]
+    PeerNotCached(i64),
+    /// Telegram asked us to back off for this long.
+    #[error("Flood wait, retry after {seconds}s")]
+    FloodWait { seconds: u32 },
+    /// Telegram rejected the peer reference, e.g. a stale access hash.
+    #[error("Peer reference rejected as invalid")]
+    InvalidPeer,
+    /// Telegram doesn't let this account mark the chat read.
+    #[error("Not allowed to mark this chat read")]
+    NotPermitted,
+    /// The account is no longer in the chat, or was banned from it.
+    #[error("No longer has access to this chat")]
+    NoAccess,
+    /// No folder (dialog filter) has this id.
+    #[error("No folder with id {0}")]
+    UnknownFolder(i32),
+    /// The session was revoked or expired.
+    #[error("Session is no longer authorized: {0}")]
+    Unauthorized(#[source] InvocationError),
+    /// Any other API or network failure.
+    #[error(transparent)]
+    Invocation(InvocationError),
+}
+
+impl MarkerError {
+    /// Whether retrying or continuing is pointless because the session is
+    /// no longer authorized.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, MarkerError::Unauthorized(_))
+    }
+}
+
+impl From<InvocationError> for MarkerError {
+    fn from(err: InvocationError) -> Self {
+        if let InvocationError::Rpc(rpc) = &err {
+            if is_auth_rpc_error(rpc.code, &rpc.name) {
+                return MarkerError::Unauthorized(err);
+            }
+            if let Some(known) = classify_rpc_error(&rpc.name, rpc.value) {
+                return known;
+            }
         }
+        MarkerError::Invocation(err)
+    }
+}
+
+/// The error an RPC error short of an auth one stands for, if we treat it
+/// specially.
+fn classify_rpc_error(name: &str, value: Option<u32>) -> Option<MarkerError> {
+    if let Some(seconds) = flood_wait_seconds(name, value) {
+        return Some(MarkerError::FloodWait { seconds });
+    }
+    if INVALID_PEER_ERRORS.contains(&name) {
+        return Some(MarkerError::InvalidPeer);
+    }
+    if NOT_PERMITTED_ERRORS.contains(&name) {
+        return Some(MarkerError::NotPermitted);
     }
+    NO_ACCESS_ERRORS
+        .contains(&name)
+        .then_some(MarkerError::NoAccess)
+}
 
-    /// Populate the peer cache by iterating all dialogs.
-    pub async fn build_peer_cache(&mut self) -> Result<()> {
-        let mut dialogs = self.client.iter_dialogs();
-        let total = dialogs.total().await?;
-        info!("Building peer cache from {} dialogs", total);
+/// Shorthand for marker results.
+pub type Result<T, E = MarkerError> = std::result::Result<T, E>;
 
-        while let Some(dialog) = dialogs.next().await? {
-            let peer = dialog.peer();
-            let chat_id = peer.id().bot_api_dialog_id();
-            if let Some(peer_ref) = peer.to_ref().await {
-                let name = peer.name().unwrap_or("unnamed").to_owned();
-                self.peer_cache.insert(chat_id, (peer_ref, name));
-            }
+/// What a mark-read that didn't fail came to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MarkOutcome {
+    /// The read was sent.
+    Marked,
+    /// The read was left out on purpose.
+    Skipped(SkipReason),
+}
+
+/// Why a mark-read was left out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SkipReason {
+    /// The chat is muted and `TG_SKIP_MUTED` is set.
+    Muted,
+    /// The chat refused a read before, see `UnmarkableChats`.
+    Unmarkable,
+}
+
+impl SkipReason {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SkipReason::Muted => "muted",
+            SkipReason::Unmarkable => "unmarkable",
+        }
+    }
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The read RPC used for a location.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ReadRpc {
+    /// `messages.readDiscussion`: only the given thread (supergroups only).
+    Thread { top_msg_id: i32 },
+    /// `channels.readHistory`: the whole channel/supergroup.
+    ChannelHistory,
+    /// `messages.readHistory`: the whole basic group or private chat.
+    History,
+}
+
+/// The `max_id` to send for a read of everything up to and including
+/// `max_id`, covering `read_ahead` more message ids past it. All three read
+/// RPCs treat their max id as inclusive, so no margin is needed for the
+/// forward itself; the margin is for reading a little past it.
+fn read_max_id(max_id: i32, read_ahead: u32) -> i32 {
+    max_id.saturating_add_unsigned(read_ahead)
+}
+
+/// Threads only exist in channels/supergroups; elsewhere a thread id is
+/// ignored and the whole chat is read.
+fn select_read_rpc(is_channel: bool, top_msg_id: Option<i32>) -> ReadRpc {
+    match (is_channel, top_msg_id) {
+        (true, Some(top_msg_id)) => ReadRpc::Thread { top_msg_id },
+        (true, None) => ReadRpc::ChannelHistory,
+        (false, _) => ReadRpc::History,
+    }
+}
+
+/// The RPC and `max_id` that read everything up to `max_id` in `peer`'s
+/// chat, plus `read_ahead` more ids.
+fn read_request(
+    peer: PeerId,
+    max_id: i32,
+    top_msg_id: Option<i32>,
+    read_ahead: u32,
+) -> (ReadRpc, i32) {
+    let is_channel = peer.kind() == PeerKind::Channel;
+    (select_read_rpc(is_channel, top_msg_id), read_max_id(max_id, read_ahead))
+}
+
+/// Whether a chat whose notifications are muted until `mute_until` (Unix
+/// seconds, as in `PeerNotifySettings`) is still muted at `now`. Telegram
+/// uses a far-future date for "forever".
+fn is_muted(mute_until: Option<i32>, now: i64) -> bool {
+    mute_until.is_some_and(|until| i64::from(until) > now)
+}
+
+/// Whether a read in a chat should be skipped under `TG_SKIP_MUTED`.
+fn should_skip_read(skip_muted: bool, mute_until: Option<i32>, now: i64) -> bool {
+    skip_muted && is_muted(mute_until, now)
+}
+
+/// `mute_until` from a dialog's notify settings, if it has any.
+fn dialog_mute_until(dialog: &tl::enums::Dialog) -> Option<i32> {
+    match dialog {
+        tl::enums::Dialog::Dialog(d) => {
+            let tl::enums::PeerNotifySettings::Settings(settings) = &d.notify_settings;
+            settings.mute_until
+        }
+        tl::enums::Dialog::Folder(_) => None,
+    }
+}
+
+/// Which unread badges a chat showed in the dialog list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Badges {
+    mentions: bool,
+    reactions: bool,
+}
+
+impl Badges {
+    /// What to assume for chats the dialog list didn't cover.
+    const UNKNOWN: Badges = Badges {
+        mentions: true,
+        reactions: true,
+    };
+
+    fn shows(&self, badge: Badge) -> bool {
+        match badge {
+            Badge::Mention => self.mentions,
+            Badge::Reaction => self.reactions,
+        }
+    }
+
+    /// Note a badge that turned up since the dialog scan.
+    fn show(&mut self, badge: Badge) {
+        match badge {
+            Badge::Mention => self.mentions = true,
+            Badge::Reaction => self.reactions = true,
         }
+    }
+}
+
+/// An unread badge a chat can show besides its unread count.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Badge {
+    Mention,
+    Reaction,
+}
+
+/// Whether to clear `badge` after marking a chat read. Only badges the
+/// chat shows are cleared, and only when nothing unread under them is past
+/// the marked range: Telegram clears a badge for the whole chat or thread,
+/// not up to a message.
+fn should_clear_badge(shown: Option<Badges>, badge: Badge, unread_past_marked: bool) -> bool {
+    shown.unwrap_or(Badges::UNKNOWN).shows(badge) && !unread_past_marked
+}
+
+/// The newest incoming message the user has read in a dialog, if it is a
+/// chat.
+fn dialog_read_cursor(dialog: &tl::enums::Dialog) -> Option<i32> {
+    match dialog {
+        tl::enums::Dialog::Dialog(d) => Some(d.read_inbox_max_id),
+        tl::enums::Dialog::Folder(_) => None,
+    }
+}
+
+/// Unread mention and reaction badges of a dialog, if it is a chat.
+fn dialog_badges(dialog: &tl::enums::Dialog) -> Option<Badges> {
+    match dialog {
+        tl::enums::Dialog::Dialog(d) => Some(Badges {
+            mentions: d.unread_mentions_count > 0,
+            reactions: d.unread_reactions_count > 0,
+        }),
+        tl::enums::Dialog::Folder(_) => None,
+    }
+}
+
+/// What to do with other copies once one has been read.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DupAction {
+    /// Mark them read.
+    #[default]
+    Read,
+    /// Move their chats to the archive folder.
+    Archive,
+    /// Mark them read and archive their chats.
+    Both,
+}
+
+impl DupAction {
+    pub fn marks_read(self) -> bool {
+        matches!(self, DupAction::Read | DupAction::Both)
+    }
+
+    pub fn archives(self) -> bool {
+        matches!(self, DupAction::Archive | DupAction::Both)
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("expected one of read, archive, both")]
+pub struct ParseDupActionError;
+
+impl FromStr for DupAction {
+    type Err = ParseDupActionError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "read" => Ok(DupAction::Read),
+            "archive" => Ok(DupAction::Archive),
+            "both" => Ok(DupAction::Both),
+            _ => Err(ParseDupActionError),
+        }
+    }
+}
+
+impl fmt::Display for DupAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DupAction::Read => "read",
+            DupAction::Archive => "archive",
+            DupAction::Both => "both",
+        })
+    }
+}
+
+/// Per-chat overrides of `MARK_READ_DELAY`, from `chat_id:ms` pairs. The
+/// delay may carry a unit instead, as in `chat_id:2s`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChatDelays(HashMap<i64, Duration>);
+
+impl ChatDelays {
+    /// How long to wait before marking in `next` after marking in `prev`.
+    /// An override only paces repeated marks in its own chat; moving on to
+    /// another chat waits the global delay.
+    pub fn between(&self, prev: i64, next: i64) -> Duration {
+        if prev != next {
+            return MARK_READ_DELAY;
+        }
+        self.0.get(&next).copied().unwrap_or(MARK_READ_DELAY)
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("expected comma-separated chat_id:ms pairs, got {0:?}")]
+pub struct ParseChatDelaysError(String);
+
+impl FromStr for ChatDelays {
+    type Err = ParseChatDelaysError;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (chat_id, ms) = pair
+                    .split_once(':')
+                    .ok_or_else(|| ParseChatDelaysError(pair.to_owned()))?;
+                let chat_id = chat_id.trim().parse();
+                let delay = parse_duration_or(ms, Duration::from_millis(1));
+                match (chat_id, delay) {
+                    (Ok(chat_id), Ok(delay)) => Ok((chat_id, delay)),
+                    _ => Err(ParseChatDelaysError(pair.to_owned())),
+                }
+            })
+            .collect::<std::result::Result<_, _>>()
+            .map(ChatDelays)
+    }
+}
 
-        info!("Peer cache built with {} entries", self.peer_cache.len());
+/// Sorted `chat_id:ms` pairs, as `TG_CHAT_DELAYS` takes them.
+impl fmt::Display for ChatDelays {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut pairs: Vec<_> = self.0.iter().collect();
+        pairs.sort();
+        for (i, (chat_id, delay)) in pairs.into_iter().enumerate() {
+            let sep = if i == 0 { "" } else { "," };
+            write!(f, "{}{}:{}", sep, chat_id, delay.as_millis())?;
+        }
         Ok(())
     }
+}
+
+/// The folder id Telegram uses for the archive.
+const ARCHIVE_FOLDER_ID: i32 = 1;
+
+/// How long a message found deleted is remembered as such, sparing a fetch
+/// when several reads propagate to it in a row.
+const MISSING_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Tries at each dialog list request before the scan gives up on it.
+const SCAN_ATTEMPTS: u32 = 4;
+
+/// Wait before retrying a failed dialog list request, doubled after each
+/// further failure. Flood waits are waited out as long as asked instead.
+const SCAN_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// Messages recently found deleted, keyed by (chat_id, message_id).
+#[derive(Debug, Default)]
+struct MissingCache {
+    entries: Mutex<HashMap<(i64, i32), Instant>>,
+}
+
+impl MissingCache {
+    fn contains(&self, key: (i64, i32), now: Instant) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, seen| now.duration_since(*seen) < MISSING_TTL);
+        entries.contains_key(&key)
+    }
 
+    fn insert(&self, key: (i64, i32), now: Instant) {
+        self.entries.lock().unwrap().insert(key, now);
+    }
+}
+
+/// Whether a `GetMessages` result holds message `id`. Deleted messages come
+/// back as `MessageEmpty`.
+fn contains_message(messages: &tl::enums::messages::Messages, id: i32) -> bool {
+    use tl::enums::messages::Messages;
+    let list = match messages {
+        Messages::Messages(m) => &m.messages,
+        Messages::Slice(m) => &m.messages,
+        Messages::ChannelMessages(m) => &m.messages,
+        // Nothing to compare against; don't block the read on it
+        Messages::NotModified(_) => return true,
+    };
+    list.iter().any(|m| match m {
+        tl::enums::Message::Message(m) => m.id == id,
+        tl::enums::Message::Service(m) => m.id == id,
+        tl::enums::Message::Empty(_) => false,
+    })
+}
+
+/// Whether a `GetUnreadMentions` or `GetUnreadReactions` result holds any
+/// message.
+fn holds_messages(messages: &tl::enums::messages::Messages) -> bool {
+    use tl::enums::messages::Messages;
+    match messages {
+        Messages::Messages(m) => !m.messages.is_empty(),
+        Messages::Slice(m) => !m.messages.is_empty(),
+        Messages::ChannelMessages(m) => !m.messages.is_empty(),
+        // Can't tell; leave the badge for the user
+        Messages::NotModified(_) => true,
+    }
+}
+
+/// What the handler needs from a marker: peer bookkeeping and issuing
+/// reads. `Marker` implements it against a live client; tests use a mock
+/// that records the reads instead.
+pub trait ReadMarker: Send + Sync {
     /// Cache a peer reference we learn about from an incoming update.
-    pub fn cache_peer(&mut self, chat_id: i64, peer_ref: PeerRef, name: String) {
-        self.peer_cache.entry(chat_id).or_insert((peer_ref, name));
+    fn cache_peer(&mut self, chat_id: i64, peer_ref: PeerRef, name: String);
+
+    /// Cache the chat a forward came from, if we can make calls for it.
+    fn cache_origin(&mut self, peer_id: PeerId);
+
+    /// Look up the display name for a chat, falling back to "unknown".
+    fn get_chat_name(&self, chat_id: i64) -> &str;
+
+    /// Mark messages up to `max_id` as read in a given chat, or only within
+    /// the discussion thread `top_msg_id` if given. Says whether the read
+    /// was sent or skipped.
+    fn mark_read(
+        &self,
+        chat_id: i64,
+        max_id: i32,
+        top_msg_id: Option<i32>,
+    ) -> impl Future<Output = Result<MarkOutcome>> + Send;
+
+    /// Record how long one whole propagation took. No-op by default.
+    fn record_propagation(&mut self, _elapsed: Duration) {}
+
+    /// How many propagations of one batch may run at once.
+    fn max_concurrent_propagations(&self) -> usize {
+        1
     }
 
-    /// Look up the display name for a chat, falling back to its numeric ID.
-    pub fn get_chat_name(&self, chat_id: i64) -> &str {
-        self.peer_cache
-            .get(&chat_id)
-            .map(|(_, name)| name.as_str())
-            .unwrap_or("unknown")
+    /// Hold off on every propagation, even those planned before
+    /// observe-only was switched on.
+    fn observe_only(&self) -> bool {
+        false
     }
 
-    /// Mark messages up to `max_id` as read in a given chat.
-    pub async fn mark_read(&self, chat_id: i64, max_id: i32) -> Result<()> {
-        let peer_ref = match self.peer_cache.get(&chat_id) {
-            Some((p, _)) => *p,
-            None => {
-                bail!("No cached peer for chat_id={}, cannot mark as read", chat_id);
+    /// What to do with other copies of a read post.
+    fn dup_action(&self) -> DupAction {
+        DupAction::Read
+    }
+
+    /// Move a chat to the archive folder.
+    fn archive_chat(&self, chat_id: i64) -> impl Future<Output = Result<()>> + Send;
+
+    /// The chats in the folder (dialog filter) `folder_id`.
+    fn folder_chats(&self, folder_id: i32) -> impl Future<Output = Result<HashSet<i64>>> + Send;
+
+    /// Where to record each mark-read attempt, if anywhere.
+    fn audit_log(&self) -> Option<&AuditLog> {
+        None
+    }
+
+    /// Where to note each mark-read attempt for `/duprecent`, if anywhere.
+    fn recent_events(&self) -> Option<&RecentEvents> {
+        None
+    }
+
+    /// Rate limit on the per-forward "Marking as read" lines, if any.
+    fn log_gate(&self) -> Option<&LogGate> {
+        None
+    }
+
+    /// Where to confirm each propagation that ran, if anywhere.
+    fn pending_marks(&self) -> Option<&PendingMarks> {
+        None
+    }
+
+    /// Where to record the copies marked read, if anywhere.
+    fn marked_copies(&self) -> Option<&MarkedCopies> {
+        None
+    }
+
+    /// Also clear mention and reaction badges in chats marked read.
+    fn clear_mentions(&self) -> bool {
+        false
+    }
+
+    /// Clear unread mention and reaction badges in a chat, or in one of its
+    /// discussion threads, that only cover messages up to `max_id`.
+    fn clear_badges(
+        &self,
+        chat_id: i64,
+        max_id: i32,
+        top_msg_id: Option<i32>,
+    ) -> impl Future<Output = Result<()>> + Send;
+
+    /// Note that a chat got a new unread badge since the peer cache was
+    /// built. No-op by default.
+    fn note_badge(&mut self, _chat_id: i64, _badge: Badge) {}
+
+    /// Check that each forward still exists before marking it read.
+    fn verify_before_read(&self) -> bool {
+        false
+    }
+
+    /// Check the chat's read cursor after each mark and mark again if it
+    /// didn't move.
+    fn verify_after_read(&self) -> bool {
+        false
+    }
+
+    /// How far a chat is read, fetched fresh from Telegram. None if there's
+    /// nothing to check, e.g. reads in the chat are skipped anyway. Only
+    /// asked when `verify_after_read` is set.
+    fn read_cursor(&self, chat_id: i64) -> impl Future<Output = Result<Option<i32>>> + Send;
+
+    /// Per-chat overrides of the delay between marks, if any.
+    fn chat_delays(&self) -> Option<&ChatDelays> {
+        None
+    }
+
+    /// Whether a message is still there. Only asked when
+    /// `verify_before_read` is set.
+    fn message_exists(
+        &self,
+        chat_id: i64,
+        message_id: i32,
+    ) -> impl Future<Output = Result<bool>> + Send;
+
+    /// Send a plain text message to a chat.
+    fn send_message(&self, chat_id: i64, text: &str) -> impl Future<Output = Result<()>> + Send;
+
+    /// Mark a list of forward locations as read, with delays between calls
+    /// to avoid Telegram flood limits. Individual failures are logged and
+    /// skipped; only fatal (auth) errors are returned. Every attempt is
+    /// written to the audit log and the recent events, if kept. Returns the
+    /// forwards that were actually marked, leaving out skipped and failed ones.
+    fn mark_forwards_read(
+        &self,
+        forwards: &[(OriginalMessageId, ForwardLocation)],
+    ) -> impl Future<Output = Result<Vec<(OriginalMessageId, ForwardLocation)>>> + Send {
+        async move {
+            let mut marked = Vec::new();
+            let mut cleared = HashSet::new();
+            // How far this batch marks each chat or thread, which bounds
+            // the badges its first mark there may clear
+            let mut reach: HashMap<(i64, Option<i32>), i32> = HashMap::new();
+            for (_, fwd) in forwards {
+                let max_id = reach.entry((fwd.chat_id, fwd.top_msg_id)).or_default();
+                *max_id = (*max_id).max(fwd.message_id);
+            }
+            for (i, (original, fwd)) in forwards.iter().enumerate() {
+                if i > 0 {
+                    let prev = forwards[i - 1].1.chat_id;
+                    let delay = self
+                        .chat_delays()
+                        .map_or(MARK_READ_DELAY, |d| d.between(prev, fwd.chat_id));
+                    sleep(delay).await;
+                }
+                // A deleted target would still move the read cursor past
+                // whatever came after it, so leave such chats alone
+                if self.verify_before_read() {
+                    match self.message_exists(fwd.chat_id, fwd.message_id).await {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            info!(
+                                chat_id = fwd.chat_id,
+                                message_id = fwd.message_id,
+                                "Skipping read, message was deleted"
+                            );
+                            continue;
+                        }
+                        Err(e) if e.is_fatal() => return Err(e),
+                        Err(e) => {
+                            warn!(
+                                chat_id = fwd.chat_id,
+                                message_id = fwd.message_id,
+                                error = %e,
+                                "Failed to check forward, skipping it"
+                            );
+                            continue;
+                        }
+                    }
+                }
+                let result = self
+                    .mark_read(fwd.chat_id, fwd.message_id, fwd.top_msg_id)
+                    .await;
+                if let Some(audit) = self.audit_log() {
+                    let name = self.get_chat_name(fwd.chat_id);
+                    audit.record(&AuditEntry::new(original, fwd, name, &result));
+                }
+                if let Some(recent) = self.recent_events() {
+                    let (original, forward) = (original.clone(), fwd.clone());
+                    recent.record(match &result {
+                        Ok(MarkOutcome::Skipped(reason)) => Event::Skipped {
+                            original,
+                            forward,
+                            reason: *reason,
+                        },
+                        _ => Event::Marked {
+                            original,
+                            forward,
+                            ok: result.is_ok(),
+                        },
+                    });
+                }
+                // Badges are per chat (or thread); clear each once per batch
+                if matches!(result, Ok(MarkOutcome::Marked))
+                    && self.clear_mentions()
+                    && cleared.insert((fwd.chat_id, fwd.top_msg_id))
+                {
+                    let max_id = reach[&(fwd.chat_id, fwd.top_msg_id)];
+                    if let Err(e) = self.clear_badges(fwd.chat_id, max_id, fwd.top_msg_id).await {
+                        if e.is_fatal() {
+                            return Err(e);
+                        }
+                        warn!(
+                            chat_id = fwd.chat_id,
+                            error = %e,
+                            "Failed to clear mentions"
+                        );
+                    }
+                }
+                match result {
+                    // The dialog's cursor is for the whole chat, not threads
+                    Ok(MarkOutcome::Marked)
+                        if self.verify_after_read() && fwd.top_msg_id.is_none() =>
+                    {
+                        verify_read(self, fwd).await?;
+                        marked.push((original.clone(), fwd.clone()));
+                    }
+                    Ok(MarkOutcome::Marked) => marked.push((original.clone(), fwd.clone())),
+                    Ok(MarkOutcome::Skipped(reason)) => {
+                        debug!(
+                            chat_id = fwd.chat_id,
+                            message_id = fwd.message_id,
+                            %reason,
+                            "Skipped marking forward as read"
+                        );
+                    }
+                    Err(e) if e.is_fatal() => return Err(e),
+                    Err(MarkerError::NoAccess) => {
+                        warn!(
+                            chat_id = fwd.chat_id,
+                            "Lost access to the chat, its copies can't be marked; \
+                             `forget chat {}` drops them",
+                            fwd.chat_id
+                        );
+                    }
+                    Err(e) => {
+                        warn!(
+                            chat_id = fwd.chat_id,
+                            message_id = fwd.message_id,
+                            error = %e,
+                            "Failed to mark forward as read"
+                        );
+                    }
+                }
+            }
+            Ok(marked)
+        }
+    }
+}
+
+/// Check that marking `fwd` read moved its chat's read cursor past it,
+/// marking it again up to `VERIFY_RETRIES` times if not. Reads that still
+/// don't take are logged; only fatal errors are returned.
+async fn verify_read<M: ReadMarker + ?Sized>(marker: &M, fwd: &ForwardLocation) -> Result<()> {
+    for retry in 0..=VERIFY_RETRIES {
+        let cursor = match marker.read_cursor(fwd.chat_id).await {
+            Ok(Some(cursor)) => cursor,
+            Ok(None) => return Ok(()),
+            Err(e) if e.is_fatal() => return Err(e),
+            Err(e) => {
+                warn!(chat_id = fwd.chat_id, error = %e, "Failed to check the read took");
+                return Ok(());
             }
         };
+        if cursor >= fwd.message_id {
+            if retry > 0 {
+                info!(chat_id = fwd.chat_id, "Read took after marking again");
+            }
+            return Ok(());
+        }
+        if retry == VERIFY_RETRIES {
+            warn!(
+                chat_id = fwd.chat_id,
+                message_id = fwd.message_id,
+                read_up_to = cursor,
+                "Marked read, but the chat still shows it unread"
+            );
+            break;
+        }
+        debug!(
+            chat_id = fwd.chat_id,
+            message_id = fwd.message_id,
+            read_up_to = cursor,
+            "Read didn't take, marking again"
+        );
+        sleep(MARK_READ_DELAY).await;
+        if let Err(e) = marker.mark_read(fwd.chat_id, fwd.message_id, None).await {
+            if e.is_fatal() {
+                return Err(e);
+            }
+            warn!(chat_id = fwd.chat_id, error = %e, "Failed to mark forward as read again");
+            break;
+        }
+    }
+    Ok(())
+}
 
-        debug!("Marking as read: chat_id={}, max_id={}", chat_id, max_id);
+/// Run `request` against a chat's cached peer. If Telegram rejects it as
+/// invalid, `refresh` is asked for a current reference and the request is
+/// retried once with that. Returns the request's result and the fresh
+/// reference when one was used, so the caller can replace the stale one.
+async fn retry_with_fresh_peer<P, T, F, Fut, R>(
+    chat_id: i64,
+    cached: P,
+    refresh: impl FnOnce() -> R,
+    request: F,
+) -> Result<(T, Option<P>)>
+where
+    P: Copy,
+    F: Fn(P) -> Fut,
+    Fut: Future<Output = Result<T>>,
+    R: Future<Output = Option<P>>,
+{
+    match request(cached).await {
+        Err(MarkerError::InvalidPeer) => {}
+        other => return other.map(|value| (value, None)),
+    }
+    let Some(fresh) = refresh().await else {
+        warn!(chat_id, "Cached peer was rejected and can't be resolved again");
+        return Err(MarkerError::InvalidPeer);
+    };
+    debug!(chat_id, "Cached peer was rejected, retrying with a fresh one");
+    let value = request(fresh).await?;
+    Ok((value, Some(fresh)))
+}
 
-        if peer_ref.id.kind() == PeerKind::Channel {
-            self.client
-                .invoke(&tl::functions::channels::ReadHistory {
-                    channel: peer_ref.into(),
-                    max_id,
-                })
-                .await
-                .map(drop)?;
-        } else {
-            self.client
-                .invoke(&tl::functions::messages::ReadHistory {
-                    peer: peer_ref.into(),
-                    max_id,
-                })
-                .await
-                .map(drop)?;
+/// Read the dialog list from `source` up to `chat_id`'s entry, if it has
+/// one. Unlike a scan nothing is retried: this runs while a request waits.
+async fn find_dialog(
+    source: &mut impl DialogSource,
+    chat_id: i64,
+) -> Result<Option<ScannedDialog>> {
+    while let Some(dialog) = source.next().await? {
+        if dialog.chat_id == chat_id {
+            return Ok(Some(dialog));
         }
+    }
+    Ok(None)
+}
 
-        Ok(())
+/// Run `request` unless `chat_id` is flagged in `unmarkable`, in which
+/// case the read is skipped. A refusal for lack of permission flags the
+/// chat, so it's tried and logged once rather than on every read.
+async fn unless_unmarkable<Fut>(
+    chat_id: i64,
+    unmarkable: &UnmarkableChats,
+    request: impl FnOnce() -> Fut,
+) -> Result<MarkOutcome>
+where
+    Fut: Future<Output = Result<()>>,
+{
+    if unmarkable.contains(chat_id) {
+        debug!(chat_id, "Skipping read in a chat that doesn't allow marking");
+        return Ok(MarkOutcome::Skipped(SkipReason::Unmarkable));
+    }
+    let result = request().await.map(|()| MarkOutcome::Marked);
+    if matches!(result, Err(MarkerError::NotPermitted)) && unmarkable.insert(chat_id) {
+        warn!(
+            chat_id,
+            "Chat doesn't allow marking reads, skipping it from now on \
+             (TG_RESET_UNMARKABLE=true clears this)"
+        );
     }
+    result
+}
 
-    /// Mark a list of forward locations as read, with delays between calls
-    /// to avoid Telegram flood limits.
-    pub async fn mark_forwards_read(&self, forwards: &[ForwardLocation]) -> Result<()> {
-        for (i, fwd) in forwards.iter().enumerate() {
-            if i > 0 {
-                sleep(MARK_READ_DELAY).await;
+/// The peer of a chat missing from the cache: one resolved before, or in
+/// lazy mode one from the `session`, or failing that `look_up` through the
+/// API. Eager mode never resolves on demand, since the dialog scan already
+/// covered every chat we can mark.
+async fn resolve_uncached<P, F>(
+    known: Option<P>,
+    lazy: bool,
+    session: impl FnOnce() -> Option<P>,
+    look_up: impl FnOnce() -> F,
+) -> Option<P>
+where
+    P: Copy,
+    F: Future<Output = Option<P>>,
+{
+    if !lazy {
+        return None;
+    }
+    match known.or_else(session) {
+        Some(peer) => Some(peer),
+        None => look_up().await,
+    }
+}
+
+/// What the session file alone can name `chat_id` offline. grammers keeps
+/// no chat titles there, only which user is our own, so that is the one
+/// chat it names.
+pub fn session_chat_name(session: &SqliteSession, chat_id: i64) -> Option<String> {
+    match session.peer(peer_id_of(chat_id))? {
+        PeerInfo::User {
+            is_self: Some(true),
+            ..
+        } => Some("Saved Messages".to_owned()),
+        _ => None,
+    }
+}
+
+/// The peer behind a Bot API dialog id: users are positive, channels and
+/// supergroups are offset below -10^12, and small groups are the negative
+/// ids in between.
+fn peer_id_of(chat_id: i64) -> PeerId {
+    const CHANNEL_OFFSET: i64 = -1_000_000_000_000;
+    if chat_id > 0 {
+        PeerId::user(chat_id)
+    } else if chat_id < CHANNEL_OFFSET {
+        PeerId::channel(CHANNEL_OFFSET - chat_id)
+    } else {
+        PeerId::chat(-chat_id)
+    }
+}
+
+/// The Bot API id of a peer listed in a folder, if it names one.
+fn input_peer_chat_id(peer: &tl::enums::InputPeer) -> Option<i64> {
+    let peer_id = match peer {
+        tl::enums::InputPeer::User(p) => PeerId::user(p.user_id),
+        tl::enums::InputPeer::UserFromMessage(p) => PeerId::user(p.user_id),
+        tl::enums::InputPeer::Chat(p) => PeerId::chat(p.chat_id),
+        tl::enums::InputPeer::Channel(p) => PeerId::channel(p.channel_id),
+        tl::enums::InputPeer::ChannelFromMessage(p) => PeerId::channel(p.channel_id),
+        tl::enums::InputPeer::Empty | tl::enums::InputPeer::PeerSelf => return None,
+    };
+    Some(peer_id.bot_api_dialog_id())
+}
+
+/// A folder's chats: the ones it includes or pins, less the ones it
+/// excludes.
+fn folder_members(
+    include: &[tl::enums::InputPeer],
+    pinned: &[tl::enums::InputPeer],
+    exclude: &[tl::enums::InputPeer],
+) -> HashSet<i64> {
+    let excluded: HashSet<i64> = exclude.iter().filter_map(input_peer_chat_id).collect();
+    include
+        .iter()
+        .chain(pinned)
+        .filter_map(input_peer_chat_id)
+        .filter(|chat_id| !excluded.contains(chat_id))
+        .collect()
+}
+
+/// What we know about a chat we can make API calls for.
+struct CachedPeer {
+    peer_ref: PeerRef,
+    name: String,
+    /// Notifications muted until this Unix time, from the dialog list.
+    /// Unknown (None) for peers only learned from updates.
+    mute_until: Option<i32>,
+    /// Unread badges as of the dialog scan. Unknown (None) for peers only
+    /// learned from updates.
+    badges: Option<Badges>,
+}
+
+/// Cache a chat seen in an update under the name it carried. A chat cached
+/// earlier only as a forward origin has its numeric placeholder replaced;
+/// what the dialog scan knew about it is kept.
+fn remember_peer(
+    cache: &mut HashMap<i64, CachedPeer>,
+    chat_id: i64,
+    peer_ref: PeerRef,
+    name: String,
+) {
+    cache
+        .entry(chat_id)
+        .and_modify(|peer| peer.name.clone_from(&name))
+        .or_insert_with(|| CachedPeer {
+            peer_ref,
+            name,
+            mute_until: None,
+            badges: None,
+        });
+}
+
+/// Cache a forward origin, named by its id until an update from the chat
+/// itself names it. Never overrides an entry that is already there.
+fn remember_origin(cache: &mut HashMap<i64, CachedPeer>, chat_id: i64, peer_ref: PeerRef) {
+    cache.entry(chat_id).or_insert_with(|| CachedPeer {
+        peer_ref,
+        name: chat_id.to_string(),
+        mute_until: None,
+        badges: None,
+    });
+}
+
+/// Peers collected from the dialog list, ready to merge into a `Marker`,
+/// and each chat's read cursor as of the scan.
+pub struct DialogScan {
+    peers: Vec<(i64, CachedPeer)>,
+    read_cursors: Vec<(i64, i32)>,
+    owned: HashSet<i64>,
+    /// Whether the whole dialog list was read, rather than the scan being
+    /// cut short by errors that outlasted the retries.
+    complete: bool,
+}
+
+impl DialogScan {
+    /// Whether every dialog was scanned. A partial scan still resolves the
+    /// chats it reached, but says nothing about the ones it didn't.
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    /// (chat_id, read_inbox_max_id) of every chat in the dialog list.
+    pub fn read_cursors(&self) -> &[(i64, i32)] {
+        &self.read_cursors
+    }
+
+    /// Chat ids of every chat in the dialog list, i.e. the sources the user
+    /// follows: channels and supergroups, and the users and bots they talk
+    /// to, whose messages can be forwarded just the same.
+    pub fn followed_ids(&self) -> HashSet<i64> {
+        self.peers.iter().map(|(chat_id, _)| *chat_id).collect()
+    }
+
+    /// Chat ids of the channels and supergroups the user created or is an
+    /// admin of.
+    pub fn owned_channel_ids(&self) -> &HashSet<i64> {
+        &self.owned
+    }
+}
+
+/// Whether the user created or administers a dialog's chat. `role` holds
+/// whether they created it and whether they have admin rights there, for
+/// a channel or supergroup; any other chat (None) is never their own.
+fn is_own_channel(role: Option<(bool, bool)>) -> bool {
+    matches!(role, Some((true, _) | (_, true)))
+}
+
+/// What a dialog scan keeps of one dialog list entry.
+struct ScannedDialog {
+    chat_id: i64,
+    /// A channel or supergroup the user created or administers.
+    owned: bool,
+    read_cursor: Option<i32>,
+    /// None if no calls can be made for the chat.
+    peer: Option<CachedPeer>,
+}
+
+/// The dialog list, read one entry at a time: the client's dialog
+/// iterator, or a scripted one in tests.
+trait DialogSource: Send {
+    fn total(&mut self) -> impl Future<Output = Result<usize>> + Send;
+    fn next(&mut self) -> impl Future<Output = Result<Option<ScannedDialog>>> + Send;
+}
+
+impl DialogSource for grammers_client::client::DialogIter {
+    async fn total(&mut self) -> Result<usize> {
+        Ok(grammers_client::client::DialogIter::total(self).await?)
+    }
+
+    async fn next(&mut self) -> Result<Option<ScannedDialog>> {
+        let Some(dialog) = grammers_client::client::DialogIter::next(self).await? else {
+            return Ok(None);
+        };
+        let peer = dialog.peer();
+        let role = match peer {
+            grammers_client::peer::Peer::Channel(channel) => {
+                Some((channel.raw.creator, channel.raw.admin_rights.is_some()))
+            }
+            _ => None,
+        };
+        let owned = is_own_channel(role);
+        let cached = peer.to_ref().await.map(|peer_ref| CachedPeer {
+            peer_ref,
+            name: peer.name().unwrap_or("unnamed").to_owned(),
+            mute_until: dialog_mute_until(&dialog.raw),
+            badges: dialog_badges(&dialog.raw),
+        });
+        Ok(Some(ScannedDialog {
+            chat_id: peer.id().bot_api_dialog_id(),
+            owned,
+            read_cursor: dialog_read_cursor(&dialog.raw),
+            peer: cached,
+        }))
+    }
+}
+
+/// Retries of one failing dialog list request.
+#[derive(Default)]
+struct Backoff {
+    failures: u32,
+}
+
+impl Backoff {
+    /// Count a failure and wait before retrying it. False if it isn't worth
+    /// retrying: the session is gone, or it has failed `SCAN_ATTEMPTS` times.
+    async fn retry(&mut self, err: &MarkerError) -> bool {
+        self.failures += 1;
+        if err.is_fatal() || self.failures >= SCAN_ATTEMPTS {
+            return false;
+        }
+        let delay = match err {
+            MarkerError::FloodWait { seconds } => Duration::from_secs(u64::from(*seconds)),
+            _ => SCAN_RETRY_DELAY * 2u32.pow(self.failures - 1),
+        };
+        warn!(
+            attempt = self.failures,
+            error = %err,
+            "Dialog list request failed, retrying in {:?}",
+            delay
+        );
+        sleep(delay).await;
+        true
+    }
+}
+
+/// Iterate all dialogs and resolve their peers. Takes only a client, so it
+/// can run in the background without holding the marker.
+pub async fn scan_dialogs(client: &Client) -> Result<DialogScan> {
+    collect_dialogs(&mut client.iter_dialogs()).await
+}
+
+/// Read the dialog list from `source`, retrying failed requests with
+/// backoff. If the list can't be read to the end, the dialogs read so far
+/// make a partial scan; only a scan that got nowhere fails.
+async fn collect_dialogs(source: &mut impl DialogSource) -> Result<DialogScan> {
+    let mut backoff = Backoff::default();
+    let total = loop {
+        match source.total().await {
+            Ok(total) => break total,
+            Err(e) => {
+                if !backoff.retry(&e).await {
+                    return Err(e);
+                }
             }
-            if let Err(e) = self.mark_read(fwd.chat_id, fwd.message_id).await {
+        }
+    };
+    info!(dialogs = total, "Building peer cache");
+
+    let mut scan = DialogScan {
+        peers: Vec::with_capacity(total),
+        read_cursors: Vec::with_capacity(total),
+        owned: HashSet::new(),
+        complete: true,
+    };
+    let mut scanned = 0;
+    let mut backoff = Backoff::default();
+    loop {
+        let dialog = match source.next().await {
+            Ok(Some(dialog)) => dialog,
+            Ok(None) => break,
+            Err(e) => {
+                if backoff.retry(&e).await {
+                    continue;
+                }
+                if e.is_fatal() || scanned == 0 {
+                    return Err(e);
+                }
                 warn!(
-                    "Failed to mark forward as read (chat={}, msg={}): {}",
-                    fwd.chat_id, fwd.message_id, e
+                    scanned,
+                    total,
+                    error = %e,
+                    "Dialog scan cut short, continuing with the chats found so far"
                 );
+                scan.complete = false;
+                break;
             }
+        };
+        backoff = Backoff::default();
+        scanned += 1;
+        if dialog.owned {
+            scan.owned.insert(dialog.chat_id);
         }
-        Ok(())
+        if let Some(max_id) = dialog.read_cursor {
+            scan.read_cursors.push((dialog.chat_id, max_id));
+        }
+        if let Some(peer) = dialog.peer {
+            scan.peers.push((dialog.chat_id, peer));
+        }
+    }
+    Ok(scan)
+}
+
+/// Caches peer references and names so we can make API calls for any known chat.
+pub struct Marker {
+    client: Client,
+    /// Where grammers keeps access hashes of peers seen in updates.
+    session: Option<Arc<SqliteSession>>,
+    /// chat_id (bot_api_dialog_id) -> peer details
+    peer_cache: HashMap<i64, CachedPeer>,
+    /// Leave muted chats unread.
+    skip_muted: bool,
+    /// Message ids past each forward that its read also covers.
+    read_ahead: u32,
+    /// Read, archive, or both.
+    dup_action: DupAction,
+    /// Run no propagations, as reloaded.
+    observe_only: bool,
+    /// Record of every mark-read attempt, if configured.
+    audit: Option<AuditLog>,
+    /// Recent events shared with the planner, if kept.
+    recent: Option<Arc<RecentEvents>>,
+    /// Log rate limit shared with the planner, if configured.
+    log_gate: Option<Arc<LogGate>>,
+    /// Fetch each forward before marking it read.
+    verify_before_read: bool,
+    /// Re-fetch the dialog after each read to check it took.
+    verify_after_read: bool,
+    /// Forwards recently found deleted.
+    missing: MissingCache,
+    /// Clear mention and reaction badges along with reads.
+    clear_mentions: bool,
+    /// Per-chat delays between marks, if configured.
+    chat_delays: Option<ChatDelays>,
+    /// Propagations of one batch allowed in flight at once.
+    max_concurrent: usize,
+    /// Account-wide limit on read requests, if configured.
+    limiter: Option<RateLimiter>,
+    /// End-to-end durations of recent propagations.
+    latency: Arc<Mutex<LatencyHistogram>>,
+    /// Peer references re-resolved after the cached one was rejected, or
+    /// resolved on first use in lazy mode. Reads happen behind `&self`, so
+    /// these live beside `peer_cache` until the next dialog scan replaces
+    /// them.
+    refreshed: Mutex<HashMap<i64, PeerRef>>,
+    /// Skip the dialog scan and resolve chats from the session as needed.
+    lazy_peers: bool,
+    /// Chats that refused a read for lack of permission, never tried again.
+    unmarkable: Arc<UnmarkableChats>,
+    /// Planned marks, confirmed as propagations finish.
+    pending: Option<Arc<PendingMarks>>,
+    /// Copies marked read, recorded as propagations finish.
+    marked: Option<Arc<MarkedCopies>>,
+}
+
+impl Marker {
+    pub fn new(client: Client) -> Self {
+        Marker {
+            client,
+            session: None,
+            peer_cache: HashMap::new(),
+            skip_muted: false,
+            read_ahead: 0,
+            dup_action: DupAction::Read,
+            observe_only: false,
+            audit: None,
+            recent: None,
+            log_gate: None,
+            verify_before_read: false,
+            verify_after_read: false,
+            missing: MissingCache::default(),
+            clear_mentions: false,
+            chat_delays: None,
+            max_concurrent: 1,
+            limiter: None,
+            latency: Arc::default(),
+            refreshed: Mutex::new(HashMap::new()),
+            lazy_peers: false,
+            unmarkable: Arc::default(),
+            pending: None,
+            marked: None,
+        }
+    }
+
+    /// Percentiles of recent propagation durations, if any happened yet.
+    pub fn latency_summary(&self) -> Option<LatencySummary> {
+        self.latency.lock().unwrap().summary()
+    }
+
+    /// Record propagation durations into `latency`, shared with the tracker
+    /// so `/dupstats` shows them.
+    pub fn set_latency(&mut self, latency: Arc<Mutex<LatencyHistogram>>) {
+        self.latency = latency;
+    }
+
+    /// Limit read requests across all chats to `requests_per_sec`, allowing
+    /// bursts of about one second's worth.
+    pub fn set_rate_limit(&mut self, requests_per_sec: Option<f64>) {
+        self.limiter = requests_per_sec
+            .filter(|&rate| rate > 0.0)
+            .map(|rate| RateLimiter::new(rate, rate.ceil() as u32));
+    }
+
+    /// Never mark reads in chats the user has muted.
+    pub fn set_skip_muted(&mut self, skip_muted: bool) {
+        self.skip_muted = skip_muted;
+    }
+
+    /// Choose what happens to other copies of a read post.
+    pub fn set_dup_action(&mut self, dup_action: DupAction) {
+        self.dup_action = dup_action;
+    }
+
+    /// Run no propagations while `observe_only`, including those held
+    /// back or queued before it was switched on.
+    pub fn set_observe_only(&mut self, observe_only: bool) {
+        self.observe_only = observe_only;
+    }
+
+    /// Record every mark-read attempt to `audit`.
+    pub fn set_audit_log(&mut self, audit: Option<AuditLog>) {
+        self.audit = audit;
+    }
+
+    /// Note every mark-read attempt in `recent`.
+    pub fn set_recent_events(&mut self, recent: Option<Arc<RecentEvents>>) {
+        self.recent = recent;
+    }
+
+    /// Hold back "Marking as read" lines over the rate `log_gate` allows.
+    pub fn set_log_gate(&mut self, log_gate: Option<Arc<LogGate>>) {
+        self.log_gate = log_gate;
+    }
+
+    /// Check each forward still exists before marking it read, at the cost
+    /// of one extra request per forward.
+    pub fn set_verify_before_read(&mut self, verify: bool) {
+        self.verify_before_read = verify;
+    }
+
+    /// Read `read_ahead` message ids past each forward along with it.
+    pub fn set_read_ahead(&mut self, read_ahead: u32) {
+        self.read_ahead = read_ahead;
+    }
+
+    /// Check each read took by fetching the chat's dialog again, at the cost
+    /// of one extra request per forward.
+    pub fn set_verify_after_read(&mut self, verify: bool) {
+        self.verify_after_read = verify;
+    }
+
+    /// Clear mention and reaction badges in chats marked read.
+    pub fn set_clear_mentions(&mut self, clear: bool) {
+        self.clear_mentions = clear;
+    }
+
+    /// Pace repeated marks in some chats differently from the global delay.
+    pub fn set_chat_delays(&mut self, chat_delays: ChatDelays) {
+        self.chat_delays = Some(chat_delays).filter(|d| !d.0.is_empty());
+    }
+
+    /// Let up to `max_concurrent` propagations of a batch overlap. Reads
+    /// within one chat still happen one after the other.
+    pub fn set_max_concurrent_propagations(&mut self, max_concurrent: usize) {
+        self.max_concurrent = max_concurrent.max(1);
+    }
+
+    /// Flag chats that refuse reads in `unmarkable` and skip those already
+    /// in it. Usually the tracker's, so the flags are saved with the state.
+    pub fn set_unmarkable_chats(&mut self, unmarkable: Arc<UnmarkableChats>) {
+        self.unmarkable = unmarkable;
+    }
+
+    /// Confirm each finished propagation in `pending`, usually the
+    /// tracker's, so only unfinished ones are resumed after a restart.
+    pub fn set_pending_marks(&mut self, pending: Option<Arc<PendingMarks>>) {
+        self.pending = pending;
+    }
+
+    /// Record the copies each propagation marked in `marked`, usually the
+    /// tracker's, so their status is known.
+    pub fn set_marked_copies(&mut self, marked: Option<Arc<MarkedCopies>>) {
+        self.marked = marked;
+    }
+
+    /// Look up access hashes of forward origins in `session`.
+    pub fn set_session(&mut self, session: Arc<SqliteSession>) {
+        self.session = Some(session);
+    }
+
+    /// Write out buffered audit log entries.
+    pub fn flush_audit_log(&self) {
+        if let Some(audit) = &self.audit {
+            audit.flush();
+        }
+    }
+
+    /// Merge a finished dialog scan into the peer cache. Dialog entries win
+    /// over peers learned from updates since they carry mute settings.
+    pub fn merge_dialogs(&mut self, scan: DialogScan) {
+        self.peer_cache.extend(scan.peers);
+        self.refreshed.get_mut().unwrap().clear();
+        info!(entries = self.peer_cache.len(), "Peer cache built");
+        if self.skip_muted {
+            let now = epoch_secs() as i64;
+            let muted = self
+                .peer_cache
+                .values()
+                .filter(|p| is_muted(p.mute_until, now))
+                .count();
+            info!(chats = muted, "Skipping reads in muted chats");
+        }
+    }
+
+    /// Resolve chats missing from the peer cache from the session when they
+    /// are first needed, for running without a dialog scan.
+    pub fn set_lazy_peers(&mut self, lazy: bool) {
+        self.lazy_peers = lazy;
+    }
+
+    /// A chat's peer reference, mute setting and badges. In lazy mode a
+    /// chat missing from the cache is resolved from the session, or looked
+    /// up in the dialog list if the session doesn't know it, without mute
+    /// setting or badges, and remembered.
+    async fn cached_peer(&self, chat_id: i64) -> Result<(PeerRef, Option<i32>, Option<Badges>)> {
+        if let Some(p) = self.peer_cache.get(&chat_id) {
+            return Ok((p.peer_ref, p.mute_until, p.badges));
+        }
+        let known = self.refreshed.lock().unwrap().get(&chat_id).copied();
+        let peer_ref = resolve_uncached(
+            known,
+            self.lazy_peers,
+            || self.session_peer_ref(peer_id_of(chat_id)),
+            || self.dialog_peer(chat_id),
+        )
+        .await
+        .ok_or(MarkerError::PeerNotCached(chat_id))?;
+        if known.is_none() {
+            debug!(chat_id, "Resolved an uncached chat");
+            self.refreshed.lock().unwrap().insert(chat_id, peer_ref);
+        }
+        Ok((peer_ref, None, None))
+    }
+
+    /// Whether we have a peer reference for a chat, i.e. can mark it read.
+    pub fn has_peer(&self, chat_id: i64) -> bool {
+        self.peer_cache.contains_key(&chat_id)
+    }
+
+    /// A peer reference built from the access hash grammers keeps in the
+    /// session, which it updates whenever a peer shows up in an update.
+    fn session_peer_ref(&self, peer_id: PeerId) -> Option<PeerRef> {
+        let info = self.session.as_ref()?.peer(peer_id)?;
+        Some(PeerRef {
+            id: peer_id,
+            auth: info.auth(),
+        })
+    }
+
+    /// Run `request` against `chat_id`'s peer, `peer_ref` unless one was
+    /// refreshed since. If Telegram rejects it as stale, the chat is looked
+    /// up again through the API and the request retried once; the fresh
+    /// reference then replaces the stale one for later requests.
+    async fn with_current_peer<T, F, Fut>(
+        &self,
+        chat_id: i64,
+        peer_ref: PeerRef,
+        request: F,
+    ) -> Result<T>
+    where
+        F: Fn(PeerRef) -> Fut,
+        Fut: Future<Output = Result<T>>,
+    {
+        let cached = self.refreshed.lock().unwrap().get(&chat_id).copied();
+        let peer_ref = cached.unwrap_or(peer_ref);
+        let (value, fresh) =
+            retry_with_fresh_peer(chat_id, peer_ref, || self.resolve_peer(chat_id), request)
+                .await?;
+        if let Some(fresh) = fresh {
+            info!(chat_id, "Refreshed stale peer reference");
+            self.refreshed.lock().unwrap().insert(chat_id, fresh);
+        }
+        Ok(value)
+    }
+
+    /// Look a chat up afresh: in the dialog list, whose entries carry
+    /// current access hashes, or else in the session.
+    async fn resolve_peer(&self, chat_id: i64) -> Option<PeerRef> {
+        let peer_ref = self.dialog_peer(chat_id).await;
+        peer_ref.or_else(|| self.session_peer_ref(peer_id_of(chat_id)))
+    }
+
+    /// A chat's peer as its dialog list entry has it, if it has one.
+    async fn dialog_peer(&self, chat_id: i64) -> Option<PeerRef> {
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire().await;
+        }
+        match find_dialog(&mut self.client.iter_dialogs(), chat_id).await {
+            Ok(Some(ScannedDialog { peer: Some(peer), .. })) => Some(peer.peer_ref),
+            Ok(_) => {
+                debug!(chat_id, "Chat not in the dialog list");
+                None
+            }
+            Err(e) => {
+                warn!(chat_id, error = %e, "Failed to look the chat up in the dialog list");
+                None
+            }
+        }
+    }
+
+    /// Whether anything past `max_id` is unread under `badge`, which
+    /// clearing the badge would clear too.
+    async fn unread_past(
+        &self,
+        badge: Badge,
+        peer_ref: PeerRef,
+        max_id: i32,
+        top_msg_id: Option<i32>,
+    ) -> Result<bool> {
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire().await;
+        }
+        let peer = peer_ref.into();
+        // `min_id` keeps only newer messages; one is enough to know
+        let messages = match badge {
+            Badge::Mention => {
+                self.client
+                    .invoke(&tl::functions::messages::GetUnreadMentions {
+                        peer,
+                        top_msg_id,
+                        offset_id: 0,
+                        add_offset: 0,
+                        limit: 1,
+                        max_id: 0,
+                        min_id: max_id,
+                    })
+                    .await?
+            }
+            Badge::Reaction => {
+                self.client
+                    .invoke(&tl::functions::messages::GetUnreadReactions {
+                        peer,
+                        top_msg_id,
+                        offset_id: 0,
+                        add_offset: 0,
+                        limit: 1,
+                        max_id: 0,
+                        min_id: max_id,
+                    })
+                    .await?
+            }
+        };
+        Ok(holds_messages(&messages))
+    }
+
+    /// Clear `badge` in `peer_ref`'s chat, or in thread `top_msg_id` only.
+    async fn read_badge(
+        &self,
+        badge: Badge,
+        peer_ref: PeerRef,
+        top_msg_id: Option<i32>,
+    ) -> Result<()> {
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire().await;
+        }
+        let peer = peer_ref.into();
+        match badge {
+            Badge::Mention => self
+                .client
+                .invoke(&tl::functions::messages::ReadMentions { peer, top_msg_id })
+                .await
+                .map(drop)?,
+            Badge::Reaction => self
+                .client
+                .invoke(&tl::functions::messages::ReadReactions { peer, top_msg_id })
+                .await
+                .map(drop)?,
+        }
+        Ok(())
+    }
+
+    /// Issue the read RPC for a location in `peer_ref`'s chat.
+    async fn read_history(
+        &self,
+        peer_ref: PeerRef,
+        max_id: i32,
+        top_msg_id: Option<i32>,
+    ) -> Result<()> {
+        let (rpc, max_id) = read_request(peer_ref.id, max_id, top_msg_id, self.read_ahead);
+        match rpc {
+            ReadRpc::Thread { top_msg_id } => {
+                self.client
+                    .invoke(&tl::functions::messages::ReadDiscussion {
+                        peer: peer_ref.into(),
+                        msg_id: top_msg_id,
+                        read_max_id: max_id,
+                    })
+                    .await
+                    .map(drop)?;
+            }
+            ReadRpc::ChannelHistory => {
+                self.client
+                    .invoke(&tl::functions::channels::ReadHistory {
+                        channel: peer_ref.into(),
+                        max_id,
+                    })
+                    .await
+                    .map(drop)?;
+            }
+            ReadRpc::History => {
+                self.client
+                    .invoke(&tl::functions::messages::ReadHistory {
+                        peer: peer_ref.into(),
+                        max_id,
+                    })
+                    .await
+                    .map(drop)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl ReadMarker for Marker {
+    fn record_propagation(&mut self, elapsed: Duration) {
+        self.latency.lock().unwrap().record(elapsed);
+    }
+
+    fn max_concurrent_propagations(&self) -> usize {
+        self.max_concurrent
+    }
+
+    fn audit_log(&self) -> Option<&AuditLog> {
+        self.audit.as_ref()
+    }
+
+    fn recent_events(&self) -> Option<&RecentEvents> {
+        self.recent.as_deref()
+    }
+
+    fn log_gate(&self) -> Option<&LogGate> {
+        self.log_gate.as_deref()
+    }
+
+    fn pending_marks(&self) -> Option<&PendingMarks> {
+        self.pending.as_deref()
+    }
+
+    fn marked_copies(&self) -> Option<&MarkedCopies> {
+        self.marked.as_deref()
+    }
+
+    fn verify_before_read(&self) -> bool {
+        self.verify_before_read
+    }
+
+    fn verify_after_read(&self) -> bool {
+        self.verify_after_read
+    }
+
+    async fn read_cursor(&self, chat_id: i64) -> Result<Option<i32>> {
+        let (peer_ref, mute_until, _) = self.cached_peer(chat_id).await?;
+        // mark_read left these alone, so there's nothing to see
+        if should_skip_read(self.skip_muted, mute_until, epoch_secs() as i64)
+            || self.unmarkable.contains(chat_id)
+        {
+            return Ok(None);
+        }
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire().await;
+        }
+        let cached = self.refreshed.lock().unwrap().get(&chat_id).copied();
+        let peer_ref = cached.unwrap_or(peer_ref);
+        let tl::enums::messages::PeerDialogs::Dialogs(dialogs) = self
+            .client
+            .invoke(&tl::functions::messages::GetPeerDialogs {
+                peers: vec![tl::types::InputDialogPeer {
+                    peer: peer_ref.into(),
+                }
+                .into()],
+            })
+            .await?;
+        Ok(dialogs.dialogs.iter().find_map(|dialog| match dialog {
+            tl::enums::Dialog::Dialog(d) => Some(d.read_inbox_max_id),
+            tl::enums::Dialog::Folder(_) => None,
+        }))
+    }
+
+    fn clear_mentions(&self) -> bool {
+        self.clear_mentions
+    }
+
+    async fn clear_badges(&self, chat_id: i64, max_id: i32, top_msg_id: Option<i32>) -> Result<()> {
+        let (peer_ref, mute_until, badges) = self.cached_peer(chat_id).await?;
+        if should_skip_read(self.skip_muted, mute_until, epoch_secs() as i64) {
+            return Ok(());
+        }
+        let max_id = read_max_id(max_id, self.read_ahead);
+        for badge in [Badge::Mention, Badge::Reaction] {
+            if !should_clear_badge(badges, badge, false) {
+                continue;
+            }
+            let past = self
+                .with_current_peer(chat_id, peer_ref, |peer_ref| {
+                    self.unread_past(badge, peer_ref, max_id, top_msg_id)
+                })
+                .await?;
+            if !should_clear_badge(badges, badge, past) {
+                debug!(chat_id, ?badge, "Leaving a badge for messages past the marked ones");
+                continue;
+            }
+            self.with_current_peer(chat_id, peer_ref, |peer_ref| {
+                self.read_badge(badge, peer_ref, top_msg_id)
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    fn note_badge(&mut self, chat_id: i64, badge: Badge) {
+        // Unknown badges already count as shown
+        if let Some(badges) = self.peer_cache.get_mut(&chat_id).and_then(|p| p.badges.as_mut()) {
+            badges.show(badge);
+        }
+    }
+
+    async fn message_exists(&self, chat_id: i64, message_id: i32) -> Result<bool> {
+        let (peer_ref, _, _) = self.cached_peer(chat_id).await?;
+        let now = Instant::now();
+        if self.missing.contains((chat_id, message_id), now) {
+            return Ok(false);
+        }
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire().await;
+        }
+
+        let id = vec![tl::types::InputMessageId { id: message_id }.into()];
+        let messages = if peer_ref.id.kind() == PeerKind::Channel {
+            self.client
+                .invoke(&tl::functions::channels::GetMessages {
+                    channel: peer_ref.into(),
+                    id,
+                })
+                .await?
+        } else {
+            self.client
+                .invoke(&tl::functions::messages::GetMessages { id })
+                .await?
+        };
+
+        let exists = contains_message(&messages, message_id);
+        if !exists {
+            self.missing.insert((chat_id, message_id), now);
+        }
+        Ok(exists)
+    }
+
+    fn observe_only(&self) -> bool {
+        self.observe_only
+    }
+
+    fn dup_action(&self) -> DupAction {
+        self.dup_action
+    }
+
+    async fn archive_chat(&self, chat_id: i64) -> Result<()> {
+        let (peer_ref, _, _) = self.cached_peer(chat_id).await?;
+        debug!(chat_id, "Archiving");
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire().await;
+        }
+        self.client
+            .invoke(&tl::functions::folders::EditPeerFolders {
+                folder_peers: vec![tl::types::InputFolderPeer {
+                    peer: peer_ref.into(),
+                    folder_id: ARCHIVE_FOLDER_ID,
+                }
+                .into()],
+            })
+            .await
+            .map(drop)?;
+        Ok(())
+    }
+
+    async fn folder_chats(&self, folder_id: i32) -> Result<HashSet<i64>> {
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire().await;
+        }
+        let tl::enums::messages::DialogFilters::Filters(filters) = self
+            .client
+            .invoke(&tl::functions::messages::GetDialogFilters {})
+            .await?;
+        for filter in &filters.filters {
+            match filter {
+                tl::enums::DialogFilter::Filter(f) if f.id == folder_id => {
+                    // Folders can also take whole categories ("all groups",
+                    // "contacts"), which would need every dialog to expand
+                    if f.contacts || f.non_contacts || f.groups || f.broadcasts || f.bots {
+                        warn!(
+                            folder_id,
+                            "Folder includes chat categories, only its listed chats are used"
+                        );
+                    }
+                    return Ok(folder_members(&f.include_peers, &f.pinned_peers, &f.exclude_peers));
+                }
+                tl::enums::DialogFilter::Chatlist(f) if f.id == folder_id => {
+                    return Ok(folder_members(&f.include_peers, &f.pinned_peers, &[]));
+                }
+                _ => {}
+            }
+        }
+        Err(MarkerError::UnknownFolder(folder_id))
+    }
+
+    fn cache_peer(&mut self, chat_id: i64, peer_ref: PeerRef, name: String) {
+        remember_peer(&mut self.peer_cache, chat_id, peer_ref, name);
+    }
+
+    fn cache_origin(&mut self, peer_id: PeerId) {
+        let chat_id = peer_id.bot_api_dialog_id();
+        if self.peer_cache.contains_key(&chat_id) {
+            return;
+        }
+        // The update carrying a forward also carries its origin, whose access
+        // hash grammers stores in the session; origins only known from the
+        // header (no access hash) can't be targeted
+        let Some(peer_ref) = self.session_peer_ref(peer_id) else {
+            debug!(chat_id, "No access hash for forward origin");
+            return;
+        };
+        debug!(chat_id, "Cached forward origin");
+        remember_origin(&mut self.peer_cache, chat_id, peer_ref);
+    }
+
+    fn get_chat_name(&self, chat_id: i64) -> &str {
+        self.peer_cache
+            .get(&chat_id)
+            .map(|p| p.name.as_str())
+            .unwrap_or("unknown")
+    }
+
+    async fn send_message(&self, chat_id: i64, text: &str) -> Result<()> {
+        let (peer_ref, _, _) = self.cached_peer(chat_id).await?;
+        self.client.send_message(peer_ref, text).await.map(drop)?;
+        Ok(())
+    }
+
+    async fn mark_read(
+        &self,
+        chat_id: i64,
+        max_id: i32,
+        top_msg_id: Option<i32>,
+    ) -> Result<MarkOutcome> {
+        let (peer_ref, mute_until, _) = self.cached_peer(chat_id).await?;
+
+        let now = epoch_secs() as i64;
+        if should_skip_read(self.skip_muted, mute_until, now) {
+            debug!(chat_id, "Skipping read in muted chat");
+            return Ok(MarkOutcome::Skipped(SkipReason::Muted));
+        }
+
+        debug!(chat_id, max_id, ?top_msg_id, "Marking as read");
+
+        if let Some(limiter) = &self.limiter {
+            limiter.acquire().await;
+        }
+
+        unless_unmarkable(chat_id, &self.unmarkable, || {
+            self.with_current_peer(chat_id, peer_ref, |peer_ref| {
+                self.read_history(peer_ref, max_id, top_msg_id)
+            })
+        })
+        .await
+    }
+}
+
+/// A `ReadMarker` that records reads instead of calling Telegram.
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[derive(Default)]
+    pub struct MockMarker {
+        /// (chat_id, max_id) of every read issued, in order.
+        reads: Mutex<Vec<(i64, i32)>>,
+        /// (chat_id, RPC, max_id sent) of every read issued, in order, as
+        /// the real marker would make them.
+        rpcs: Mutex<Vec<(i64, ReadRpc, i32)>>,
+        /// Message ids past each forward read along with it.
+        pub read_ahead: u32,
+        /// (chat_id, text) of every message sent, in order.
+        sent: Mutex<Vec<(i64, String)>>,
+        /// chat_id of every archive issued, in order.
+        archived: Mutex<Vec<i64>>,
+        /// chat_id of every badge clear issued, in order.
+        cleared: Mutex<Vec<i64>>,
+        pub clear_mentions: bool,
+        /// Chat ids of every forward origin cached, in order.
+        pub origins: Vec<i64>,
+        pub dup_action: DupAction,
+        pub observe_only: bool,
+        names: HashMap<i64, String>,
+        /// Chats where reads fail, reported as an uncached peer.
+        pub failing: HashSet<i64>,
+        /// Chats where reads are skipped as muted.
+        pub muted: HashSet<i64>,
+        /// Resolve chats in `failing` on demand, like a lazy peer cache.
+        pub lazy: bool,
+        /// Chats in `failing` that lazy resolution finds in the session.
+        pub resolvable: HashSet<i64>,
+        /// Chats in `failing` the session doesn't know but the dialog list
+        /// lookup behind it finds.
+        pub listed: HashSet<i64>,
+        /// Every propagation duration recorded.
+        pub propagations: Vec<Duration>,
+        pub audit: Option<AuditLog>,
+        pub recent: Option<Arc<RecentEvents>>,
+        pub log_gate: Option<Arc<LogGate>>,
+        pub pending: Option<Arc<PendingMarks>>,
+        pub marked: Option<Arc<MarkedCopies>>,
+        pub verify: bool,
+        /// (chat_id, message_id) of messages that no longer exist.
+        pub missing: HashSet<(i64, i32)>,
+        pub verify_after: bool,
+        /// Chats whose read cursor never moves, however often they're read.
+        pub not_sticking: HashSet<i64>,
+        pub chat_delays: Option<ChatDelays>,
+        pub max_concurrent: usize,
+        /// How long each read takes.
+        pub read_latency: Duration,
+        /// Chats in each folder, by folder id.
+        pub folders: HashMap<i32, HashSet<i64>>,
+        /// Reads in progress, overall and per chat.
+        in_flight: Mutex<HashMap<Option<i64>, usize>>,
+        /// Most reads seen in progress at once, overall and per chat.
+        peak_in_flight: Mutex<HashMap<Option<i64>, usize>>,
+    }
+
+    impl MockMarker {
+        pub fn reads(&self) -> Vec<(i64, i32)> {
+            self.reads.lock().unwrap().clone()
+        }
+
+        pub(super) fn rpcs(&self) -> Vec<(i64, ReadRpc, i32)> {
+            self.rpcs.lock().unwrap().clone()
+        }
+
+        pub fn sent(&self) -> Vec<(i64, String)> {
+            self.sent.lock().unwrap().clone()
+        }
+
+        pub fn archived(&self) -> Vec<i64> {
+            self.archived.lock().unwrap().clone()
+        }
+
+        pub fn cleared(&self) -> Vec<i64> {
+            self.cleared.lock().unwrap().clone()
+        }
+
+        /// Most reads that were in progress at once, in `chat_id` or
+        /// across all chats if None.
+        pub fn peak_in_flight(&self, chat_id: Option<i64>) -> usize {
+            self.peak_in_flight
+                .lock()
+                .unwrap()
+                .get(&chat_id)
+                .copied()
+                .unwrap_or(0)
+        }
+
+        fn adjust_in_flight(&self, chat_id: i64, starting: bool) {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            let mut peak = self.peak_in_flight.lock().unwrap();
+            for key in [None, Some(chat_id)] {
+                let n = in_flight.entry(key).or_default();
+                if starting {
+                    *n += 1;
+                    let p = peak.entry(key).or_default();
+                    *p = (*p).max(*n);
+                } else {
+                    *n -= 1;
+                }
+            }
+        }
+    }
+
+    impl ReadMarker for MockMarker {
+        fn record_propagation(&mut self, elapsed: Duration) {
+            self.propagations.push(elapsed);
+        }
+
+        fn max_concurrent_propagations(&self) -> usize {
+            self.max_concurrent.max(1)
+        }
+
+        fn audit_log(&self) -> Option<&AuditLog> {
+            self.audit.as_ref()
+        }
+
+        fn recent_events(&self) -> Option<&RecentEvents> {
+            self.recent.as_deref()
+        }
+
+        fn log_gate(&self) -> Option<&LogGate> {
+            self.log_gate.as_deref()
+        }
+
+        fn pending_marks(&self) -> Option<&PendingMarks> {
+            self.pending.as_deref()
+        }
+
+        fn marked_copies(&self) -> Option<&MarkedCopies> {
+            self.marked.as_deref()
+        }
+
+        fn verify_before_read(&self) -> bool {
+            self.verify
+        }
+
+        fn chat_delays(&self) -> Option<&ChatDelays> {
+            self.chat_delays.as_ref()
+        }
+
+        fn clear_mentions(&self) -> bool {
+            self.clear_mentions
+        }
+
+        async fn clear_badges(
+            &self,
+            chat_id: i64,
+            _max_id: i32,
+            _top_msg_id: Option<i32>,
+        ) -> Result<()> {
+            self.cleared.lock().unwrap().push(chat_id);
+            Ok(())
+        }
+
+        async fn message_exists(&self, chat_id: i64, message_id: i32) -> Result<bool> {
+            Ok(!self.missing.contains(&(chat_id, message_id)))
+        }
+
+        fn verify_after_read(&self) -> bool {
+            self.verify_after
+        }
+
+        async fn read_cursor(&self, chat_id: i64) -> Result<Option<i32>> {
+            if self.not_sticking.contains(&chat_id) {
+                return Ok(Some(0));
+            }
+            let reads = self.reads.lock().unwrap();
+            Ok(reads.iter().filter(|(c, _)| *c == chat_id).map(|(_, max_id)| *max_id).max())
+        }
+
+        fn observe_only(&self) -> bool {
+            self.observe_only
+        }
+
+        fn dup_action(&self) -> DupAction {
+            self.dup_action
+        }
+
+        async fn archive_chat(&self, chat_id: i64) -> Result<()> {
+            self.archived.lock().unwrap().push(chat_id);
+            Ok(())
+        }
+
+        async fn folder_chats(&self, folder_id: i32) -> Result<HashSet<i64>> {
+            self.folders
+                .get(&folder_id)
+                .cloned()
+                .ok_or(MarkerError::UnknownFolder(folder_id))
+        }
+
+        fn cache_peer(&mut self, chat_id: i64, _peer_ref: PeerRef, name: String) {
+            self.names.insert(chat_id, name);
+        }
+
+        fn cache_origin(&mut self, peer_id: PeerId) {
+            self.origins.push(peer_id.bot_api_dialog_id());
+        }
+
+        fn get_chat_name(&self, chat_id: i64) -> &str {
+            self.names.get(&chat_id).map_or("unknown", String::as_str)
+        }
+
+        async fn send_message(&self, chat_id: i64, text: &str) -> Result<()> {
+            self.sent.lock().unwrap().push((chat_id, text.to_owned()));
+            Ok(())
+        }
+
+        async fn mark_read(
+            &self,
+            chat_id: i64,
+            max_id: i32,
+            top_msg_id: Option<i32>,
+        ) -> Result<MarkOutcome> {
+            if self.muted.contains(&chat_id) {
+                return Ok(MarkOutcome::Skipped(SkipReason::Muted));
+            }
+            if self.failing.contains(&chat_id) {
+                let session = || self.resolvable.contains(&chat_id).then_some(());
+                let look_up = || async { self.listed.contains(&chat_id).then_some(()) };
+                resolve_uncached(None, self.lazy, session, look_up)
+                    .await
+                    .ok_or(MarkerError::PeerNotCached(chat_id))?;
+            }
+            self.adjust_in_flight(chat_id, true);
+            sleep(self.read_latency).await;
+            self.adjust_in_flight(chat_id, false);
+            self.reads.lock().unwrap().push((chat_id, max_id));
+            let (rpc, sent_max_id) =
+                read_request(peer_id_of(chat_id), max_id, top_msg_id, self.read_ahead);
+            self.rpcs.lock().unwrap().push((chat_id, rpc, sent_max_id));
+            Ok(MarkOutcome::Marked)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::MockMarker;
+    use super::*;
+
+    #[test]
+    fn folder_members_drop_excluded_chats() {
+        let channel = |channel_id| -> tl::enums::InputPeer {
+            tl::types::InputPeerChannel {
+                channel_id,
+                access_hash: 0,
+            }
+            .into()
+        };
+        let group: tl::enums::InputPeer = tl::types::InputPeerChat { chat_id: 5 }.into();
+        let user: tl::enums::InputPeer = tl::types::InputPeerUser {
+            user_id: 42,
+            access_hash: 0,
+        }
+        .into();
+
+        let members = folder_members(
+            &[channel(1), group, tl::enums::InputPeer::PeerSelf],
+            &[user, channel(2)],
+            &[channel(2)],
+        );
+        assert_eq!(members, HashSet::from([-1000000000001, -5, 42]));
+    }
+
+    #[test]
+    fn origins_are_renamed_once_the_chat_itself_shows_up() {
+        let chat_id = -1000000000005;
+        let peer_ref = || PeerRef {
+            id: peer_id_of(chat_id),
+            auth: Default::default(),
+        };
+        let mut cache = HashMap::new();
+        remember_origin(&mut cache, chat_id, peer_ref());
+        assert_eq!(cache[&chat_id].name, "-1000000000005");
+
+        cache.get_mut(&chat_id).unwrap().mute_until = Some(i32::MAX);
+        remember_peer(&mut cache, chat_id, peer_ref(), "News".to_owned());
+        assert_eq!(cache[&chat_id].name, "News");
+        assert_eq!(cache[&chat_id].mute_until, Some(i32::MAX));
+
+        // A later sighting as an origin keeps the real name
+        remember_origin(&mut cache, chat_id, peer_ref());
+        assert_eq!(cache[&chat_id].name, "News");
+    }
+
+    #[test]
+    fn badges_clear_only_when_shown_and_nothing_newer_is_unread() {
+        let none = Badges {
+            mentions: false,
+            reactions: false,
+        };
+        assert!(!should_clear_badge(Some(none), Badge::Mention, false));
+        // Chats the scan didn't cover may show either badge
+        assert!(should_clear_badge(None, Badge::Reaction, false));
+        assert!(!should_clear_badge(None, Badge::Reaction, true));
+
+        // A mention arriving after the scan shows the badge from then on
+        let mut badges = none;
+        badges.show(Badge::Mention);
+        assert!(should_clear_badge(Some(badges), Badge::Mention, false));
+        assert!(!should_clear_badge(Some(badges), Badge::Mention, true));
+        assert!(!should_clear_badge(Some(badges), Badge::Reaction, false));
+    }
+
+    #[tokio::test]
+    async fn uncached_chats_resolve_only_in_lazy_mode() {
+        let resolved = Mutex::new(0);
+        let session = || {
+            *resolved.lock().unwrap() += 1;
+            Some(7)
+        };
+        let unused = || async { panic!("the session knew it") };
+        assert_eq!(resolve_uncached(None, false, session, unused).await, None);
+        assert_eq!(resolve_uncached(None, true, session, unused).await, Some(7));
+        // Once known, a chat isn't resolved again
+        assert_eq!(resolve_uncached(Some(7), true, session, unused).await, Some(7));
+        assert_eq!(*resolved.lock().unwrap(), 1);
+
+        // The API lookup only runs when the session misses
+        let look_up = || async { Some(8) };
+        assert_eq!(resolve_uncached(None, true, || None, look_up).await, Some(8));
+        assert_eq!(resolve_uncached(None, false, || None, look_up).await, None);
+        assert_eq!(resolve_uncached(None, true, || None::<i32>, || async { None }).await, None);
+    }
+
+    #[tokio::test]
+    async fn lazy_mock_marks_chats_it_can_resolve() {
+        let mut marker = MockMarker {
+            failing: HashSet::from([10, 20]),
+            resolvable: HashSet::from([10]),
+            ..Default::default()
+        };
+        assert!(marker.mark_read(10, 5, None).await.is_err(), "eager");
+
+        marker.lazy = true;
+        marker.mark_read(10, 5, None).await.unwrap();
+        assert!(matches!(
+            marker.mark_read(20, 5, None).await,
+            Err(MarkerError::PeerNotCached(20))
+        ));
+        assert_eq!(marker.reads(), vec![(10, 5)]);
+    }
+
+    #[test]
+    fn own_channels_are_created_or_administered() {
+        assert!(is_own_channel(Some((true, false))), "created");
+        assert!(is_own_channel(Some((false, true))), "administered");
+        assert!(is_own_channel(Some((true, true))));
+        assert!(!is_own_channel(Some((false, false))), "someone else's");
+        assert!(!is_own_channel(None), "not a channel");
+    }
+
+    #[test]
+    fn dialog_ids_map_back_to_peers() {
+        for chat_id in [12345, -4567, -1001234567890] {
+            assert_eq!(peer_id_of(chat_id).bot_api_dialog_id(), chat_id);
+        }
+    }
+
+    #[test]
+    fn thread_reads_use_read_discussion() {
+        assert_eq!(
+            select_read_rpc(true, Some(7)),
+            ReadRpc::Thread { top_msg_id: 7 }
+        );
+    }
+
+    #[test]
+    fn unthreaded_reads_use_history() {
+        assert_eq!(select_read_rpc(true, None), ReadRpc::ChannelHistory);
+        assert_eq!(select_read_rpc(false, None), ReadRpc::History);
+        // Basic groups have no threads, fall back to a chat-wide read
+        assert_eq!(select_read_rpc(false, Some(7)), ReadRpc::History);
+    }
+
+    #[test]
+    fn reads_include_the_forward_itself() {
+        // channels.readHistory and messages.readHistory both read messages
+        // with ids up to and including max_id, as does readDiscussion's
+        // read_max_id, so the forward's own id is sent as is
+        assert_eq!(read_max_id(100, 0), 100);
+        // A margin reads that many ids past the forward
+        assert_eq!(read_max_id(100, 3), 103);
+        assert_eq!(read_max_id(i32::MAX - 1, 5), i32::MAX);
+    }
+
+    #[test]
+    fn mute_expires_at_mute_until() {
+        assert!(!is_muted(None, 1000));
+        assert!(is_muted(Some(2000), 1000));
+        assert!(!is_muted(Some(1000), 1000));
+        assert!(!is_muted(Some(0), 1000));
+        // "Muted forever" is a far-future date
+        assert!(is_muted(Some(i32::MAX), 1000));
+    }
+
+    #[test]
+    fn muted_chats_are_skipped_only_when_enabled() {
+        assert!(should_skip_read(true, Some(i32::MAX), 1000));
+        assert!(!should_skip_read(false, Some(i32::MAX), 1000));
+        assert!(!should_skip_read(true, None, 1000));
+        assert!(!should_skip_read(true, Some(500), 1000));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lazy_misses_fall_back_to_the_dialog_list() {
+        // 10 is in the session, 20 only in the dialog list, 30 in neither
+        let mut marker = MockMarker {
+            failing: HashSet::from([10, 20, 30]),
+            resolvable: HashSet::from([10]),
+            listed: HashSet::from([20]),
+            lazy: true,
+            ..Default::default()
+        };
+        let forwards = [
+            (original(), ForwardLocation::new(10, 1)),
+            (original(), ForwardLocation::new(20, 2)),
+            (original(), ForwardLocation::new(30, 3)),
+        ];
+        marker.mark_forwards_read(&forwards).await.unwrap();
+        assert_eq!(marker.reads(), vec![(10, 1), (20, 2)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reads_use_the_rpc_for_each_kind_of_chat() {
+        let (channel, group, user) = (-1000000000001, -5, 42);
+        let in_thread = |chat_id, message_id| ForwardLocation {
+            top_msg_id: Some(3),
+            ..ForwardLocation::new(chat_id, message_id)
+        };
+        let forwards = [
+            (original(), ForwardLocation::new(channel, 10)),
+            (original(), in_thread(channel, 11)),
+            (original(), in_thread(group, 20)),
+            (original(), ForwardLocation::new(user, 30)),
+        ];
+        let marker = MockMarker::default();
+        marker.mark_forwards_read(&forwards).await.unwrap();
+        // The forward itself is the max_id: every read RPC counts it in
+        assert_eq!(
+            marker.rpcs(),
+            vec![
+                (channel, ReadRpc::ChannelHistory, 10),
+                (channel, ReadRpc::Thread { top_msg_id: 3 }, 11),
+                // Basic groups have no threads to read
+                (group, ReadRpc::History, 20),
+                (user, ReadRpc::History, 30),
+            ]
+        );
+
+        let marker = MockMarker {
+            read_ahead: 2,
+            ..Default::default()
+        };
+        marker.mark_forwards_read(&forwards[2..]).await.unwrap();
+        assert_eq!(
+            marker.rpcs(),
+            vec![(group, ReadRpc::History, 22), (user, ReadRpc::History, 32)]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn mark_forwards_read_issues_each_read_and_skips_failures() {
+        let mut marker = MockMarker::default();
+        marker.failing.insert(20);
+        let forwards = [
+            (original(), ForwardLocation::new(10, 1)),
+            (original(), ForwardLocation::new(20, 2)),
+            (original(), ForwardLocation::new(30, 3)),
+        ];
+
+        marker.mark_forwards_read(&forwards).await.unwrap();
+
+        assert_eq!(marker.reads(), vec![(10, 1), (30, 3)]);
+    }
+
+    fn original() -> OriginalMessageId {
+        OriginalMessageId {
+            peer_id: -1005,
+            message_id: 7,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn mark_forwards_read_writes_audit_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("audit.jsonl");
+        let mut marker = MockMarker::default();
+        marker.failing.insert(20);
+        marker.muted.insert(30);
+        marker.audit = Some(AuditLog::open(&path).unwrap());
+        let mut threaded = ForwardLocation::new(10, 1);
+        threaded.top_msg_id = Some(9);
+        let forwards = [
+            (original(), threaded),
+            (original(), ForwardLocation::new(20, 2)),
+            (original(), ForwardLocation::new(30, 3)),
+        ];
+
+        marker.mark_forwards_read(&forwards).await.unwrap();
+        marker.audit.as_ref().unwrap().flush();
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        let records: Vec<serde_json::Value> = contents
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 3);
+
+        let mut first = records[0].clone();
+        assert!(first["ts"].as_u64().unwrap() > 0);
+        first.as_object_mut().unwrap().remove("ts");
+        assert_eq!(
+            first,
+            serde_json::json!({
+                "chat_id": 10,
+                "chat_name": "unknown",
+                "message_id": 1,
+                "top_msg_id": 9,
+                "original": { "peer_id": -1005, "message_id": 7 },
+                "ok": true,
+            })
+        );
+
+        assert_eq!(records[1]["chat_id"], 20);
+        assert_eq!(records[1]["ok"], false);
+        assert!(records[1]["error"].as_str().is_some());
+
+        // A muted chat is skipped, not failed
+        assert_eq!(records[2]["chat_id"], 30);
+        assert_eq!(records[2]["ok"], false);
+        assert_eq!(records[2]["skipped"], "muted");
+        assert!(records[2].get("error").is_none());
+        assert_eq!(marker.reads(), vec![(10, 1)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn verify_before_read_skips_deleted_forwards() {
+        let mut marker = MockMarker::default();
+        marker.missing.insert((20, 2));
+        let forwards = [
+            (original(), ForwardLocation::new(10, 1)),
+            (original(), ForwardLocation::new(20, 2)),
+        ];
+
+        // Without verification the deleted one is marked anyway
+        marker.mark_forwards_read(&forwards).await.unwrap();
+        assert_eq!(marker.reads(), vec![(10, 1), (20, 2)]);
+
+        let mut marker = MockMarker {
+            verify: true,
+            ..Default::default()
+        };
+        marker.missing.insert((20, 2));
+        marker.mark_forwards_read(&forwards).await.unwrap();
+        assert_eq!(marker.reads(), vec![(10, 1)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn verify_after_read_marks_again_when_a_read_does_not_take() {
+        let mut threaded = ForwardLocation::new(30, 3);
+        threaded.top_msg_id = Some(9);
+        let forwards = [
+            (original(), ForwardLocation::new(10, 1)),
+            (original(), ForwardLocation::new(20, 2)),
+            (original(), threaded),
+        ];
+        let marker = |verify_after| MockMarker {
+            verify_after,
+            not_sticking: [20, 30].into(),
+            ..Default::default()
+        };
+
+        // Without verification nobody notices
+        let unverified = marker(false);
+        unverified.mark_forwards_read(&forwards).await.unwrap();
+        assert_eq!(unverified.reads(), vec![(10, 1), (20, 2), (30, 3)]);
+
+        // Chat 20 is marked once more, then left with a warning. Thread
+        // reads can't be checked against the dialog, so 30 isn't retried.
+        let marker = marker(true);
+        marker.mark_forwards_read(&forwards).await.unwrap();
+        assert_eq!(marker.reads(), vec![(10, 1), (20, 2), (20, 2), (30, 3)]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn mentions_are_cleared_only_when_enabled_and_read() {
+        let forwards = [
+            (original(), ForwardLocation::new(10, 1)),
+            (original(), ForwardLocation::new(10, 2)),
+            (original(), ForwardLocation::new(20, 3)),
+            (original(), ForwardLocation::new(30, 4)),
+        ];
+
+        let mut marker = MockMarker::default();
+        marker.mark_forwards_read(&forwards).await.unwrap();
+        assert!(marker.cleared().is_empty());
+
+        // Once per chat, and never where the read itself failed
+        let mut marker = MockMarker {
+            clear_mentions: true,
+            ..Default::default()
+        };
+        marker.failing.insert(20);
+        marker.mark_forwards_read(&forwards).await.unwrap();
+        assert_eq!(marker.cleared(), vec![10, 30]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn missing_cache_forgets_after_ttl() {
+        let cache = MissingCache::default();
+        let start = Instant::now();
+        cache.insert((10, 1), start);
+
+        assert!(cache.contains((10, 1), start + MISSING_TTL / 2));
+        assert!(!cache.contains((20, 1), start));
+        assert!(!cache.contains((10, 1), start + MISSING_TTL));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn mark_forwards_read_notes_recent_events() {
+        let mut marker = MockMarker::default();
+        marker.failing.insert(20);
+        marker.muted.insert(30);
+        marker.recent = Some(Arc::new(RecentEvents::new(10)));
+        let forwards = [
+            (original(), ForwardLocation::new(10, 1)),
+            (original(), ForwardLocation::new(20, 2)),
+            (original(), ForwardLocation::new(30, 3)),
+        ];
+
+        marker.mark_forwards_read(&forwards).await.unwrap();
+
+        let events: Vec<_> = marker
+            .recent
+            .unwrap()
+            .snapshot()
+            .into_iter()
+            .map(|e| e.event)
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                Event::Marked {
+                    original: original(),
+                    forward: ForwardLocation::new(10, 1),
+                    ok: true,
+                },
+                Event::Marked {
+                    original: original(),
+                    forward: ForwardLocation::new(20, 2),
+                    ok: false,
+                },
+                Event::Skipped {
+                    original: original(),
+                    forward: ForwardLocation::new(30, 3),
+                    reason: SkipReason::Muted,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn only_permission_errors_flag_a_chat_unmarkable() {
+        let kind = |name| classify_rpc_error(name, None);
+        for name in ["CHAT_ADMIN_REQUIRED", "CHAT_WRITE_FORBIDDEN"] {
+            assert!(matches!(kind(name), Some(MarkerError::NotPermitted)), "{}", name);
+        }
+        for name in ["CHAT_FORBIDDEN", "CHANNEL_PRIVATE", "USER_BANNED_IN_CHANNEL"] {
+            assert!(matches!(kind(name), Some(MarkerError::NoAccess)), "{}", name);
+        }
+        assert!(matches!(
+            classify_rpc_error("FLOOD_WAIT", Some(3)),
+            Some(MarkerError::FloodWait { seconds: 3 })
+        ));
+        assert!(matches!(kind("CHANNEL_INVALID"), Some(MarkerError::InvalidPeer)));
+        assert!(kind("MESSAGE_ID_INVALID").is_none());
+    }
+
+    #[test]
+    fn auth_errors_are_fatal() {
+        assert!(is_auth_rpc_error(401, "AUTH_KEY_UNREGISTERED"));
+        assert!(is_auth_rpc_error(401, "SESSION_REVOKED"));
+        // Classified by name even if the code is unexpected
+        assert!(is_auth_rpc_error(400, "USER_DEACTIVATED"));
+    }
+
+    #[test]
+    fn other_errors_are_not_fatal() {
+        assert!(!is_auth_rpc_error(420, "FLOOD_WAIT"));
+        assert!(!is_auth_rpc_error(400, "PEER_ID_INVALID"));
+        assert!(!MarkerError::PeerNotCached(1).is_fatal());
+        assert!(!MarkerError::FloodWait { seconds: 5 }.is_fatal());
+    }
+
+    #[test]
+    fn dup_action_parses_and_selects_steps() {
+        assert_eq!("read".parse::<DupAction>().unwrap(), DupAction::Read);
+        assert_eq!("Archive".parse::<DupAction>().unwrap(), DupAction::Archive);
+        assert_eq!("both".parse::<DupAction>().unwrap(), DupAction::Both);
+        assert!("delete".parse::<DupAction>().is_err());
+
+        assert!(DupAction::Read.marks_read() && !DupAction::Read.archives());
+        assert!(!DupAction::Archive.marks_read() && DupAction::Archive.archives());
+        assert!(DupAction::Both.marks_read() && DupAction::Both.archives());
+    }
+
+    #[test]
+    fn chat_delays_parse_pairs() {
+        let delays: ChatDelays = "-1001234:2000, 42:0,".parse().unwrap();
+        assert_eq!(delays.between(-1001234, -1001234), Duration::from_secs(2));
+        assert_eq!(delays.between(42, 42), Duration::ZERO);
+        assert_eq!(delays.between(7, 7), MARK_READ_DELAY);
+        assert_eq!("".parse::<ChatDelays>().unwrap(), ChatDelays::default());
+        let delays: ChatDelays = "10:1s500ms".parse().unwrap();
+        assert_eq!(delays.between(10, 10), Duration::from_millis(1500));
+
+        assert!("-1001234".parse::<ChatDelays>().is_err());
+        assert!("-1001234:fast".parse::<ChatDelays>().is_err());
+        assert!("chat:100".parse::<ChatDelays>().is_err());
+
+        let delays: ChatDelays = "42:0,-1001234:2s".parse().unwrap();
+        assert_eq!(delays.to_string(), "-1001234:2000,42:0");
+        assert_eq!(delays.to_string().parse::<ChatDelays>().unwrap(), delays);
+    }
+
+    #[test]
+    fn chat_delays_only_pace_repeated_marks_in_one_chat() {
+        let delays: ChatDelays = "10:3000".parse().unwrap();
+        assert_eq!(delays.between(10, 10), Duration::from_secs(3));
+        assert_eq!(delays.between(20, 10), MARK_READ_DELAY);
+        assert_eq!(delays.between(10, 20), MARK_READ_DELAY);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn mark_forwards_read_waits_the_chat_delay() {
+        let marker = MockMarker {
+            chat_delays: Some("10:3000".parse().unwrap()),
+            ..Default::default()
+        };
+        let forwards = [
+            (original(), ForwardLocation::new(10, 1)),
+            (original(), ForwardLocation::new(10, 2)),
+            (original(), ForwardLocation::new(20, 3)),
+        ];
+
+        let start = Instant::now();
+        marker.mark_forwards_read(&forwards).await.unwrap();
+
+        assert_eq!(start.elapsed(), Duration::from_secs(3) + MARK_READ_DELAY);
+        assert_eq!(marker.reads(), vec![(10, 1), (10, 2), (20, 3)]);
+    }
+
+    #[test]
+    fn flood_waits_are_recognized() {
+        assert_eq!(flood_wait_seconds("FLOOD_WAIT", Some(30)), Some(30));
+        assert_eq!(flood_wait_seconds("SLOWMODE_WAIT", None), Some(0));
+        assert_eq!(flood_wait_seconds("PEER_ID_INVALID", None), None);
+    }
+
+    #[tokio::test]
+    async fn rejected_peers_are_resolved_again_and_retried_once() {
+        // Stand-ins for access hashes: 1 is stale, 2 is current
+        let attempts = Mutex::new(Vec::new());
+        let request = |hash: u32| {
+            attempts.lock().unwrap().push(hash);
+            async move {
+                if hash == 1 {
+                    Err(MarkerError::InvalidPeer)
+                } else {
+                    Ok(())
+                }
+            }
+        };
+
+        let resolved = retry_with_fresh_peer(10, 1, || async { Some(2) }, request).await;
+        assert_eq!(resolved.unwrap(), ((), Some(2)));
+        assert_eq!(*attempts.lock().unwrap(), vec![1, 2]);
+
+        attempts.lock().unwrap().clear();
+        let resolved = retry_with_fresh_peer(10, 2, not_stale, request).await;
+        assert_eq!(resolved.unwrap(), ((), None));
+        assert_eq!(*attempts.lock().unwrap(), vec![2]);
+    }
+
+    /// A `refresh` for requests that mustn't be rejected.
+    fn not_stale() -> std::future::Ready<Option<u32>> {
+        panic!("not stale")
+    }
+
+    #[tokio::test]
+    async fn rejected_peers_give_up_if_resolving_fails() {
+        let attempts = Mutex::new(0);
+        let request = |_hash: u32| {
+            *attempts.lock().unwrap() += 1;
+            async { Err(MarkerError::InvalidPeer) }
+        };
+
+        let err = retry_with_fresh_peer(10, 1, || async { None }, request).await;
+        assert!(matches!(err, Err(MarkerError::InvalidPeer)));
+        assert_eq!(*attempts.lock().unwrap(), 1);
+
+        // Still rejected after resolving again: one retry only
+        let err = retry_with_fresh_peer(10, 1, || async { Some(1) }, request).await;
+        assert!(matches!(err, Err(MarkerError::InvalidPeer)));
+        assert_eq!(*attempts.lock().unwrap(), 3);
+
+        // Other failures aren't retried
+        let flood = |_hash: u32| async { Err(MarkerError::FloodWait { seconds: 3 }) };
+        let err = retry_with_fresh_peer(10, 1, not_stale, flood).await;
+        assert!(matches!(err, Err(MarkerError::FloodWait { seconds: 3 })));
+    }
+
+    #[tokio::test]
+    async fn chats_refusing_reads_are_skipped_after_the_first_refusal() {
+        let unmarkable = UnmarkableChats::default();
+        let attempts = Mutex::new(Vec::new());
+        let request = |chat_id: i64| {
+            attempts.lock().unwrap().push(chat_id);
+            async move {
+                if chat_id == 10 {
+                    Err(MarkerError::NotPermitted)
+                } else {
+                    Ok(())
+                }
+            }
+        };
+
+        let first = unless_unmarkable(10, &unmarkable, || request(10)).await;
+        assert!(matches!(first, Err(MarkerError::NotPermitted)));
+        assert!(unmarkable.contains(10));
+        for (chat_id, outcome) in [
+            (10, MarkOutcome::Skipped(SkipReason::Unmarkable)),
+            (20, MarkOutcome::Marked),
+            (10, MarkOutcome::Skipped(SkipReason::Unmarkable)),
+        ] {
+            let result = unless_unmarkable(chat_id, &unmarkable, || request(chat_id)).await;
+            assert_eq!(result.unwrap(), outcome);
+        }
+        assert_eq!(*attempts.lock().unwrap(), vec![10, 20]);
+
+        // Other failures don't flag the chat
+        let flood = || async { Err(MarkerError::FloodWait { seconds: 3 }) };
+        assert!(unless_unmarkable(30, &unmarkable, flood).await.is_err());
+        assert_eq!(unmarkable.chats(), vec![10]);
+
+        assert_eq!(unmarkable.clear(), 1);
+        assert!(unless_unmarkable(10, &unmarkable, || request(10)).await.is_err());
+        assert_eq!(attempts.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn uncached_peer_is_a_typed_error() {
+        let marker = MockMarker {
+            failing: [10].into(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            marker.mark_read(10, 1, None).await,
+            Err(MarkerError::PeerNotCached(10))
+        ));
+        assert!(marker.mark_read(20, 1, None).await.is_ok());
+    }
+
+    /// A dialog list that answers from a script: each request takes the
+    /// next scripted result, and `next` runs out into the end of the list.
+    #[derive(Default)]
+    struct ScriptedDialogs {
+        totals: std::collections::VecDeque<Result<usize>>,
+        dialogs: std::collections::VecDeque<Result<ScannedDialog>>,
+        requests: u32,
+    }
+
+    impl DialogSource for ScriptedDialogs {
+        async fn total(&mut self) -> Result<usize> {
+            self.requests += 1;
+            self.totals.pop_front().unwrap_or(Ok(self.dialogs.len()))
+        }
+
+        async fn next(&mut self) -> Result<Option<ScannedDialog>> {
+            self.requests += 1;
+            self.dialogs.pop_front().transpose()
+        }
+    }
+
+    fn dialog(chat_id: i64) -> Result<ScannedDialog> {
+        Ok(ScannedDialog {
+            chat_id,
+            owned: chat_id == 30,
+            read_cursor: Some(7),
+            peer: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn stale_peers_are_looked_up_in_the_dialog_list() {
+        let mut source = ScriptedDialogs {
+            dialogs: [dialog(10), dialog(20), dialog(30)].into(),
+            ..Default::default()
+        };
+        let found = find_dialog(&mut source, 20).await.unwrap();
+        assert_eq!(found.map(|d| d.chat_id), Some(20));
+        // Stops at the chat rather than reading the whole list
+        assert_eq!(source.requests, 2);
+
+        assert!(find_dialog(&mut source, 40).await.unwrap().is_none());
+        let mut failing = ScriptedDialogs {
+            dialogs: [dialog(10), Err(MarkerError::FloodWait { seconds: 3 })].into(),
+            ..Default::default()
+        };
+        assert!(find_dialog(&mut failing, 20).await.is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dialog_scan_retries_transient_errors() {
+        let mut source = ScriptedDialogs {
+            totals: [Err(MarkerError::FloodWait { seconds: 3 }), Ok(3)].into(),
+            dialogs: [
+                dialog(10),
+                Err(MarkerError::PeerNotCached(0)),
+                Err(MarkerError::PeerNotCached(0)),
+                dialog(20),
+                Err(MarkerError::PeerNotCached(0)),
+                dialog(30),
+            ]
+            .into(),
+            ..Default::default()
+        };
+        let started = Instant::now();
+        let scan = collect_dialogs(&mut source).await.unwrap();
+
+        assert!(scan.is_complete());
+        assert_eq!(scan.read_cursors(), &[(10, 7), (20, 7), (30, 7)]);
+        assert_eq!(scan.owned, HashSet::from([30]));
+        // The flood wait as asked, then 2s and 4s, then 2s again after a
+        // dialog came through
+        assert_eq!(started.elapsed(), Duration::from_secs(3 + 2 + 4 + 2));
+    }
+
+    #[tokio::test]
+    async fn every_dialog_peer_is_followed() {
+        let listed = |chat_id| {
+            Ok(ScannedDialog {
+                peer: Some(CachedPeer {
+                    peer_ref: PeerRef {
+                        id: peer_id_of(chat_id),
+                        auth: Default::default(),
+                    },
+                    name: chat_id.to_string(),
+                    mute_until: None,
+                    badges: None,
+                }),
+                ..dialog(chat_id).unwrap()
+            })
+        };
+        // A channel, a user or bot and a basic group, plus one that can't
+        // be called
+        let mut source = ScriptedDialogs {
+            dialogs: [listed(-1000000000001), listed(42), listed(-5), dialog(7)].into(),
+            ..Default::default()
+        };
+        let scan = collect_dialogs(&mut source).await.unwrap();
+        assert_eq!(scan.followed_ids(), HashSet::from([-1000000000001, 42, -5]));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dialog_scan_gives_up_on_a_persistent_error() {
+        let mut source = ScriptedDialogs {
+            totals: (0..SCAN_ATTEMPTS + 1)
+                .map(|_| Err(MarkerError::PeerNotCached(0)))
+                .collect(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            collect_dialogs(&mut source).await,
+            Err(MarkerError::PeerNotCached(0))
+        ));
+        assert_eq!(source.requests, SCAN_ATTEMPTS);
+
+        // Failing before any dialog came through leaves nothing to go on
+        let mut source = ScriptedDialogs {
+            totals: [Ok(2)].into(),
+            dialogs: (0..SCAN_ATTEMPTS)
+                .map(|_| Err(MarkerError::InvalidPeer))
+                .collect(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            collect_dialogs(&mut source).await,
+            Err(MarkerError::InvalidPeer)
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dialog_scan_cut_short_keeps_what_it_found() {
+        let mut dialogs: std::collections::VecDeque<_> = [dialog(10), dialog(20)].into();
+        dialogs.extend((0..SCAN_ATTEMPTS).map(|_| Err(MarkerError::InvalidPeer)));
+        dialogs.push_back(dialog(30));
+        let mut source = ScriptedDialogs {
+            totals: [Ok(3)].into(),
+            dialogs,
+            ..Default::default()
+        };
+        let scan = collect_dialogs(&mut source).await.unwrap();
+
+        assert!(!scan.is_complete());
+        assert_eq!(scan.read_cursors(), &[(10, 7), (20, 7)]);
+        assert!(scan.owned.is_empty());
     }
 }
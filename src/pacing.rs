@@ -0,0 +1,147 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+use crate::handler::{self, Action};
+
+/// How long to wait between batches of one oversized propagation.
+pub const BATCH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Spreads a propagation that touches more than `max_chats` chats over
+/// several batches, so one read of a viral post doesn't hit hundreds of
+/// chats at once. The first batch runs right away, the rest one per
+/// interval; propagations within the limit pass straight through.
+pub struct PacedQueue {
+    max_chats: usize,
+    interval: Duration,
+    batches: VecDeque<Action>,
+    /// When the next held batch is due, while any are held.
+    wake: Option<Instant>,
+}
+
+impl PacedQueue {
+    pub fn new(max_chats: usize, interval: Duration) -> Self {
+        PacedQueue {
+            max_chats: max_chats.max(1),
+            interval,
+            batches: VecDeque::new(),
+            wake: None,
+        }
+    }
+
+    /// Returns the action, or its first batch if it touches too many chats,
+    /// holding the rest back.
+    pub fn offer(&mut self, action: Action, now: Instant) -> Option<Action> {
+        let Action::MarkForwards { forwards } = action else {
+            return Some(action);
+        };
+        if handler::chat_count(&forwards) <= self.max_chats {
+            return Some(Action::MarkForwards { forwards });
+        }
+        let mut batches = handler::split_by_chats(forwards, self.max_chats)
+            .into_iter()
+            .map(|forwards| Action::MarkForwards { forwards });
+        let first = batches.next();
+        self.batches.extend(batches);
+        if self.wake.is_none() {
+            self.wake = Some(now + self.interval);
+        }
+        first
+    }
+
+    /// The next held batch, if it is due at `now`.
+    pub fn due(&mut self, now: Instant) -> Option<Action> {
+        if self.wake.is_none_or(|wake| wake > now) {
+            return None;
+        }
+        let batch = self.batches.pop_front();
+        self.wake = (!self.batches.is_empty()).then(|| now + self.interval);
+        batch
+    }
+
+    /// How many batches are held back. Their marks are pending, so on
+    /// shutdown they are left for the next start to resume rather than run
+    /// all at once.
+    pub fn len(&self) -> usize {
+        self.batches.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.batches.is_empty()
+    }
+
+    /// When `due` should next be called, if anything is held.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.wake
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracker::{ForwardLocation, OriginalMessageId};
+
+    const INTERVAL: Duration = Duration::from_secs(30);
+
+    /// A propagation marking one copy in each of `chats`.
+    fn marks(chats: &[i64]) -> Action {
+        let original = OriginalMessageId {
+            peer_id: 1,
+            message_id: 100,
+        };
+        Action::MarkForwards {
+            forwards: chats
+                .iter()
+                .map(|chat| (original.clone(), ForwardLocation::new(*chat, 5)))
+                .collect(),
+        }
+    }
+
+    fn chats(action: Option<Action>) -> Vec<i64> {
+        match action {
+            Some(Action::MarkForwards { forwards }) => {
+                forwards.iter().map(|(_, f)| f.chat_id).collect()
+            }
+            Some(_) => panic!("expected MarkForwards"),
+            None => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn propagation_at_the_limit_passes_through() {
+        let now = Instant::now();
+        let mut q = PacedQueue::new(3, INTERVAL);
+        assert_eq!(chats(q.offer(marks(&[10, 20, 30]), now)), vec![10, 20, 30]);
+        assert_eq!(q.next_deadline(), None);
+        assert!(matches!(q.offer(Action::None, now), Some(Action::None)));
+    }
+
+    #[test]
+    fn propagation_over_the_limit_runs_a_batch_per_interval() {
+        let now = Instant::now();
+        let mut q = PacedQueue::new(2, INTERVAL);
+        assert_eq!(chats(q.offer(marks(&[10, 20, 30, 40, 50]), now)), vec![10, 20]);
+        assert_eq!(q.next_deadline(), Some(now + INTERVAL));
+
+        assert!(q.due(now + Duration::from_secs(29)).is_none());
+        assert_eq!(chats(q.due(now + INTERVAL)), vec![30, 40]);
+        assert_eq!(q.next_deadline(), Some(now + INTERVAL * 2));
+        assert_eq!(chats(q.due(now + INTERVAL * 2)), vec![50]);
+        assert_eq!(q.next_deadline(), None);
+    }
+
+    #[test]
+    fn held_batches_are_counted_until_due() {
+        let now = Instant::now();
+        let mut q = PacedQueue::new(1, INTERVAL);
+        assert!(q.is_empty());
+        q.offer(marks(&[10, 20, 30]), now);
+
+        assert_eq!(q.len(), 2);
+        q.due(now + INTERVAL);
+        assert_eq!(q.len(), 1);
+        q.due(now + INTERVAL * 2);
+        assert!(q.is_empty());
+    }
+}
@@ -0,0 +1,257 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use thiserror::Error;
+use tokio::sync::Notify;
+
+/// What to do with a new item when the queue is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FullPolicy {
+    /// Wait for the consumer to make room, pausing intake meanwhile.
+    #[default]
+    Block,
+    /// Drop the oldest queued item to make room, so intake never waits.
+    DropOldest,
+}
+
+#[derive(Debug, Error)]
+#[error("expected one of block, drop-oldest")]
+pub struct ParseFullPolicyError;
+
+impl FromStr for FullPolicy {
+    type Err = ParseFullPolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "block" => Ok(FullPolicy::Block),
+            "drop-oldest" => Ok(FullPolicy::DropOldest),
+            _ => Err(ParseFullPolicyError),
+        }
+    }
+}
+
+impl fmt::Display for FullPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FullPolicy::Block => "block",
+            FullPolicy::DropOldest => "drop-oldest",
+        })
+    }
+}
+
+/// A bounded queue between the update loop and the executor task, so a
+/// slow mark-read doesn't stop updates from being read off the stream.
+/// Meant for one producer and one consumer.
+pub struct BoundedQueue<T> {
+    state: Mutex<State<T>>,
+    capacity: usize,
+    policy: FullPolicy,
+    /// Which items `DropOldest` may drop.
+    evictable: fn(&T) -> bool,
+    /// Woken when items arrive or the queue is closed.
+    filled: Notify,
+    /// Woken when room frees up or the queue is closed.
+    emptied: Notify,
+}
+
+struct State<T> {
+    items: VecDeque<T>,
+    closed: bool,
+}
+
+impl<T> BoundedQueue<T> {
+    pub fn new(capacity: usize, policy: FullPolicy) -> Self {
+        BoundedQueue {
+            state: Mutex::new(State {
+                items: VecDeque::new(),
+                closed: false,
+            }),
+            capacity: capacity.max(1),
+            policy,
+            evictable: |_| true,
+            filled: Notify::new(),
+            emptied: Notify::new(),
+        }
+    }
+
+    /// Let `DropOldest` drop only items `evictable` accepts. While none of
+    /// the queued items is, a push waits for room as under `Block`.
+    pub fn evicting(mut self, evictable: fn(&T) -> bool) -> Self {
+        self.evictable = evictable;
+        self
+    }
+
+    /// Add an item. When full this waits for room under `Block`, or drops
+    /// the oldest evictable item under `DropOldest`. Returns whatever
+    /// didn't make it in: the dropped item, or `item` itself if the queue is
+    /// closed.
+    pub async fn push(&self, item: T) -> Option<T> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.closed {
+                    return Some(item);
+                }
+                let room = if state.items.len() < self.capacity {
+                    Some(None)
+                } else if self.policy == FullPolicy::DropOldest {
+                    let oldest = state.items.iter().position(|queued| (self.evictable)(queued));
+                    oldest.map(|i| state.items.remove(i))
+                } else {
+                    None
+                };
+                if let Some(dropped) = room {
+                    state.items.push_back(item);
+                    drop(state);
+                    self.filled.notify_one();
+                    return dropped;
+                }
+            }
+            self.emptied.notified().await;
+        }
+    }
+
+    /// Wait for items and take all of them, oldest first. Returns None once
+    /// the queue is closed and everything has been taken.
+    pub async fn pop_all(&self) -> Option<Vec<T>> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if !state.items.is_empty() {
+                    let items = state.items.drain(..).collect();
+                    drop(state);
+                    self.emptied.notify_one();
+                    return Some(items);
+                }
+                if state.closed {
+                    return None;
+                }
+            }
+            self.filled.notified().await;
+        }
+    }
+
+    /// Stop accepting items. Those already queued are still handed out.
+    pub fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.filled.notify_one();
+        self.emptied.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::time::{sleep, Instant};
+
+    /// Collects everything with a pause after each batch, like an executor
+    /// stuck on slow mark-read requests.
+    fn slow_consumer(queue: Arc<BoundedQueue<u32>>) -> tokio::task::JoinHandle<Vec<u32>> {
+        tokio::spawn(async move {
+            let mut seen = Vec::new();
+            while let Some(items) = queue.pop_all().await {
+                seen.extend(items);
+                sleep(Duration::from_secs(1)).await;
+            }
+            seen
+        })
+    }
+
+    #[test]
+    fn parses_policies() {
+        assert_eq!("block".parse::<FullPolicy>().unwrap(), FullPolicy::Block);
+        assert_eq!(
+            "Drop-Oldest".parse::<FullPolicy>().unwrap(),
+            FullPolicy::DropOldest
+        );
+        assert!("drop-newest".parse::<FullPolicy>().is_err());
+        assert_eq!(FullPolicy::DropOldest.to_string(), "drop-oldest");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn blocking_waits_for_a_slow_consumer_and_loses_nothing() {
+        let queue = Arc::new(BoundedQueue::new(2, FullPolicy::Block));
+        let consumer = slow_consumer(Arc::clone(&queue));
+
+        let start = Instant::now();
+        for i in 0..6 {
+            assert_eq!(queue.push(i).await, None);
+        }
+        assert!(start.elapsed() >= Duration::from_secs(1), "producer had to wait");
+        queue.close();
+
+        assert_eq!(consumer.await.unwrap(), vec![0, 1, 2, 3, 4, 5]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dropping_keeps_intake_going_and_keeps_the_newest() {
+        let queue = Arc::new(BoundedQueue::new(2, FullPolicy::DropOldest));
+        // Take the first item, then stall
+        let consumer = slow_consumer(Arc::clone(&queue));
+        assert_eq!(queue.push(0).await, None);
+        tokio::task::yield_now().await;
+
+        let start = Instant::now();
+        let mut dropped = Vec::new();
+        for i in 1..6 {
+            dropped.extend(queue.push(i).await);
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO, "producer never waited");
+        assert_eq!(dropped, vec![1, 2, 3]);
+        queue.close();
+
+        assert_eq!(consumer.await.unwrap(), vec![0, 4, 5]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn dropping_spares_items_that_arent_evictable() {
+        // Odd items stand in for control replies
+        let queue = BoundedQueue::new(2, FullPolicy::DropOldest).evicting(|i| i % 2 == 0);
+        let queue = Arc::new(queue);
+        assert_eq!(queue.push(1).await, None);
+        assert_eq!(queue.push(2).await, None);
+        assert_eq!(queue.push(4).await, Some(2));
+
+        // Nothing evictable left: the push waits for the consumer
+        assert_eq!(queue.push(3).await, Some(4));
+        let producer = tokio::spawn({
+            let queue = Arc::clone(&queue);
+            async move { queue.push(5).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!producer.is_finished());
+        assert_eq!(queue.pop_all().await, Some(vec![1, 3]));
+        assert_eq!(producer.await.unwrap(), None);
+        assert_eq!(queue.pop_all().await, Some(vec![5]));
+    }
+
+    #[tokio::test]
+    async fn closing_hands_out_the_rest_then_refuses_items() {
+        let queue = BoundedQueue::new(4, FullPolicy::Block);
+        queue.push(1).await;
+        queue.push(2).await;
+        queue.close();
+
+        assert_eq!(queue.push(3).await, Some(3));
+        assert_eq!(queue.pop_all().await, Some(vec![1, 2]));
+        assert_eq!(queue.pop_all().await, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn closing_releases_a_blocked_producer() {
+        let queue = Arc::new(BoundedQueue::new(1, FullPolicy::Block));
+        queue.push(1).await;
+        let producer = tokio::spawn({
+            let queue = Arc::clone(&queue);
+            async move { queue.push(2).await }
+        });
+        tokio::task::yield_now().await;
+
+        queue.close();
+        assert_eq!(producer.await.unwrap(), Some(2));
+    }
+}
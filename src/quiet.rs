@@ -0,0 +1,253 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use chrono::Timelike;
+use tokio::time::Instant;
+
+use crate::handler::Action;
+use crate::{parse_hhmm_window, TimeParseError};
+
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+/// A daily window in local time, from `start` up to (not including) `end`,
+/// both in minutes since midnight. A window whose end is before its start
+/// wraps around midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuietHours {
+    start: u16,
+    end: u16,
+}
+
+impl QuietHours {
+    /// Whether `minute` (since local midnight) falls inside the window.
+    pub fn contains(self, minute: u16) -> bool {
+        if self.start <= self.end {
+            self.start <= minute && minute < self.end
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+
+    /// Minutes from `minute` until the window ends, 0 if outside it.
+    pub fn minutes_left(self, minute: u16) -> u16 {
+        if !self.contains(minute) {
+            return 0;
+        }
+        (self.end + MINUTES_PER_DAY - minute) % MINUTES_PER_DAY
+    }
+
+    /// How long from `minute` until the window is over, at least a minute
+    /// so a wakeup right at the end doesn't spin.
+    fn until_end(self, minute: u16) -> Duration {
+        Duration::from_secs(u64::from(self.minutes_left(minute).max(1)) * 60)
+    }
+}
+
+impl FromStr for QuietHours {
+    type Err = TimeParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = parse_hhmm_window(s)?;
+        Ok(QuietHours { start, end })
+    }
+}
+
+impl fmt::Display for QuietHours {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:02}:{:02}-{:02}:{:02}",
+            self.start / 60,
+            self.start % 60,
+            self.end / 60,
+            self.end % 60
+        )
+    }
+}
+
+/// Minutes since midnight, local time.
+pub fn local_minute() -> u16 {
+    let now = chrono::Local::now();
+    (now.hour() * 60 + now.minute()) as u16
+}
+
+/// Holds back mark-read actions during quiet hours and releases them once
+/// the window is over. Everything else, tracking included, carries on.
+pub struct QuietQueue {
+    hours: QuietHours,
+    queued: Vec<Action>,
+    /// When to check whether the window is over, while anything is queued.
+    wake: Option<Instant>,
+}
+
+impl QuietQueue {
+    pub fn new(hours: QuietHours) -> Self {
+        QuietQueue {
+            hours,
+            queued: Vec::new(),
+            wake: None,
+        }
+    }
+
+    /// Returns the action if it can run now, or keeps it until quiet hours
+    /// end. `minute` is the local time of day at `now`.
+    pub fn offer(&mut self, action: Action, minute: u16, now: Instant) -> Option<Action> {
+        if !matches!(action, Action::MarkForwards { .. }) || !self.hours.contains(minute) {
+            return Some(action);
+        }
+        self.queued.push(action);
+        if self.wake.is_none() {
+            self.wake = Some(now + self.hours.until_end(minute));
+        }
+        None
+    }
+
+    /// Switch to a different window, `minute` being the local time of day
+    /// at `now`. Held actions are released at once if it isn't quiet any
+    /// more, else once the new window is over.
+    pub fn set_hours(&mut self, hours: QuietHours, minute: u16, now: Instant) {
+        self.hours = hours;
+        if !self.queued.is_empty() {
+            let quiet = hours.contains(minute);
+            self.wake = Some(if quiet { now + hours.until_end(minute) } else { now });
+        }
+    }
+
+    /// Everything held back, e.g. once quiet hours are switched off.
+    pub fn drain(&mut self) -> Vec<Action> {
+        self.wake = None;
+        std::mem::take(&mut self.queued)
+    }
+
+    /// When `release` should next be called, if anything is waiting.
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.wake
+    }
+
+    /// Everything held back, in arrival order, if quiet hours are over at
+    /// `minute`. Otherwise (say the clock moved) checks again later.
+    pub fn release(&mut self, minute: u16, now: Instant) -> Vec<Action> {
+        if self.hours.contains(minute) {
+            self.wake = Some(now + self.hours.until_end(minute));
+            return Vec::new();
+        }
+        self.wake = None;
+        std::mem::take(&mut self.queued)
+    }
+
+    /// How many actions are waiting.
+    pub fn len(&self) -> usize {
+        self.queued.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queued.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracker::{ForwardLocation, OriginalMessageId};
+
+    fn at(h: u16, m: u16) -> u16 {
+        h * 60 + m
+    }
+
+    fn hours(s: &str) -> QuietHours {
+        s.parse().unwrap()
+    }
+
+    fn mark(chat_id: i64) -> Action {
+        let original = OriginalMessageId {
+            peer_id: 1,
+            message_id: 100,
+        };
+        Action::MarkForwards {
+            forwards: vec![(original, ForwardLocation::new(chat_id, 5))],
+        }
+    }
+
+    #[test]
+    fn same_day_window() {
+        let h = hours("13:30-15:00");
+        assert!(!h.contains(at(13, 29)));
+        assert!(h.contains(at(13, 30)));
+        assert!(h.contains(at(14, 59)));
+        assert!(!h.contains(at(15, 0)));
+        assert_eq!(h.minutes_left(at(14, 0)), 60);
+        assert_eq!(h.minutes_left(at(16, 0)), 0);
+    }
+
+    #[test]
+    fn window_wrapping_midnight() {
+        let h = hours("23:00-07:00");
+        assert!(!h.contains(at(22, 59)));
+        assert!(h.contains(at(23, 0)));
+        assert!(h.contains(at(23, 59)));
+        assert!(h.contains(at(0, 0)));
+        assert!(h.contains(at(6, 59)));
+        assert!(!h.contains(at(7, 0)));
+        assert!(!h.contains(at(12, 0)));
+        assert_eq!(h.minutes_left(at(23, 0)), 8 * 60);
+        assert_eq!(h.minutes_left(at(0, 30)), 6 * 60 + 30);
+    }
+
+    #[test]
+    fn window_ending_at_midnight() {
+        let h = hours("22:00-00:00");
+        assert!(h.contains(at(23, 59)));
+        assert!(!h.contains(at(0, 0)));
+        assert_eq!(h.minutes_left(at(23, 30)), 30);
+    }
+
+    #[test]
+    fn parses_and_displays_windows() {
+        assert_eq!(hours(" 23:00 - 7:05 ").to_string(), "23:00-07:05");
+        let bad = ["23:00", "23:00-24:00", "25:00-07:00", "23:60-07:00", "7-8", "07:00-07:00"];
+        for bad in bad {
+            assert!(bad.parse::<QuietHours>().is_err(), "{}", bad);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn marks_wait_for_quiet_hours_to_end() {
+        let mut q = QuietQueue::new(hours("23:00-07:00"));
+        let now = Instant::now();
+
+        assert!(q.offer(mark(10), at(12, 0), now).is_some(), "outside the window");
+        assert!(q.offer(mark(10), at(23, 30), now).is_none());
+        assert!(q.offer(mark(20), at(6, 0), now).is_none());
+        assert!(matches!(q.offer(Action::None, at(23, 30), now), Some(Action::None)));
+        assert_eq!(q.len(), 2);
+        // Woken when the first held mark's window is over
+        assert_eq!(q.next_deadline(), Some(now + Duration::from_secs(450 * 60)));
+
+        assert!(q.release(at(6, 59), now).is_empty(), "still quiet");
+        assert_eq!(q.next_deadline(), Some(now + Duration::from_secs(60)));
+
+        let released = q.release(at(7, 0), now);
+        assert_eq!(released.len(), 2);
+        assert!(q.is_empty());
+        assert_eq!(q.next_deadline(), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn changed_hours_move_the_wakeup() {
+        let mut q = QuietQueue::new(hours("23:00-07:00"));
+        let now = Instant::now();
+        assert!(q.offer(mark(10), at(6, 0), now).is_none());
+        assert_eq!(q.next_deadline(), Some(now + Duration::from_secs(60 * 60)));
+
+        q.set_hours(hours("05:00-09:00"), at(6, 0), now);
+        assert_eq!(q.next_deadline(), Some(now + Duration::from_secs(3 * 60 * 60)));
+        q.set_hours(hours("23:00-05:00"), at(6, 0), now);
+        assert_eq!(q.next_deadline(), Some(now), "no longer quiet");
+        assert_eq!(q.release(at(6, 0), now).len(), 1);
+
+        assert!(q.offer(mark(10), at(23, 30), now).is_none());
+        assert_eq!(q.drain().len(), 1);
+        assert_eq!(q.next_deadline(), None);
+    }
+}
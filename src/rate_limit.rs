@@ -0,0 +1,129 @@
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::time::{sleep, Instant};
+
+/// Token-bucket rate limiter shared by every request a `Marker` makes, so
+/// the account-wide request rate stays bounded no matter how reads are
+/// spread across chats. Allows bursts of up to `burst` requests, refilling
+/// at `rate_per_sec`.
+pub struct RateLimiter {
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    rate_per_sec: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate_per_sec).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// Take a token at `now`, or return how long until one is available.
+    fn try_take(&mut self, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate_per_sec))
+        }
+    }
+}
+
+impl RateLimiter {
+    /// A limiter starting with a full bucket. `rate_per_sec` must be
+    /// positive; `burst` is clamped to at least 1.
+    pub fn new(rate_per_sec: f64, burst: u32) -> Self {
+        let capacity = f64::from(burst.max(1));
+        RateLimiter {
+            bucket: Mutex::new(Bucket {
+                rate_per_sec,
+                capacity,
+                tokens: capacity,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Wait until a request may be made.
+    pub async fn acquire(&self) {
+        loop {
+            let wait = match self.bucket.lock().unwrap().try_take(Instant::now()) {
+                Ok(()) => return,
+                Err(wait) => wait,
+            };
+            sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bucket(rate_per_sec: f64, capacity: f64, now: Instant) -> Bucket {
+        Bucket {
+            rate_per_sec,
+            capacity,
+            tokens: capacity,
+            last_refill: now,
+        }
+    }
+
+    #[test]
+    fn allows_burst_then_waits() {
+        let now = Instant::now();
+        let mut b = bucket(2.0, 3.0, now);
+
+        assert!(b.try_take(now).is_ok());
+        assert!(b.try_take(now).is_ok());
+        assert!(b.try_take(now).is_ok());
+        // Bucket empty: the next token arrives after 1/rate seconds
+        assert_eq!(b.try_take(now), Err(Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn steady_state_follows_rate() {
+        let start = Instant::now();
+        let mut b = bucket(2.0, 1.0, start);
+        assert!(b.try_take(start).is_ok());
+
+        for i in 1..=5 {
+            let t = start + Duration::from_millis(500 * i);
+            assert!(b.try_take(t).is_ok(), "token {} should be available", i);
+            assert!(b.try_take(t).is_err(), "only one token per interval");
+        }
+    }
+
+    #[test]
+    fn idle_time_refills_no_more_than_capacity() {
+        let start = Instant::now();
+        let mut b = bucket(10.0, 2.0, start);
+        b.try_take(start).unwrap();
+        b.try_take(start).unwrap();
+
+        let later = start + Duration::from_secs(60);
+        assert!(b.try_take(later).is_ok());
+        assert!(b.try_take(later).is_ok());
+        assert!(b.try_take(later).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn acquire_paces_requests() {
+        let limiter = RateLimiter::new(5.0, 1);
+        let start = Instant::now();
+        for _ in 0..10 {
+            limiter.acquire().await;
+        }
+        // First is free, the other nine wait 200ms each
+        assert!(start.elapsed() >= Duration::from_millis(1800));
+        assert!(start.elapsed() < Duration::from_millis(2000));
+    }
+}
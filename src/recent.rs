@@ -0,0 +1,191 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::Mutex;
+
+use crate::marker::SkipReason;
+use crate::tracker::{epoch_secs, ForwardLocation, OriginalMessageId};
+
+/// Default number of events kept, see `TG_RECENT_EVENTS`.
+pub const DEFAULT_CAPACITY: usize = 100;
+
+/// Something the daemon saw or did, kept for debugging.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// A new copy of a post was tracked.
+    ForwardDetected {
+        original: OriginalMessageId,
+        forward: ForwardLocation,
+    },
+    /// A read in a chat made tracked posts read.
+    Read { chat_id: i64, max_id: i32 },
+    /// A copy was marked read (or the attempt failed).
+    Marked {
+        original: OriginalMessageId,
+        forward: ForwardLocation,
+        ok: bool,
+    },
+    /// Marking a copy read was left out on purpose.
+    Skipped {
+        original: OriginalMessageId,
+        forward: ForwardLocation,
+        reason: SkipReason,
+    },
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Event::ForwardDetected { original, forward } => write!(
+                f,
+                "forward ({}, {}) of ({}, {})",
+                forward.chat_id, forward.message_id, original.peer_id, original.message_id
+            ),
+            Event::Read { chat_id, max_id } => {
+                write!(f, "read chat {} up to {}", chat_id, max_id)
+            }
+            Event::Marked {
+                original,
+                forward,
+                ok,
+            } => write!(
+                f,
+                "{} ({}, {}) of ({}, {})",
+                if *ok { "marked" } else { "failed to mark" },
+                forward.chat_id,
+                forward.message_id,
+                original.peer_id,
+                original.message_id
+            ),
+            Event::Skipped {
+                original,
+                forward,
+                reason,
+            } => write!(
+                f,
+                "skipped ({}, {}) of ({}, {}): {}",
+                forward.chat_id,
+                forward.message_id,
+                original.peer_id,
+                original.message_id,
+                reason
+            ),
+        }
+    }
+}
+
+/// An event with the Unix time it happened.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecentEvent {
+    pub ts: u64,
+    pub event: Event,
+}
+
+/// Bounded ring of the most recent events, shared between the planner and
+/// the marker. In memory only; lost on restart.
+#[derive(Debug)]
+pub struct RecentEvents {
+    events: Mutex<VecDeque<RecentEvent>>,
+    capacity: usize,
+}
+
+impl RecentEvents {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(1);
+        RecentEvents {
+            events: Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+        }
+    }
+
+    pub fn record(&self, event: Event) {
+        self.record_at(epoch_secs(), event);
+    }
+
+    fn record_at(&self, ts: u64, event: Event) {
+        let mut events = self.events.lock().unwrap();
+        if events.len() == self.capacity {
+            events.pop_front();
+        }
+        events.push_back(RecentEvent { ts, event });
+    }
+
+    /// Held events, oldest first.
+    pub fn snapshot(&self) -> Vec<RecentEvent> {
+        self.events.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// The newest `limit` events as text, oldest first, with ages relative
+    /// to `now`.
+    pub fn render(&self, now: u64, limit: usize) -> String {
+        let events = self.snapshot();
+        if events.is_empty() {
+            return "No recent events".to_owned();
+        }
+        let skip = events.len().saturating_sub(limit);
+        events[skip..]
+            .iter()
+            .map(|e| format!("{}s ago: {}", now.saturating_sub(e.ts), e.event))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(chat_id: i64) -> Event {
+        Event::Read { chat_id, max_id: 1 }
+    }
+
+    #[test]
+    fn keeps_events_in_order() {
+        let recent = RecentEvents::new(3);
+        recent.record_at(1, read(10));
+        recent.record_at(2, read(20));
+
+        let held: Vec<_> = recent.snapshot().into_iter().map(|e| e.ts).collect();
+        assert_eq!(held, vec![1, 2]);
+    }
+
+    #[test]
+    fn wraps_around_dropping_oldest() {
+        let recent = RecentEvents::new(3);
+        for ts in 1..=5 {
+            recent.record_at(ts, read(ts as i64));
+        }
+
+        let held = recent.snapshot();
+        assert_eq!(held.len(), 3);
+        assert_eq!(held.iter().map(|e| e.ts).collect::<Vec<_>>(), vec![3, 4, 5]);
+        assert_eq!(held[0].event, read(3));
+    }
+
+    #[test]
+    fn render_shows_newest_with_ages() {
+        let recent = RecentEvents::new(10);
+        assert_eq!(recent.render(100, 2), "No recent events");
+
+        recent.record_at(90, read(10));
+        recent.record_at(95, read(20));
+        recent.record_at(99, Event::Marked {
+            original: OriginalMessageId { peer_id: 1, message_id: 100 },
+            forward: ForwardLocation::new(30, 7),
+            ok: false,
+        });
+
+        assert_eq!(
+            recent.render(100, 2),
+            "5s ago: read chat 20 up to 1\n1s ago: failed to mark (30, 7) of (1, 100)"
+        );
+
+        recent.record_at(100, Event::Skipped {
+            original: OriginalMessageId { peer_id: 1, message_id: 100 },
+            forward: ForwardLocation::new(40, 8),
+            reason: SkipReason::Unmarkable,
+        });
+        assert!(recent
+            .render(100, 1)
+            .ends_with("0s ago: skipped (40, 8) of (1, 100): unmarkable"));
+    }
+}
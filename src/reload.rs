@@ -0,0 +1,39 @@
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tracing::warn;
+
+/// Config reload requests from operators: SIGHUP on Unix, e.g.
+/// `kill -HUP <pid>` after editing `.env`. Elsewhere this never fires.
+pub struct ReloadSignal {
+    #[cfg(unix)]
+    signal: Option<Signal>,
+}
+
+impl ReloadSignal {
+    /// Start listening. Must be called inside the runtime. If the handler
+    /// can't be installed this logs a warning and never fires.
+    pub fn new() -> Self {
+        #[cfg(unix)]
+        {
+            let signal = signal(SignalKind::hangup())
+                .map_err(|e| warn!("Failed to install SIGHUP handler: {}", e))
+                .ok();
+            ReloadSignal { signal }
+        }
+        #[cfg(not(unix))]
+        {
+            ReloadSignal {}
+        }
+    }
+
+    /// Wait for the next request.
+    pub async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = &mut self.signal {
+            if signal.recv().await.is_some() {
+                return;
+            }
+        }
+        std::future::pending::<()>().await;
+    }
+}
@@ -0,0 +1,368 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use grammers_client::update::Update;
+use grammers_tl_types as tl;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::handler::{self, Action, IncomingMessage, PlanSettings};
+use crate::tracker::DuplicateTracker;
+
+/// One line of a recording: an update, in the form the planner reads it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum RecordedUpdate {
+    NewMessage(IncomingMessage),
+    MessageEdited {
+        chat_id: i64,
+        message_id: i32,
+        #[serde(default)]
+        text: String,
+    },
+    /// A read, as the raw TL update it arrives in. Other raw updates are
+    /// not recorded.
+    Raw {
+        #[serde(with = "tl_base64")]
+        update: tl::enums::Update,
+    },
+}
+
+impl RecordedUpdate {
+    /// The recordable form of `update`, or None for kinds the planner
+    /// ignores anyway.
+    pub fn of(update: &Update) -> Option<Self> {
+        match update {
+            Update::NewMessage(message) => {
+                Some(RecordedUpdate::NewMessage(IncomingMessage::of(message)))
+            }
+            Update::MessageEdited(message) => Some(RecordedUpdate::MessageEdited {
+                chat_id: message.peer_id().bot_api_dialog_id(),
+                message_id: message.id(),
+                text: message.text().to_owned(),
+            }),
+            Update::Raw(raw) => Self::of_raw(&raw.raw),
+            _ => None,
+        }
+    }
+
+    /// The recordable form of a raw update: only reads are kept, the rest
+    /// of the raw stream is noise for a replay.
+    fn of_raw(update: &tl::enums::Update) -> Option<Self> {
+        handler::raw_read_event(update)?;
+        Some(RecordedUpdate::Raw {
+            update: update.clone(),
+        })
+    }
+}
+
+/// Plan a recorded update the way `handler::plan_update` plans a live one.
+/// Offline there is no session, so actions never carry a peer reference.
+pub async fn plan(
+    update: &RecordedUpdate,
+    tracker: &mut DuplicateTracker,
+    settings: &mut PlanSettings,
+) -> Vec<Action> {
+    let action = match update {
+        RecordedUpdate::NewMessage(message) => {
+            let no_peer = std::future::ready(None);
+            return handler::plan_new_message(message, no_peer, tracker, settings).await;
+        }
+        RecordedUpdate::MessageEdited {
+            chat_id,
+            message_id,
+            text,
+        } => handler::plan_edit(*chat_id, *message_id, text, tracker, settings),
+        RecordedUpdate::Raw { update } => handler::plan_raw_update(update, tracker, settings),
+    };
+    vec![action]
+}
+
+/// One line per action worth showing; `CachePeer`, `NoteBadge` and `None`
+/// change nothing offline.
+pub fn describe(action: &Action) -> Vec<String> {
+    match action {
+        Action::None | Action::CachePeer { .. } | Action::NoteBadge { .. } => Vec::new(),
+        Action::MarkForwards { forwards } => forwards
+            .iter()
+            .map(|(orig, fwd)| {
+                format!(
+                    "mark chat {} message {} (copy of ({}, {}))",
+                    fwd.chat_id, fwd.message_id, orig.peer_id, orig.message_id
+                )
+            })
+            .collect(),
+        Action::MarkFolder {
+            folder_id,
+            forwards,
+            ..
+        } => vec![format!(
+            "mark duplicates in folder {} ({} candidate copies)",
+            folder_id,
+            forwards.len()
+        )],
+        Action::Reply { chat_id, text, .. } => {
+            vec![format!("reply to chat {}: {:?}", chat_id, text)]
+        }
+    }
+}
+
+/// Plan each recorded update in turn and render the actions produced,
+/// numbered by the update they came from.
+pub async fn replay_recording(
+    updates: &[RecordedUpdate],
+    tracker: &mut DuplicateTracker,
+    settings: &mut PlanSettings,
+) -> String {
+    let mut out = String::new();
+    for (i, update) in updates.iter().enumerate() {
+        for action in plan(update, tracker, settings).await {
+            for line in describe(&action) {
+                out.push_str(&format!("{}: {}\n", i + 1, line));
+            }
+        }
+    }
+    out.push_str(&format!("Replayed {} updates\n", updates.len()));
+    out
+}
+
+/// Read a recording written by `UpdateRecorder`. Blank lines are skipped.
+pub fn read_recording(path: &Path) -> Result<Vec<RecordedUpdate>> {
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open recording {}", path.display()))?;
+    let mut updates = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.with_context(|| format!("Failed to read {}", path.display()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let update = serde_json::from_str(&line).with_context(|| {
+            format!("{} line {} is not a recorded update", path.display(), i + 1)
+        })?;
+        updates.push(update);
+    }
+    Ok(updates)
+}
+
+/// Append-only JSONL recording of incoming updates (`TG_RECORD_UPDATES`),
+/// so a bug report can be replayed offline. Each line is written out as it
+/// comes, so a crash loses nothing. Recordings hold message text; share them
+/// with the same care as the state file.
+pub struct UpdateRecorder {
+    file: File,
+}
+
+impl UpdateRecorder {
+    /// Open `path` for appending, creating it if needed.
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open update recording {}", path.display()))?;
+        Ok(UpdateRecorder { file })
+    }
+
+    /// Append `update`, if it's a kind the planner looks at. Failures are
+    /// logged rather than returned, like the audit log's.
+    pub fn record(&mut self, update: &Update) {
+        if let Some(update) = RecordedUpdate::of(update) {
+            self.write(&update);
+        }
+    }
+
+    fn write(&mut self, update: &RecordedUpdate) {
+        let result = serde_json::to_vec(update)
+            .map_err(std::io::Error::from)
+            .and_then(|mut line| {
+                line.push(b'\n');
+                self.file.write_all(&line)
+            });
+        if let Err(e) = result {
+            warn!("Failed to record update: {}", e);
+        }
+    }
+}
+
+/// TL objects as base64 of their binary encoding, which keeps every field
+/// without mirroring the schema in serde.
+pub(crate) mod tl_base64 {
+    use base64::engine::general_purpose::STANDARD;
+    use base64::Engine;
+    use grammers_tl_types::{Deserializable, Serializable};
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S, T>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        T: Serializable,
+    {
+        serializer.serialize_str(&STANDARD.encode(value.to_bytes()))
+    }
+
+    pub fn deserialize<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: Deserializable,
+    {
+        let encoded = String::deserialize(deserializer)?;
+        let bytes = STANDARD.decode(encoded).map_err(D::Error::custom)?;
+        T::from_bytes(&bytes).map_err(|e| D::Error::custom(format!("bad TL object: {}", e)))
+    }
+
+    pub mod option {
+        use super::*;
+
+        pub fn serialize<S, T>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+            T: Serializable,
+        {
+            match value {
+                Some(value) => super::serialize(value, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+        where
+            D: Deserializer<'de>,
+            T: Deserializable,
+        {
+            let encoded = Option::<String>::deserialize(deserializer)?;
+            encoded
+                .map(|encoded| {
+                    let bytes = STANDARD.decode(encoded).map_err(D::Error::custom)?;
+                    T::from_bytes(&bytes)
+                        .map_err(|e| D::Error::custom(format!("bad TL object: {}", e)))
+                })
+                .transpose()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::fixtures::incoming;
+    use crate::tracker::OriginalMessageId;
+
+    fn forward_of(channel_id: i64, channel_post: i32) -> tl::enums::MessageFwdHeader {
+        tl::types::MessageFwdHeader {
+            imported: false,
+            saved_out: false,
+            from_id: Some(tl::types::PeerChannel { channel_id }.into()),
+            from_name: None,
+            date: 1_700_000_000,
+            channel_post: Some(channel_post),
+            post_author: None,
+            saved_from_peer: None,
+            saved_from_msg_id: None,
+            saved_from_id: None,
+            saved_from_name: None,
+            saved_date: None,
+            psa_type: None,
+        }
+        .into()
+    }
+
+    /// A forward of post `post` from channel 5, as message `message_id` of
+    /// `chat_id`.
+    fn copy(chat_id: i64, message_id: i32, post: i32) -> RecordedUpdate {
+        RecordedUpdate::NewMessage(IncomingMessage {
+            forward: Some(forward_of(5, post)),
+            ..incoming(chat_id, message_id)
+        })
+    }
+
+    fn channel_read(channel_id: i64, max_id: i32) -> RecordedUpdate {
+        RecordedUpdate::Raw {
+            update: tl::enums::Update::ReadChannelInbox(tl::types::UpdateReadChannelInbox {
+                folder_id: None,
+                channel_id,
+                max_id,
+                still_unread_count: 0,
+                pts: 1,
+            }),
+        }
+    }
+
+    #[test]
+    fn only_raw_reads_are_recorded() {
+        let RecordedUpdate::Raw { update: read } = channel_read(1234, 50) else {
+            panic!("expected a raw update");
+        };
+        assert!(RecordedUpdate::of_raw(&read).is_some());
+        let outbox = tl::enums::Update::ReadChannelOutbox(tl::types::UpdateReadChannelOutbox {
+            channel_id: 1234,
+            max_id: 50,
+        });
+        assert_eq!(RecordedUpdate::of_raw(&outbox), None);
+    }
+
+    /// Write `updates` as a recording and read them back.
+    fn round_trip(updates: &[RecordedUpdate]) -> Vec<RecordedUpdate> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("updates.jsonl");
+        let mut recorder = UpdateRecorder::open(&path).unwrap();
+        for update in updates {
+            recorder.write(update);
+        }
+        read_recording(&path).unwrap()
+    }
+
+    #[tokio::test]
+    async fn replaying_a_forward_and_a_read_marks_the_other_copy() {
+        let recording = round_trip(&[
+            copy(-1000000000010, 50, 7),
+            copy(-1000000000020, 60, 7),
+            copy(-1000000000020, 61, 8),
+            channel_read(10, 50),
+        ]);
+        assert_eq!(recording.len(), 4);
+
+        let mut tracker = DuplicateTracker::default();
+        let mut settings = PlanSettings::default();
+        let mut lines = Vec::new();
+        for update in &recording {
+            for action in plan(update, &mut tracker, &mut settings).await {
+                lines.extend(describe(&action));
+            }
+        }
+        assert_eq!(
+            lines,
+            vec!["mark chat -1000000000020 message 60 (copy of (-1000000000005, 7))"]
+        );
+        let post = OriginalMessageId {
+            peer_id: -1000000000005,
+            message_id: 7,
+        };
+        assert!(tracker.is_original_read(&post));
+        assert_eq!(tracker.forward_count(&post), 2);
+        let other = OriginalMessageId { message_id: 8, ..post };
+        assert!(!tracker.is_original_read(&other));
+    }
+
+    #[test]
+    fn recordings_round_trip_and_reject_garbage() {
+        let updates = vec![
+            copy(-1000000000010, 50, 7),
+            RecordedUpdate::MessageEdited {
+                chat_id: -1000000000005,
+                message_id: 7,
+                text: "fixed typo".to_owned(),
+            },
+            channel_read(10, 50),
+        ];
+        assert_eq!(round_trip(&updates), updates);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bad.jsonl");
+        std::fs::write(&path, "{\"kind\":\"raw\",\"update\":\"AAAA\"}\n").unwrap();
+        let err = read_recording(&path).unwrap_err();
+        assert!(format!("{:#}", err).contains("line 1"));
+    }
+}
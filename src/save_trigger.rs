@@ -0,0 +1,117 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::sync::Notify;
+
+/// Requests a state save after every `every` tracker changes, so a crash
+/// loses at most that many events instead of a whole save interval.
+///
+/// Requests coalesce: however many thresholds are crossed before the save
+/// task wakes up, it saves once. Any save resets the count.
+pub struct SaveTrigger {
+    /// None disables event-based saves entirely.
+    every: Option<u64>,
+    pending: AtomicU64,
+    notify: Notify,
+}
+
+impl SaveTrigger {
+    pub fn new(every: Option<u64>) -> Self {
+        SaveTrigger {
+            every: every.filter(|&n| n > 0),
+            pending: AtomicU64::new(0),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Record `n` tracker changes. Returns whether this crossed the
+    /// threshold and requested a save.
+    pub fn record(&self, n: u64) -> bool {
+        let Some(every) = self.every else {
+            return false;
+        };
+        if n == 0 {
+            return false;
+        }
+        let total = self.pending.fetch_add(n, Ordering::Relaxed) + n;
+        if total < every {
+            return false;
+        }
+        self.pending.store(0, Ordering::Relaxed);
+        // Notify holds at most one permit, which is what coalesces requests
+        self.notify.notify_one();
+        true
+    }
+
+    /// Reset the count after a save from any source (e.g. the timer).
+    pub fn reset(&self) {
+        self.pending.store(0, Ordering::Relaxed);
+    }
+
+    /// Wait until a save is requested. Never resolves when disabled.
+    pub async fn requested(&self) {
+        if self.every.is_none() {
+            std::future::pending::<()>().await;
+        }
+        self.notify.notified().await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[test]
+    fn disabled_never_requests() {
+        let t = SaveTrigger::new(None);
+        assert!(!t.record(1_000));
+        // Zero means off as well
+        let t = SaveTrigger::new(Some(0));
+        assert!(!t.record(1_000));
+    }
+
+    #[test]
+    fn requests_once_per_threshold() {
+        let t = SaveTrigger::new(Some(3));
+        assert!(!t.record(1));
+        assert!(!t.record(1));
+        assert!(t.record(1));
+        // Count starts over after a request
+        assert!(!t.record(2));
+        assert!(t.record(1));
+    }
+
+    #[test]
+    fn batch_crossing_threshold_requests() {
+        let t = SaveTrigger::new(Some(3));
+        assert!(!t.record(0));
+        assert!(t.record(5));
+        assert!(!t.record(1));
+    }
+
+    #[test]
+    fn reset_clears_pending_count() {
+        let t = SaveTrigger::new(Some(3));
+        t.record(2);
+        t.reset();
+        assert!(!t.record(2));
+        assert!(t.record(1));
+    }
+
+    #[tokio::test]
+    async fn multiple_requests_coalesce_into_one_wakeup() {
+        let t = SaveTrigger::new(Some(1));
+        assert!(t.record(1));
+        assert!(t.record(1));
+        assert!(t.record(1));
+
+        // The three requests leave a single permit behind
+        timeout(Duration::from_millis(50), t.requested())
+            .await
+            .expect("save should be requested");
+        assert!(timeout(Duration::from_millis(50), t.requested())
+            .await
+            .is_err());
+    }
+}
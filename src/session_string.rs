@@ -0,0 +1,150 @@
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+
+use anyhow::{bail, ensure, Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use grammers_session::storages::SqliteSession;
+use grammers_session::types::DcOption;
+use grammers_session::Session;
+
+/// Leading character of session strings in the current format, so the
+/// layout can change later without misreading old strings.
+const VERSION: char = '1';
+
+/// dc_id (4) + IPv4 (4) + port (2) + IPv6 (16) + port (2) + auth key (256).
+const PAYLOAD_LEN: usize = 4 + 4 + 2 + 16 + 2 + 256;
+
+/// The part of a session needed to resume it elsewhere: the home datacenter
+/// and the authorization key for it. Everything else (peer cache, update
+/// state) is rebuilt after connecting.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PortableSession {
+    pub dc_id: i32,
+    pub ipv4: SocketAddrV4,
+    pub ipv6: SocketAddrV6,
+    pub auth_key: [u8; 256],
+}
+
+/// Serialize a session as a single printable line.
+pub fn encode(session: &PortableSession) -> String {
+    let mut bytes = Vec::with_capacity(PAYLOAD_LEN);
+    bytes.extend_from_slice(&session.dc_id.to_be_bytes());
+    bytes.extend_from_slice(&session.ipv4.ip().octets());
+    bytes.extend_from_slice(&session.ipv4.port().to_be_bytes());
+    bytes.extend_from_slice(&session.ipv6.ip().octets());
+    bytes.extend_from_slice(&session.ipv6.port().to_be_bytes());
+    bytes.extend_from_slice(&session.auth_key);
+    format!("{}{}", VERSION, URL_SAFE_NO_PAD.encode(bytes))
+}
+
+/// Parse a string produced by `encode`.
+pub fn decode(s: &str) -> Result<PortableSession> {
+    let s = s.trim();
+    let Some(payload) = s.strip_prefix(VERSION) else {
+        bail!("Unsupported session string version");
+    };
+    let bytes = URL_SAFE_NO_PAD
+        .decode(payload)
+        .context("Session string is not valid base64")?;
+    ensure!(
+        bytes.len() == PAYLOAD_LEN,
+        "Session string has the wrong length ({} bytes, expected {})",
+        bytes.len(),
+        PAYLOAD_LEN
+    );
+
+    let (dc_id, rest) = bytes.split_at(4);
+    let (ipv4, rest) = rest.split_at(4);
+    let (port4, rest) = rest.split_at(2);
+    let (ipv6, rest) = rest.split_at(16);
+    let (port6, auth_key) = rest.split_at(2);
+
+    let ipv4: [u8; 4] = ipv4.try_into().unwrap();
+    let ipv6: [u8; 16] = ipv6.try_into().unwrap();
+    Ok(PortableSession {
+        dc_id: i32::from_be_bytes(dc_id.try_into().unwrap()),
+        ipv4: SocketAddrV4::new(
+            Ipv4Addr::from(ipv4),
+            u16::from_be_bytes(port4.try_into().unwrap()),
+        ),
+        ipv6: SocketAddrV6::new(
+            Ipv6Addr::from(ipv6),
+            u16::from_be_bytes(port6.try_into().unwrap()),
+            0,
+            0,
+        ),
+        auth_key: auth_key.try_into().unwrap(),
+    })
+}
+
+/// Read the home datacenter's authorization out of a session.
+pub fn export(session: &SqliteSession) -> Result<PortableSession> {
+    let dc_id = session.home_dc_id();
+    let option = session
+        .dc_option(dc_id)
+        .with_context(|| format!("Session has no options for home DC {}", dc_id))?;
+    let auth_key = option
+        .auth_key
+        .context("Session is not authorized yet; run the daemon and sign in first")?;
+    Ok(PortableSession {
+        dc_id,
+        ipv4: option.ipv4,
+        ipv6: option.ipv6,
+        auth_key,
+    })
+}
+
+/// Write an imported authorization into a (fresh) session.
+pub async fn import(session: &SqliteSession, portable: &PortableSession) {
+    session
+        .set_dc_option(&DcOption {
+            id: portable.dc_id,
+            ipv4: portable.ipv4,
+            ipv6: portable.ipv6,
+            auth_key: Some(portable.auth_key),
+        })
+        .await;
+    session.set_home_dc_id(portable.dc_id).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> PortableSession {
+        let mut auth_key = [0u8; 256];
+        for (i, b) in auth_key.iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        PortableSession {
+            dc_id: 2,
+            ipv4: "149.154.167.51:443".parse().unwrap(),
+            ipv6: "[2001:67c:4e8:f002::a]:443".parse().unwrap(),
+            auth_key,
+        }
+    }
+
+    #[test]
+    fn round_trips() {
+        let session = sample();
+        let encoded = encode(&session);
+        assert!(encoded.starts_with(VERSION));
+        assert!(!encoded.contains(['\n', '=', '+', '/']));
+        assert_eq!(decode(&encoded).unwrap(), session);
+    }
+
+    #[test]
+    fn surrounding_whitespace_is_ignored() {
+        let encoded = format!("  {}\n", encode(&sample()));
+        assert_eq!(decode(&encoded).unwrap(), sample());
+    }
+
+    #[test]
+    fn rejects_malformed_strings() {
+        let encoded = encode(&sample());
+        assert!(decode("").is_err());
+        assert!(decode(&format!("9{}", &encoded[1..])).is_err());
+        assert!(decode(&encoded[..encoded.len() - 4]).is_err());
+        assert!(decode("1not*base64").is_err());
+    }
+}
@@ -0,0 +1,105 @@
+use std::fmt::Display;
+use std::future::Future;
+use std::time::Duration;
+
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+/// Tries at reaching Telegram before startup gives up.
+const CONNECT_ATTEMPTS: u32 = 5;
+
+/// Wait before the second try, doubled after each further failure.
+const CONNECT_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Wait out `TG_STARTUP_DELAY_SECS`, if set, before connecting.
+pub async fn startup_delay(delay: Option<Duration>) {
+    if let Some(delay) = delay.filter(|d| !d.is_zero()) {
+        info!("Waiting {:?} before connecting", delay);
+        sleep(delay).await;
+    }
+}
+
+/// Run `probe` until it gets through, retrying with backoff while
+/// `is_transient` says the error is the network not being up yet, e.g. DNS
+/// still starting in a container. Other errors, and the last transient one
+/// after `CONNECT_ATTEMPTS` tries, are returned.
+pub async fn until_connected<T, E, F, Fut>(
+    mut probe: F,
+    is_transient: impl Fn(&E) -> bool,
+) -> Result<T, E>
+where
+    E: Display,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, E>>,
+{
+    let mut delay = CONNECT_RETRY_DELAY;
+    let mut attempt = 1;
+    loop {
+        match probe().await {
+            Err(e) if attempt < CONNECT_ATTEMPTS && is_transient(&e) => {
+                warn!(attempt, "Telegram not reachable yet, retrying in {:?}: {}", delay, e);
+                sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use tokio::time::Instant;
+
+    /// A probe failing with `errors` in turn, then succeeding.
+    fn probe<'a>(
+        errors: &'a [&'static str],
+        calls: &'a Cell<usize>,
+    ) -> impl FnMut() -> std::future::Ready<Result<usize, &'static str>> + 'a {
+        move || {
+            let call = calls.get();
+            calls.set(call + 1);
+            std::future::ready(errors.get(call).map_or(Ok(call), |e| Err(*e)))
+        }
+    }
+
+    fn is_network(e: &&'static str) -> bool {
+        *e == "network"
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn startup_delay_waits_as_configured() {
+        let start = Instant::now();
+        startup_delay(None).await;
+        startup_delay(Some(Duration::ZERO)).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        startup_delay(Some(Duration::from_millis(250))).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(250));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn transient_errors_are_retried_with_backoff() {
+        let calls = Cell::new(0);
+        let start = Instant::now();
+        let result = until_connected(probe(&["network", "network"], &calls), is_network).await;
+        assert_eq!(result, Ok(2));
+        assert_eq!(calls.get(), 3);
+        assert_eq!(start.elapsed(), Duration::from_secs(1 + 2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn persistent_and_other_errors_surface() {
+        let calls = Cell::new(0);
+        let errors = ["network"; CONNECT_ATTEMPTS as usize + 1];
+        let result = until_connected(probe(&errors, &calls), is_network).await;
+        assert_eq!(result, Err("network"));
+        assert_eq!(calls.get(), CONNECT_ATTEMPTS as usize);
+
+        let calls = Cell::new(0);
+        let result = until_connected(probe(&["bad api id"], &calls), is_network).await;
+        assert_eq!(result, Err("bad api id"));
+        assert_eq!(calls.get(), 1);
+    }
+}
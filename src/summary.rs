@@ -0,0 +1,181 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::Timelike;
+
+use crate::timeparse::{parse_hhmm, TimeParseError};
+
+const SECS_PER_DAY: u32 = 24 * 60 * 60;
+
+/// How many sources the summary lists.
+const TOP_SOURCES: usize = 5;
+
+/// What happened since the last summary.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DayCounters {
+    /// Copies of posts already tracked elsewhere.
+    pub duplicates: usize,
+    /// Copies planned to be marked read.
+    pub propagated: usize,
+    /// Originals dropped by cleanup.
+    pub cleaned_up: usize,
+    /// Duplicates per source peer.
+    pub sources: HashMap<i64, usize>,
+}
+
+impl DayCounters {
+    /// The summary message, with sources named by `name`. Sources with the
+    /// most duplicates come first, ties by id so the order is stable.
+    pub fn render(&self, name: impl Fn(i64) -> String) -> String {
+        let mut text = format!(
+            "Daily summary\n\
+             Duplicates detected: {}\n\
+             Reads propagated:    {}\n\
+             Cleaned up:          {}\n",
+            self.duplicates, self.propagated, self.cleaned_up
+        );
+        let mut sources: Vec<(i64, usize)> = self.sources.iter().map(|(&s, &n)| (s, n)).collect();
+        sources.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        if !sources.is_empty() {
+            text.push_str("Top sources:\n");
+        }
+        for (source, count) in sources.into_iter().take(TOP_SOURCES) {
+            text.push_str(&format!("  {}: {}\n", name(source), count));
+        }
+        text
+    }
+}
+
+/// Counters for the daily summary, shared between the planner and the
+/// save task. In memory only; a restart starts the day over.
+#[derive(Debug, Default)]
+pub struct DailyStats {
+    counters: Mutex<DayCounters>,
+}
+
+impl DailyStats {
+    /// A copy of a post already tracked elsewhere, forwarded from `source`
+    /// if known.
+    pub fn record_duplicate(&self, source: Option<i64>) {
+        let mut counters = self.counters.lock().unwrap();
+        counters.duplicates += 1;
+        if let Some(source) = source {
+            *counters.sources.entry(source).or_default() += 1;
+        }
+    }
+
+    pub fn record_propagated(&self, copies: usize) {
+        self.counters.lock().unwrap().propagated += copies;
+    }
+
+    pub fn record_cleanup(&self, removed: usize) {
+        self.counters.lock().unwrap().cleaned_up += removed;
+    }
+
+    /// Everything counted so far, starting the next day from zero.
+    pub fn take(&self) -> DayCounters {
+        std::mem::take(&mut *self.counters.lock().unwrap())
+    }
+}
+
+/// A local time of day the summary is sent at, in minutes since midnight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DailyTime(u16);
+
+impl DailyTime {
+    /// How long from `second` (since local midnight) until this time next
+    /// comes around. Right at the time, that's a whole day.
+    pub fn until_next(self, second: u32) -> Duration {
+        let target = u32::from(self.0) * 60;
+        let secs = (target + SECS_PER_DAY - second % SECS_PER_DAY) % SECS_PER_DAY;
+        Duration::from_secs(u64::from(if secs == 0 { SECS_PER_DAY } else { secs }))
+    }
+}
+
+/// Seconds since midnight, local time.
+pub fn local_second() -> u32 {
+    chrono::Local::now().num_seconds_from_midnight()
+}
+
+impl FromStr for DailyTime {
+    type Err = TimeParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        parse_hhmm(s).map(DailyTime)
+    }
+}
+
+impl fmt::Display for DailyTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.0 / 60, self.0 % 60)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_lists_counts_and_top_sources() {
+        let stats = DailyStats::default();
+        for source in [-1001, -1002, -1002, -1003, -1003, -1003] {
+            stats.record_duplicate(Some(source));
+        }
+        stats.record_duplicate(None);
+        stats.record_propagated(4);
+        stats.record_propagated(2);
+        stats.record_cleanup(3);
+
+        let text = stats.take().render(|source| format!("chan{}", -source));
+        assert_eq!(
+            text,
+            "Daily summary\n\
+             Duplicates detected: 7\n\
+             Reads propagated:    6\n\
+             Cleaned up:          3\n\
+             Top sources:\n  \
+             chan1003: 3\n  \
+             chan1002: 2\n  \
+             chan1001: 1\n"
+        );
+    }
+
+    #[test]
+    fn taking_starts_the_next_day_empty() {
+        let stats = DailyStats::default();
+        stats.record_duplicate(Some(-1001));
+        stats.take();
+
+        let counters = stats.take();
+        assert_eq!(counters, DayCounters::default());
+        assert!(!counters.render(|s| s.to_string()).contains("Top sources"));
+    }
+
+    #[test]
+    fn only_the_top_sources_are_listed() {
+        let counters = DayCounters {
+            sources: (1..=8).map(|s| (-s, s as usize)).collect(),
+            ..Default::default()
+        };
+        let text = counters.render(|s| s.to_string());
+        assert!(text.contains("  -8: 8\n"));
+        assert!(text.contains("  -4: 4\n"));
+        assert!(!text.contains("  -3: 3\n"));
+    }
+
+    #[test]
+    fn next_summary_time_wraps_to_tomorrow() {
+        let at: DailyTime = "21:30".parse().unwrap();
+        assert_eq!(at.to_string(), "21:30");
+        let nine_pm = 21 * 3600;
+        assert_eq!(at.until_next(nine_pm), Duration::from_secs(30 * 60));
+        assert_eq!(at.until_next(nine_pm + 30 * 60), Duration::from_secs(24 * 3600));
+        assert_eq!(at.until_next(22 * 3600), Duration::from_secs(23 * 3600 + 30 * 60));
+        for bad in ["24:00", "21:60", "21", "nine"] {
+            assert!(bad.parse::<DailyTime>().is_err(), "{}", bad);
+        }
+    }
+}
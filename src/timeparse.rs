@@ -0,0 +1,269 @@
+use std::time::Duration;
+
+use thiserror::Error;
+
+/// Why a duration, time of day or window didn't parse.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum TimeParseError {
+    #[error("expected a duration like 90s, 5m or 1h30m, got {0:?}")]
+    InvalidDuration(String),
+    #[error("unknown unit {unit:?} in {input:?}, expected ms, s, m, h or d")]
+    UnknownUnit { unit: String, input: String },
+    #[error("duration {0:?} is too long")]
+    Overflow(String),
+    #[error("expected a time of day like 21:30, got {0:?}")]
+    InvalidTimeOfDay(String),
+    #[error("expected a window like 23:00-07:00, got {0:?}")]
+    InvalidWindow(String),
+    #[error("window {0:?} starts and ends at the same time")]
+    EmptyWindow(String),
+}
+
+/// Parse a duration made of number-unit pairs, e.g. `500ms`, `90s`, `5m`,
+/// `1h30m` or `7d`. A bare number counts in `bare` units, so a variable
+/// that used to take plain seconds keeps accepting them.
+pub fn parse_duration_or(s: &str, bare: Duration) -> Result<Duration, TimeParseError> {
+    parse_duration_in(s, Some(bare))
+}
+
+fn parse_duration_in(s: &str, bare: Option<Duration>) -> Result<Duration, TimeParseError> {
+    let input = s.trim();
+    let invalid = || TimeParseError::InvalidDuration(input.to_owned());
+    let overflow = || TimeParseError::Overflow(input.to_owned());
+    if input.is_empty() {
+        return Err(invalid());
+    }
+    if let Some(bare) = bare.filter(|_| input.bytes().all(|b| b.is_ascii_digit())) {
+        let n: u64 = input.parse().map_err(|_| overflow())?;
+        return times(bare, n).ok_or_else(overflow);
+    }
+
+    let mut total = Duration::ZERO;
+    let mut rest = input;
+    while !rest.is_empty() {
+        let digits = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let unit_len = rest[digits..]
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len() - digits);
+        let (number, unit) = (&rest[..digits], rest[digits..digits + unit_len].trim());
+        if number.is_empty() || unit.is_empty() {
+            return Err(invalid());
+        }
+        let n: u64 = number.parse().map_err(|_| overflow())?;
+        let unit = match unit {
+            "ms" => Duration::from_millis(1),
+            "s" => Duration::from_secs(1),
+            "m" => Duration::from_secs(60),
+            "h" => Duration::from_secs(60 * 60),
+            "d" => Duration::from_secs(24 * 60 * 60),
+            _ => {
+                return Err(TimeParseError::UnknownUnit {
+                    unit: unit.to_owned(),
+                    input: input.to_owned(),
+                })
+            }
+        };
+        total = times(unit, n)
+            .and_then(|part| total.checked_add(part))
+            .ok_or_else(overflow)?;
+        rest = &rest[digits + unit_len..];
+    }
+    Ok(total)
+}
+
+/// Write a duration the way `parse_duration_or` reads it, largest unit
+/// first, e.g. `1h30m` or `1s500ms`. Anything under a millisecond is
+/// dropped, which no setting is precise to anyway.
+pub fn format_duration(d: Duration) -> String {
+    const UNITS: [(&str, u128); 5] = [
+        ("d", 24 * 60 * 60 * 1000),
+        ("h", 60 * 60 * 1000),
+        ("m", 60 * 1000),
+        ("s", 1000),
+        ("ms", 1),
+    ];
+    let mut rest = d.as_millis();
+    if rest == 0 {
+        return "0s".to_owned();
+    }
+    let mut out = String::new();
+    for (unit, millis) in UNITS {
+        let n = rest / millis;
+        if n > 0 {
+            out.push_str(&format!("{}{}", n, unit));
+            rest %= millis;
+        }
+    }
+    out
+}
+
+/// `unit * n`, or None if that doesn't fit a `Duration`.
+fn times(unit: Duration, n: u64) -> Option<Duration> {
+    let nanos = unit.as_nanos().checked_mul(u128::from(n))?;
+    let secs = u64::try_from(nanos / 1_000_000_000).ok()?;
+    Some(Duration::new(secs, (nanos % 1_000_000_000) as u32))
+}
+
+/// Parse `HH:MM` (24-hour) into minutes since midnight.
+pub fn parse_hhmm(s: &str) -> Result<u16, TimeParseError> {
+    let invalid = || TimeParseError::InvalidTimeOfDay(s.trim().to_owned());
+    let (h, m) = s.trim().split_once(':').ok_or_else(invalid)?;
+    let all_digits = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+    if !all_digits(h) || !all_digits(m) || h.len() > 2 || m.len() != 2 {
+        return Err(invalid());
+    }
+    let h: u16 = h.parse().map_err(|_| invalid())?;
+    let m: u16 = m.parse().map_err(|_| invalid())?;
+    if h >= 24 || m >= 60 {
+        return Err(invalid());
+    }
+    Ok(h * 60 + m)
+}
+
+/// Parse a daily window `HH:MM-HH:MM` into its start and end in minutes
+/// since midnight. The end may be before the start for windows spanning
+/// midnight; an empty window is rejected as almost certainly a typo.
+pub fn parse_hhmm_window(s: &str) -> Result<(u16, u16), TimeParseError> {
+    let input = s.trim();
+    let (start, end) = input
+        .split_once('-')
+        .ok_or_else(|| TimeParseError::InvalidWindow(input.to_owned()))?;
+    let (start, end) = (parse_hhmm(start)?, parse_hhmm(end)?);
+    if start == end {
+        return Err(TimeParseError::EmptyWindow(input.to_owned()));
+    }
+    Ok((start, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every number needs a unit.
+    fn parse_duration(s: &str) -> Result<Duration, TimeParseError> {
+        parse_duration_in(s, None)
+    }
+
+    #[test]
+    fn formatted_durations_parse_back() {
+        let millis = Duration::from_millis;
+        for (d, text) in [
+            (Duration::ZERO, "0s"),
+            (millis(1500), "1s500ms"),
+            (millis(250), "250ms"),
+            (Duration::from_secs(90), "1m30s"),
+            (Duration::from_secs(5400), "1h30m"),
+            (Duration::from_secs(7 * 86_400 + 1), "7d1s"),
+        ] {
+            assert_eq!(format_duration(d), text);
+            assert_eq!(parse_duration(text), Ok(d));
+        }
+        assert_eq!(format_duration(Duration::from_nanos(1_500_000)), "1ms");
+    }
+
+    #[test]
+    fn durations_with_units() {
+        let secs = Duration::from_secs;
+        assert_eq!(parse_duration("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_duration("90s"), Ok(secs(90)));
+        assert_eq!(parse_duration("5m"), Ok(secs(300)));
+        assert_eq!(parse_duration("2h"), Ok(secs(7200)));
+        assert_eq!(parse_duration("7d"), Ok(secs(7 * 86_400)));
+        assert_eq!(parse_duration("1h30m"), Ok(secs(5400)));
+        assert_eq!(parse_duration(" 1m 30s "), Ok(secs(90)));
+        assert_eq!(parse_duration("1s500ms"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("0s"), Ok(Duration::ZERO));
+    }
+
+    #[test]
+    fn bare_numbers_need_a_default_unit() {
+        assert_eq!(
+            parse_duration("30"),
+            Err(TimeParseError::InvalidDuration("30".to_owned()))
+        );
+        assert_eq!(
+            parse_duration_or("30", Duration::from_secs(1)),
+            Ok(Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_duration_or("750", Duration::from_millis(1)),
+            Ok(Duration::from_millis(750))
+        );
+        assert_eq!(
+            parse_duration_or("5000000000", Duration::from_millis(1)),
+            Ok(Duration::from_secs(5_000_000))
+        );
+        // Units still win over the default
+        assert_eq!(
+            parse_duration_or("2h", Duration::from_millis(1)),
+            Ok(Duration::from_secs(7200))
+        );
+    }
+
+    #[test]
+    fn malformed_durations() {
+        for bad in ["", "  ", "m", "5 minutes", "1.5h", "-5m", "h5", "5m3"] {
+            assert!(parse_duration(bad).is_err(), "{:?}", bad);
+        }
+        assert_eq!(
+            parse_duration("5w"),
+            Err(TimeParseError::UnknownUnit {
+                unit: "w".to_owned(),
+                input: "5w".to_owned()
+            })
+        );
+        assert_eq!(
+            parse_duration("99999999999999999999s"),
+            Err(TimeParseError::Overflow("99999999999999999999s".to_owned()))
+        );
+        assert!(matches!(
+            parse_duration("18446744073709551615d"),
+            Err(TimeParseError::Overflow(_))
+        ));
+        assert!(matches!(
+            parse_duration_or("4294967295", Duration::from_secs(u64::MAX / 2)),
+            Err(TimeParseError::Overflow(_))
+        ));
+    }
+
+    #[test]
+    fn times_of_day() {
+        assert_eq!(parse_hhmm("00:00"), Ok(0));
+        assert_eq!(parse_hhmm("7:05"), Ok(7 * 60 + 5));
+        assert_eq!(parse_hhmm(" 23:59 "), Ok(23 * 60 + 59));
+        for bad in ["24:00", "12:60", "12", "12:5", "012:00", ":30", "12:", "+1:30", "nine"] {
+            assert_eq!(
+                parse_hhmm(bad),
+                Err(TimeParseError::InvalidTimeOfDay(bad.trim().to_owned())),
+                "{:?}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn windows() {
+        assert_eq!(parse_hhmm_window("23:00-07:00"), Ok((23 * 60, 7 * 60)));
+        assert_eq!(parse_hhmm_window("09:30 - 17:00"), Ok((9 * 60 + 30, 17 * 60)));
+        assert_eq!(
+            parse_hhmm_window("23:00"),
+            Err(TimeParseError::InvalidWindow("23:00".to_owned()))
+        );
+        assert_eq!(
+            parse_hhmm_window("08:00-08:00"),
+            Err(TimeParseError::EmptyWindow("08:00-08:00".to_owned()))
+        );
+        assert_eq!(
+            parse_hhmm_window("23:00-25:00"),
+            Err(TimeParseError::InvalidTimeOfDay("25:00".to_owned()))
+        );
+    }
+
+    #[test]
+    fn errors_name_the_input() {
+        let err = parse_duration("5w").unwrap_err().to_string();
+        assert_eq!(err, "unknown unit \"w\" in \"5w\", expected ms, s, m, h or d");
+        let err = parse_hhmm_window("8-9").unwrap_err().to_string();
+        assert_eq!(err, "expected a time of day like 21:30, got \"8\"");
+    }
+}
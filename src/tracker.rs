@@ -1,9 +1,129 @@
-use anyhow::{Context, Result};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use std::collections::{HashMap, HashSet};
-use std::hash::Hash;
-use std::path::Path;
-use tracing::info;
+use std::hash::{Hash, Hasher};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tracing::{debug, info, warn};
+
+use crate::clock::{Clock, SharedClock};
+use crate::identity::MediaKey;
+use crate::latency::{LatencyHistogram, LatencySummary};
+
+/// Why loading or saving the state file failed.
+#[derive(Debug, Error)]
+pub enum TrackerError {
+    #[error("Failed to {action} state file {}: {source}", .path.display())]
+    Io {
+        action: &'static str,
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Failed to parse state file {}: {source}", .path.display())]
+    Parse {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+    #[error("Failed to serialize state: {0}")]
+    Serialize(#[source] serde_json::Error),
+    #[error("Failed to decode state file {}: {source}", .path.display())]
+    Decode {
+        path: PathBuf,
+        source: bincode::Error,
+    },
+    #[error("Failed to encode state: {0}")]
+    Encode(#[source] bincode::Error),
+    #[error("State file {} is corrupt: {reason}", .path.display())]
+    Corrupt { path: PathBuf, reason: String },
+}
+
+impl TrackerError {
+    /// Whether the file was there but its contents are damaged, as opposed
+    /// to unreadable.
+    pub fn is_corrupt(&self) -> bool {
+        matches!(
+            self,
+            TrackerError::Parse { .. } | TrackerError::Decode { .. } | TrackerError::Corrupt { .. }
+        )
+    }
+
+    fn io(action: &'static str, path: &Path) -> impl FnOnce(std::io::Error) -> Self {
+        let path = path.to_owned();
+        move |source| TrackerError::Io {
+            action,
+            path,
+            source,
+        }
+    }
+}
+
+/// Default age after which entries are cleaned up: 30 days in seconds.
+pub const CLEANUP_MAX_AGE: u64 = 30 * 24 * 60 * 60;
+
+/// Saves taking longer than this log a warning, since the tracker lock is
+/// held throughout.
+const SLOW_SAVE: Duration = Duration::from_secs(1);
+
+/// Start of a bincode state file written without a checksum. JSON can't
+/// start with these bytes, which is how `load` tells the formats apart.
+/// Unchecked files come in more than one layout, see `decode_unchecked`.
+const BINCODE_MAGIC: &[u8] = b"TGDUP\0\x01";
+
+/// Start of a bincode state file written with a checksum: the CRC32 of
+/// the encoded state follows, little-endian, then the state. bincode has no
+/// field names, so a file only decodes into the fields it was written
+/// with: changing the persisted fields needs a new magic, with the old one
+/// still decoding into the old layout.
+const CHECKED_BINCODE_MAGIC: &[u8] = b"TGDUP\0\x03";
+
+/// Start of a checked bincode state file written before the marked copies
+/// were saved, see `decode_before_marked`.
+const CHECKED_BINCODE_MAGIC_BEFORE_MARKED: &[u8] = b"TGDUP\0\x02";
+
+/// Start of a JSON state file written with a checksum, which is its first
+/// key: the CRC32 in hex of the file with that key left out. Being a
+/// plain key keeps the file valid JSON for other tools.
+const JSON_CHECKSUM_KEY: &[u8] = b"{\n  \"checksum\": \"";
+
+/// How the state file is written. Loading accepts either.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StateFormat {
+    /// Pretty-printed JSON, easy to inspect and edit.
+    #[default]
+    Json,
+    /// bincode behind a magic prefix, smaller and faster for big states.
+    Bincode,
+}
+
+#[derive(Debug, Error)]
+#[error("expected one of json, bincode")]
+pub struct ParseStateFormatError;
+
+impl FromStr for StateFormat {
+    type Err = ParseStateFormatError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "json" => Ok(StateFormat::Json),
+            "bincode" => Ok(StateFormat::Bincode),
+            _ => Err(ParseStateFormatError),
+        }
+    }
+}
+
+impl fmt::Display for StateFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StateFormat::Json => "json",
+            StateFormat::Bincode => "bincode",
+        })
+    }
+}
 
 #[derive(Debug, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
 pub struct OriginalMessageId {
@@ -11,10 +131,86 @@ pub struct OriginalMessageId {
     pub message_id: i32,
 }
 
-#[derive(Debug, Clone, Hash, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
 pub struct ForwardLocation {
     pub chat_id: i64,
     pub message_id: i32,
+    /// Discussion thread the message lives in, if any. When set, reads are
+    /// propagated to that thread only rather than the whole chat. Absent in
+    /// state files written before this was tracked.
+    #[serde(default)]
+    pub top_msg_id: Option<i32>,
+}
+
+// Like a derived impl with `skip_serializing_if = "Option::is_none"` on
+// top_msg_id, except for binary formats: those have no field names to
+// notice a missing field by, so they always get all three.
+impl Serialize for ForwardLocation {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let skip_thread = serializer.is_human_readable() && self.top_msg_id.is_none();
+        let mut s = serializer.serialize_struct("ForwardLocation", 3 - usize::from(skip_thread))?;
+        s.serialize_field("chat_id", &self.chat_id)?;
+        s.serialize_field("message_id", &self.message_id)?;
+        if skip_thread {
+            s.skip_field("top_msg_id")?;
+        } else {
+            s.serialize_field("top_msg_id", &self.top_msg_id)?;
+        }
+        s.end()
+    }
+}
+
+impl ForwardLocation {
+    /// A location outside any discussion thread.
+    pub fn new(chat_id: i64, message_id: i32) -> Self {
+        ForwardLocation {
+            chat_id,
+            message_id,
+            top_msg_id: None,
+        }
+    }
+}
+
+// A message belongs to exactly one thread, so (chat_id, message_id) alone
+// identifies a location. Keeping top_msg_id out of equality and hashing
+// lets lookups work without knowing the thread.
+impl PartialEq for ForwardLocation {
+    fn eq(&self, other: &Self) -> bool {
+        self.chat_id == other.chat_id && self.message_id == other.message_id
+    }
+}
+
+impl Eq for ForwardLocation {}
+
+impl Hash for ForwardLocation {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.chat_id.hash(state);
+        self.message_id.hash(state);
+    }
+}
+
+/// Saves what an `Arc` points to as if it were held directly, for state
+/// shared with other components but persisted by the tracker.
+mod shared {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::sync::Arc;
+
+    pub fn serialize<S, T>(value: &Arc<T>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+        T: Serialize,
+    {
+        (**value).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D, T>(deserializer: D) -> Result<Arc<T>, D::Error>
+    where
+        D: Deserializer<'de>,
+        T: Deserialize<'de>,
+    {
+        T::deserialize(deserializer).map(Arc::new)
+    }
 }
 
 /// serde_json can't use structs as map keys (JSON keys must be strings).
@@ -43,6 +239,434 @@ mod map_as_vec {
     }
 }
 
+/// The persisted fields bincode state files started out with. Unchecked
+/// files written before the chats refusing reads were saved end here.
+#[derive(Deserialize)]
+struct LegacyState {
+    #[serde(with = "map_as_vec")]
+    originals: HashMap<OriginalMessageId, Vec<ForwardLocation>>,
+    #[serde(with = "map_as_vec")]
+    forward_index: HashMap<ForwardLocation, OriginalMessageId>,
+    read_originals: HashSet<OriginalMessageId>,
+    #[serde(with = "map_as_vec")]
+    read_at: HashMap<OriginalMessageId, u64>,
+    #[serde(with = "map_as_vec")]
+    first_seen: HashMap<OriginalMessageId, u64>,
+    #[serde(with = "map_as_vec")]
+    previews: HashMap<OriginalMessageId, String>,
+}
+
+impl LegacyState {
+    fn into_tracker(self, unmarkable: UnmarkableChats, pending: PendingMarks) -> DuplicateTracker {
+        DuplicateTracker {
+            originals: self.originals,
+            forward_index: self.forward_index,
+            read_originals: self.read_originals,
+            read_at: self.read_at,
+            first_seen: self.first_seen,
+            previews: self.previews,
+            unmarkable: Arc::new(unmarkable),
+            pending: Arc::new(pending),
+            marked: unrecorded_marks(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Decode the state after `BINCODE_MAGIC`. That magic was kept when the
+/// chats refusing reads and then the pending marks were added to the
+/// state, so the file may be in any of three layouts; unchecked files were
+/// no longer written by the time the marked copies were added. Fields were
+/// only ever added at the end, so an older file runs out of bytes before a
+/// newer layout is filled: trying the newest layout first can't mistake
+/// one for the other. Fails with the error of the newest layout.
+fn decode_unchecked(encoded: &[u8]) -> bincode::Result<DuplicateTracker> {
+    decode_before_marked(encoded).or_else(|err| {
+        // bincode writes a struct as a tuple of its fields
+        bincode::deserialize::<(LegacyState, UnmarkableChats)>(encoded)
+            .map(|(state, unmarkable)| state.into_tracker(unmarkable, PendingMarks::default()))
+            .or_else(|_| {
+                bincode::deserialize::<LegacyState>(encoded).map(|state| {
+                    state.into_tracker(UnmarkableChats::default(), PendingMarks::default())
+                })
+            })
+            .map_err(|_| err)
+    })
+}
+
+/// Decode a state in the layout from before the marked copies were saved,
+/// which ends with the pending marks.
+fn decode_before_marked(encoded: &[u8]) -> bincode::Result<DuplicateTracker> {
+    bincode::deserialize::<(LegacyState, UnmarkableChats, PendingMarks)>(encoded)
+        .map(|(state, unmarkable, pending)| state.into_tracker(unmarkable, pending))
+}
+
+/// Summary counts over the tracker's state.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TrackerStats {
+    pub originals: usize,
+    pub forwards: usize,
+    pub read_originals: usize,
+    /// Distinct chats holding at least one tracked forward.
+    pub chats: usize,
+    /// Distinct source peers of tracked originals.
+    pub sources: usize,
+    /// Percentiles of recent propagations, timed from when they were planned.
+    pub latency: Option<LatencySummary>,
+}
+
+impl fmt::Display for TrackerStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Originals:      {}", self.originals)?;
+        writeln!(f, "Forwards:       {}", self.forwards)?;
+        writeln!(f, "Read originals: {}", self.read_originals)?;
+        writeln!(f, "Chats:          {}", self.chats)?;
+        writeln!(f, "Sources:        {}", self.sources)?;
+        if let Some(latency) = &self.latency {
+            writeln!(f, "Latency:        {}", latency)?;
+        }
+        Ok(())
+    }
+}
+
+/// Chats that refused a read for lack of permission, such as broadcast
+/// channels that won't let a member move the read cursor. The marker adds
+/// to it and skips these chats; the tracker saves it with the state.
+#[derive(Debug, Default)]
+pub struct UnmarkableChats(Mutex<HashSet<i64>>);
+
+impl UnmarkableChats {
+    pub fn contains(&self, chat_id: i64) -> bool {
+        self.0.lock().unwrap().contains(&chat_id)
+    }
+
+    /// Flag `chat_id`. Returns whether it wasn't flagged already.
+    pub fn insert(&self, chat_id: i64) -> bool {
+        self.0.lock().unwrap().insert(chat_id)
+    }
+
+    pub fn remove(&self, chat_id: i64) -> bool {
+        self.0.lock().unwrap().remove(&chat_id)
+    }
+
+    /// Drop every flag, returning how many there were.
+    pub fn clear(&self) -> usize {
+        let mut chats = self.0.lock().unwrap();
+        let count = chats.len();
+        chats.clear();
+        count
+    }
+
+    /// Flagged chats, sorted.
+    pub fn chats(&self) -> Vec<i64> {
+        let mut chats: Vec<i64> = self.0.lock().unwrap().iter().copied().collect();
+        chats.sort_unstable();
+        chats
+    }
+}
+
+impl Serialize for UnmarkableChats {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // Sorted, so equal states save equal bytes
+        self.chats().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for UnmarkableChats {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let chats = Vec::<i64>::deserialize(deserializer)?;
+        Ok(UnmarkableChats(Mutex::new(chats.into_iter().collect())))
+    }
+}
+
+/// Marks planned but not yet confirmed done, so a propagation cut short
+/// by a crash or a fatal error is finished on the next start. The planner
+/// adds to it, the marker confirms each propagation that ran, and the
+/// tracker saves it with the state.
+#[derive(Debug, Default)]
+pub struct PendingMarks(Mutex<HashMap<ForwardLocation, PendingMark>>);
+
+#[derive(Debug)]
+struct PendingMark {
+    original: OriginalMessageId,
+    /// When the mark was planned. Not persisted, so marks resumed after a
+    /// restart have none.
+    planned: Option<Instant>,
+}
+
+impl PendingMarks {
+    pub fn add(&self, forwards: &[(OriginalMessageId, ForwardLocation)]) {
+        self.insert(forwards, Some(Instant::now()));
+    }
+
+    /// Planning a mark again keeps when it was first planned.
+    fn insert(
+        &self,
+        forwards: &[(OriginalMessageId, ForwardLocation)],
+        planned: Option<Instant>,
+    ) {
+        let mut pending = self.0.lock().unwrap();
+        for (original, fwd) in forwards {
+            pending
+                .entry(fwd.clone())
+                .and_modify(|mark| mark.original = original.clone())
+                .or_insert_with(|| PendingMark {
+                    original: original.clone(),
+                    planned,
+                });
+        }
+    }
+
+    /// Drop `forwards`, now that they were marked.
+    pub fn confirm(&self, forwards: &[(OriginalMessageId, ForwardLocation)]) {
+        let mut pending = self.0.lock().unwrap();
+        for (_, fwd) in forwards {
+            pending.remove(fwd);
+        }
+    }
+
+    /// When the earliest of `forwards` still pending was planned, if
+    /// this run planned any of them.
+    pub fn planned_at(
+        &self,
+        forwards: &[(OriginalMessageId, ForwardLocation)],
+    ) -> Option<Instant> {
+        let pending = self.0.lock().unwrap();
+        forwards
+            .iter()
+            .filter_map(|(_, fwd)| pending.get(fwd)?.planned)
+            .min()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether a mark of `forward` is planned but not confirmed.
+    pub fn contains(&self, forward: &ForwardLocation) -> bool {
+        self.0.lock().unwrap().contains_key(forward)
+    }
+
+    /// Pending marks, sorted by chat and message.
+    pub fn forwards(&self) -> Vec<(OriginalMessageId, ForwardLocation)> {
+        let mut forwards: Vec<_> = self
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(fwd, mark)| (mark.original.clone(), fwd.clone()))
+            .collect();
+        forwards.sort_by_key(|(_, fwd)| (fwd.chat_id, fwd.message_id));
+        forwards
+    }
+
+    fn retain(&self, mut keep: impl FnMut(&OriginalMessageId, &ForwardLocation) -> bool) {
+        self.0.lock().unwrap().retain(|fwd, mark| keep(&mark.original, fwd));
+    }
+}
+
+impl Serialize for PendingMarks {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.forwards().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for PendingMarks {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let forwards = Vec::<(OriginalMessageId, ForwardLocation)>::deserialize(deserializer)?;
+        let pending = PendingMarks::default();
+        pending.insert(&forwards, None);
+        Ok(pending)
+    }
+}
+
+/// Copies known to be read: marked by a propagation that ran, or read by
+/// the user in their own chat. The planner adds the copies the user read,
+/// the marker those it marked, and the tracker saves them with the state
+/// and drops those of copies it forgets.
+#[derive(Debug, Default)]
+pub struct MarkedCopies {
+    copies: Mutex<HashSet<ForwardLocation>>,
+    /// Loaded from a state saved before marked copies were, which doesn't
+    /// say. See `DuplicateTracker::assume_read_copies_marked`.
+    unrecorded: bool,
+}
+
+impl MarkedCopies {
+    pub fn record(&self, forwards: &[(OriginalMessageId, ForwardLocation)]) {
+        let mut copies = self.copies.lock().unwrap();
+        for (_, fwd) in forwards {
+            copies.insert(fwd.clone());
+        }
+    }
+
+    pub fn contains(&self, forward: &ForwardLocation) -> bool {
+        self.copies.lock().unwrap().contains(forward)
+    }
+
+    pub fn len(&self) -> usize {
+        self.copies.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn remove(&self, forward: &ForwardLocation) {
+        self.copies.lock().unwrap().remove(forward);
+    }
+
+    /// Marked copies, sorted by chat and message.
+    fn forwards(&self) -> Vec<ForwardLocation> {
+        let mut copies: Vec<ForwardLocation> =
+            self.copies.lock().unwrap().iter().cloned().collect();
+        copies.sort_by_key(|fwd| (fwd.chat_id, fwd.message_id));
+        copies
+    }
+}
+
+impl Serialize for MarkedCopies {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.forwards().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for MarkedCopies {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let copies = Vec::<ForwardLocation>::deserialize(deserializer)?;
+        Ok(MarkedCopies {
+            copies: Mutex::new(copies.into_iter().collect()),
+            unrecorded: false,
+        })
+    }
+}
+
+/// What a state saved before marked copies were starts out with.
+fn unrecorded_marks() -> Arc<MarkedCopies> {
+    Arc::new(MarkedCopies {
+        unrecorded: true,
+        ..Default::default()
+    })
+}
+
+/// The key totals of `TrackerStats`, kept in atomics next to the maps so
+/// they can be read without the lock the tracker is behind. Updated at
+/// every mutation from the maps themselves, so they never drift from
+/// `stats()`, which stays the way to get the full picture.
+#[derive(Debug, Default)]
+pub struct LiveCounts {
+    originals: AtomicU64,
+    forwards: AtomicU64,
+    read_originals: AtomicU64,
+    chats: AtomicU64,
+    sources: AtomicU64,
+}
+
+impl LiveCounts {
+    pub fn originals(&self) -> u64 {
+        self.originals.load(Ordering::Relaxed)
+    }
+
+    pub fn forwards(&self) -> u64 {
+        self.forwards.load(Ordering::Relaxed)
+    }
+
+    pub fn read_originals(&self) -> u64 {
+        self.read_originals.load(Ordering::Relaxed)
+    }
+
+    pub fn chats(&self) -> u64 {
+        self.chats.load(Ordering::Relaxed)
+    }
+
+    pub fn sources(&self) -> u64 {
+        self.sources.load(Ordering::Relaxed)
+    }
+}
+
+/// What `/dupstats` reports, readable without the tracker lock: the live
+/// counts, plus the save metrics and latency histogram the tracker shares
+/// behind locks of their own. See `DuplicateTracker::live_stats`.
+#[derive(Debug, Clone)]
+pub struct LiveStats {
+    counts: Arc<LiveCounts>,
+    last_save: Arc<Mutex<Option<SaveMetrics>>>,
+    latency: Arc<Mutex<LatencyHistogram>>,
+}
+
+impl LiveStats {
+    /// `DuplicateTracker::stats` as of the tracker's last change.
+    pub fn stats(&self) -> TrackerStats {
+        let c = &self.counts;
+        TrackerStats {
+            originals: c.originals() as usize,
+            forwards: c.forwards() as usize,
+            read_originals: c.read_originals() as usize,
+            chats: c.chats() as usize,
+            sources: c.sources() as usize,
+            latency: self.latency.lock().unwrap().summary(),
+        }
+    }
+
+    /// Size and duration of the last successful save, if any.
+    pub fn last_save(&self) -> Option<SaveMetrics> {
+        *self.last_save.lock().unwrap()
+    }
+}
+
+/// Where one copy of a post stands, as far as the tracker knows, worked out
+/// from the post's read state, the pending marks, the chats refusing reads
+/// and the marked copies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ForwardStatus {
+    /// The post hasn't been read anywhere yet.
+    Unread,
+    /// The post was read and marking this copy is planned but not done.
+    Pending,
+    /// The post was read and this copy marked, or read by the user.
+    Read,
+    /// The post was read, but this chat refuses reads.
+    Unmarkable,
+    /// The post was read, but this copy was left alone: its chat is never
+    /// marked, or it turned up later and was kept unread.
+    Unmarked,
+}
+
+/// One tracked copy of a post, see `DuplicateTracker::forward_details`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForwardDetail {
+    pub chat_id: i64,
+    pub message_id: i32,
+    pub top_msg_id: Option<i32>,
+    pub status: ForwardStatus,
+}
+
+impl ForwardDetail {
+    /// Whether this copy has been marked read.
+    pub fn is_read(&self) -> bool {
+        self.status == ForwardStatus::Read
+    }
+}
+
+/// Size and duration of a state file save.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SaveMetrics {
+    /// Serialized state size.
+    pub bytes: u64,
+    /// Wall-clock time from serializing to the synced rename.
+    pub duration: Duration,
+}
+
+impl fmt::Display for SaveMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} bytes in {:?}", self.bytes, self.duration)
+    }
+}
+
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct DuplicateTracker {
     /// original -> all known forwards
@@ -53,62 +677,433 @@ pub struct DuplicateTracker {
     forward_index: HashMap<ForwardLocation, OriginalMessageId>,
     /// originals the user has read
     read_originals: HashSet<OriginalMessageId>,
+    /// timestamp (seconds since epoch) when each read original was read
+    #[serde(default, with = "map_as_vec")]
+    read_at: HashMap<OriginalMessageId, u64>,
     /// timestamp (seconds since epoch) when each original was first seen
     #[serde(default, with = "map_as_vec")]
     first_seen: HashMap<OriginalMessageId, u64>,
+    /// Short text preview of each original, kept current when the source
+    /// channel edits the post.
+    #[serde(default, with = "map_as_vec")]
+    previews: HashMap<OriginalMessageId, String>,
+    /// Chats the marker found it may not mark read. Shared with the marker,
+    /// see `unmarkable_chats`.
+    #[serde(default, with = "shared")]
+    unmarkable: Arc<UnmarkableChats>,
+    /// Marks planned but not confirmed yet. Shared with the marker, see
+    /// `pending_marks`.
+    #[serde(default, with = "shared")]
+    pending: Arc<PendingMarks>,
+    /// Copies known to be read. Shared with the marker, see
+    /// `marked_copies`.
+    #[serde(default = "unrecorded_marks", with = "shared")]
+    marked: Arc<MarkedCopies>,
     /// chat_id -> set of (message_id, original) for O(1) read-event lookups.
     /// Rebuilt from forward_index on load, so not critical to persist.
     #[serde(skip)]
     chat_index: HashMap<i64, Vec<(i32, OriginalMessageId)>>,
+    /// source peer_id -> originals from that source, for per-channel queries.
+    /// Rebuilt from originals on load, like chat_index.
+    #[serde(skip)]
+    source_index: HashMap<i64, Vec<OriginalMessageId>>,
+    /// media file -> copies carrying it, so a repost of the file joins the
+    /// original its earlier copies are tracked under. Not persisted: after
+    /// a restart reposts group by the file's own key alone.
+    #[serde(skip)]
+    media_index: HashMap<MediaKey, Vec<ForwardLocation>>,
+    /// Maximum forwards retained per original (None = unlimited). Runtime
+    /// setting, not persisted.
+    #[serde(skip)]
+    max_forwards_per_original: Option<usize>,
+    /// How long read state lasts once a new copy shows up (None = forever).
+    /// Runtime setting, not persisted.
+    #[serde(skip)]
+    read_state_ttl: Option<u64>,
+    /// How `save` writes the state file. Runtime setting, not persisted.
+    #[serde(skip)]
+    state_format: StateFormat,
+    /// What the last successful save took. Behind a lock because `save`
+    /// only borrows the tracker, and shared with snapshots so saving one
+    /// counts too. Not persisted.
+    #[serde(skip)]
+    last_save: Arc<Mutex<Option<SaveMetrics>>>,
+    /// Durations of recent propagations, recorded by the marker. Not
+    /// persisted.
+    #[serde(skip)]
+    latency: Arc<Mutex<LatencyHistogram>>,
+    /// Count of state changes (new forwards, newly read originals) since
+    /// startup, used to trigger saves. Not persisted.
+    #[serde(skip)]
+    changes: u64,
+    /// When each forward seen since startup was registered. Forwards loaded
+    /// from the state file have no entry and count as old. Not persisted.
+    #[serde(skip)]
+    registered_at: HashMap<ForwardLocation, u64>,
+    /// Copies a read left alone for being too new, waiting to come of age.
+    /// Not persisted: they are pending marks too, and after a restart every
+    /// copy counts as old, so `resumable_marks` picks them up.
+    #[serde(skip)]
+    aging: HashMap<ForwardLocation, OriginalMessageId>,
+    /// Totals readable without the lock, see `live_counts`. Not persisted.
+    #[serde(skip)]
+    live: Arc<LiveCounts>,
+    /// Where `first_seen` and cleanup ages get the time from.
+    #[serde(skip)]
+    clock: SharedClock,
 }
 
 impl DuplicateTracker {
+    /// Limit how many forwards are retained per original. Once an original
+    /// reaches the cap, further forwards of it are ignored; reads still
+    /// propagate to the retained ones.
+    pub fn set_max_forwards_per_original(&mut self, cap: Option<usize>) {
+        self.max_forwards_per_original = cap;
+    }
+
+    /// Size and duration of the last successful save, if any.
+    pub fn last_save(&self) -> Option<SaveMetrics> {
+        *self.last_save.lock().unwrap()
+    }
+
+    /// The histogram of propagation durations, shared so the marker can
+    /// record them and `stats` can report them.
+    pub fn propagation_latency(&self) -> Arc<Mutex<LatencyHistogram>> {
+        Arc::clone(&self.latency)
+    }
+
+    /// Write the state file as `format` from now on.
+    pub fn set_state_format(&mut self, format: StateFormat) {
+        self.state_format = format;
+    }
+
+    /// Forget that an original was read when a new copy of it arrives more
+    /// than `ttl_secs` after the read, so the repost surfaces as unread.
+    /// A read exactly `ttl_secs` ago still counts.
+    pub fn set_read_state_ttl(&mut self, ttl_secs: Option<u64>) {
+        self.read_state_ttl = ttl_secs;
+    }
+
+    /// Take the current time from `clock` instead of the system clock.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = SharedClock::new(clock);
+    }
+
     /// Register a forwarded message as a copy of an original.
     pub fn register_forward(
         &mut self,
         original: OriginalMessageId,
         forward: ForwardLocation,
     ) {
-        let now = epoch_secs();
+        let (tracked, indexed) = self.membership(&original, &forward);
+        if self.insert_forward(original, forward, tracked, indexed) {
+            self.publish_counts();
+        }
+    }
+
+    /// Register many copies at once, e.g. a burst of history. Same result
+    /// as calling `register_forward` for each in order, but the live counts
+    /// are only published once.
+    pub fn register_forwards<I>(&mut self, copies: I)
+    where
+        I: IntoIterator<Item = (OriginalMessageId, ForwardLocation)>,
+    {
+        for (original, forward) in copies {
+            let (tracked, indexed) = self.membership(&original, &forward);
+            self.insert_forward(original, forward, tracked, indexed);
+        }
+        self.publish_counts();
+    }
+
+    /// Note that the tracked copy at `forward` carries `media`. Copies of the
+    /// file no longer tracked are dropped from its list on the way.
+    pub fn register_media(&mut self, media: MediaKey, forward: ForwardLocation) {
+        let forward_index = &self.forward_index;
+        let copies = self.media_index.entry(media).or_default();
+        copies.retain(|f| forward_index.contains_key(f));
+        if forward_index.contains_key(&forward) && !copies.contains(&forward) {
+            copies.push(forward);
+        }
+        if copies.is_empty() {
+            self.media_index.remove(&media);
+        }
+    }
+
+    /// The original that tracked copies of `media` belong to, if any.
+    pub fn original_of_media(&self, media: &MediaKey) -> Option<&OriginalMessageId> {
+        self.media_index
+            .get(media)?
+            .iter()
+            .find_map(|f| self.forward_index.get(f))
+    }
+
+    /// Whether `forward` is already among `original`'s copies, and whether
+    /// its message id is already in the chat index. The forward index holds
+    /// exactly the locations in the chat index, each under the original it
+    /// was last registered for, so it answers both without scanning; only a
+    /// location claimed by another original has this one's copies scanned.
+    fn membership(&self, original: &OriginalMessageId, forward: &ForwardLocation) -> (bool, bool) {
+        match self.forward_index.get(forward) {
+            Some(owner) if owner == original => (true, true),
+            Some(_) => {
+                let forwards = self.originals.get(original);
+                (forwards.is_some_and(|f| f.contains(forward)), true)
+            }
+            None => (false, false),
+        }
+    }
+
+    /// The body of `register_forward`, given whether `forward` is already
+    /// among the original's copies (`tracked`) and its message id already
+    /// in the chat index (`indexed`). Returns false if the cap kept it out.
+    fn insert_forward(
+        &mut self,
+        original: OriginalMessageId,
+        forward: ForwardLocation,
+        tracked: bool,
+        indexed: bool,
+    ) -> bool {
+        if let (Some(cap), Some(existing)) =
+            (self.max_forwards_per_original, self.originals.get(&original))
+        {
+            if existing.len() >= cap && !tracked {
+                debug!(
+                    "Forward cap reached for original ({}, {}), not tracking chat={} msg={}",
+                    original.peer_id, original.message_id, forward.chat_id, forward.message_id
+                );
+                return false;
+            }
+        }
+
+        let now = self.clock.now();
         self.first_seen.entry(original.clone()).or_insert(now);
 
+        if !self.originals.contains_key(&original) {
+            self.source_index
+                .entry(original.peer_id)
+                .or_default()
+                .push(original.clone());
+        }
+
         let forwards = self.originals.entry(original.clone()).or_default();
-        if !forwards.contains(&forward) {
+        if !tracked {
             forwards.push(forward.clone());
+            self.registered_at.insert(forward.clone(), now);
+            self.changes += 1;
+            let read_at = self.read_at.get(&original).copied();
+            if let (Some(ttl), Some(read_at)) = (self.read_state_ttl, read_at) {
+                if now.saturating_sub(read_at) > ttl {
+                    debug!(
+                        peer_id = original.peer_id,
+                        message_id = original.message_id,
+                        "Read state expired, treating the new copy as unread"
+                    );
+                    self.read_originals.remove(&original);
+                    self.read_at.remove(&original);
+                }
+            }
+            if self.max_forwards_per_original == Some(forwards.len()) {
+                info!(
+                    "Original ({}, {}) reached the cap of {} forwards, further copies won't be tracked",
+                    original.peer_id,
+                    original.message_id,
+                    forwards.len()
+                );
+            }
         }
 
         // Update chat_index for fast read-event lookups
-        let chat_entries = self.chat_index.entry(forward.chat_id).or_default();
-        if !chat_entries.iter().any(|(mid, _)| *mid == forward.message_id) {
-            chat_entries.push((forward.message_id, original.clone()));
+        if !indexed {
+            self.chat_index
+                .entry(forward.chat_id)
+                .or_default()
+                .push((forward.message_id, original.clone()));
         }
 
         self.forward_index
             .insert(forward, original);
+        true
     }
 
     /// Mark an original as read. Returns all forward locations
     /// that should also be marked as read.
     pub fn mark_original_read(&mut self, original: &OriginalMessageId) -> Vec<ForwardLocation> {
-        self.read_originals.insert(original.clone());
+        if self.read_originals.insert(original.clone()) {
+            self.read_at.insert(original.clone(), self.clock.now());
+            self.changes += 1;
+            self.publish_counts();
+        }
         self.originals
             .get(original)
             .cloned()
             .unwrap_or_default()
     }
 
+    /// Number of state changes since startup. Only ever increases, so the
+    /// difference across an operation says how much it changed.
+    pub fn changes(&self) -> u64 {
+        self.changes
+    }
+
     /// Look up which original a forward belongs to.
-    #[allow(dead_code)]
     pub fn lookup_forward(&self, forward: &ForwardLocation) -> Option<&OriginalMessageId> {
         self.forward_index.get(forward)
     }
 
-    /// Check if an original has been read.
-    #[allow(dead_code)]
+    /// Seconds since `forward` was registered, if that happened since
+    /// startup. None for forwards loaded from the state file. A forward
+    /// registered before the clock jumped back past its registration is 0
+    /// seconds old, so it waits out the minimum age again rather than
+    /// passing for one from before startup.
+    pub fn forward_age(&self, forward: &ForwardLocation) -> Option<u64> {
+        let registered = self.registered_at.get(forward)?;
+        Some(self.clock.now().saturating_sub(*registered))
+    }
+
+    /// Hold copies too new to mark until they come of age, see
+    /// `take_aged_forwards`.
+    pub fn hold_until_aged(&mut self, forwards: &[(OriginalMessageId, ForwardLocation)]) {
+        for (original, fwd) in forwards {
+            self.aging.insert(fwd.clone(), original.clone());
+        }
+    }
+
+    /// Release the held copies that are at least `min_age_secs` old now,
+    /// sorted by chat and message. Those forgotten meanwhile are dropped.
+    pub fn take_aged_forwards(
+        &mut self,
+        min_age_secs: u64,
+    ) -> Vec<(OriginalMessageId, ForwardLocation)> {
+        let mut aged = Vec::new();
+        for (fwd, original) in std::mem::take(&mut self.aging) {
+            if self.forward_index.get(&fwd) != Some(&original) {
+                continue;
+            }
+            if self.forward_age(&fwd).is_some_and(|age| age < min_age_secs) {
+                self.aging.insert(fwd, original);
+            } else {
+                aged.push((original, fwd));
+            }
+        }
+        aged.sort_by_key(|(_, f)| (f.chat_id, f.message_id));
+        aged
+    }
+
+    /// Seconds until the first held copy is `min_age_secs` old, while any
+    /// are held.
+    pub fn next_aged_in(&self, min_age_secs: u64) -> Option<u64> {
+        self.aging
+            .keys()
+            .map(|fwd| match self.forward_age(fwd) {
+                Some(age) => min_age_secs.saturating_sub(age),
+                None => 0,
+            })
+            .min()
+    }
+
+    /// Remember a preview for a tracked original, unless it already has one.
+    pub fn set_preview_if_absent(&mut self, original: &OriginalMessageId, preview: String) {
+        if self.originals.contains_key(original) && !self.previews.contains_key(original) {
+            self.previews.insert(original.clone(), preview);
+        }
+    }
+
+    /// Replace the preview of a tracked original after its post was edited.
+    /// Returns whether anything changed; untracked originals are ignored.
+    pub fn refresh_preview(&mut self, original: &OriginalMessageId, preview: String) -> bool {
+        if !self.originals.contains_key(original) {
+            return false;
+        }
+        if self.previews.get(original) == Some(&preview) {
+            return false;
+        }
+        self.previews.insert(original.clone(), preview);
+        self.changes += 1;
+        true
+    }
+
+    /// The stored preview of an original, if any.
+    pub fn preview(&self, original: &OriginalMessageId) -> Option<&str> {
+        self.previews.get(original).map(String::as_str)
+    }
+
+    /// Whether any forwards of an original are tracked.
+    pub fn is_tracked(&self, original: &OriginalMessageId) -> bool {
+        self.originals.contains_key(original)
+    }
+
+    /// Every tracked copy of an original, in the order they were found.
+    pub fn forwards_of(&self, original: &OriginalMessageId) -> &[ForwardLocation] {
+        self.originals.get(original).map_or(&[], Vec::as_slice)
+    }
+
+    /// The distinct chats an original has been forwarded to, in the order
+    /// its first copy in each was found.
+    pub fn chats_for_original(&self, original: &OriginalMessageId) -> Vec<i64> {
+        let mut seen = HashSet::new();
+        self.forwards_of(original)
+            .iter()
+            .map(|forward| forward.chat_id)
+            .filter(|chat_id| seen.insert(*chat_id))
+            .collect()
+    }
+
+    /// `chats_for_original` with each chat named by `name`, falling back to
+    /// its id, for reports like "this post appeared in A, B and C".
+    pub fn chat_names_for_original(
+        &self,
+        original: &OriginalMessageId,
+        name: impl Fn(i64) -> Option<String>,
+    ) -> Vec<String> {
+        self.chats_for_original(original)
+            .into_iter()
+            .map(|chat_id| name(chat_id).unwrap_or_else(|| chat_id.to_string()))
+            .collect()
+    }
+
+    /// Every tracked copy of an original with where it stands, in the order
+    /// they were found. Empty if the original isn't tracked.
+    pub fn forward_details(&self, original: &OriginalMessageId) -> Vec<ForwardDetail> {
+        let read = self.read_originals.contains(original);
+        self.forwards_of(original)
+            .iter()
+            .map(|forward| ForwardDetail {
+                chat_id: forward.chat_id,
+                message_id: forward.message_id,
+                top_msg_id: forward.top_msg_id,
+                status: if !read {
+                    ForwardStatus::Unread
+                } else if self.pending.contains(forward) {
+                    ForwardStatus::Pending
+                } else if self.unmarkable.contains(forward.chat_id) {
+                    ForwardStatus::Unmarkable
+                } else if self.marked.contains(forward) {
+                    ForwardStatus::Read
+                } else {
+                    ForwardStatus::Unmarked
+                },
+            })
+            .collect()
+    }
+
+    /// How many copies of an original are tracked.
+    pub fn forward_count(&self, original: &OriginalMessageId) -> usize {
+        self.originals.get(original).map_or(0, Vec::len)
+    }
+
+    /// Check if an original has been read.
     pub fn is_original_read(&self, original: &OriginalMessageId) -> bool {
         self.read_originals.contains(original)
     }
 
+    /// All tracked originals from a given source peer.
+    pub fn originals_for_source(&self, peer_id: i64) -> &[OriginalMessageId] {
+        self.source_index
+            .get(&peer_id)
+            .map(Vec::as_slice)
+            .unwrap_or(&[])
+    }
+
     /// Find originals for forwards in a given chat with message_id <= max_id
     /// that haven't been marked as read yet. Uses the chat_index for O(1)
     /// lookup by chat_id instead of scanning the entire forward_index.
@@ -131,259 +1126,2166 @@ impl DuplicateTracker {
         originals
     }
 
-    /// Remove entries older than `max_age_secs`.
-    pub fn cleanup(&mut self, max_age_secs: u64) {
-        let cutoff = epoch_secs().saturating_sub(max_age_secs);
-        let old_originals: Vec<OriginalMessageId> = self
-            .first_seen
-            .iter()
-            .filter(|(_, &ts)| ts < cutoff)
-            .map(|(k, _)| k.clone())
-            .collect();
+    /// Every copy of a post that is tracked in more than one place and not
+    /// read yet, grouped by post. The order is unspecified.
+    pub fn unread_duplicates(&self) -> Vec<(OriginalMessageId, ForwardLocation)> {
+        self.originals
+            .iter()
+            .filter(|(orig, forwards)| forwards.len() > 1 && !self.read_originals.contains(*orig))
+            .flat_map(|(orig, forwards)| forwards.iter().map(|f| (orig.clone(), f.clone())))
+            .collect()
+    }
+
+    /// Remove entries strictly older than `max_age_secs`: an entry first
+    /// seen exactly `max_age_secs` ago is kept. The clock is read once, so
+    /// the cutoff can't shift mid-cleanup. Returns how many originals were
+    /// removed.
+    pub fn cleanup(&mut self, max_age_secs: u64) -> usize {
+        let now = self.clock.now();
+        self.clamp_future_timestamps(now);
+        self.cleanup_before(now.saturating_sub(max_age_secs))
+    }
+
+    /// Pull timestamps later than `now` back to it. They were taken before
+    /// the wall clock jumped backward (an NTP correction, a restored VM),
+    /// and left alone they would keep their entries from aging until the
+    /// clock caught up again. Clamped, they age from now. Returns how many
+    /// were clamped.
+    fn clamp_future_timestamps(&mut self, now: u64) -> usize {
+        let mut clamped = 0;
+        let stamps = self.first_seen.values_mut().chain(self.read_at.values_mut());
+        for ts in stamps.chain(self.registered_at.values_mut()) {
+            if *ts > now {
+                *ts = now;
+                clamped += 1;
+            }
+        }
+        if clamped > 0 {
+            warn!(
+                clamped,
+                now,
+                "Found timestamps in the future, the clock went backward; aging them from now"
+            );
+        }
+        clamped
+    }
+
+    /// Remove entries first seen strictly before `cutoff` (seconds since
+    /// epoch). Returns how many originals were removed.
+    pub fn cleanup_before(&mut self, cutoff: u64) -> usize {
+        let old_originals: Vec<OriginalMessageId> = self
+            .first_seen
+            .iter()
+            .filter(|(_, &ts)| ts < cutoff)
+            .map(|(k, _)| k.clone())
+            .collect();
+
+        let count = old_originals.len();
+        for orig in &old_originals {
+            self.remove_original(orig);
+        }
+        if count > 0 {
+            info!("Cleaned up {} old entries", count);
+        }
+        count
+    }
+
+    /// Originals first seen at or after `ts` (seconds since epoch), oldest
+    /// first. The complement of what `cleanup_before(ts)` removes.
+    pub fn originals_since(&self, ts: u64) -> Vec<&OriginalMessageId> {
+        let mut recent: Vec<(u64, &OriginalMessageId)> = self
+            .first_seen
+            .iter()
+            .filter(|(_, &seen)| seen >= ts)
+            .map(|(orig, &seen)| (seen, orig))
+            .collect();
+        recent.sort_by_key(|(seen, orig)| (*seen, orig.peer_id, orig.message_id));
+        recent.into_iter().map(|(_, orig)| orig).collect()
+    }
+
+    /// The chats flagged as refusing reads, shared so the marker can flag
+    /// them as it finds them and they are saved with the rest of the state.
+    pub fn unmarkable_chats(&self) -> Arc<UnmarkableChats> {
+        Arc::clone(&self.unmarkable)
+    }
+
+    /// The marks planned but not confirmed yet, shared so the planner can
+    /// add to them, the marker can confirm them, and whatever is left is
+    /// saved with the rest of the state.
+    pub fn pending_marks(&self) -> Arc<PendingMarks> {
+        Arc::clone(&self.pending)
+    }
+
+    /// Pending marks left over from a previous run that still point at
+    /// tracked copies. Those of copies forgotten since are dropped.
+    pub fn resumable_marks(&self) -> Vec<(OriginalMessageId, ForwardLocation)> {
+        self.pending
+            .retain(|original, fwd| self.forward_index.get(fwd) == Some(original));
+        self.pending.forwards()
+    }
+
+    /// The copies known to be read, shared so the planner can add those
+    /// the user read, the marker those it marked, and they are saved with
+    /// the rest of the state.
+    pub fn marked_copies(&self) -> Arc<MarkedCopies> {
+        Arc::clone(&self.marked)
+    }
+
+    /// Count the copies of read posts that aren't pending as marked. States
+    /// saved before marked copies were don't say, and those copies were
+    /// marked as far as anyone knows.
+    fn assume_read_copies_marked(&mut self) {
+        let marked = MarkedCopies::default();
+        for original in &self.read_originals {
+            let forwards = self.forwards_of(original).iter();
+            let done = forwards
+                .filter(|fwd| !self.pending.contains(fwd))
+                .map(|fwd| (original.clone(), fwd.clone()));
+            marked.record(&done.collect::<Vec<_>>());
+        }
+        self.marked = Arc::new(marked);
+    }
+
+    /// A handle on the key totals that stays current as the tracker
+    /// changes. Take it once and read it from anywhere without locking.
+    pub fn live_counts(&self) -> Arc<LiveCounts> {
+        Arc::clone(&self.live)
+    }
+
+    /// A handle on everything `stats` and `last_save` report, current the
+    /// same way as `live_counts`.
+    pub fn live_stats(&self) -> LiveStats {
+        LiveStats {
+            counts: self.live_counts(),
+            last_save: Arc::clone(&self.last_save),
+            latency: self.propagation_latency(),
+        }
+    }
+
+    /// Copy the map sizes into `live`. Called after every change to
+    /// `originals`, `forward_index` or `read_originals`, which the chat and
+    /// source indices change along with.
+    fn publish_counts(&self) {
+        let set = |counter: &AtomicU64, len: usize| counter.store(len as u64, Ordering::Relaxed);
+        set(&self.live.originals, self.originals.len());
+        set(&self.live.forwards, self.forward_index.len());
+        set(&self.live.read_originals, self.read_originals.len());
+        set(&self.live.chats, self.chat_index.len());
+        set(&self.live.sources, self.source_index.len());
+    }
+
+    /// Summary counts for reporting.
+    pub fn stats(&self) -> TrackerStats {
+        TrackerStats {
+            originals: self.originals.len(),
+            forwards: self.forward_index.len(),
+            read_originals: self.read_originals.len(),
+            chats: self.chat_index.len(),
+            sources: self.source_index.len(),
+            latency: self.latency.lock().unwrap().summary(),
+        }
+    }
+
+    /// Write the cross-post graph in GraphViz DOT: a node per original, an
+    /// edge from it to each chat holding a copy. Nodes are labeled with
+    /// `name(peer_id)` where that knows one, else with the id. Read
+    /// originals are filled. Output is sorted, so equal states give equal
+    /// graphs.
+    pub fn export_dot<W: Write>(
+        &self,
+        mut w: W,
+        name: impl Fn(i64) -> Option<String>,
+    ) -> std::io::Result<()> {
+        let label = |id: i64| dot_escape(&name(id).unwrap_or_else(|| id.to_string()));
+        let mut originals: Vec<_> = self.originals.iter().collect();
+        originals.sort_by_key(|(o, _)| (o.peer_id, o.message_id));
+        let mut chats: Vec<i64> = self.chat_index.keys().copied().collect();
+        chats.sort_unstable();
+
+        writeln!(w, "digraph duplicates {{")?;
+        for (original, _) in &originals {
+            let style = if self.read_originals.contains(original) {
+                ", style=filled"
+            } else {
+                ""
+            };
+            writeln!(
+                w,
+                r#"    "o{}_{}" [label="{} #{}"{}];"#,
+                original.peer_id,
+                original.message_id,
+                label(original.peer_id),
+                original.message_id,
+                style
+            )?;
+        }
+        for chat_id in &chats {
+            writeln!(w, r#"    "c{}" [label="{}", shape=box];"#, chat_id, label(*chat_id))?;
+        }
+        for (original, forwards) in &originals {
+            let mut targets: Vec<i64> = forwards.iter().map(|f| f.chat_id).collect();
+            targets.sort_unstable();
+            targets.dedup();
+            for chat_id in targets {
+                writeln!(
+                    w,
+                    r#"    "o{}_{}" -> "c{}";"#,
+                    original.peer_id, original.message_id, chat_id
+                )?;
+            }
+        }
+        writeln!(w, "}}")
+    }
+
+    /// Forget an original and all of its forwards. Returns whether it was
+    /// tracked. A later forward of it will be tracked afresh.
+    pub fn forget_original(&mut self, original: &OriginalMessageId) -> bool {
+        let tracked = self.originals.contains_key(original);
+        self.remove_original(original);
+        tracked
+    }
+
+    /// Forget everything related to a chat: forwards located in it, and
+    /// originals whose source is that chat. Returns how many forwards were
+    /// dropped.
+    pub fn forget_chat(&mut self, chat_id: i64) -> usize {
+        self.unmarkable.remove(chat_id);
+        let mut removed = self.forget_forwards_in_chat(chat_id);
+        for orig in self.source_index.get(&chat_id).cloned().unwrap_or_default() {
+            removed += self.originals.get(&orig).map_or(0, Vec::len);
+            self.remove_original(&orig);
+        }
+        removed
+    }
+
+    /// Drop every forward located in a chat, leaving copies elsewhere alone.
+    /// Originals left with no forwards are removed. Returns how many
+    /// forwards were dropped.
+    pub fn forget_forwards_in_chat(&mut self, chat_id: i64) -> usize {
+        let entries = self.chat_index.remove(&chat_id).unwrap_or_default();
+        let removed = entries.len();
+        for (message_id, orig) in entries {
+            let fwd = ForwardLocation::new(chat_id, message_id);
+            self.forward_index.remove(&fwd);
+            self.registered_at.remove(&fwd);
+            self.marked.remove(&fwd);
+            let now_empty = match self.originals.get_mut(&orig) {
+                Some(forwards) => {
+                    forwards.retain(|f| *f != fwd);
+                    forwards.is_empty()
+                }
+                None => false,
+            };
+            // An original with no remaining forwards is no longer useful
+            if now_empty {
+                self.remove_original(&orig);
+            }
+        }
+        self.publish_counts();
+        removed
+    }
+
+    /// Chats holding tracked forwards that `is_resolvable` doesn't know,
+    /// with how many forwards each holds, sorted by chat_id. Used at startup
+    /// to find chats the user has left, which can never be marked read.
+    pub fn unresolvable_chats(&self, is_resolvable: impl Fn(i64) -> bool) -> Vec<(i64, usize)> {
+        let mut chats: Vec<(i64, usize)> = self
+            .chat_index
+            .iter()
+            .filter(|(chat_id, _)| !is_resolvable(**chat_id))
+            .map(|(chat_id, entries)| (*chat_id, entries.len()))
+            .collect();
+        chats.sort_unstable();
+        chats
+    }
+
+    /// Remove an original and all its forwards from every index.
+    fn remove_original(&mut self, orig: &OriginalMessageId) {
+        if let Some(forwards) = self.originals.remove(orig) {
+            for fwd in &forwards {
+                self.forward_index.remove(fwd);
+                self.registered_at.remove(fwd);
+                self.marked.remove(fwd);
+                if let Some(chat_entries) = self.chat_index.get_mut(&fwd.chat_id) {
+                    chat_entries.retain(|(mid, _)| *mid != fwd.message_id);
+                    if chat_entries.is_empty() {
+                        self.chat_index.remove(&fwd.chat_id);
+                    }
+                }
+            }
+        }
+        if let Some(source_entries) = self.source_index.get_mut(&orig.peer_id) {
+            source_entries.retain(|o| o != orig);
+            if source_entries.is_empty() {
+                self.source_index.remove(&orig.peer_id);
+            }
+        }
+        self.read_originals.remove(orig);
+        self.read_at.remove(orig);
+        self.first_seen.remove(orig);
+        self.previews.remove(orig);
+        self.publish_counts();
+    }
+
+    /// Fold another tracker's state into this one, e.g. from a second
+    /// machine. Conflicts resolve as:
+    /// - forwards of the same original are unioned;
+    /// - a location both sides attribute to different originals keeps this
+    ///   side's attribution;
+    /// - an original read on either side is read (read wins);
+    /// - the earliest `first_seen` wins;
+    /// - this side's preview wins when both have one.
+    pub fn merge(&mut self, other: DuplicateTracker) {
+        for (original, forwards) in other.originals {
+            for fwd in forwards {
+                if self.forward_index.contains_key(&fwd) {
+                    continue;
+                }
+                self.forward_index.insert(fwd.clone(), original.clone());
+                self.originals.entry(original.clone()).or_default().push(fwd);
+            }
+        }
+        self.read_originals.extend(
+            other
+                .read_originals
+                .into_iter()
+                .filter(|o| self.originals.contains_key(o)),
+        );
+        // The latest read wins, so merged state expires no sooner
+        for (original, ts) in other.read_at {
+            if !self.read_originals.contains(&original) {
+                continue;
+            }
+            self.read_at
+                .entry(original)
+                .and_modify(|t| *t = (*t).max(ts))
+                .or_insert(ts);
+        }
+        self.backfill_read_at();
+        for (original, ts) in other.first_seen {
+            if !self.originals.contains_key(&original) {
+                continue;
+            }
+            self.first_seen
+                .entry(original)
+                .and_modify(|t| *t = (*t).min(ts))
+                .or_insert(ts);
+        }
+        for (original, preview) in other.previews {
+            if self.originals.contains_key(&original) {
+                self.previews.entry(original).or_insert(preview);
+            }
+        }
+        for chat_id in other.unmarkable.chats() {
+            self.unmarkable.insert(chat_id);
+        }
+        let marked: Vec<_> = other
+            .marked
+            .forwards()
+            .into_iter()
+            .filter_map(|fwd| Some((self.forward_index.get(&fwd)?.clone(), fwd)))
+            .collect();
+        self.marked.record(&marked);
+        self.rebuild_chat_index();
+        self.rebuild_source_index();
+        self.publish_counts();
+        self.changes += 1;
+    }
+
+    /// Drop the copies located in chat `old_id` after it was migrated to a
+    /// supergroup. The supergroup numbers its messages afresh, so the
+    /// copies can't be moved to its id: their message ids would point at
+    /// unrelated messages there. They are lost instead, along with the
+    /// chat's unmarkable flag. Originals posted in the old chat stay under
+    /// its id, where those posts still are. Returns how many copies were
+    /// dropped.
+    pub fn forget_migrated_chat(&mut self, old_id: i64) -> usize {
+        self.unmarkable.remove(old_id);
+        let dropped = self.forget_forwards_in_chat(old_id);
+        if dropped > 0 {
+            self.changes += 1;
+        }
+        dropped
+    }
+
+    /// Give read originals without a read time (older state files) the
+    /// current time, so the read state TTL counts from now for them.
+    fn backfill_read_at(&mut self) {
+        let now = self.clock.now();
+        for original in &self.read_originals {
+            self.read_at.entry(original.clone()).or_insert(now);
+        }
+    }
+
+    /// Rebuild the chat_index from forward_index.
+    fn rebuild_chat_index(&mut self) {
+        self.chat_index.clear();
+        for (fwd, orig) in &self.forward_index {
+            self.chat_index
+                .entry(fwd.chat_id)
+                .or_default()
+                .push((fwd.message_id, orig.clone()));
+        }
+    }
+
+    /// Rebuild the source_index from originals.
+    fn rebuild_source_index(&mut self) {
+        self.source_index.clear();
+        for orig in self.originals.keys() {
+            self.source_index
+                .entry(orig.peer_id)
+                .or_default()
+                .push(orig.clone());
+        }
+    }
+
+    /// Load state from a file in either `StateFormat`, told apart by the
+    /// bincode magic prefix.
+    pub fn load(path: &Path) -> Result<Self, TrackerError> {
+        let data = std::fs::read(path).map_err(TrackerError::io("read", path))?;
+        let decoded = |result: bincode::Result<Self>| {
+            result.map_err(|source| TrackerError::Decode {
+                path: path.to_owned(),
+                source,
+            })
+        };
+        let mut tracker: Self = if let Some(encoded) = data.strip_prefix(BINCODE_MAGIC) {
+            decoded(decode_unchecked(encoded))?
+        } else if let Some(checked) = data.strip_prefix(CHECKED_BINCODE_MAGIC) {
+            let encoded = verify_bincode(checked).map_err(corrupt(path))?;
+            decoded(bincode::deserialize(encoded))?
+        } else if let Some(checked) = data.strip_prefix(CHECKED_BINCODE_MAGIC_BEFORE_MARKED) {
+            let encoded = verify_bincode(checked).map_err(corrupt(path))?;
+            decoded(decode_before_marked(encoded))?
+        } else {
+            let json = match data.strip_prefix(JSON_CHECKSUM_KEY) {
+                Some(checked) => verify_json(checked).map_err(corrupt(path))?,
+                None => data,
+            };
+            serde_json::from_slice(&json).map_err(|source| TrackerError::Parse {
+                path: path.to_owned(),
+                source,
+            })?
+        };
+        // Derived indices are skipped during serde, always rebuild them
+        tracker.rebuild_chat_index();
+        tracker.rebuild_source_index();
+        tracker.backfill_read_at();
+        if tracker.marked.unrecorded {
+            tracker.assume_read_copies_marked();
+        }
+        let now = tracker.clock.now();
+        tracker.clamp_future_timestamps(now);
+        tracker.publish_counts();
+        Ok(tracker)
+    }
+
+    /// Like `load`, but if `path` turns out corrupt, fall back on the backup
+    /// `save` keeps beside it. The corrupt file is moved aside, so it can
+    /// be looked at and is never taken for a backup itself. Returns the
+    /// original error if there is no usable backup either.
+    pub fn load_or_backup(path: &Path) -> Result<Self, TrackerError> {
+        let err = match Self::load(path) {
+            Err(e) if e.is_corrupt() => e,
+            result => return result,
+        };
+        let aside = path.with_extension("json.corrupt");
+        match std::fs::rename(path, &aside) {
+            Ok(()) => warn!("{}; moved it to {}", err, aside.display()),
+            Err(e) => warn!("{}; could not move it aside: {}", err, e),
+        }
+        let backup = backup_path(path);
+        match Self::load(&backup) {
+            Ok(tracker) => {
+                warn!("Restored state from the backup {}", backup.display());
+                Ok(tracker)
+            }
+            Err(backup_err) => {
+                warn!("No usable backup: {}", backup_err);
+                Err(err)
+            }
+        }
+    }
+
+    /// Save state in the configured format atomically: write a .tmp file,
+    /// fsync it, then rename it over the old one. A crash at any point
+    /// leaves either the complete old state or the complete new state on
+    /// disk, never a partial file. On Unix the directory is fsynced too, so
+    /// the rename itself survives power loss. The file replaced is kept as
+    /// the backup `load_or_backup` falls back on.
+    pub async fn save(&self, path: &Path) -> Result<(), TrackerError> {
+        self.write_state(path).await
+    }
+
+    /// Copy the persisted state, e.g. to save it without holding the lock
+    /// the tracker is behind. Copying the maps is much quicker than
+    /// serializing them and syncing the file.
+    pub fn snapshot(&self) -> StateSnapshot {
+        StateSnapshot(DuplicateTracker {
+            originals: self.originals.clone(),
+            forward_index: self.forward_index.clone(),
+            read_originals: self.read_originals.clone(),
+            read_at: self.read_at.clone(),
+            first_seen: self.first_seen.clone(),
+            previews: self.previews.clone(),
+            unmarkable: Arc::clone(&self.unmarkable),
+            pending: Arc::clone(&self.pending),
+            marked: Arc::clone(&self.marked),
+            state_format: self.state_format,
+            last_save: Arc::clone(&self.last_save),
+            ..Default::default()
+        })
+    }
+
+    async fn write_state(&self, path: &Path) -> Result<(), TrackerError> {
+        let start = Instant::now();
+        let tmp_path = path.with_extension("json.tmp");
+        let data = match self.state_format {
+            StateFormat::Json => {
+                let json = serde_json::to_vec_pretty(self).map_err(TrackerError::Serialize)?;
+                let mut data = format!(
+                    "{}{:08x}\",",
+                    String::from_utf8_lossy(JSON_CHECKSUM_KEY),
+                    crc32fast::hash(&json)
+                )
+                .into_bytes();
+                // Everything after the opening brace, which the key took
+                data.extend_from_slice(&json[1..]);
+                data
+            }
+            StateFormat::Bincode => {
+                let encoded = bincode::serialize(self).map_err(TrackerError::Encode)?;
+                let mut data = CHECKED_BINCODE_MAGIC.to_vec();
+                data.extend_from_slice(&crc32fast::hash(&encoded).to_le_bytes());
+                data.extend_from_slice(&encoded);
+                data
+            }
+        };
+        write_synced(&tmp_path, &data)
+            .map_err(TrackerError::io("write temp", &tmp_path))?;
+        keep_backup(path);
+        replace_file(&tmp_path, path)
+            .await
+            .map_err(TrackerError::io("rename temp", &tmp_path))?;
+        sync_parent_dir(path).map_err(TrackerError::io("sync directory of", path))?;
+
+        let metrics = SaveMetrics {
+            bytes: data.len() as u64,
+            duration: start.elapsed(),
+        };
+        debug!(bytes = metrics.bytes, duration = ?metrics.duration, "Wrote state file");
+        if metrics.duration > SLOW_SAVE {
+            warn!(
+                "Saving state took {}; consider TG_STATE_FORMAT=bincode or forgetting old chats",
+                metrics
+            );
+        }
+        *self.last_save.lock().unwrap() = Some(metrics);
+        Ok(())
+    }
+}
+
+/// The persisted state as of [`DuplicateTracker::snapshot`], which saves
+/// exactly like the tracker it was taken from would have.
+pub struct StateSnapshot(DuplicateTracker);
+
+impl StateSnapshot {
+    /// Save the snapshot the way [`DuplicateTracker::save`] does. The
+    /// tracker's last save metrics are updated on success.
+    pub async fn save(&self, path: &Path) -> Result<(), TrackerError> {
+        self.0.write_state(path).await
+    }
+}
+
+/// Where `save` keeps the state file it replaces.
+pub fn backup_path(path: &Path) -> PathBuf {
+    path.with_extension("json.bak")
+}
+
+/// Keep the state file at `path` as the backup before it is replaced. A
+/// hard link, so nothing is copied; best-effort, since the save matters
+/// more than the backup.
+fn keep_backup(path: &Path) {
+    let backup = backup_path(path);
+    let result = match std::fs::remove_file(&backup) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => std::fs::hard_link(path, &backup),
+    };
+    match result {
+        Ok(()) => {}
+        // Nothing saved yet
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+        Err(e) => debug!("Failed to keep a backup of {}: {}", path.display(), e),
+    }
+}
+
+fn corrupt(path: &Path) -> impl FnOnce(String) -> TrackerError {
+    let path = path.to_owned();
+    move |reason| TrackerError::Corrupt { path, reason }
+}
+
+fn checksum_mismatch(expected: u32, actual: u32) -> String {
+    format!("checksum {:08x} doesn't match its contents ({:08x})", expected, actual)
+}
+
+/// The encoded state after `CHECKED_BINCODE_MAGIC`, if its checksum holds.
+fn verify_bincode(checked: &[u8]) -> Result<&[u8], String> {
+    if checked.len() < 4 {
+        return Err("the checksum is cut off".to_owned());
+    }
+    let (sum, encoded) = checked.split_at(4);
+    let expected = u32::from_le_bytes(sum.try_into().unwrap());
+    let actual = crc32fast::hash(encoded);
+    if expected != actual {
+        return Err(checksum_mismatch(expected, actual));
+    }
+    Ok(encoded)
+}
+
+/// The JSON state after `JSON_CHECKSUM_KEY`, with the key taken out, if
+/// its checksum holds.
+fn verify_json(checked: &[u8]) -> Result<Vec<u8>, String> {
+    let malformed = || "the checksum is malformed".to_owned();
+    let (hex, rest) = match (checked.get(..8), checked.get(8..)) {
+        (Some(hex), Some(rest)) => (hex, rest),
+        _ => return Err(malformed()),
+    };
+    let expected = std::str::from_utf8(hex)
+        .ok()
+        .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+        .ok_or_else(malformed)?;
+    let rest = rest.strip_prefix(b"\",").ok_or_else(malformed)?;
+    let mut json = Vec::with_capacity(rest.len() + 1);
+    json.push(b'{');
+    json.extend_from_slice(rest);
+    let actual = crc32fast::hash(&json);
+    if expected != actual {
+        return Err(checksum_mismatch(expected, actual));
+    }
+    Ok(json)
+}
+
+/// Write `data` to `path` and flush it to disk before returning.
+fn write_synced(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    use std::io::Write;
+    let mut file = std::fs::File::create(path)?;
+    file.write_all(data)?;
+    file.sync_all()
+}
+
+/// Rename `from` over `to`. Unix renames over an existing file atomically.
+/// Windows replaces too, but fails while something (a virus scanner, a
+/// backup tool, an editor) has the destination open, so retry briefly,
+/// without holding up the runtime thread the save runs on.
+async fn replace_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    const ATTEMPTS: u32 = if cfg!(windows) { 5 } else { 1 };
+    let mut attempt = 1;
+    loop {
+        match std::fs::rename(from, to) {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt >= ATTEMPTS => return Err(e),
+            Err(e) => {
+                debug!("Replacing {} failed, retrying: {}", to.display(), e);
+                tokio::time::sleep(std::time::Duration::from_millis(50 * u64::from(attempt))).await;
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// Persist a rename by fsyncing the containing directory. Directories
+/// can't be opened for syncing on Windows, where this is a no-op.
+fn sync_parent_dir(path: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        let parent = match path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        std::fs::File::open(parent)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// Escape text for a quoted DOT string.
+fn dot_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str(r#"\""#),
+            '\\' => escaped.push_str(r"\\"),
+            '\n' => escaped.push_str(r"\n"),
+            '\r' => {}
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Current Unix time in seconds, or 0 if the system clock is set before
+/// the epoch.
+pub fn epoch_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::manual::ManualClock;
+    use tempfile::NamedTempFile;
+
+    fn orig(peer: i64, msg: i32) -> OriginalMessageId {
+        OriginalMessageId { peer_id: peer, message_id: msg }
+    }
+
+    /// A tracker whose clock starts at `now` and only moves when told.
+    fn at(now: u64) -> (DuplicateTracker, Arc<ManualClock>) {
+        let clock = ManualClock::at(now);
+        let mut t = DuplicateTracker::default();
+        t.set_clock(clock.clone());
+        (t, clock)
+    }
+
+    fn fwd(chat: i64, msg: i32) -> ForwardLocation {
+        ForwardLocation::new(chat, msg)
+    }
+
+    #[test]
+    fn register_and_lookup() {
+        let mut t = DuplicateTracker::default();
+        let o = orig(1, 100);
+        let f = fwd(2, 200);
+
+        t.register_forward(o.clone(), f.clone());
+
+        assert_eq!(t.lookup_forward(&f), Some(&o));
+        assert!(!t.is_original_read(&o));
+    }
+
+    #[test]
+    fn register_duplicate_forward_is_idempotent() {
+        let mut t = DuplicateTracker::default();
+        let o = orig(1, 100);
+        let f = fwd(2, 200);
+
+        t.register_forward(o.clone(), f.clone());
+        t.register_forward(o.clone(), f.clone());
+
+        // Should only have one entry, not two
+        assert_eq!(t.originals.get(&o).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn a_location_claimed_by_two_originals_stays_single_in_each() {
+        let mut t = DuplicateTracker::default();
+        let (a, b, f) = (orig(1, 100), orig(1, 101), fwd(2, 200));
+
+        for original in [&a, &b, &a, &b] {
+            t.register_forward(original.clone(), f.clone());
+        }
+
+        assert_eq!(t.originals[&a], vec![f.clone()]);
+        assert_eq!(t.originals[&b], vec![f.clone()]);
+        assert_eq!(t.chat_index[&2].len(), 1);
+        assert_eq!(t.lookup_forward(&f), Some(&b));
+    }
+
+    #[test]
+    fn media_index_forgets_copies_no_longer_tracked() {
+        let mut t = DuplicateTracker::default();
+        t.register_forward(orig(1, 100), fwd(10, 50));
+        t.register_media(MediaKey::Photo(7), fwd(10, 50));
+        assert_eq!(t.original_of_media(&MediaKey::Photo(7)), Some(&orig(1, 100)));
+
+        t.forget_original(&orig(1, 100));
+        assert_eq!(t.original_of_media(&MediaKey::Photo(7)), None);
+        // An untracked location isn't indexed at all
+        t.register_media(MediaKey::Photo(7), fwd(20, 60));
+        assert_eq!(t.original_of_media(&MediaKey::Photo(7)), None);
+    }
+
+    #[test]
+    fn multiple_forwards_of_same_original() {
+        let mut t = DuplicateTracker::default();
+        let o = orig(1, 100);
+        let f1 = fwd(2, 200);
+        let f2 = fwd(3, 300);
+
+        t.register_forward(o.clone(), f1.clone());
+        t.register_forward(o.clone(), f2.clone());
+
+        assert_eq!(t.originals.get(&o).unwrap().len(), 2);
+        assert_eq!(t.lookup_forward(&f1), Some(&o));
+        assert_eq!(t.lookup_forward(&f2), Some(&o));
+    }
+
+    #[test]
+    fn mark_original_read_returns_all_forwards() {
+        let mut t = DuplicateTracker::default();
+        let o = orig(1, 100);
+        let f1 = fwd(2, 200);
+        let f2 = fwd(3, 300);
+
+        t.register_forward(o.clone(), f1.clone());
+        t.register_forward(o.clone(), f2.clone());
+
+        let result = t.mark_original_read(&o);
+        assert_eq!(result.len(), 2);
+        assert!(result.contains(&f1));
+        assert!(result.contains(&f2));
+        assert!(t.is_original_read(&o));
+    }
+
+    #[test]
+    fn find_read_originals_filters_by_chat_and_max_id() {
+        let mut t = DuplicateTracker::default();
+        let o1 = orig(1, 100);
+        let o2 = orig(1, 101);
+
+        // Both forwarded to chat 10, different message IDs
+        t.register_forward(o1.clone(), fwd(10, 50));
+        t.register_forward(o2.clone(), fwd(10, 60));
+
+        // max_id=55 should only find the forward with msg_id=50
+        let found = t.find_read_originals_in_chat(10, 55);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0], o1);
+
+        // max_id=60 should find both
+        let found = t.find_read_originals_in_chat(10, 60);
+        assert_eq!(found.len(), 2);
+    }
+
+    #[test]
+    fn find_read_originals_excludes_already_read() {
+        let mut t = DuplicateTracker::default();
+        let o = orig(1, 100);
+        t.register_forward(o.clone(), fwd(10, 50));
+
+        // Mark as read first
+        t.mark_original_read(&o);
+
+        // Should not appear again
+        let found = t.find_read_originals_in_chat(10, 50);
+        assert!(found.is_empty());
+    }
+
+    #[test]
+    fn find_read_originals_empty_for_unknown_chat() {
+        let t = DuplicateTracker::default();
+        assert!(t.find_read_originals_in_chat(999, 100).is_empty());
+    }
+
+    #[test]
+    fn cleanup_removes_old_entries() {
+        let (mut t, clock) = at(1000);
+        let o = orig(1, 100);
+        let f = fwd(2, 200);
+        t.register_forward(o.clone(), f.clone());
+
+        clock.advance(2);
+        t.cleanup(1);
+
+        assert!(t.originals.is_empty());
+        assert!(t.forward_index.is_empty());
+        assert!(t.read_originals.is_empty());
+        assert!(t.first_seen.is_empty());
+        assert!(t.chat_index.is_empty());
+        assert!(t.source_index.is_empty());
+    }
+
+    #[test]
+    fn read_state_expires_for_copies_arriving_after_the_ttl() {
+        let (mut t, clock) = at(1000);
+        t.set_read_state_ttl(Some(100));
+        let o = orig(1, 100);
+        t.register_forward(o.clone(), fwd(10, 1));
+        t.mark_original_read(&o);
+
+        // Exactly the TTL later the read still counts
+        clock.advance(100);
+        t.register_forward(o.clone(), fwd(20, 1));
+        assert!(t.is_original_read(&o));
+        assert!(t.find_read_originals_in_chat(20, 1).is_empty());
+
+        clock.advance(1);
+        t.register_forward(o.clone(), fwd(30, 1));
+        assert!(!t.is_original_read(&o));
+        assert_eq!(t.find_read_originals_in_chat(30, 1), vec![o.clone()]);
+
+        // Reading it again starts a new TTL
+        assert_eq!(t.mark_original_read(&o).len(), 3);
+        clock.advance(50);
+        t.register_forward(o.clone(), fwd(40, 1));
+        assert!(t.is_original_read(&o));
+    }
+
+    #[test]
+    fn read_state_never_expires_without_a_ttl() {
+        let (mut t, clock) = at(1000);
+        let o = orig(1, 100);
+        t.register_forward(o.clone(), fwd(10, 1));
+        t.mark_original_read(&o);
+
+        clock.advance(1_000_000);
+        t.register_forward(o.clone(), fwd(20, 1));
+        assert!(t.is_original_read(&o));
+    }
+
+    #[test]
+    fn known_copies_do_not_expire_read_state() {
+        let (mut t, clock) = at(1000);
+        t.set_read_state_ttl(Some(100));
+        let o = orig(1, 100);
+        t.register_forward(o.clone(), fwd(10, 1));
+        t.mark_original_read(&o);
+
+        clock.advance(1000);
+        t.register_forward(o.clone(), fwd(10, 1));
+        assert!(t.is_original_read(&o));
+    }
+
+    #[test]
+    fn cleanup_keeps_recent_entries() {
+        let (mut t, clock) = at(1000);
+        let o = orig(1, 100);
+        let f = fwd(2, 200);
+        t.register_forward(o.clone(), f.clone());
+
+        clock.advance(999_999);
+        t.cleanup(1_000_000);
+
+        assert_eq!(t.originals.len(), 1);
+        assert_eq!(t.forward_index.len(), 1);
+    }
+
+    /// Simulates the discussion group scenario: a channel post forwarded to
+    /// two discussion groups. Reading the thread root (top_msg_id) in one
+    /// group should propagate to the other.
+    #[test]
+    fn discussion_group_read_propagation() {
+        let mut t = DuplicateTracker::default();
+        let o = orig(1, 100); // original channel post
+
+        // Same post forwarded to two discussion groups as thread roots
+        let f1 = fwd(10, 300); // discussion group A, thread root msg 300
+        let f2 = fwd(20, 400); // discussion group B, thread root msg 400
+
+        t.register_forward(o.clone(), f1.clone());
+        t.register_forward(o.clone(), f2.clone());
+
+        // User opens thread in group A → ReadChannelDiscussionInbox
+        // with top_msg_id=300. We look up originals with msg_id <= 300
+        // in chat 10, which finds f1.
+        let found = t.find_read_originals_in_chat(10, 300);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0], o);
+
+        // Mark original as read, get all forwards to propagate
+        let all_fwds = t.mark_original_read(&o);
+        assert_eq!(all_fwds.len(), 2);
+
+        // Filter out the one the user already read (f1 in chat 10)
+        let to_mark: Vec<_> = all_fwds
+            .into_iter()
+            .filter(|f| !(f.chat_id == 10 && f.message_id <= 300))
+            .collect();
+        assert_eq!(to_mark.len(), 1);
+        assert_eq!(to_mark[0], f2);
+    }
+
+    #[test]
+    fn forward_details_show_each_copys_status() {
+        let mut t = DuplicateTracker::default();
+        let o = orig(1, 100);
+        for forward in [fwd(10, 1), fwd(20, 2), fwd(30, 3), fwd(40, 4)] {
+            t.register_forward(o.clone(), forward);
+        }
+        let status = |t: &DuplicateTracker| {
+            t.forward_details(&o)
+                .iter()
+                .map(|d| (d.chat_id, d.message_id, d.status))
+                .collect::<Vec<_>>()
+        };
+        assert!(t.forward_details(&o).iter().all(|d| !d.is_read()));
+        assert_eq!(status(&t)[1], (20, 2, ForwardStatus::Unread));
+
+        // Read in chat 10; the mark in 20 hasn't gone out yet, chat 30
+        // refuses reads and 40 was left alone
+        t.mark_original_read(&o);
+        t.marked_copies().record(&[(o.clone(), fwd(10, 1))]);
+        t.pending_marks().add(&[(o.clone(), fwd(20, 2))]);
+        t.unmarkable_chats().insert(30);
+        assert_eq!(
+            status(&t),
+            vec![
+                (10, 1, ForwardStatus::Read),
+                (20, 2, ForwardStatus::Pending),
+                (30, 3, ForwardStatus::Unmarkable),
+                (40, 4, ForwardStatus::Unmarked),
+            ]
+        );
+        assert_eq!(t.forward_details(&o).iter().filter(|d| d.is_read()).count(), 1);
+
+        t.pending_marks().confirm(&[(o.clone(), fwd(20, 2))]);
+        t.marked_copies().record(&[(o.clone(), fwd(20, 2))]);
+        assert!(t.forward_details(&o)[1].is_read());
+
+        // Forgotten copies aren't remembered as marked
+        t.forget_forwards_in_chat(10);
+        assert!(!t.marked_copies().contains(&fwd(10, 1)));
+        assert!(t.forward_details(&orig(1, 999)).is_empty());
+    }
+
+    #[test]
+    fn chats_for_original_are_distinct_in_first_seen_order() {
+        let mut t = DuplicateTracker::default();
+        let o = orig(1, 100);
+        for forward in [fwd(30, 1), fwd(10, 2), fwd(30, 3), fwd(20, 4), fwd(10, 5)] {
+            t.register_forward(o.clone(), forward);
+        }
+        t.register_forward(orig(1, 101), fwd(40, 6));
+
+        assert_eq!(t.chats_for_original(&o), vec![30, 10, 20]);
+        assert!(t.chats_for_original(&orig(1, 999)).is_empty());
+
+        let names = t.chat_names_for_original(&o, |chat_id| {
+            (chat_id == 10).then(|| "Ten".to_owned())
+        });
+        assert_eq!(names, vec!["30", "Ten", "20"]);
+    }
+
+    #[test]
+    fn bulk_registration_matches_one_at_a_time() {
+        let post = orig(-1001, 100);
+        let capped = orig(-1003, 1);
+        let mut copies = vec![
+            // Already tracked, and repeated within the burst
+            (post.clone(), fwd(10, 1)),
+            (post.clone(), fwd(30, 5)),
+            (post.clone(), fwd(30, 5)),
+            // A message id already indexed in its chat, for another post
+            (orig(-1002, 5), fwd(10, 3)),
+            (orig(-1002, 5), fwd(40, 1)),
+        ];
+        copies.extend((0..6).map(|i| (capped.clone(), fwd(50 + i, 9))));
+        copies.push((capped.clone(), fwd(50, 9)));
+
+        let mut one_by_one = populated();
+        let mut bulk = populated();
+        for t in [&mut one_by_one, &mut bulk] {
+            t.set_max_forwards_per_original(Some(4));
+        }
+        for (original, forward) in copies.clone() {
+            one_by_one.register_forward(original, forward);
+        }
+        bulk.register_forwards(copies);
+
+        assert_eq!(bulk.originals, one_by_one.originals);
+        assert_eq!(bulk.forward_index, one_by_one.forward_index);
+        assert_eq!(bulk.chat_index, one_by_one.chat_index);
+        assert_eq!(bulk.source_index, one_by_one.source_index);
+        assert_eq!(bulk.first_seen, one_by_one.first_seen);
+        assert_eq!(bulk.registered_at, one_by_one.registered_at);
+        assert_eq!(bulk.changes(), one_by_one.changes());
+        assert_eq!(bulk.live_counts().forwards(), one_by_one.live_counts().forwards());
+        assert_eq!(bulk.originals.get(&capped).unwrap().len(), 4);
+    }
+
+    #[test]
+    fn bulk_registration_of_a_hot_original_dedups() {
+        let mut t = DuplicateTracker::default();
+        let post = orig(1, 100);
+        let copies = (0..2000).map(|i| (post.clone(), fwd(10 + i % 500, (i % 500) as i32)));
+        t.register_forwards(copies);
+
+        assert_eq!(t.forward_count(&post), 500);
+        assert_eq!(t.stats().forwards, 500);
+        assert_eq!(t.live_counts().forwards(), 500);
+        assert_eq!(t.lookup_forward(&fwd(10, 0)), Some(&post));
+    }
+
+    #[test]
+    fn forward_cap_stops_tracking_new_forwards() {
+        let mut t = DuplicateTracker::default();
+        t.set_max_forwards_per_original(Some(2));
+        let o = orig(1, 100);
+
+        t.register_forward(o.clone(), fwd(10, 1));
+        t.register_forward(o.clone(), fwd(20, 2));
+        t.register_forward(o.clone(), fwd(30, 3));
+
+        assert_eq!(t.originals.get(&o).unwrap().len(), 2);
+        assert_eq!(t.lookup_forward(&fwd(30, 3)), None);
+        assert!(t.find_read_originals_in_chat(30, 3).is_empty());
+
+        // Re-registering a retained forward at the cap is still a no-op
+        t.register_forward(o.clone(), fwd(10, 1));
+        assert_eq!(t.originals.get(&o).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn forward_cap_keeps_retained_forwards_working() {
+        let mut t = DuplicateTracker::default();
+        t.set_max_forwards_per_original(Some(2));
+        let o = orig(1, 100);
+        let other = orig(1, 101);
+
+        t.register_forward(o.clone(), fwd(10, 1));
+        t.register_forward(o.clone(), fwd(20, 2));
+        t.register_forward(o.clone(), fwd(30, 3));
+        // The cap is per original
+        t.register_forward(other.clone(), fwd(30, 4));
+
+        assert_eq!(t.lookup_forward(&fwd(10, 1)), Some(&o));
+        assert_eq!(t.lookup_forward(&fwd(20, 2)), Some(&o));
+        assert_eq!(t.lookup_forward(&fwd(30, 4)), Some(&other));
+        assert_eq!(t.find_read_originals_in_chat(20, 2), vec![o.clone()]);
+
+        let marked = t.mark_original_read(&o);
+        assert_eq!(marked, vec![fwd(10, 1), fwd(20, 2)]);
+    }
+
+    /// Every index must agree with `originals` after a removal.
+    fn assert_consistent(t: &DuplicateTracker) {
+        let forward_count: usize = t.originals.values().map(Vec::len).sum();
+        assert_eq!(t.forward_index.len(), forward_count);
+        let chat_count: usize = t.chat_index.values().map(Vec::len).sum();
+        assert_eq!(chat_count, forward_count);
+        let source_count: usize = t.source_index.values().map(Vec::len).sum();
+        assert_eq!(source_count, t.originals.len());
+        for (o, forwards) in &t.originals {
+            assert!(t.first_seen.contains_key(o));
+            for f in forwards {
+                assert_eq!(t.forward_index.get(f), Some(o));
+            }
+        }
+        assert!(t.read_originals.iter().all(|o| t.originals.contains_key(o)));
+    }
+
+    #[test]
+    fn forget_original_removes_it_everywhere() {
+        let mut t = DuplicateTracker::default();
+        let o = orig(1, 100);
+        let keep = orig(1, 101);
+        t.register_forward(o.clone(), fwd(10, 50));
+        t.register_forward(o.clone(), fwd(20, 60));
+        t.register_forward(keep.clone(), fwd(10, 51));
+        t.mark_original_read(&o);
+
+        assert!(t.forget_original(&o));
+        assert!(!t.forget_original(&o));
+
+        assert!(!t.originals.contains_key(&o));
+        assert!(!t.is_original_read(&o));
+        assert_eq!(t.lookup_forward(&fwd(20, 60)), None);
+        assert!(!t.chat_index.contains_key(&20));
+        assert_eq!(t.originals_for_source(1), &[keep.clone()][..]);
+        assert_eq!(t.find_read_originals_in_chat(10, 100), vec![keep]);
+        assert_consistent(&t);
+    }
+
+    #[test]
+    fn forget_chat_removes_forwards_in_it_and_originals_from_it() {
+        let mut t = DuplicateTracker::default();
+        let shared = orig(1, 100); // forwarded to chats 10 and 20
+        let only_here = orig(1, 101); // forwarded only to chat 10
+        let from_chat = orig(10, 5); // chat 10 is itself the source
+        t.register_forward(shared.clone(), fwd(10, 50));
+        t.register_forward(shared.clone(), fwd(20, 60));
+        t.register_forward(only_here.clone(), fwd(10, 51));
+        t.register_forward(from_chat.clone(), fwd(30, 70));
+
+        // 2 forwards in chat 10, plus from_chat's single forward
+        assert_eq!(t.forget_chat(10), 3);
+
+        assert_eq!(t.originals.get(&shared).unwrap(), &vec![fwd(20, 60)]);
+        assert!(!t.originals.contains_key(&only_here));
+        assert!(!t.originals.contains_key(&from_chat));
+        assert!(t.find_read_originals_in_chat(10, 100).is_empty());
+        assert_eq!(t.lookup_forward(&fwd(30, 70)), None);
+        assert_consistent(&t);
+    }
+
+    #[test]
+    fn forget_forwards_in_chat_keeps_originals_from_it() {
+        let mut t = DuplicateTracker::default();
+        let from_chat = orig(10, 5);
+        t.register_forward(orig(1, 100), fwd(10, 50));
+        t.register_forward(from_chat.clone(), fwd(30, 70));
+
+        assert_eq!(t.forget_forwards_in_chat(10), 1);
+
+        // Chat 10's single forward is gone along with its now-empty original,
+        // but originals sourced from chat 10 are untouched
+        assert!(!t.originals.contains_key(&orig(1, 100)));
+        assert_eq!(t.lookup_forward(&fwd(30, 70)), Some(&from_chat));
+        assert_consistent(&t);
+    }
+
+    #[test]
+    fn unread_duplicates_skip_single_copies_and_read_posts() {
+        let mut t = DuplicateTracker::default();
+        t.register_forward(orig(1, 100), fwd(10, 50));
+        t.register_forward(orig(1, 100), fwd(20, 60));
+        t.register_forward(orig(1, 101), fwd(10, 51));
+        t.register_forward(orig(1, 102), fwd(10, 52));
+        t.register_forward(orig(1, 102), fwd(30, 70));
+        t.mark_original_read(&orig(1, 102));
+
+        let mut dups = t.unread_duplicates();
+        dups.sort_by_key(|(_, f)| f.chat_id);
+        assert_eq!(
+            dups,
+            vec![(orig(1, 100), fwd(10, 50)), (orig(1, 100), fwd(20, 60))]
+        );
+    }
+
+    #[test]
+    fn unresolvable_chats_cross_references_cache() {
+        let mut t = DuplicateTracker::default();
+        t.register_forward(orig(1, 100), fwd(10, 50));
+        t.register_forward(orig(1, 101), fwd(10, 51));
+        t.register_forward(orig(1, 100), fwd(20, 60));
+        t.register_forward(orig(1, 102), fwd(30, 70));
+
+        let cache: HashSet<i64> = [20].into_iter().collect();
+        let missing = t.unresolvable_chats(|chat_id| cache.contains(&chat_id));
+        assert_eq!(missing, vec![(10, 2), (30, 1)]);
+
+        assert!(t.unresolvable_chats(|_| true).is_empty());
+    }
+
+    #[test]
+    fn changes_count_new_forwards_and_reads_only() {
+        let mut t = DuplicateTracker::default();
+        let o = orig(1, 100);
+
+        t.register_forward(o.clone(), fwd(10, 50));
+        t.register_forward(o.clone(), fwd(20, 60));
+        t.register_forward(o.clone(), fwd(20, 60)); // duplicate
+        assert_eq!(t.changes(), 2);
+
+        t.mark_original_read(&o);
+        t.mark_original_read(&o); // already read
+        assert_eq!(t.changes(), 3);
+    }
+
+    #[test]
+    fn cleanup_age_boundary_is_exact() {
+        let (mut t, clock) = at(1000);
+        t.register_forward(orig(1, 100), fwd(10, 50));
+
+        // Exactly max_age old is kept, one second more is removed
+        clock.set(1060);
+        assert_eq!(t.cleanup(60), 0);
+        clock.set(1061);
+        assert_eq!(t.cleanup(60), 1);
+    }
+
+    #[test]
+    fn cleanup_keeps_the_cutoff_and_newer_removes_older() {
+        let (mut t, clock) = at(1000);
+        let (older, exact, newer) = (orig(1, 1), orig(1, 2), orig(1, 3));
+        t.register_forward(older.clone(), fwd(10, 1));
+        clock.set(1001);
+        t.register_forward(exact.clone(), fwd(10, 2));
+        clock.set(1002);
+        t.register_forward(newer.clone(), fwd(10, 3));
+
+        // now - max_age = 1001, the exact entry's first_seen
+        clock.set(1101);
+        assert_eq!(t.cleanup(100), 1);
+        assert!(!t.is_tracked(&older));
+        assert!(t.is_tracked(&exact));
+        assert!(t.is_tracked(&newer));
+        assert_consistent(&t);
+    }
+
+    #[test]
+    fn cleanup_with_age_beyond_the_epoch_removes_nothing() {
+        let (mut t, _clock) = at(50);
+        t.register_forward(orig(1, 1), fwd(10, 1));
+        assert_eq!(t.cleanup(100), 0);
+    }
+
+    #[test]
+    fn cleanup_survives_the_clock_going_backward() {
+        let (mut t, clock) = at(10_000);
+        let (o, f) = (orig(1, 100), fwd(10, 50));
+        t.register_forward(o.clone(), f.clone());
+        t.mark_original_read(&o);
+
+        // Corrected back by 9000s: the entry looks like it is from the future
+        clock.set(1000);
+        assert_eq!(t.forward_age(&f), Some(0));
+        assert_eq!(t.cleanup(60), 0);
+        assert_eq!(t.first_seen[&o], 1000);
+        assert_eq!(t.read_at[&o], 1000);
+        assert_eq!(t.forward_age(&f), Some(0));
+
+        // It ages from the correction, rather than from 10_000
+        clock.set(1060);
+        assert_eq!(t.cleanup(60), 0);
+        clock.set(1061);
+        assert_eq!(t.cleanup(60), 1);
+        assert_consistent(&t);
+    }
+
+    #[tokio::test]
+    async fn future_timestamps_are_clamped_on_load() {
+        let file = NamedTempFile::new().unwrap();
+        let ahead = epoch_secs() + 1_000_000;
+        let (mut t, _clock) = at(ahead);
+        let o = orig(1, 100);
+        t.register_forward(o.clone(), fwd(10, 50));
+        t.mark_original_read(&o);
+        t.save(file.path()).await.unwrap();
+
+        let loaded = DuplicateTracker::load(file.path()).unwrap();
+        assert!(loaded.first_seen[&o] <= epoch_secs());
+        assert!(loaded.read_at[&o] <= epoch_secs());
+    }
+
+    #[test]
+    fn first_seen_is_not_moved_by_later_forwards() {
+        let (mut t, clock) = at(1000);
+        let o = orig(1, 100);
+        t.register_forward(o.clone(), fwd(10, 50));
+        clock.advance(500);
+        t.register_forward(o.clone(), fwd(20, 60));
+
+        assert_eq!(t.first_seen[&o], 1000);
+        assert_eq!(t.originals_since(1001), Vec::<&OriginalMessageId>::new());
+    }
+
+    #[test]
+    fn cleanup_before_uses_absolute_cutoff() {
+        let (mut t, clock) = at(999);
+        let (a, b, c) = (orig(1, 1), orig(1, 2), orig(1, 3));
+        t.register_forward(a.clone(), fwd(10, 1));
+        clock.advance(1);
+        t.register_forward(b.clone(), fwd(10, 2));
+        clock.advance(1);
+        t.register_forward(c.clone(), fwd(10, 3));
+
+        // Strictly before the cutoff is removed; exactly at it is kept
+        assert_eq!(t.cleanup_before(1000), 1);
+        assert!(!t.originals.contains_key(&a));
+        assert!(t.originals.contains_key(&b));
+        assert!(t.originals.contains_key(&c));
+    }
+
+    #[test]
+    fn originals_since_includes_boundary_and_sorts_oldest_first() {
+        let (mut t, clock) = at(999);
+        let (a, b, c) = (orig(1, 1), orig(1, 2), orig(1, 3));
+        t.register_forward(a.clone(), fwd(10, 1));
+        clock.set(1001);
+        t.register_forward(b.clone(), fwd(10, 2));
+        clock.set(1000);
+        t.register_forward(c.clone(), fwd(10, 3));
+
+        assert_eq!(t.originals_since(1000), vec![&c, &b]);
+        assert_eq!(t.originals_since(1002), Vec::<&OriginalMessageId>::new());
+        assert_eq!(t.originals_since(0).len(), 3);
+    }
+
+    #[test]
+    fn export_dot_links_originals_to_forward_chats() {
+        let mut t = DuplicateTracker::default();
+        t.register_forward(orig(-1005, 7), fwd(10, 1));
+        t.register_forward(orig(-1005, 7), fwd(20, 2));
+        t.register_forward(orig(-1005, 7), fwd(20, 3));
+        t.register_forward(orig(-1006, 8), fwd(10, 4));
+        t.mark_original_read(&orig(-1006, 8));
+        let names = |id: i64| (id == -1005).then(|| "Tech News".to_owned());
+
+        let mut out = Vec::new();
+        t.export_dot(&mut out, names).unwrap();
+        let dot = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = dot.lines().collect();
+
+        assert_eq!(lines.first(), Some(&"digraph duplicates {"));
+        assert_eq!(lines.last(), Some(&"}"));
+        for expected in [
+            r#"    "o-1005_7" [label="Tech News #7"];"#,
+            r#"    "o-1006_8" [label="-1006 #8", style=filled];"#,
+            r#"    "c10" [label="10", shape=box];"#,
+            r#"    "c20" [label="20", shape=box];"#,
+            r#"    "o-1005_7" -> "c10";"#,
+            r#"    "o-1005_7" -> "c20";"#,
+            r#"    "o-1006_8" -> "c10";"#,
+        ] {
+            assert!(lines.contains(&expected), "missing {:?} in\n{}", expected, dot);
+        }
+        // Two copies in chat 20 are still one edge
+        assert_eq!(lines.len(), 2 + 4 + 3);
+    }
+
+    #[test]
+    fn export_dot_escapes_labels() {
+        let mut t = DuplicateTracker::default();
+        t.register_forward(orig(-1005, 7), fwd(10, 1));
+        let names = |_| Some("say \"hi\"\\\nbye".to_owned());
+
+        let mut out = Vec::new();
+        t.export_dot(&mut out, names).unwrap();
+        let dot = String::from_utf8(out).unwrap();
+
+        assert!(dot.contains(r#"[label="say \"hi\"\\\nbye", shape=box]"#), "{}", dot);
+        assert_eq!(dot.lines().count(), 5, "no raw newline inside a label");
+    }
+
+    #[test]
+    fn migrated_chats_lose_their_copies_but_keep_their_posts() {
+        let mut t = DuplicateTracker::default();
+        // Posts from the migrated chat, and copies in it of other posts
+        t.register_forward(orig(-5, 1), fwd(10, 100));
+        t.register_forward(orig(-5, 1), fwd(20, 200));
+        t.register_forward(orig(-1006, 2), fwd(-5, 7));
+        t.register_forward(orig(-1006, 2), fwd(30, 300));
+        t.register_forward(orig(-1006, 3), fwd(-5, 8));
+        t.unmarkable_chats().insert(-5);
+        let before = t.changes();
+
+        assert_eq!(t.forget_migrated_chat(-5), 2);
+
+        // Message 7 of the supergroup is some other message, so nothing
+        // moved there
+        assert_eq!(t.lookup_forward(&fwd(-5, 7)), None);
+        assert_eq!(t.lookup_forward(&fwd(-1009, 7)), None);
+        assert!(t.find_read_originals_in_chat(-1009, 7).is_empty());
+        assert_eq!(t.mark_original_read(&orig(-1006, 2)), vec![fwd(30, 300)]);
+        assert!(!t.is_tracked(&orig(-1006, 3)));
+        // The old chat's posts still propagate between their copies
+        assert_eq!(t.forward_count(&orig(-5, 1)), 2);
+        assert_eq!(t.originals_for_source(-5), &[orig(-5, 1)]);
+        assert!(t.unmarkable_chats().chats().is_empty());
+        assert!(t.changes() > before);
+        assert_consistent(&t);
+    }
+
+    #[test]
+    fn migrating_a_chat_without_copies_changes_nothing() {
+        let mut t = DuplicateTracker::default();
+        t.register_forward(orig(-5, 1), fwd(30, 300));
+        let before = t.changes();
+        assert_eq!(t.forget_migrated_chat(-5), 0);
+        assert_eq!(t.changes(), before);
+        assert!(t.is_tracked(&orig(-5, 1)));
+    }
 
-        let count = old_originals.len();
-        for orig in &old_originals {
-            if let Some(forwards) = self.originals.remove(orig) {
-                for fwd in &forwards {
-                    self.forward_index.remove(fwd);
-                    if let Some(chat_entries) = self.chat_index.get_mut(&fwd.chat_id) {
-                        chat_entries.retain(|(mid, _)| *mid != fwd.message_id);
-                        if chat_entries.is_empty() {
-                            self.chat_index.remove(&fwd.chat_id);
-                        }
-                    }
-                }
+    #[test]
+    fn stats_counts_state() {
+        let mut t = DuplicateTracker::default();
+        t.register_forward(orig(1, 100), fwd(10, 50));
+        t.register_forward(orig(1, 100), fwd(20, 60));
+        t.register_forward(orig(2, 100), fwd(10, 51));
+        t.mark_original_read(&orig(1, 100));
+
+        assert_eq!(
+            t.stats(),
+            TrackerStats {
+                originals: 2,
+                forwards: 3,
+                read_originals: 1,
+                chats: 2,
+                sources: 2,
+                latency: None,
             }
-            self.read_originals.remove(orig);
-            self.first_seen.remove(orig);
-        }
-        if count > 0 {
-            info!("Cleaned up {} old entries", count);
-        }
+        );
     }
 
-    /// Rebuild the chat_index from forward_index.
-    fn rebuild_chat_index(&mut self) {
-        self.chat_index.clear();
-        for (fwd, orig) in &self.forward_index {
-            self.chat_index
-                .entry(fwd.chat_id)
-                .or_default()
-                .push((fwd.message_id, orig.clone()));
-        }
+    /// The live counters agree with the maps behind `stats()`.
+    fn assert_live_matches(t: &DuplicateTracker, live: &LiveCounts) {
+        let stats = t.stats();
+        assert_eq!(live.originals(), stats.originals as u64);
+        assert_eq!(live.forwards(), stats.forwards as u64);
+        assert_eq!(live.read_originals(), stats.read_originals as u64);
+        assert_eq!(live.chats(), stats.chats as u64);
+        assert_eq!(live.sources(), stats.sources as u64);
     }
 
-    /// Load state from a JSON file.
-    pub fn load(path: &Path) -> Result<Self> {
-        let data = std::fs::read_to_string(path)
-            .context("Failed to read state file")?;
-        let mut tracker: Self =
-            serde_json::from_str(&data).context("Failed to parse state file")?;
-        // chat_index is skipped during serde, always rebuild it
-        tracker.rebuild_chat_index();
-        Ok(tracker)
+    #[test]
+    fn live_stats_report_what_stats_does() {
+        let mut t = DuplicateTracker::default();
+        let live = t.live_stats();
+        t.register_forward(orig(1, 100), fwd(10, 50));
+        t.register_forward(orig(2, 100), fwd(20, 60));
+        t.mark_original_read(&orig(1, 100));
+        t.propagation_latency().lock().unwrap().record(Duration::from_millis(40));
+        assert_eq!(live.stats(), t.stats());
+        assert_eq!(live.last_save(), None);
     }
 
-    /// Save state to a JSON file atomically (write .tmp then rename).
-    pub fn save(&self, path: &Path) -> Result<()> {
-        let tmp_path = path.with_extension("json.tmp");
-        let data = serde_json::to_string_pretty(self)
-            .context("Failed to serialize state")?;
-        std::fs::write(&tmp_path, data)
-            .context("Failed to write temp state file")?;
-        std::fs::rename(&tmp_path, path)
-            .context("Failed to rename temp state file")?;
-        Ok(())
-    }
-}
+    #[test]
+    fn live_counts_follow_every_mutation() {
+        let (mut t, clock) = at(1000);
+        let live = t.live_counts();
+        assert_live_matches(&t, &live);
 
-fn epoch_secs() -> u64 {
-    std::time::SystemTime::now()
-        .duration_since(std::time::UNIX_EPOCH)
-        .unwrap()
-        .as_secs()
-}
+        t.register_forward(orig(1, 100), fwd(10, 50));
+        t.register_forward(orig(1, 100), fwd(20, 60));
+        t.register_forward(orig(1, 100), fwd(20, 60));
+        t.register_forward(orig(2, 100), fwd(10, 51));
+        t.register_forward(orig(3, 100), fwd(30, 70));
+        assert_eq!((live.originals(), live.forwards()), (3, 4));
+        t.mark_original_read(&orig(1, 100));
+        t.mark_original_read(&orig(1, 100));
+        assert_eq!(live.read_originals(), 1);
+        assert_live_matches(&t, &live);
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use tempfile::NamedTempFile;
+        t.forget_forwards_in_chat(20);
+        assert_live_matches(&t, &live);
+        t.forget_chat(3);
+        assert_live_matches(&t, &live);
+        t.register_forward(orig(6, 100), fwd(60, 95));
+        t.forget_migrated_chat(60);
+        assert_live_matches(&t, &live);
 
-    fn orig(peer: i64, msg: i32) -> OriginalMessageId {
-        OriginalMessageId { peer_id: peer, message_id: msg }
+        let (mut other, _) = at(1000);
+        other.register_forward(orig(4, 100), fwd(40, 80));
+        other.mark_original_read(&orig(4, 100));
+        t.merge(other);
+        assert_live_matches(&t, &live);
+        assert_eq!(live.read_originals(), 2);
+
+        clock.advance(100);
+        t.register_forward(orig(5, 100), fwd(50, 90));
+        t.cleanup_before(1050);
+        assert_live_matches(&t, &live);
+        assert_eq!((live.originals(), live.forwards()), (1, 1));
+        t.forget_original(&orig(5, 100));
+        assert_live_matches(&t, &live);
+        assert_eq!(live.originals(), 0);
     }
 
-    fn fwd(chat: i64, msg: i32) -> ForwardLocation {
-        ForwardLocation { chat_id: chat, message_id: msg }
+    #[tokio::test]
+    async fn live_counts_of_a_loaded_tracker_start_from_the_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let mut t = DuplicateTracker::default();
+        t.register_forward(orig(1, 100), fwd(10, 50));
+        t.register_forward(orig(1, 100), fwd(20, 60));
+        t.mark_original_read(&orig(1, 100));
+        t.save(&path).await.unwrap();
+
+        let loaded = DuplicateTracker::load(&path).unwrap();
+        assert_live_matches(&loaded, &loaded.live_counts());
+        assert_eq!(loaded.live_counts().forwards(), 2);
     }
 
     #[test]
-    fn register_and_lookup() {
-        let mut t = DuplicateTracker::default();
-        let o = orig(1, 100);
-        let f = fwd(2, 200);
+    fn forward_location_thread_id_serialization() {
+        // Older state files have no top_msg_id
+        let old: ForwardLocation =
+            serde_json::from_str(r#"{"chat_id":10,"message_id":50}"#).unwrap();
+        assert_eq!(old.top_msg_id, None);
 
-        t.register_forward(o.clone(), f.clone());
+        // Absent thread id is not written, so new files stay compatible
+        let json = serde_json::to_string(&fwd(10, 50)).unwrap();
+        assert!(!json.contains("top_msg_id"));
 
-        assert_eq!(t.lookup_forward(&f), Some(&o));
-        assert!(!t.is_original_read(&o));
+        let threaded = ForwardLocation {
+            top_msg_id: Some(7),
+            ..fwd(10, 50)
+        };
+        let json = serde_json::to_string(&threaded).unwrap();
+        let back: ForwardLocation = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.top_msg_id, Some(7));
     }
 
     #[test]
-    fn register_duplicate_forward_is_idempotent() {
+    fn forward_location_identity_ignores_thread() {
         let mut t = DuplicateTracker::default();
         let o = orig(1, 100);
-        let f = fwd(2, 200);
-
-        t.register_forward(o.clone(), f.clone());
-        t.register_forward(o.clone(), f.clone());
+        let threaded = ForwardLocation {
+            top_msg_id: Some(7),
+            ..fwd(10, 50)
+        };
+        t.register_forward(o.clone(), threaded);
 
-        // Should only have one entry, not two
-        assert_eq!(t.originals.get(&o).unwrap().len(), 1);
+        assert_eq!(t.lookup_forward(&fwd(10, 50)), Some(&o));
+        // The thread id survives for propagation
+        assert_eq!(t.mark_original_read(&o)[0].top_msg_id, Some(7));
     }
 
     #[test]
-    fn multiple_forwards_of_same_original() {
+    fn edit_refreshes_preview_without_new_original() {
         let mut t = DuplicateTracker::default();
         let o = orig(1, 100);
-        let f1 = fwd(2, 200);
-        let f2 = fwd(3, 300);
-
-        t.register_forward(o.clone(), f1.clone());
-        t.register_forward(o.clone(), f2.clone());
+        t.register_forward(o.clone(), fwd(10, 50));
+        t.set_preview_if_absent(&o, "first".to_owned());
+        // A later copy doesn't overwrite the preview
+        t.register_forward(o.clone(), fwd(20, 60));
+        t.set_preview_if_absent(&o, "second copy".to_owned());
+        assert_eq!(t.preview(&o), Some("first"));
 
-        assert_eq!(t.originals.get(&o).unwrap().len(), 2);
-        assert_eq!(t.lookup_forward(&f1), Some(&o));
-        assert_eq!(t.lookup_forward(&f2), Some(&o));
+        let before = t.changes();
+        assert!(t.refresh_preview(&o, "edited".to_owned()));
+        assert_eq!(t.preview(&o), Some("edited"));
+        assert_eq!(t.changes(), before + 1);
+        // Same text again is not a change
+        assert!(!t.refresh_preview(&o, "edited".to_owned()));
+        assert_eq!(t.stats().originals, 1);
+        assert_consistent(&t);
     }
 
     #[test]
-    fn mark_original_read_returns_all_forwards() {
+    fn previews_only_for_tracked_originals() {
         let mut t = DuplicateTracker::default();
         let o = orig(1, 100);
-        let f1 = fwd(2, 200);
-        let f2 = fwd(3, 300);
+        assert!(!t.refresh_preview(&o, "edited".to_owned()));
+        t.set_preview_if_absent(&o, "x".to_owned());
+        assert_eq!(t.preview(&o), None);
+        assert_eq!(t.stats().originals, 0);
 
-        t.register_forward(o.clone(), f1.clone());
-        t.register_forward(o.clone(), f2.clone());
+        t.register_forward(o.clone(), fwd(10, 50));
+        t.set_preview_if_absent(&o, "x".to_owned());
+        assert!(t.forget_original(&o));
+        assert_eq!(t.preview(&o), None);
+    }
 
-        let result = t.mark_original_read(&o);
-        assert_eq!(result.len(), 2);
-        assert!(result.contains(&f1));
-        assert!(result.contains(&f2));
-        assert!(t.is_original_read(&o));
+    #[tokio::test]
+    async fn save_replaces_existing_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        std::fs::write(&path, "old contents").unwrap();
+
+        let mut t = DuplicateTracker::default();
+        t.register_forward(orig(1, 100), fwd(10, 50));
+        t.save(&path).await.unwrap();
+        // Saving again over our own output works too
+        t.register_forward(orig(1, 100), fwd(20, 60));
+        t.save(&path).await.unwrap();
+
+        assert_eq!(DuplicateTracker::load(&path).unwrap().stats().forwards, 2);
+        assert!(!path.with_extension("json.tmp").exists());
+    }
+
+    #[cfg(windows)]
+    #[tokio::test]
+    async fn save_replaces_file_open_for_reading() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        DuplicateTracker::default().save(&path).await.unwrap();
+
+        // std opens files with FILE_SHARE_DELETE, like most readers do
+        let _reader = std::fs::File::open(&path).unwrap();
+        DuplicateTracker::default().save(&path).await.unwrap();
     }
 
     #[test]
-    fn find_read_originals_filters_by_chat_and_max_id() {
+    fn merge_disjoint_states_unions_everything() {
+        let mut a = DuplicateTracker::default();
+        a.register_forward(orig(1, 100), fwd(10, 50));
+        let mut b = DuplicateTracker::default();
+        b.register_forward(orig(2, 200), fwd(20, 60));
+        b.mark_original_read(&orig(2, 200));
+
+        a.merge(b);
+
+        assert_eq!(a.stats().originals, 2);
+        assert_eq!(a.lookup_forward(&fwd(20, 60)), Some(&orig(2, 200)));
+        assert!(a.is_original_read(&orig(2, 200)));
+        assert!(!a.is_original_read(&orig(1, 100)));
+        assert_eq!(a.find_read_originals_in_chat(10, 50), vec![orig(1, 100)]);
+        assert_consistent(&a);
+    }
+
+    #[tokio::test]
+    async fn unmarkable_chats_are_saved_and_follow_the_chat() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
         let mut t = DuplicateTracker::default();
-        let o1 = orig(1, 100);
-        let o2 = orig(1, 101);
+        t.register_forward(orig(1, 100), fwd(10, 50));
+        // The marker holds the same set and flags chats behind our back
+        let flags = t.unmarkable_chats();
+        flags.insert(10);
+        flags.insert(20);
+        t.save(&path).await.unwrap();
 
-        // Both forwarded to chat 10, different message IDs
-        t.register_forward(o1.clone(), fwd(10, 50));
-        t.register_forward(o2.clone(), fwd(10, 60));
+        let mut loaded = DuplicateTracker::load(&path).unwrap();
+        assert_eq!(loaded.unmarkable_chats().chats(), vec![10, 20]);
+        loaded.forget_migrated_chat(10);
+        assert_eq!(loaded.unmarkable_chats().chats(), vec![20]);
+        loaded.forget_chat(20);
+        assert!(loaded.unmarkable_chats().chats().is_empty());
 
-        // max_id=55 should only find the forward with msg_id=50
-        let found = t.find_read_originals_in_chat(10, 55);
-        assert_eq!(found.len(), 1);
-        assert_eq!(found[0], o1);
+        let other = DuplicateTracker::default();
+        other.unmarkable_chats().insert(30);
+        loaded.merge(other);
+        assert_eq!(loaded.unmarkable_chats().chats(), vec![30]);
 
-        // max_id=60 should find both
-        let found = t.find_read_originals_in_chat(10, 60);
-        assert_eq!(found.len(), 2);
+        // Older state files have none
+        std::fs::write(&path, r#"{"originals":[],"forward_index":[],"read_originals":[]}"#)
+            .unwrap();
+        assert!(DuplicateTracker::load(&path).unwrap().unmarkable_chats().chats().is_empty());
     }
 
     #[test]
-    fn find_read_originals_excludes_already_read() {
+    fn pending_marks_remember_when_they_were_first_planned() {
+        let pending = PendingMarks::default();
+        let o = orig(1, 100);
+        let both = [(o.clone(), fwd(10, 1)), (o.clone(), fwd(20, 2))];
+        pending.add(&both[..1]);
+        let first = pending.planned_at(&both).unwrap();
+        pending.add(&both);
+        assert_eq!(pending.planned_at(&both), Some(first));
+
+        // Marks resumed after a restart carry no planning time
+        let json = serde_json::to_string(&pending).unwrap();
+        let loaded: PendingMarks = serde_json::from_str(&json).unwrap();
+        assert_eq!(loaded.len(), 2);
+        assert_eq!(loaded.planned_at(&both), None);
+
+        pending.confirm(&both);
+        assert_eq!(pending.planned_at(&both), None);
+    }
+
+    #[tokio::test]
+    async fn pending_marks_survive_a_restart_until_confirmed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
         let mut t = DuplicateTracker::default();
+        t.register_forward(orig(1, 100), fwd(10, 50));
+        t.register_forward(orig(1, 100), fwd(20, 60));
+        t.register_forward(orig(1, 100), fwd(30, 70));
+        let planned: Vec<_> = t
+            .mark_original_read(&orig(1, 100))
+            .into_iter()
+            .map(|f| (orig(1, 100), f))
+            .collect();
+        t.pending_marks().add(&planned);
+        t.pending_marks().confirm(&planned[..1]);
+        t.save(&path).await.unwrap();
+
+        let mut loaded = DuplicateTracker::load(&path).unwrap();
+        assert_eq!(loaded.resumable_marks(), planned[1..].to_vec());
+
+        // A copy forgotten in the meantime is no longer resumed
+        loaded.forget_chat(30);
+        assert_eq!(loaded.resumable_marks(), vec![(orig(1, 100), fwd(20, 60))]);
+        assert_eq!(loaded.pending_marks().len(), 1);
+
+        std::fs::write(&path, r#"{"originals":[],"forward_index":[],"read_originals":[]}"#)
+            .unwrap();
+        assert!(DuplicateTracker::load(&path).unwrap().pending_marks().is_empty());
+    }
+
+    #[test]
+    fn merge_overlapping_states_resolves_conflicts() {
         let o = orig(1, 100);
-        t.register_forward(o.clone(), fwd(10, 50));
+        let mut a = DuplicateTracker::default();
+        a.register_forward(o.clone(), fwd(10, 50));
+        a.register_forward(o.clone(), fwd(20, 60));
+        a.first_seen.insert(o.clone(), 2_000);
+        a.set_preview_if_absent(&o, "from a".to_owned());
 
-        // Mark as read first
+        let mut b = DuplicateTracker::default();
+        b.register_forward(o.clone(), fwd(20, 60));
+        b.register_forward(o.clone(), fwd(30, 70));
+        // The same location attributed to another original
+        b.register_forward(orig(9, 900), fwd(10, 50));
+        b.first_seen.insert(o.clone(), 1_000);
+        b.set_preview_if_absent(&o, "from b".to_owned());
+        b.mark_original_read(&o);
+
+        a.merge(b);
+
+        let mut forwards: Vec<i64> = a.originals[&o].iter().map(|f| f.chat_id).collect();
+        forwards.sort_unstable();
+        assert_eq!(forwards, vec![10, 20, 30]);
+        // Our attribution of (10, 50) stands and the loser gets no forwards
+        assert_eq!(a.lookup_forward(&fwd(10, 50)), Some(&o));
+        assert!(!a.originals.contains_key(&orig(9, 900)));
+        // Read wins, earliest first_seen wins, our preview wins
+        assert!(a.is_original_read(&o));
+        assert_eq!(a.first_seen[&o], 1_000);
+        assert_eq!(a.preview(&o), Some("from a"));
+        assert_consistent(&a);
+    }
+
+    #[test]
+    fn load_errors_are_typed() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing.json");
+        assert!(matches!(
+            DuplicateTracker::load(&missing),
+            Err(TrackerError::Io { action: "read", .. })
+        ));
+
+        let garbage = dir.path().join("garbage.json");
+        std::fs::write(&garbage, "not json").unwrap();
+        let err = DuplicateTracker::load(&garbage).unwrap_err();
+        assert!(matches!(err, TrackerError::Parse { .. }));
+        assert!(err.to_string().contains("garbage.json"));
+    }
+
+    /// A tracker exercising every persisted field, threads included.
+    fn populated() -> DuplicateTracker {
+        let (mut t, _clock) = at(1000);
+        let o = orig(-1001, 100);
+        t.register_forward(o.clone(), fwd(10, 1));
+        t.register_forward(
+            o.clone(),
+            ForwardLocation {
+                top_msg_id: Some(7),
+                ..fwd(20, 2)
+            },
+        );
+        t.register_forward(orig(-1001, 101), fwd(10, 3));
+        t.set_preview_if_absent(&o, "hello".to_owned());
         t.mark_original_read(&o);
+        t
+    }
 
-        // Should not appear again
-        let found = t.find_read_originals_in_chat(10, 50);
-        assert!(found.is_empty());
+    #[tokio::test]
+    async fn both_formats_round_trip_and_load_detects_them() {
+        let dir = tempfile::tempdir().unwrap();
+        for format in [StateFormat::Json, StateFormat::Bincode] {
+            let mut t = populated();
+            t.set_state_format(format);
+            let path = dir.path().join(format!("state.{}", format));
+            t.save(&path).await.unwrap();
+
+            let data = std::fs::read(&path).unwrap();
+            assert_eq!(data.starts_with(CHECKED_BINCODE_MAGIC), format == StateFormat::Bincode);
+            assert_eq!(data.starts_with(JSON_CHECKSUM_KEY), format == StateFormat::Json);
+
+            let loaded = DuplicateTracker::load(&path).unwrap();
+            assert_eq!(loaded.stats(), t.stats(), "{}", format);
+            assert!(loaded.is_original_read(&orig(-1001, 100)));
+            assert_eq!(loaded.preview(&orig(-1001, 100)), Some("hello"));
+            assert_eq!(loaded.read_at, t.read_at);
+            assert_eq!(loaded.first_seen, t.first_seen);
+            let threaded = loaded.originals[&orig(-1001, 100)]
+                .iter()
+                .find(|f| f.chat_id == 20)
+                .unwrap();
+            assert_eq!(threaded.top_msg_id, Some(7));
+            assert_eq!(
+                loaded.find_read_originals_in_chat(10, 3),
+                vec![orig(-1001, 101)]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn bincode_is_smaller_than_json() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut t = populated();
+        let json = dir.path().join("state.json");
+        t.save(&json).await.unwrap();
+        t.set_state_format(StateFormat::Bincode);
+        let bin = dir.path().join("state.bin");
+        t.save(&bin).await.unwrap();
+
+        let size = |p: &Path| std::fs::metadata(p).unwrap().len();
+        assert!(size(&bin) < size(&json));
     }
 
     #[test]
-    fn find_read_originals_empty_for_unknown_chat() {
-        let t = DuplicateTracker::default();
-        assert!(t.find_read_originals_in_chat(999, 100).is_empty());
+    fn corrupt_bincode_is_a_decode_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let mut data = BINCODE_MAGIC.to_vec();
+        data.extend_from_slice(b"\xff\xff");
+        std::fs::write(&path, data).unwrap();
+
+        let err = DuplicateTracker::load(&path).unwrap_err();
+        assert!(matches!(err, TrackerError::Decode { .. }));
+        assert!(err.to_string().contains("state.json"));
+    }
+
+    #[tokio::test]
+    async fn tampered_state_fails_its_checksum() {
+        let dir = tempfile::tempdir().unwrap();
+        for format in [StateFormat::Json, StateFormat::Bincode] {
+            let mut t = populated();
+            t.set_state_format(format);
+            let path = dir.path().join(format!("state.{}", format));
+            t.save(&path).await.unwrap();
+
+            // Still well-formed, just not what was saved
+            let mut data = std::fs::read(&path).unwrap();
+            let i = match format {
+                StateFormat::Json => {
+                    let text = String::from_utf8_lossy(&data);
+                    text.find("\"message_id\": 100").unwrap() + "\"message_id\": 10".len()
+                }
+                StateFormat::Bincode => data.len() - 1,
+            };
+            data[i] ^= 1;
+            std::fs::write(&path, &data).unwrap();
+
+            let err = DuplicateTracker::load(&path).unwrap_err();
+            assert!(matches!(err, TrackerError::Corrupt { .. }), "{}: {}", format, err);
+            assert!(err.is_corrupt());
+            assert!(err.to_string().contains("doesn't match its contents"), "{}", err);
+        }
+    }
+
+    /// An unchecked bincode state, after `BINCODE_MAGIC`, as saved before
+    /// the chats refusing reads were: one read, previewed post first seen
+    /// at 1000, with a copy in chat 10 and one in thread 7 of chat 20.
+    #[rustfmt::skip]
+    const LEGACY_STATE: &[u8] = &[
+        // originals
+        0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x17, 0xfc, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0x64, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x01, 0x00, 0x00, 0x00, 0x00, 0x14, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x02, 0x00, 0x00, 0x00, 0x01, 0x07, 0x00, 0x00, 0x00,
+        // forward_index
+        0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0a, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x17, 0xfc, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0x64, 0x00, 0x00, 0x00, 0x14, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00, 0x00, 0x00, 0x01, 0x07, 0x00,
+        0x00, 0x00, 0x17, 0xfc, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x64, 0x00,
+        0x00, 0x00,
+        // read_originals
+        0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x17, 0xfc, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0x64, 0x00, 0x00, 0x00,
+        // read_at
+        0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x17, 0xfc, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0x64, 0x00, 0x00, 0x00, 0xe8, 0x03, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+        // first_seen
+        0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x17, 0xfc, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0x64, 0x00, 0x00, 0x00, 0xe8, 0x03, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00,
+        // previews
+        0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x17, 0xfc, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0x64, 0x00, 0x00, 0x00, 0x05, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x68, 0x65, 0x6c, 0x6c, 0x6f,
+    ];
+
+    /// Assert `t` holds what `LEGACY_STATE` does.
+    fn assert_legacy_state(t: &DuplicateTracker) {
+        let o = orig(-1001, 100);
+        assert_eq!(t.forwards_of(&o), [fwd(10, 1), fwd(20, 2)]);
+        assert_eq!(t.forwards_of(&o)[1].top_msg_id, Some(7));
+        assert!(t.is_original_read(&o));
+        assert_eq!(t.read_at[&o], 1000);
+        assert_eq!(t.first_seen[&o], 1000);
+        assert_eq!(t.preview(&o), Some("hello"));
+        assert_eq!(t.lookup_forward(&fwd(20, 2)), Some(&o));
+        // Marks weren't recorded yet, so copies of read posts count as marked
+        assert!(t.forward_details(&o).iter().all(ForwardDetail::is_read));
+        assert_consistent(t);
     }
 
     #[test]
-    fn cleanup_removes_old_entries() {
-        let mut t = DuplicateTracker::default();
-        let o = orig(1, 100);
-        let f = fwd(2, 200);
-        t.register_forward(o.clone(), f.clone());
+    fn bincode_state_from_before_unmarkable_chats_loads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        std::fs::write(&path, [BINCODE_MAGIC, LEGACY_STATE].concat()).unwrap();
 
-        // Backdate the first_seen to make it old
-        t.first_seen.insert(o.clone(), 0);
+        let t = DuplicateTracker::load(&path).unwrap();
+        assert_legacy_state(&t);
+        assert!(t.unmarkable.chats().is_empty());
+        assert!(t.pending.is_empty());
 
-        t.cleanup(1); // max_age_secs=1, anything older than ~now is removed
+        // Cut off inside the old layout is still an error
+        let cut = &LEGACY_STATE[..LEGACY_STATE.len() - 1];
+        std::fs::write(&path, [BINCODE_MAGIC, cut].concat()).unwrap();
+        assert!(matches!(DuplicateTracker::load(&path), Err(TrackerError::Decode { .. })));
+    }
 
-        assert!(t.originals.is_empty());
-        assert!(t.forward_index.is_empty());
-        assert!(t.read_originals.is_empty());
-        assert!(t.first_seen.is_empty());
-        assert!(t.chat_index.is_empty());
+    #[test]
+    fn bincode_state_from_before_pending_marks_loads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        // The unmarkable chats followed the previews: just chat -1002
+        #[rustfmt::skip]
+        let unmarkable: &[u8] = &[
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x16, 0xfc, 0xff, 0xff,
+            0xff, 0xff, 0xff, 0xff,
+        ];
+        std::fs::write(&path, [BINCODE_MAGIC, LEGACY_STATE, unmarkable].concat()).unwrap();
+
+        let t = DuplicateTracker::load(&path).unwrap();
+        assert_legacy_state(&t);
+        assert_eq!(t.unmarkable.chats(), vec![-1002]);
+        assert!(t.pending.is_empty());
     }
 
     #[test]
-    fn cleanup_keeps_recent_entries() {
-        let mut t = DuplicateTracker::default();
-        let o = orig(1, 100);
-        let f = fwd(2, 200);
-        t.register_forward(o.clone(), f.clone());
+    fn state_files_without_a_checksum_still_load() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        // JSON as saved before checksums, unmarkable chats or pending marks
+        let json = r#"{
+  "originals": [[{"peer_id": -1001, "message_id": 100},
+                 [{"chat_id": 10, "message_id": 1},
+                  {"chat_id": 20, "message_id": 2, "top_msg_id": 7}]]],
+  "forward_index": [[{"chat_id": 10, "message_id": 1}, {"peer_id": -1001, "message_id": 100}],
+                    [{"chat_id": 20, "message_id": 2, "top_msg_id": 7},
+                     {"peer_id": -1001, "message_id": 100}]],
+  "read_originals": [{"peer_id": -1001, "message_id": 100}],
+  "read_at": [[{"peer_id": -1001, "message_id": 100}, 1000]],
+  "first_seen": [[{"peer_id": -1001, "message_id": 100}, 1000]],
+  "previews": [[{"peer_id": -1001, "message_id": 100}, "hello"]]
+}"#;
+        std::fs::write(&path, json).unwrap();
+        assert_legacy_state(&DuplicateTracker::load(&path).unwrap());
 
-        // Very long max age — nothing should be cleaned
-        t.cleanup(999_999_999);
+        std::fs::write(&path, [BINCODE_MAGIC, LEGACY_STATE].concat()).unwrap();
+        assert_legacy_state(&DuplicateTracker::load(&path).unwrap());
 
-        assert_eq!(t.originals.len(), 1);
-        assert_eq!(t.forward_index.len(), 1);
+        // Unchecked files were also written in the layout with pending marks,
+        // which the marked copies now follow
+        let encoded = bincode::serialize(&populated()).unwrap();
+        let mut data = BINCODE_MAGIC.to_vec();
+        data.extend_from_slice(&encoded[..encoded.len() - 8]);
+        std::fs::write(&path, data).unwrap();
+        assert_eq!(DuplicateTracker::load(&path).unwrap().stats(), populated().stats());
     }
 
-    /// Simulates the discussion group scenario: a channel post forwarded to
-    /// two discussion groups. Reading the thread root (top_msg_id) in one
-    /// group should propagate to the other.
     #[test]
-    fn discussion_group_read_propagation() {
+    fn checked_bincode_from_before_marked_copies_loads() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
         let mut t = DuplicateTracker::default();
-        let o = orig(1, 100); // original channel post
+        let o = orig(1, 100);
+        t.register_forward(o.clone(), fwd(10, 1));
+        t.register_forward(o.clone(), fwd(20, 2));
+        t.mark_original_read(&o);
+        t.pending_marks().add(&[(o.clone(), fwd(20, 2))]);
+        // That layout ended with the pending marks; the marked copies are empty
+        let encoded = bincode::serialize(&t).unwrap();
+        let encoded = &encoded[..encoded.len() - 8];
+        let mut data = CHECKED_BINCODE_MAGIC_BEFORE_MARKED.to_vec();
+        data.extend_from_slice(&crc32fast::hash(encoded).to_le_bytes());
+        data.extend_from_slice(encoded);
+        std::fs::write(&path, data).unwrap();
 
-        // Same post forwarded to two discussion groups as thread roots
-        let f1 = fwd(10, 300); // discussion group A, thread root msg 300
-        let f2 = fwd(20, 400); // discussion group B, thread root msg 400
+        let loaded = DuplicateTracker::load(&path).unwrap();
+        let status: Vec<_> = loaded.forward_details(&o).iter().map(|d| d.status).collect();
+        assert_eq!(status, vec![ForwardStatus::Read, ForwardStatus::Pending]);
+        assert_eq!(loaded.pending_marks().len(), 1);
+    }
 
-        t.register_forward(o.clone(), f1.clone());
-        t.register_forward(o.clone(), f2.clone());
+    #[tokio::test]
+    async fn corrupt_state_falls_back_on_the_previous_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let mut t = DuplicateTracker::default();
+        t.register_forward(orig(1, 100), fwd(10, 50));
+        t.save(&path).await.unwrap();
+        t.register_forward(orig(1, 100), fwd(20, 60));
+        t.save(&path).await.unwrap();
 
-        // User opens thread in group A → ReadChannelDiscussionInbox
-        // with top_msg_id=300. We look up originals with msg_id <= 300
-        // in chat 10, which finds f1.
-        let found = t.find_read_originals_in_chat(10, 300);
-        assert_eq!(found.len(), 1);
-        assert_eq!(found[0], o);
+        // Cut off mid-write by something other than our own atomic save
+        let data = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &data[..data.len() / 2]).unwrap();
+        assert!(DuplicateTracker::load(&path).unwrap_err().is_corrupt());
 
-        // Mark original as read, get all forwards to propagate
-        let all_fwds = t.mark_original_read(&o);
-        assert_eq!(all_fwds.len(), 2);
+        let restored = DuplicateTracker::load_or_backup(&path).unwrap();
+        assert_eq!(restored.forward_count(&orig(1, 100)), 1);
+        assert!(!path.exists());
+        assert!(path.with_extension("json.corrupt").exists());
 
-        // Filter out the one the user already read (f1 in chat 10)
-        let to_mark: Vec<_> = all_fwds
-            .into_iter()
-            .filter(|f| !(f.chat_id == 10 && f.message_id <= 300))
-            .collect();
-        assert_eq!(to_mark.len(), 1);
-        assert_eq!(to_mark[0], f2);
+        // Without a usable backup the original error comes back
+        std::fs::write(&path, &data[..data.len() / 2]).unwrap();
+        std::fs::write(backup_path(&path), b"{").unwrap();
+        let err = DuplicateTracker::load_or_backup(&path).unwrap_err();
+        assert!(matches!(err, TrackerError::Corrupt { .. }), "{}", err);
+
+        // A missing file is not corruption and has no backup to try
+        let missing = dir.path().join("missing.json");
+        let err = DuplicateTracker::load_or_backup(&missing).unwrap_err();
+        assert!(matches!(err, TrackerError::Io { .. }));
     }
 
     #[test]
-    fn save_and_load_round_trip() {
+    fn state_formats_parse() {
+        assert_eq!("Bincode".parse::<StateFormat>().unwrap(), StateFormat::Bincode);
+        assert_eq!("json".parse::<StateFormat>().unwrap(), StateFormat::Json);
+        assert!("postcard".parse::<StateFormat>().is_err());
+    }
+
+    #[tokio::test]
+    async fn save_records_size_and_duration() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state.json");
+        let t = populated();
+        assert_eq!(t.last_save(), None);
+
+        t.save(&path).await.unwrap();
+
+        let metrics = t.last_save().unwrap();
+        assert_eq!(metrics.bytes, std::fs::metadata(&path).unwrap().len());
+        assert!(metrics.duration > Duration::ZERO);
+        assert!(metrics.to_string().starts_with(&format!("{} bytes in ", metrics.bytes)));
+    }
+
+    #[tokio::test]
+    async fn snapshot_saves_the_same_bytes_as_the_tracker() {
+        let dir = tempfile::tempdir().unwrap();
+        for format in [StateFormat::Json, StateFormat::Bincode] {
+            let mut t = populated();
+            t.set_state_format(format);
+            let locked = dir.path().join("locked.state");
+            let snapshotted = dir.path().join("snapshot.state");
+
+            t.save(&locked).await.unwrap();
+            let snapshot = t.snapshot();
+            // Changes after the snapshot don't end up in it
+            t.register_forward(orig(9, 9), fwd(99, 9));
+            snapshot.save(&snapshotted).await.unwrap();
+
+            assert_eq!(
+                std::fs::read(&locked).unwrap(),
+                std::fs::read(&snapshotted).unwrap(),
+                "{}",
+                format
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn saving_a_snapshot_updates_the_trackers_metrics() {
+        let dir = tempfile::tempdir().unwrap();
+        let t = populated();
+        let path = dir.path().join("state.json");
+
+        t.snapshot().save(&path).await.unwrap();
+        assert_eq!(
+            t.last_save().unwrap().bytes,
+            std::fs::metadata(&path).unwrap().len()
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_saves_keep_the_last_metrics() {
+        let dir = tempfile::tempdir().unwrap();
+        let t = populated();
+        t.save(&dir.path().join("state.json")).await.unwrap();
+        let first = t.last_save();
+
+        assert!(t.save(&dir.path().join("nope").join("state.json")).await.is_err());
+        assert_eq!(t.last_save(), first);
+    }
+
+    #[tokio::test]
+    async fn save_into_missing_directory_is_an_io_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("nope").join("state.json");
+        assert!(matches!(
+            DuplicateTracker::default().save(&path).await,
+            Err(TrackerError::Io { action: "write temp", .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn save_and_load_round_trip() {
         let mut t = DuplicateTracker::default();
         let o = orig(1, 100);
         let f1 = fwd(2, 200);
@@ -394,7 +3296,7 @@ mod tests {
 
         let tmp = NamedTempFile::new().unwrap();
         let path = tmp.path().to_owned();
-        t.save(&path).unwrap();
+        t.save(&path).await.unwrap();
 
         let loaded = DuplicateTracker::load(&path).unwrap();
 
@@ -406,5 +3308,59 @@ mod tests {
         assert_eq!(loaded.lookup_forward(&f2), Some(&o));
         // chat_index is rebuilt from forward_index on load
         assert!(!loaded.chat_index.is_empty());
+        // source_index is rebuilt from originals on load
+        assert_eq!(loaded.originals_for_source(1), &[o][..]);
+    }
+
+    #[tokio::test]
+    async fn state_without_read_times_expires_from_load() {
+        let (mut t, _clock) = at(1000);
+        let o = orig(1, 100);
+        t.register_forward(o.clone(), fwd(10, 1));
+        t.mark_original_read(&o);
+        t.read_at.clear();
+
+        let tmp = NamedTempFile::new().unwrap();
+        t.save(tmp.path()).await.unwrap();
+        let before = epoch_secs();
+        let loaded = DuplicateTracker::load(tmp.path()).unwrap();
+
+        // The TTL counts from the load, not from whenever it was read
+        assert!(loaded.read_at[&o] >= before);
+    }
+
+    #[test]
+    fn source_index_tracks_registered_originals() {
+        let mut t = DuplicateTracker::default();
+        let o1 = orig(1, 100);
+        let o2 = orig(1, 101);
+        let o3 = orig(2, 100);
+
+        t.register_forward(o1.clone(), fwd(10, 50));
+        // Second forward of the same original must not duplicate the entry
+        t.register_forward(o1.clone(), fwd(20, 60));
+        t.register_forward(o2.clone(), fwd(10, 51));
+        t.register_forward(o3.clone(), fwd(10, 52));
+
+        let from_one = t.originals_for_source(1);
+        assert_eq!(from_one.len(), 2);
+        assert!(from_one.contains(&o1));
+        assert!(from_one.contains(&o2));
+        assert_eq!(t.originals_for_source(2), &[o3][..]);
+        assert!(t.originals_for_source(999).is_empty());
+    }
+
+    #[test]
+    fn cleanup_prunes_source_index() {
+        let (mut t, clock) = at(0);
+        let old = orig(1, 100);
+        let recent = orig(1, 101);
+        t.register_forward(old.clone(), fwd(10, 50));
+        clock.set(2_000_000);
+        t.register_forward(recent.clone(), fwd(10, 51));
+
+        t.cleanup(1_000_000);
+
+        assert_eq!(t.originals_for_source(1), &[recent][..]);
     }
 }
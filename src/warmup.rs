@@ -0,0 +1,103 @@
+use crate::handler::Action;
+
+/// Holds back mark-read actions while the peer cache is still being built
+/// in the background, so no read is attempted against a chat that simply
+/// hasn't been cached yet. Everything else (caching peers, replies, which
+/// carry their own peer) goes through immediately.
+#[derive(Default)]
+pub struct WarmupQueue {
+    ready: bool,
+    queued: Vec<Action>,
+}
+
+impl WarmupQueue {
+    /// Whether the peer cache is complete.
+    pub fn is_ready(&self) -> bool {
+        self.ready
+    }
+
+    /// Returns the action if it can run now, or keeps it until `finish`.
+    pub fn offer(&mut self, action: Action) -> Option<Action> {
+        let marks = matches!(action, Action::MarkForwards { .. } | Action::MarkFolder { .. });
+        if self.ready || !marks {
+            return Some(action);
+        }
+        self.queued.push(action);
+        None
+    }
+
+    /// Mark the cache as complete and release everything held back, in the
+    /// order it arrived.
+    pub fn finish(&mut self) -> Vec<Action> {
+        self.ready = true;
+        std::mem::take(&mut self.queued)
+    }
+
+    /// How many actions are waiting.
+    pub fn len(&self) -> usize {
+        self.queued.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queued.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tracker::{ForwardLocation, OriginalMessageId};
+
+    fn mark(chat_id: i64) -> Action {
+        let original = OriginalMessageId {
+            peer_id: 1,
+            message_id: 100,
+        };
+        Action::MarkForwards {
+            forwards: vec![(original, ForwardLocation::new(chat_id, 5))],
+        }
+    }
+
+    fn chats(actions: &[Action]) -> Vec<i64> {
+        actions
+            .iter()
+            .map(|a| match a {
+                Action::MarkForwards { forwards } => forwards[0].1.chat_id,
+                _ => panic!("expected MarkForwards"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn marks_wait_until_the_cache_is_ready() {
+        let mut q = WarmupQueue::default();
+        assert!(q.offer(mark(10)).is_none());
+        assert!(q.offer(mark(20)).is_none());
+        assert_eq!(q.len(), 2);
+
+        let released = q.finish();
+        assert_eq!(chats(&released), vec![10, 20]);
+        assert!(q.is_ready());
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn other_actions_are_never_held() {
+        let mut q = WarmupQueue::default();
+        assert!(matches!(q.offer(Action::None), Some(Action::None)));
+        let reply = Action::Reply {
+            chat_id: 1,
+            peer_ref: None,
+            text: "hi".to_owned(),
+        };
+        assert!(matches!(q.offer(reply), Some(Action::Reply { .. })));
+        assert_eq!(q.len(), 0);
+    }
+
+    #[test]
+    fn marks_pass_through_once_ready() {
+        let mut q = WarmupQueue::default();
+        assert!(q.finish().is_empty());
+        assert_eq!(chats(&[q.offer(mark(30)).unwrap()]), vec![30]);
+    }
+}
@@ -0,0 +1,71 @@
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+/// Notices when no updates have arrived for too long, which usually means
+/// the connection stalled without erroring.
+#[derive(Debug)]
+pub struct Watchdog {
+    timeout: Duration,
+    last_activity: Instant,
+}
+
+impl Watchdog {
+    pub fn new(timeout: Duration, now: Instant) -> Self {
+        Watchdog {
+            timeout,
+            last_activity: now,
+        }
+    }
+
+    /// Record that something arrived (or that we just reconnected).
+    pub fn touch(&mut self, now: Instant) {
+        self.last_activity = now;
+    }
+
+    /// When the watchdog fires if nothing arrives before then.
+    pub fn deadline(&self) -> Instant {
+        self.last_activity + self.timeout
+    }
+
+    /// Whether it has been quiet for the whole timeout as of `now`.
+    pub fn should_reconnect(&self, now: Instant) -> bool {
+        now >= self.deadline()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn fires_only_after_a_full_quiet_timeout() {
+        let start = Instant::now();
+        let w = Watchdog::new(TIMEOUT, start);
+        assert!(!w.should_reconnect(start));
+        assert!(!w.should_reconnect(start + Duration::from_secs(59)));
+        assert!(w.should_reconnect(start + TIMEOUT));
+    }
+
+    #[test]
+    fn activity_pushes_the_deadline_back() {
+        let start = Instant::now();
+        let mut w = Watchdog::new(TIMEOUT, start);
+        w.touch(start + Duration::from_secs(50));
+        assert!(!w.should_reconnect(start + Duration::from_secs(100)));
+        assert_eq!(w.deadline(), start + Duration::from_secs(110));
+        assert!(w.should_reconnect(start + Duration::from_secs(110)));
+    }
+
+    #[test]
+    fn touching_after_a_reconnect_rearms() {
+        let start = Instant::now();
+        let mut w = Watchdog::new(TIMEOUT, start);
+        let fired_at = start + TIMEOUT;
+        assert!(w.should_reconnect(fired_at));
+        w.touch(fired_at);
+        assert!(!w.should_reconnect(fired_at + Duration::from_secs(1)));
+    }
+}
@@ -0,0 +1,434 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use serde::Serialize;
+use thiserror::Error;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::time::sleep;
+use tracing::{debug, warn};
+
+use crate::tracker::{epoch_secs, ForwardLocation, OriginalMessageId};
+
+/// Events waiting for delivery before new ones are dropped.
+const QUEUE_CAPACITY: usize = 64;
+
+/// How long one POST may take, connecting included.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Further attempts after a failed POST, and the pause before each.
+const RETRIES: u32 = 2;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// What the webhook is told about, POSTed as one JSON object each. The
+/// `event` field says which kind it is.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum WebhookEvent {
+    /// A post turned up in one more chat.
+    DuplicateDetected {
+        /// Unix time of the detection, in seconds.
+        ts: u64,
+        /// The post in its source; `peer_id` is the source channel.
+        original: OriginalMessageId,
+        /// The copy that just arrived.
+        forward: ForwardLocation,
+        /// Every chat holding a copy, the new one included, sorted.
+        chats: Vec<i64>,
+    },
+    /// A read in `chat_id` up to `max_id` is being propagated.
+    ReadPropagated {
+        ts: u64,
+        chat_id: i64,
+        max_id: i32,
+        copies: Vec<PropagatedCopy>,
+    },
+}
+
+/// One copy a read propagates to.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct PropagatedCopy {
+    pub original: OriginalMessageId,
+    pub forward: ForwardLocation,
+}
+
+impl WebhookEvent {
+    /// A detection of `forward`, timestamped now.
+    pub fn duplicate(
+        original: &OriginalMessageId,
+        forward: &ForwardLocation,
+        copies: &[ForwardLocation],
+    ) -> Self {
+        let mut chats: Vec<i64> = copies.iter().map(|f| f.chat_id).collect();
+        chats.sort_unstable();
+        chats.dedup();
+        WebhookEvent::DuplicateDetected {
+            ts: epoch_secs(),
+            original: original.clone(),
+            forward: forward.clone(),
+            chats,
+        }
+    }
+
+    /// A read propagating to `forwards`, timestamped now.
+    pub fn read(
+        chat_id: i64,
+        max_id: i32,
+        forwards: &[(OriginalMessageId, ForwardLocation)],
+    ) -> Self {
+        WebhookEvent::ReadPropagated {
+            ts: epoch_secs(),
+            chat_id,
+            max_id,
+            copies: forwards
+                .iter()
+                .map(|(original, forward)| PropagatedCopy {
+                    original: original.clone(),
+                    forward: forward.clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Which events to POST (`TG_WEBHOOK_EVENTS`), from a comma-separated list
+/// of `duplicates` and `reads`. Both by default.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WebhookEvents {
+    pub duplicates: bool,
+    pub reads: bool,
+}
+
+impl WebhookEvents {
+    fn includes(&self, event: &WebhookEvent) -> bool {
+        match event {
+            WebhookEvent::DuplicateDetected { .. } => self.duplicates,
+            WebhookEvent::ReadPropagated { .. } => self.reads,
+        }
+    }
+}
+
+impl Default for WebhookEvents {
+    fn default() -> Self {
+        WebhookEvents {
+            duplicates: true,
+            reads: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("expected a comma-separated list of duplicates and reads, got {0:?}")]
+pub struct ParseWebhookEventsError(String);
+
+impl FromStr for WebhookEvents {
+    type Err = ParseWebhookEventsError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut events = WebhookEvents {
+            duplicates: false,
+            reads: false,
+        };
+        for name in s.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            match name.to_ascii_lowercase().as_str() {
+                "duplicates" => events.duplicates = true,
+                "reads" => events.reads = true,
+                _ => return Err(ParseWebhookEventsError(s.to_owned())),
+            }
+        }
+        if !events.duplicates && !events.reads {
+            return Err(ParseWebhookEventsError(s.to_owned()));
+        }
+        Ok(events)
+    }
+}
+
+impl fmt::Display for WebhookEvents {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = [(self.duplicates, "duplicates"), (self.reads, "reads")]
+            .into_iter()
+            .filter_map(|(on, name)| on.then_some(name))
+            .collect();
+        f.write_str(&names.join(","))
+    }
+}
+
+/// Best-effort outbound webhook (`TG_WEBHOOK_URL`). `send` only queues the
+/// event; a background task POSTs it with a short timeout and a couple of
+/// retries, so a slow or dead endpoint never holds up planning. Events
+/// that don't fit in the queue are dropped.
+#[derive(Debug)]
+pub struct Webhook {
+    events: WebhookEvents,
+    queue: mpsc::Sender<WebhookEvent>,
+    /// Whether the queue was full last time, so a dead endpoint warns once
+    /// rather than for every event.
+    dropping: AtomicBool,
+}
+
+impl Webhook {
+    /// Start delivering `events` to `url`. Must be called within a Tokio
+    /// runtime, which runs the delivery task.
+    pub fn spawn(url: &str, events: WebhookEvents) -> Self {
+        Self::spawn_with(url, events, TIMEOUT, RETRY_DELAY)
+    }
+
+    fn spawn_with(
+        url: &str,
+        events: WebhookEvents,
+        timeout: Duration,
+        retry_delay: Duration,
+    ) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(timeout)
+            .build()
+            .expect("an HTTP client with only a timeout set always builds");
+        let (queue, rx) = mpsc::channel(QUEUE_CAPACITY);
+        tokio::spawn(deliver(client, url.to_owned(), rx, retry_delay));
+        Webhook {
+            events,
+            queue,
+            dropping: AtomicBool::new(false),
+        }
+    }
+
+    /// Queue `event` for delivery, unless its kind is turned off. Never
+    /// waits. Returns whether it was queued.
+    pub fn send(&self, event: WebhookEvent) -> bool {
+        if !self.events.includes(&event) {
+            return false;
+        }
+        match self.queue.try_send(event) {
+            Ok(()) => {
+                self.dropping.store(false, Ordering::Relaxed);
+                true
+            }
+            Err(TrySendError::Full(_)) => {
+                if !self.dropping.swap(true, Ordering::Relaxed) {
+                    warn!("Webhook is falling behind, dropping events until it catches up");
+                }
+                false
+            }
+            Err(TrySendError::Closed(_)) => false,
+        }
+    }
+}
+
+/// POST each queued event in turn until the `Webhook` is dropped. The URL
+/// may carry a secret token, so it is never logged.
+async fn deliver(
+    client: reqwest::Client,
+    url: String,
+    mut queue: mpsc::Receiver<WebhookEvent>,
+    retry_delay: Duration,
+) {
+    while let Some(event) = queue.recv().await {
+        for attempt in 0..=RETRIES {
+            if attempt > 0 {
+                sleep(retry_delay).await;
+            }
+            match post(&client, &url, &event).await {
+                Ok(()) => break,
+                Err(e) if attempt == RETRIES => {
+                    let e = e.without_url();
+                    warn!(error = %e, "Webhook delivery failed, dropping the event");
+                }
+                Err(e) => {
+                    let e = e.without_url();
+                    debug!(attempt, error = %e, "Webhook delivery failed, retrying");
+                }
+            }
+        }
+    }
+}
+
+async fn post(client: &reqwest::Client, url: &str, event: &WebhookEvent) -> reqwest::Result<()> {
+    client
+        .post(url)
+        .json(event)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+
+    fn original() -> OriginalMessageId {
+        OriginalMessageId {
+            peer_id: -1005,
+            message_id: 7,
+        }
+    }
+
+    /// Read one HTTP request off `stream` and return its body.
+    async fn read_body(stream: &mut TcpStream) -> String {
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        let header_end = loop {
+            let n = stream.read(&mut buf).await.unwrap();
+            assert!(n > 0, "connection closed mid-request");
+            request.extend_from_slice(&buf[..n]);
+            if let Some(i) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                break i + 4;
+            }
+        };
+        let headers = String::from_utf8_lossy(&request[..header_end]).to_ascii_lowercase();
+        let length: usize = headers
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length:"))
+            .map(|v| v.trim().parse().unwrap())
+            .unwrap_or(0);
+        while request.len() < header_end + length {
+            let n = stream.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+        }
+        String::from_utf8(request[header_end..].to_vec()).unwrap()
+    }
+
+    /// A local HTTP sink answering its requests with `statuses` in turn,
+    /// sending on each body it received.
+    async fn sink(statuses: Vec<u16>) -> (String, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let _ = tx.send(read_body(&mut stream).await);
+                let response = format!(
+                    "HTTP/1.1 {} X\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                    status
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    #[test]
+    fn payloads_serialize_with_their_kind() {
+        let copies = [
+            ForwardLocation::new(-1020, 60),
+            ForwardLocation::new(-1010, 50),
+            ForwardLocation::new(-1020, 61),
+        ];
+        let event = WebhookEvent::duplicate(&original(), &copies[2], &copies);
+        let WebhookEvent::DuplicateDetected { ts, .. } = &event else {
+            unreachable!()
+        };
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({
+                "event": "duplicate_detected",
+                "ts": ts,
+                "original": {"peer_id": -1005, "message_id": 7},
+                "forward": {"chat_id": -1020, "message_id": 61},
+                "chats": [-1020, -1010],
+            })
+        );
+
+        let event = WebhookEvent::read(-1010, 50, &[(original(), copies[0].clone())]);
+        let WebhookEvent::ReadPropagated { ts, .. } = &event else {
+            unreachable!()
+        };
+        assert_eq!(
+            serde_json::to_value(&event).unwrap(),
+            serde_json::json!({
+                "event": "read_propagated",
+                "ts": ts,
+                "chat_id": -1010,
+                "max_id": 50,
+                "copies": [{
+                    "original": {"peer_id": -1005, "message_id": 7},
+                    "forward": {"chat_id": -1020, "message_id": 60},
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn event_lists() {
+        let both = WebhookEvents::default();
+        assert_eq!("reads, duplicates".parse(), Ok(both));
+        assert_eq!(
+            "Reads".parse(),
+            Ok(WebhookEvents {
+                duplicates: false,
+                reads: true
+            })
+        );
+        assert_eq!(both.to_string(), "duplicates,reads");
+        for bad in ["", ",", "reads,marks"] {
+            assert!(bad.parse::<WebhookEvents>().is_err(), "{:?}", bad);
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_posts_are_retried() {
+        let (url, mut bodies) = sink(vec![500, 200]).await;
+        let webhook = Webhook::spawn_with(
+            &url,
+            WebhookEvents::default(),
+            Duration::from_secs(5),
+            Duration::from_millis(10),
+        );
+        let event = WebhookEvent::read(-1010, 50, &[]);
+        assert!(webhook.send(event.clone()));
+
+        let expected = serde_json::to_value(&event).unwrap();
+        for _ in 0..2 {
+            let body = bodies.recv().await.unwrap();
+            assert_eq!(serde_json::from_str::<serde_json::Value>(&body).unwrap(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn send_never_waits_on_a_stalled_endpoint() {
+        // Accepts connections but never answers them
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                held.push(stream);
+            }
+        });
+        let webhook = Webhook::spawn_with(
+            &url,
+            WebhookEvents::default(),
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+        );
+
+        let start = Instant::now();
+        let queued = (0..QUEUE_CAPACITY * 2)
+            .filter(|_| webhook.send(WebhookEvent::read(-1010, 50, &[])))
+            .count();
+        assert!(start.elapsed() < Duration::from_secs(1));
+        // At most one is already out for delivery
+        assert!((QUEUE_CAPACITY..=QUEUE_CAPACITY + 1).contains(&queued), "{}", queued);
+    }
+
+    #[tokio::test]
+    async fn events_turned_off_are_not_sent() {
+        let (url, mut bodies) = sink(vec![200]).await;
+        let reads_only = WebhookEvents {
+            duplicates: false,
+            reads: true,
+        };
+        let webhook = Webhook::spawn(&url, reads_only);
+        let copy = ForwardLocation::new(-1010, 50);
+        assert!(!webhook.send(WebhookEvent::duplicate(&original(), &copy, &[])));
+        assert!(webhook.send(WebhookEvent::read(-1010, 50, &[])));
+
+        let body = bodies.recv().await.unwrap();
+        assert!(body.contains("read_propagated"), "{}", body);
+    }
+}